@@ -0,0 +1,19 @@
+//! Feeds arbitrary ICMP payloads to the packet parser, which must never panic.
+//!
+//! Run with `cargo +nightly fuzz run from_bytes`; libFuzzer's `-timeout` and `-malloc_limit_mb` options catch slow or excessively allocating inputs.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use pingxelflut::format::{CoordinateMode, Packet};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(packet) = Packet::from_bytes(data) {
+        // A parsed packet never holds more than its input, so its size is bounded by the payload size.
+        assert!(packet.to_bytes().len() <= data.len());
+    }
+    if let Ok(packet) = Packet::from_bytes_with_mode(data, CoordinateMode::Compact) {
+        assert!(packet.to_bytes_with_mode(CoordinateMode::Compact).len() <= data.len());
+    }
+    let _ = Packet::from_bytes_bounded(data, 1920, 1080);
+});
@@ -0,0 +1,130 @@
+//! Packet encoding and decoding benchmarks; run with `cargo bench -p pingxelflut`.
+
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use pingxelflut::format::{Color, CoordinateMode, Packet};
+
+/// Packets of the commonly used types, with realistic contents.
+fn packets() -> Vec<(&'static str, Packet)> {
+    let color = Color::from_rgb([0x12, 0x34, 0x56]);
+    vec![
+        ("size_request", Packet::SizeRequest { canvas_id: 0 }),
+        (
+            "size_response",
+            Packet::SizeResponse {
+                width: 1920,
+                height: 1080,
+                version: 1,
+                capabilities: 0,
+            },
+        ),
+        (
+            "set_pixel",
+            Packet::SetPixel {
+                x: 12,
+                y: 34,
+                color,
+                canvas_id: 0,
+            },
+        ),
+        (
+            "set_pixel_rgba",
+            Packet::SetPixelRgba {
+                x: 12,
+                y: 34,
+                color: Color::from_rgba([0x12, 0x34, 0x56, 0x78]),
+            },
+        ),
+        (
+            "set_gray",
+            Packet::SetGray {
+                x: 12,
+                y: 34,
+                value: 0x80,
+            },
+        ),
+        (
+            "fill_rect",
+            Packet::FillRect {
+                x: 12,
+                y: 34,
+                width: 56,
+                height: 78,
+                color,
+            },
+        ),
+        (
+            "set_row_rle",
+            Packet::SetRowRle {
+                x: 12,
+                y: 34,
+                runs: (0..Packet::MAX_RLE_RUNS as u16)
+                    .map(|i| (i % 8 + 1, color))
+                    .collect(),
+            },
+        ),
+        (
+            "draw_text",
+            Packet::DrawText {
+                x: 12,
+                y: 34,
+                color,
+                text: "Team Pingxelflut: 1337 points".to_owned(),
+            },
+        ),
+        ("get_pixel", Packet::GetPixel { x: 12, y: 34 }),
+        (
+            "pixel_response",
+            Packet::PixelResponse {
+                x: 12,
+                y: 34,
+                color,
+            },
+        ),
+        (
+            "set_pixels",
+            Packet::SetPixels {
+                pixels: (0..Packet::MAX_BATCH_SIZE as u16)
+                    .map(|i| (i, i, color))
+                    .collect(),
+            },
+        ),
+        ("clear", Packet::Clear),
+    ]
+}
+
+fn from_bytes(c: &mut Criterion) {
+    let packets = packets();
+    let mut group = c.benchmark_group("from_bytes");
+    for (name, packet) in &packets {
+        let bytes = packet.to_bytes();
+        group.bench_with_input(BenchmarkId::from_parameter(name), &bytes, |b, bytes| {
+            b.iter(|| Packet::from_bytes(black_box(bytes)).unwrap())
+        });
+    }
+    let compact = packets[2].1.to_bytes_with_mode(CoordinateMode::Compact);
+    group.bench_with_input(
+        BenchmarkId::new("compact", "set_pixel"),
+        &compact,
+        |b, bytes| {
+            b.iter(|| {
+                Packet::from_bytes_with_mode(black_box(bytes), CoordinateMode::Compact).unwrap()
+            })
+        },
+    );
+    group.finish();
+}
+
+fn to_bytes(c: &mut Criterion) {
+    let mut group = c.benchmark_group("to_bytes");
+    for (name, packet) in &packets() {
+        group.bench_with_input(BenchmarkId::from_parameter(name), packet, |b, packet| {
+            b.iter(|| black_box(packet).to_bytes())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, from_bytes, to_bytes);
+criterion_main!(benches);
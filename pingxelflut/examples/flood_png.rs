@@ -0,0 +1,136 @@
+//! Flood a PNG image onto a Pingxelflut server, for demos and load testing.
+//!
+//! Usage: `cargo run -p pingxelflut --example flood_png -- --target <address> --image <path>`
+//!
+//! Sending ICMP packets requires raw socket privileges, see [`pingxelflut::client`].
+
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::thread;
+
+use clap::Parser;
+use image::{Rgba, RgbaImage};
+use pingxelflut::client::Client;
+use pingxelflut::format::Color;
+
+/// Flood a PNG image onto a Pingxelflut server.
+#[derive(Parser, Debug)]
+struct Arguments {
+    /// Server to send the image to.
+    #[arg(short, long, value_name = "ADDRESS")]
+    target: IpAddr,
+    /// PNG image to send.
+    #[arg(short, long, value_name = "IMAGE")]
+    image: PathBuf,
+    /// Canvas X position of the image’s left edge.
+    #[arg(long, value_name = "X", default_value_t = 0)]
+    x_offset: u32,
+    /// Canvas Y position of the image’s top edge.
+    #[arg(long, value_name = "Y", default_value_t = 0)]
+    y_offset: u32,
+    /// Number of threads sending pixels in parallel.
+    #[arg(long, value_name = "THREADS", default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    threads: u32,
+    /// Color of the image that is treated as transparent and not sent, as `#rrggbb`.
+    #[arg(long, value_name = "COLOR")]
+    transparent_color: Option<Color>,
+    /// Send the image only once instead of repeating it forever.
+    #[arg(long)]
+    once: bool,
+}
+
+/// The pixels of an image placed at an offset on a canvas of the given size, as canvas coordinates and colors.
+/// Transparent pixels, pixels of the transparent color and pixels that end up outside the canvas are skipped;
+/// all other pixels are made opaque.
+fn canvas_pixels(
+    image: &RgbaImage,
+    (x_offset, y_offset): (u32, u32),
+    (width, height): (u16, u16),
+    transparent_color: Option<Color>,
+) -> Vec<(u16, u16, Color)> {
+    let is_keyed_out = |pixel: &Rgba<u8>| {
+        transparent_color.is_some_and(|color| pixel.0[..3] == [color.red, color.green, color.blue])
+    };
+    image
+        .enumerate_pixels()
+        .filter(|(_, _, pixel)| pixel.0[3] != 0 && !is_keyed_out(pixel))
+        .filter_map(|(x, y, pixel)| {
+            let x = u16::try_from(x + x_offset).ok()?;
+            let y = u16::try_from(y + y_offset).ok()?;
+            (x < width && y < height).then_some((
+                x,
+                y,
+                Color::from_rgb([pixel.0[0], pixel.0[1], pixel.0[2]]),
+            ))
+        })
+        .collect()
+}
+
+fn main() -> anyhow::Result<()> {
+    let arguments = Arguments::parse();
+    let image = image::open(&arguments.image)?.into_rgba8();
+    let (width, height) = Client::new().request_size(arguments.target)?;
+    println!("canvas of {} is {}x{}", arguments.target, width, height);
+
+    let pixels = canvas_pixels(
+        &image,
+        (arguments.x_offset, arguments.y_offset),
+        (width, height),
+        arguments.transparent_color,
+    );
+    println!("sending {} pixels", pixels.len());
+
+    let chunk_size = pixels.len().div_ceil(arguments.threads as usize).max(1);
+    let arguments = &arguments;
+    thread::scope(|scope| {
+        for chunk in pixels.chunks(chunk_size) {
+            scope.spawn(move || {
+                let mut client = Client::new();
+                loop {
+                    for &(x, y, color) in chunk {
+                        if let Err(why) = client.set_pixel(arguments.target, x, y, color) {
+                            eprintln!("error while sending pixel: {}", why);
+                        }
+                    }
+                    if arguments.once {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skips_transparent_and_off_canvas_pixels() {
+        let mut image = RgbaImage::new(3, 2);
+        image.put_pixel(0, 0, Rgba([0x12, 0x34, 0x56, 0x80]));
+        // Keyed out by the transparent color, and off the canvas.
+        image.put_pixel(1, 0, Rgba([0xff, 0x00, 0xff, 0xff]));
+        image.put_pixel(2, 0, Rgba([0xff, 0xff, 0xff, 0xff]));
+        image.put_pixel(0, 1, Rgba([0xaa, 0xbb, 0xcc, 0xff]));
+        // Close to the transparent color, but not exactly it.
+        image.put_pixel(1, 1, Rgba([0xfe, 0x00, 0xff, 0xff]));
+        // The rest of the image is fully transparent.
+
+        let pixels = canvas_pixels(
+            &image,
+            (10, 20),
+            (12, 22),
+            Some(Color::from_rgb([0xff, 0x00, 0xff])),
+        );
+        assert_eq!(
+            pixels,
+            [
+                (10, 20, Color::from_rgb([0x12, 0x34, 0x56])),
+                (10, 21, Color::from_rgb([0xaa, 0xbb, 0xcc])),
+                (11, 21, Color::from_rgb([0xfe, 0x00, 0xff])),
+            ]
+        );
+    }
+}
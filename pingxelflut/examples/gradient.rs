@@ -0,0 +1,37 @@
+//! Draw a color gradient across the whole canvas of a Pingxelflut server.
+//!
+//! Usage: `cargo run -p pingxelflut --example gradient -- <server address>`
+//!
+//! Sending ICMP packets requires raw socket privileges, see [`pingxelflut::client`].
+
+use std::env;
+use std::io;
+use std::net::IpAddr;
+
+use pingxelflut::client::Client;
+use pingxelflut::format::Color;
+
+fn main() -> io::Result<()> {
+    let target: IpAddr = env::args()
+        .nth(1)
+        .and_then(|address| address.parse().ok())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "usage: gradient <server address>",
+            )
+        })?;
+
+    let mut client = Client::new();
+    let (width, height) = client.request_size(target)?;
+    println!("drawing a gradient onto {}x{} canvas", width, height);
+
+    for y in 0..height {
+        for x in 0..width {
+            let red = (x as u32 * 0xff / width as u32) as u8;
+            let green = (y as u32 * 0xff / height as u32) as u8;
+            client.set_pixel(target, x, y, Color::from_rgb([red, green, 0x80]))?;
+        }
+    }
+    Ok(())
+}
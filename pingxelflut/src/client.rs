@@ -0,0 +1,174 @@
+//! Sending side of the Pingxelflut protocol.
+//!
+//! Packets are sent via raw ICMP sockets, which need special privileges:
+//! on Linux, the program needs the `cap_net_raw` capability (for example via `setcap cap_net_raw=eip <binary>`) or has to run as root.
+//! On other systems, administrator privileges are usually required.
+//! Without these privileges, unprivileged ICMP datagram sockets are used where the system offers them, see [`SendMode`].
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+use socket2::Socket;
+
+use crate::format::{Color, CoordinateMode, Packet};
+use crate::icmp::{
+    read_icmp_packets_until, EchoDirection, Icmp, SendMode, ECHO_REPLY_V4, ECHO_REPLY_V6,
+    ICMP_HEADER_SIZE,
+};
+
+/// A Pingxelflut client, which sends packets to servers as ICMP echo requests.
+///
+/// All packets are encoded with [`Packet::to_bytes_with_mode`], so clients always speak the same protocol as the server in this crate.
+#[derive(Debug, Clone)]
+pub struct Client {
+    /// Echo identifier of all sent packets.
+    identifier: u16,
+    /// Echo sequence number of the next sent packet.
+    sequence_number: u16,
+    /// Kind of socket to send with, chosen automatically if unset.
+    send_mode: Option<SendMode>,
+    /// Coordinate mode of set pixel packets, see [`Client::request_size`].
+    coordinate_mode: CoordinateMode,
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Client {
+    /// Create a client whose echo identifier is derived from the process ID, as `ping` does.
+    pub fn new() -> Self {
+        Self::with_identifier(std::process::id() as u16)
+    }
+
+    /// Create a client with a specific echo identifier.
+    pub fn with_identifier(identifier: u16) -> Self {
+        Self {
+            identifier,
+            sequence_number: 0,
+            send_mode: None,
+            coordinate_mode: CoordinateMode::Full,
+        }
+    }
+
+    /// Always send with the given kind of socket.
+    /// By default, unprivileged datagram sockets are used where raw sockets are not permitted, see [`SendMode`].
+    pub fn with_send_mode(mut self, send_mode: SendMode) -> Self {
+        self.send_mode = Some(send_mode);
+        self
+    }
+
+    /// Send set pixel packets in the given coordinate mode, which the server must support.
+    /// By default, full coordinates are used until a size response advertises compact ones.
+    pub fn with_coordinate_mode(mut self, coordinate_mode: CoordinateMode) -> Self {
+        self.coordinate_mode = coordinate_mode;
+        self
+    }
+
+    /// Send any packet to a server.
+    /// Set pixels whose coordinates do not fit the coordinate mode are sent with full coordinates.
+    ///
+    /// Returns the socket used for sending so that responses can be received.
+    pub fn send(&mut self, target: IpAddr, packet: &Packet) -> io::Result<Socket> {
+        let coordinate_mode = match packet {
+            &Packet::SetPixel { x, y, .. } if x > 0xff || y > 0xff => CoordinateMode::Full,
+            _ => self.coordinate_mode,
+        };
+        let mut request = Icmp::new(
+            SocketAddr::new(target, 0),
+            self.identifier,
+            EchoDirection::Request,
+        );
+        request.set_sequence_number(self.sequence_number);
+        request.set_payload(packet.to_bytes_with_mode(coordinate_mode));
+        if let Some(send_mode) = self.send_mode {
+            request.set_send_mode(send_mode);
+        }
+        let socket = request.send()?;
+        self.sequence_number = self.sequence_number.wrapping_add(1);
+        Ok(socket)
+    }
+
+    /// Set a single pixel on a server.
+    pub fn set_pixel(&mut self, target: IpAddr, x: u16, y: u16, color: Color) -> io::Result<()> {
+        self.send(
+            target,
+            &Packet::SetPixel {
+                x,
+                y,
+                color,
+                canvas_id: 0,
+            },
+        )?;
+        Ok(())
+    }
+
+    /// Query the canvas size of a server.
+    /// This blocks until the server responds.
+    ///
+    /// If the server advertises compact coordinates, they are used for all further set pixel packets.
+    pub fn request_size(&mut self, target: IpAddr) -> io::Result<(u16, u16)> {
+        let mut socket = self.send(target, &Packet::SizeRequest { canvas_id: 0 })?;
+        let response = receive(&mut socket, target.is_ipv6(), |packet| {
+            matches!(packet, Packet::SizeResponse { .. })
+        })?;
+        match response {
+            Packet::SizeResponse {
+                width,
+                height,
+                capabilities,
+                ..
+            } => {
+                self.coordinate_mode = CoordinateMode::from_capabilities(capabilities);
+                Ok((width, height))
+            }
+            _ => Err(io::Error::other("unexpected packet type")),
+        }
+    }
+
+    /// Query the current color of a pixel on a server.
+    /// This blocks until the server responds, which it never does for pixels outside its canvas.
+    pub fn get_pixel(&mut self, target: IpAddr, x: u16, y: u16) -> io::Result<Color> {
+        let mut socket = self.send(target, &Packet::GetPixel { x, y })?;
+        let response = receive(
+            &mut socket,
+            target.is_ipv6(),
+            |packet| matches!(packet, &Packet::PixelResponse { x: response_x, y: response_y, .. } if (response_x, response_y) == (x, y)),
+        )?;
+        match response {
+            Packet::PixelResponse { color, .. } => Ok(color),
+            _ => Err(io::Error::other("unexpected packet type")),
+        }
+    }
+}
+
+/// Receive echo replies until one carries a Pingxelflut packet matching the condition.
+/// Replies with other or malformed payloads are skipped.
+fn receive(
+    socket: &mut Socket,
+    is_ipv6: bool,
+    condition: impl Fn(&Packet) -> bool,
+) -> io::Result<Packet> {
+    let echo_reply = if is_ipv6 {
+        ECHO_REPLY_V6
+    } else {
+        ECHO_REPLY_V4
+    };
+    let parse = |buffer: &[u8]| {
+        buffer
+            .starts_with(&[echo_reply, 0])
+            .then(|| Packet::from_bytes(buffer.get(ICMP_HEADER_SIZE..)?).ok())
+            .flatten()
+            .filter(&condition)
+    };
+    let raw_response = read_icmp_packets_until(socket, is_ipv6, |buffer| parse(buffer).is_some())?;
+    // The socket may be closed before a matching reply arrives.
+    parse(&raw_response).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "no matching response received",
+        )
+    })
+}
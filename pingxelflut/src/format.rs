@@ -1,40 +1,832 @@
 //! Pingxelflut packet format structures, most important of which the [`Packet`] enum.
 //!
 //! Refer to the [README](../../README.md) for the protocol specification.
+//! All multi-byte values, such as coordinates and sizes, are encoded in network order (big endian).
+
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use miniz_oxide::inflate::TINFLStatus;
+
+/// The protocol version implemented by this crate, sent as the first byte of every packet.
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// Capability flag in size responses: the server accepts set pixel packets with compact coordinates, see [`CoordinateMode::Compact`].
+pub const CAPABILITY_COMPACT_COORDINATES: u8 = 0x01;
+
+/// How the coordinates of set pixel packets (type `cc`) are encoded.
+///
+/// Both sides must agree on the mode, as the packet type is the same: a server advertises compact coordinates with
+/// [`CAPABILITY_COMPACT_COORDINATES`] in its size response, and only for canvases whose coordinates fit in a byte.
+/// All other packets always use full coordinates.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CoordinateMode {
+    /// Coordinates are unsigned 16-bit integers.
+    #[default]
+    Full,
+    /// Coordinates are single bytes, for canvases of up to 256x256 pixels.
+    Compact,
+}
+
+impl CoordinateMode {
+    /// Whether all coordinates of a canvas of the given size fit in compact coordinates.
+    pub fn fits_compact(width: u16, height: u16) -> bool {
+        width <= 256 && height <= 256
+    }
+
+    /// The mode to send with, given the capabilities of a size response.
+    pub fn from_capabilities(capabilities: u8) -> Self {
+        if capabilities & CAPABILITY_COMPACT_COORDINATES != 0 {
+            CoordinateMode::Compact
+        } else {
+            CoordinateMode::Full
+        }
+    }
+
+    /// The other mode, which a packet of mismatched length was presumably encoded with.
+    fn other(self) -> Self {
+        match self {
+            CoordinateMode::Full => CoordinateMode::Compact,
+            CoordinateMode::Compact => CoordinateMode::Full,
+        }
+    }
+}
+
+impl Display for CoordinateMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CoordinateMode::Full => write!(f, "full"),
+            CoordinateMode::Compact => write!(f, "compact"),
+        }
+    }
+}
+
+/// Compression level used for compressed rectangle packets, trading speed for the most pixels per packet.
+const COMPRESSION_LEVEL: u8 = 9;
 
 /// A Pingxelflut packet.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Packet {
     /// A size request, type `aa`.
-    SizeRequest,
+    /// Servers with several canvases answer with the size of the requested one; the canvas ID is only transmitted if it is not 0.
+    SizeRequest { canvas_id: u8 },
     /// A size response, type `bb`.
-    SizeResponse { width: u16, height: u16 },
-    /// A pixel set request, type `cc`
-    SetPixel { x: u16, y: u16, color: Color },
+    /// Also carries the protocol version supported by the server, and its capability flags such as
+    /// [`CAPABILITY_COMPACT_COORDINATES`]; the flags are only transmitted if any is set.
+    SizeResponse {
+        width: u16,
+        height: u16,
+        version: u8,
+        capabilities: u8,
+    },
+    /// A pixel set request, type `cc` for canvas 0 and type `cd` for other canvases.
+    SetPixel {
+        x: u16,
+        y: u16,
+        color: Color,
+        canvas_id: u8,
+    },
+    /// A pixel set request that is alpha-blended over the existing pixel, type `dd`.
+    /// The color is always transmitted with an alpha value.
+    SetPixelRgba { x: u16, y: u16, color: Color },
+    /// A pixel color request, type `ee`.
+    GetPixel { x: u16, y: u16 },
+    /// A pixel color response, type `ff`.
+    PixelResponse { x: u16, y: u16, color: Color },
+    /// A request for the colors of a rectangle, type `e1`.
+    /// The area is limited to [`Packet::MAX_GET_RECT_AREA`] pixels to limit the traffic a single request can cause.
+    GetRect {
+        x: u16,
+        y: u16,
+        width: u16,
+        height: u16,
+    },
+    /// The colors of a rectangle, type `f1`, in rows from top to bottom.
+    /// Colors are always transmitted without alpha, and at most [`Packet::MAX_RECT_RESPONSE_PIXELS`] pixels fit into one packet.
+    /// Larger rectangles are answered with several packets, see [`Packet::rect_responses`].
+    RectResponse {
+        x: u16,
+        y: u16,
+        width: u16,
+        height: u16,
+        pixels: Vec<Color>,
+    },
+    /// A request for a downscaled image of the whole canvas, type `e2`, fitting within `max_dim` pixels in both
+    /// dimensions. Servers cap the size at [`Packet::MAX_THUMBNAIL_SIZE`] to limit the traffic a single request can cause.
+    GetThumbnail { max_dim: u16 },
+    /// Rows of the colors of a thumbnail of `width` by `height` pixels, type `f2`, starting at row `y`.
+    /// Colors are always transmitted without alpha, and at most [`Packet::MAX_RECT_RESPONSE_PIXELS`] pixels fit into
+    /// one packet, so thumbnails are answered with several packets, see [`Packet::thumbnail_responses`].
+    ThumbnailResponse {
+        width: u16,
+        height: u16,
+        y: u16,
+        rows: u16,
+        pixels: Vec<Color>,
+    },
+    /// A batch of pixel set requests, type `ca`.
+    /// Colors are always transmitted without alpha, and at most [`Packet::MAX_BATCH_SIZE`] pixels fit into one packet.
+    SetPixels { pixels: Vec<(u16, u16, Color)> },
+    /// A grayscale pixel set request, type `cb`.
+    /// The single intensity value is used for all three color channels, which makes this more compact than [`Packet::SetPixel`].
+    SetGray { x: u16, y: u16, value: u8 },
+    /// A request to fill a rectangle with one color, type `c2`.
+    /// The color is always transmitted without alpha, and the area is limited to [`Packet::MAX_FILL_AREA`] pixels.
+    FillRect {
+        x: u16,
+        y: u16,
+        width: u16,
+        height: u16,
+        color: Color,
+    },
+    /// A horizontal row of color runs starting at a pixel, type `c3`.
+    /// Each run paints `count` pixels to the right with one color; colors are always transmitted without alpha.
+    /// At most [`Packet::MAX_RLE_RUNS`] runs fit into one packet, and the row must end within the coordinate range.
+    SetRowRle {
+        x: u16,
+        y: u16,
+        runs: Vec<(u16, Color)>,
+    },
+    /// A request to draw a line of text, type `c4`, with its top left corner at the given pixel.
+    /// The server renders the text with a small bitmap font; the text is at most [`Packet::MAX_TEXT_LENGTH`] bytes of UTF-8, and the color is always transmitted without alpha.
+    DrawText {
+        x: u16,
+        y: u16,
+        color: Color,
+        text: String,
+    },
+    /// A request to draw a circle around a center pixel, type `c5`, either as an outline or filled.
+    /// The color is always transmitted without alpha, and the radius is at most [`Packet::MAX_CIRCLE_RADIUS`].
+    DrawCircle {
+        cx: u16,
+        cy: u16,
+        r: u16,
+        color: Color,
+        fill: bool,
+    },
+    /// A request to draw a one pixel wide line between two pixels, including both, type `c6`.
+    /// The color is always transmitted without alpha.
+    DrawLine {
+        x0: u16,
+        y0: u16,
+        x1: u16,
+        y1: u16,
+        color: Color,
+    },
+    /// A rectangle of pixels compressed with zlib, type `c7`, for uploading images efficiently.
+    /// The data decompresses to the RGB colors of the rectangle in rows from top to bottom, see [`Packet::decompress_rect`].
+    /// The area is limited to [`Packet::MAX_COMPRESSED_RECT_AREA`] pixels, and the data to [`Packet::MAX_COMPRESSED_DATA_SIZE`] bytes.
+    SetRectCompressed {
+        x: u16,
+        y: u16,
+        width: u16,
+        height: u16,
+        data: Vec<u8>,
+    },
+    /// A request to set a pixel only if it currently has the expected color, type `c8`, so that cooperating clients
+    /// do not overwrite each other. Both colors are always transmitted without alpha.
+    SetPixelIfEqual {
+        x: u16,
+        y: u16,
+        expected: Color,
+        color: Color,
+    },
+    /// A request to clear the whole canvas, type `c0`.
+    /// Servers only accept this from administrators.
+    Clear,
 }
 
 impl Packet {
     pub const SIZE_REQUEST_ID: u8 = 0xaa;
     pub const SIZE_RESPONSE_ID: u8 = 0xbb;
     pub const SET_PIXEL_ID: u8 = 0xcc;
+    pub const SET_PIXEL_ON_CANVAS_ID: u8 = 0xcd;
+    pub const SET_PIXEL_RGBA_ID: u8 = 0xdd;
+    pub const GET_PIXEL_ID: u8 = 0xee;
+    pub const PIXEL_RESPONSE_ID: u8 = 0xff;
+    pub const GET_RECT_ID: u8 = 0xe1;
+    pub const RECT_RESPONSE_ID: u8 = 0xf1;
+    pub const GET_THUMBNAIL_ID: u8 = 0xe2;
+    pub const THUMBNAIL_RESPONSE_ID: u8 = 0xf2;
+    pub const SET_PIXELS_ID: u8 = 0xca;
+    pub const CLEAR_ID: u8 = 0xc0;
+    pub const SET_GRAY_ID: u8 = 0xcb;
+    pub const FILL_RECT_ID: u8 = 0xc2;
+    pub const SET_ROW_RLE_ID: u8 = 0xc3;
+    pub const DRAW_TEXT_ID: u8 = 0xc4;
+    pub const DRAW_CIRCLE_ID: u8 = 0xc5;
+    pub const DRAW_LINE_ID: u8 = 0xc6;
+    pub const SET_RECT_COMPRESSED_ID: u8 = 0xc7;
+    pub const SET_PIXEL_IF_EQUAL_ID: u8 = 0xc8;
+
+    // Packet sizes below exclude the version byte.
+
+    /// Size of a size response packet, including the type byte.
+    pub const SIZE_RESPONSE_SIZE: usize = 6;
+    /// Minimum size of a set pixel packet (with an RGB color), including the type byte.
+    pub const SET_PIXEL_MIN_SIZE: usize = 8;
+    /// Minimum size of a set pixel packet with compact coordinates (with an RGB color), including the type byte.
+    pub const SET_PIXEL_COMPACT_MIN_SIZE: usize = 6;
+    /// Minimum size of a set pixel packet for a specific canvas (with an RGB color), including the type byte.
+    pub const SET_PIXEL_ON_CANVAS_MIN_SIZE: usize = 9;
+    /// Size of an RGBA set pixel packet, including the type byte.
+    pub const SET_PIXEL_RGBA_SIZE: usize = 9;
+    /// Size of a get pixel packet, including the type byte.
+    pub const GET_PIXEL_SIZE: usize = 5;
+    /// Minimum size of a pixel response packet (with an RGB color), including the type byte.
+    pub const PIXEL_RESPONSE_MIN_SIZE: usize = 8;
+
+    /// Size of a grayscale set pixel packet, including the type byte.
+    pub const SET_GRAY_SIZE: usize = 6;
+
+    /// Size of a rectangle fill packet, including the type byte.
+    pub const FILL_RECT_SIZE: usize = 12;
+    /// Maximum number of pixels filled by a single rectangle fill packet, so that one packet cannot stall the server.
+    pub const MAX_FILL_AREA: usize = 1 << 16;
+
+    /// Size of the fixed part of a run-length encoded row packet (type, position and run count), including the type byte.
+    pub const SET_ROW_RLE_HEADER_SIZE: usize = 6;
+    /// Size of a single run in a run-length encoded row packet: pixel count and RGB color.
+    pub const SET_ROW_RLE_RECORD_SIZE: usize = 5;
+    /// Maximum number of runs in a run-length encoded row packet, which keeps it smaller than a full batched set pixel packet.
+    pub const MAX_RLE_RUNS: usize = 200;
+
+    /// Size of the fixed part of a draw text packet (type, position, RGB color and text length), including the type byte.
+    pub const DRAW_TEXT_HEADER_SIZE: usize = 9;
+    /// Maximum length of the text in a draw text packet in bytes.
+    pub const MAX_TEXT_LENGTH: usize = 64;
+
+    /// Size of a draw circle packet, including the type byte.
+    pub const DRAW_CIRCLE_SIZE: usize = 11;
+    /// Maximum radius of a circle, which keeps its bounding square within [`Packet::MAX_FILL_AREA`] pixels.
+    pub const MAX_CIRCLE_RADIUS: u16 = 127;
+
+    /// Size of a draw line packet, including the type byte.
+    pub const DRAW_LINE_SIZE: usize = 12;
+
+    /// Size of the fixed part of a compressed rectangle packet (type, rectangle and data length), including the type byte.
+    pub const SET_RECT_COMPRESSED_HEADER_SIZE: usize = 11;
+    /// Maximum number of pixels in a compressed rectangle packet, which also caps the decompressed size to guard against compression bombs.
+    pub const MAX_COMPRESSED_RECT_AREA: usize = 1 << 16;
+    /// Maximum size of the compressed data in a compressed rectangle packet, so that it is no larger than a full batched set pixel packet.
+    pub const MAX_COMPRESSED_DATA_SIZE: usize = Self::SET_PIXELS_HEADER_SIZE
+        + Self::MAX_BATCH_SIZE * Self::SET_PIXELS_RECORD_SIZE
+        - Self::SET_RECT_COMPRESSED_HEADER_SIZE;
+
+    /// Size of a conditional set pixel packet, including the type byte.
+    pub const SET_PIXEL_IF_EQUAL_SIZE: usize = 11;
+
+    /// Size of a rectangle request packet, including the type byte.
+    pub const GET_RECT_SIZE: usize = 9;
+    /// Maximum number of pixels requested by a single rectangle request packet.
+    /// Together with [`Packet::MAX_RECT_RESPONSE_PIXELS`], this limits a request to three response packets.
+    pub const MAX_GET_RECT_AREA: usize = 1024;
+    /// Size of the fixed part of a rectangle response packet (type and rectangle), including the type byte.
+    pub const RECT_RESPONSE_HEADER_SIZE: usize = 9;
+    /// Size of a single RGB color in a rectangle response packet.
+    pub const RECT_RESPONSE_RECORD_SIZE: usize = 3;
+    /// Maximum number of pixels in a single rectangle response packet.
+    pub const MAX_RECT_RESPONSE_PIXELS: usize = 400;
+
+    /// Size of a thumbnail request packet, including the type byte.
+    pub const GET_THUMBNAIL_SIZE: usize = 3;
+    /// Largest width and height of a thumbnail, so that it has no more pixels than [`Packet::MAX_GET_RECT_AREA`].
+    pub const MAX_THUMBNAIL_SIZE: u16 = 32;
+    /// Size of the fixed part of a thumbnail response packet (type, thumbnail size and rows), including the type byte.
+    pub const THUMBNAIL_RESPONSE_HEADER_SIZE: usize = 9;
+
+    /// Size of the fixed part of a batched set pixel packet (type and count), including the type byte.
+    pub const SET_PIXELS_HEADER_SIZE: usize = 2;
+    /// Size of a single pixel record in a batched set pixel packet: coordinates and RGB color.
+    pub const SET_PIXELS_RECORD_SIZE: usize = 7;
+    /// Maximum number of pixels in a batched set pixel packet.
+    /// This keeps the largest batch packet within the payload of an unfragmented ICMP packet on a standard 1500-byte MTU link, for both IPv4 and IPv6.
+    pub const MAX_BATCH_SIZE: usize = 200;
+
+    /// Largest size of any encoded packet, including the version byte.
+    pub const MAX_SIZE: usize =
+        1 + Self::SET_PIXELS_HEADER_SIZE + Self::MAX_BATCH_SIZE * Self::SET_PIXELS_RECORD_SIZE;
+
+    /// Parse a packet like [`Packet::from_bytes`], additionally rejecting packets that set pixels outside a canvas of the given size.
+    ///
+    /// Only packets that set a single pixel are checked.
+    /// Batches, rows and rectangle fills that are only partly on the canvas are left to the server to clip,
+    /// so that their pixels on the canvas are still drawn;
+    /// set pixel packets for other canvases than canvas 0, whose sizes may differ, are not checked either.
+    pub fn from_bytes_bounded(bytes: &[u8], width: u16, height: u16) -> Result<Self, ParseError> {
+        Self::from_bytes_bounded_with_mode(bytes, CoordinateMode::Full, width, height)
+    }
+
+    /// Parse a packet like [`Packet::from_bytes_bounded`], with set pixel packets in the given coordinate mode,
+    /// see [`Packet::from_bytes_with_mode`].
+    pub fn from_bytes_bounded_with_mode(
+        bytes: &[u8],
+        mode: CoordinateMode,
+        width: u16,
+        height: u16,
+    ) -> Result<Self, ParseError> {
+        let packet = Self::from_bytes_with_mode(bytes, mode)?;
+        let check = |x: u16, y: u16| {
+            if x < width && y < height {
+                Ok(())
+            } else {
+                Err(ParseError::OutOfBounds { x, y })
+            }
+        };
+        match &packet {
+            &Self::SetPixel {
+                x, y, canvas_id: 0, ..
+            }
+            | &Self::SetPixelRgba { x, y, .. }
+            | &Self::SetGray { x, y, .. } => check(x, y)?,
+            _ => {}
+        }
+        Ok(packet)
+    }
 
     /// Parse a packet from the start of the provided binary representation.
-    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
-        let kind = bytes[0];
+    /// Multi-byte values are read in network order (big endian).
+    ///
+    /// Packets with a protocol version other than [`PROTOCOL_VERSION`] are rejected.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ParseError> {
+        Self::from_bytes_with_mode(bytes, CoordinateMode::Full)
+    }
+
+    /// Parse a packet like [`Packet::from_bytes`], with set pixel packets (type `cc`) in the given coordinate mode.
+    ///
+    /// The payload length of a set pixel packet tells both modes apart, so a packet in the other mode is rejected
+    /// with [`ParseError::CoordinateModeMismatch`].
+    pub fn from_bytes_with_mode(bytes: &[u8], mode: CoordinateMode) -> Result<Self, ParseError> {
+        let version = *bytes.first().ok_or(ParseError::Empty)?;
+        if version != PROTOCOL_VERSION {
+            return Err(ParseError::UnsupportedVersion(version));
+        }
+        let body = &bytes[1..];
+        if body.first() == Some(&Self::SET_PIXEL_ID) {
+            let (expected_size, other_size) = match mode {
+                CoordinateMode::Full => {
+                    (Self::SET_PIXEL_MIN_SIZE, Self::SET_PIXEL_COMPACT_MIN_SIZE)
+                }
+                CoordinateMode::Compact => {
+                    (Self::SET_PIXEL_COMPACT_MIN_SIZE, Self::SET_PIXEL_MIN_SIZE)
+                }
+            };
+            // RGB and RGBA colors of the other mode.
+            if body.len() == other_size || body.len() == other_size + 1 {
+                return Err(ParseError::CoordinateModeMismatch {
+                    expected: mode,
+                    found: mode.other(),
+                });
+            }
+            if mode == CoordinateMode::Compact {
+                if body.len() < expected_size {
+                    return Err(ParseError::TruncatedSetPixel {
+                        expected: expected_size,
+                        got: body.len(),
+                    });
+                }
+                let color = Color::from_bytes(&body[3..])
+                    .ok_or(ParseError::InvalidColorLength(body.len() - 3))?;
+                return Ok(Self::SetPixel {
+                    x: body[1].into(),
+                    y: body[2].into(),
+                    color,
+                    canvas_id: 0,
+                });
+            }
+        }
+        Self::from_body(body)
+    }
+
+    /// Parse the packet body after the version byte, starting with the packet type.
+    fn from_body(bytes: &[u8]) -> Result<Self, ParseError> {
+        let kind = *bytes.first().ok_or(ParseError::Empty)?;
         match kind {
-            0xaa => Some(Self::SizeRequest),
-            0xbb => {
-                let width = u16::from_be_bytes(bytes.get(1..=2)?.try_into().unwrap());
-                let height = u16::from_be_bytes(bytes.get(3..=4)?.try_into().unwrap());
-                Some(Self::SizeResponse { width, height })
-            }
-            0xcc => {
-                let x = u16::from_be_bytes(bytes.get(1..=2)?.try_into().unwrap());
-                let y = u16::from_be_bytes(bytes.get(3..=4)?.try_into().unwrap());
-                let color = Color::from_bytes(bytes.get(5..)?)?;
-                Some(Self::SetPixel { x, y, color })
+            Self::SIZE_REQUEST_ID => Ok(Self::SizeRequest {
+                // Size requests from clients unaware of several canvases have no canvas ID.
+                canvas_id: bytes.get(1).copied().unwrap_or(0),
+            }),
+            Self::CLEAR_ID => Ok(Self::Clear),
+            Self::SIZE_RESPONSE_ID => {
+                if bytes.len() < Self::SIZE_RESPONSE_SIZE {
+                    return Err(ParseError::TruncatedSizeResponse);
+                }
+                let width = u16::from_be_bytes(bytes[1..=2].try_into().unwrap());
+                let height = u16::from_be_bytes(bytes[3..=4].try_into().unwrap());
+                let version = bytes[5];
+                // Servers without any capabilities omit the flags.
+                let capabilities = bytes.get(6).copied().unwrap_or(0);
+                Ok(Self::SizeResponse {
+                    width,
+                    height,
+                    version,
+                    capabilities,
+                })
             }
-            _ => None,
+            Self::SET_PIXEL_ID => {
+                if bytes.len() < Self::SET_PIXEL_MIN_SIZE {
+                    return Err(ParseError::TruncatedSetPixel {
+                        expected: Self::SET_PIXEL_MIN_SIZE,
+                        got: bytes.len(),
+                    });
+                }
+                let x = u16::from_be_bytes(bytes[1..=2].try_into().unwrap());
+                let y = u16::from_be_bytes(bytes[3..=4].try_into().unwrap());
+                let color = Color::from_bytes(&bytes[5..])
+                    .ok_or(ParseError::InvalidColorLength(bytes.len() - 5))?;
+                Ok(Self::SetPixel {
+                    x,
+                    y,
+                    color,
+                    canvas_id: 0,
+                })
+            }
+            Self::SET_PIXEL_ON_CANVAS_ID => {
+                if bytes.len() < Self::SET_PIXEL_ON_CANVAS_MIN_SIZE {
+                    return Err(ParseError::TruncatedSetPixel {
+                        expected: Self::SET_PIXEL_ON_CANVAS_MIN_SIZE,
+                        got: bytes.len(),
+                    });
+                }
+                let canvas_id = bytes[1];
+                let x = u16::from_be_bytes(bytes[2..=3].try_into().unwrap());
+                let y = u16::from_be_bytes(bytes[4..=5].try_into().unwrap());
+                let color = Color::from_bytes(&bytes[6..])
+                    .ok_or(ParseError::InvalidColorLength(bytes.len() - 6))?;
+                Ok(Self::SetPixel {
+                    x,
+                    y,
+                    color,
+                    canvas_id,
+                })
+            }
+            Self::SET_PIXEL_RGBA_ID => {
+                if bytes.len() < Self::SET_PIXEL_RGBA_SIZE {
+                    return Err(ParseError::TruncatedSetPixel {
+                        expected: Self::SET_PIXEL_RGBA_SIZE,
+                        got: bytes.len(),
+                    });
+                }
+                let x = u16::from_be_bytes(bytes[1..=2].try_into().unwrap());
+                let y = u16::from_be_bytes(bytes[3..=4].try_into().unwrap());
+                let color = Color::from_rgba(bytes[5..=8].try_into().unwrap());
+                Ok(Self::SetPixelRgba { x, y, color })
+            }
+            Self::SET_GRAY_ID => {
+                if bytes.len() < Self::SET_GRAY_SIZE {
+                    return Err(ParseError::TruncatedSetPixel {
+                        expected: Self::SET_GRAY_SIZE,
+                        got: bytes.len(),
+                    });
+                }
+                let x = u16::from_be_bytes(bytes[1..=2].try_into().unwrap());
+                let y = u16::from_be_bytes(bytes[3..=4].try_into().unwrap());
+                let value = bytes[5];
+                Ok(Self::SetGray { x, y, value })
+            }
+            Self::FILL_RECT_ID => {
+                if bytes.len() < Self::FILL_RECT_SIZE {
+                    return Err(ParseError::TruncatedFillRect {
+                        expected: Self::FILL_RECT_SIZE,
+                        got: bytes.len(),
+                    });
+                }
+                let (x, y, width, height) = read_rect(&bytes[1..])?;
+                let area = width as usize * height as usize;
+                if area > Self::MAX_FILL_AREA {
+                    return Err(ParseError::RectTooLarge {
+                        area,
+                        max: Self::MAX_FILL_AREA,
+                    });
+                }
+                let color = Color::from_rgb(bytes[9..=11].try_into().unwrap());
+                Ok(Self::FillRect {
+                    x,
+                    y,
+                    width,
+                    height,
+                    color,
+                })
+            }
+            Self::SET_ROW_RLE_ID => {
+                if bytes.len() < Self::SET_ROW_RLE_HEADER_SIZE {
+                    return Err(ParseError::TruncatedSetRowRle {
+                        expected: Self::SET_ROW_RLE_HEADER_SIZE,
+                        got: bytes.len(),
+                    });
+                }
+                let x = u16::from_be_bytes(bytes[1..=2].try_into().unwrap());
+                let y = u16::from_be_bytes(bytes[3..=4].try_into().unwrap());
+                let count = bytes[5] as usize;
+                // Check the count before allocating anything for it.
+                if count > Self::MAX_RLE_RUNS {
+                    return Err(ParseError::TooManyRuns(count));
+                }
+                let expected =
+                    Self::SET_ROW_RLE_HEADER_SIZE + count * Self::SET_ROW_RLE_RECORD_SIZE;
+                if bytes.len() < expected {
+                    return Err(ParseError::TruncatedSetRowRle {
+                        expected,
+                        got: bytes.len(),
+                    });
+                }
+                let runs: Vec<_> = bytes[Self::SET_ROW_RLE_HEADER_SIZE..expected]
+                    .chunks_exact(Self::SET_ROW_RLE_RECORD_SIZE)
+                    .map(|record| {
+                        let count = u16::from_be_bytes(record[0..=1].try_into().unwrap());
+                        let color = Color::from_rgb(record[2..=4].try_into().unwrap());
+                        (count, color)
+                    })
+                    .collect();
+                let length: u32 = runs.iter().map(|&(count, _)| u32::from(count)).sum();
+                if x as u32 + length > u16::MAX as u32 + 1 {
+                    return Err(ParseError::RectOutOfRange);
+                }
+                Ok(Self::SetRowRle { x, y, runs })
+            }
+            Self::DRAW_TEXT_ID => {
+                if bytes.len() < Self::DRAW_TEXT_HEADER_SIZE {
+                    return Err(ParseError::TruncatedDrawText {
+                        expected: Self::DRAW_TEXT_HEADER_SIZE,
+                        got: bytes.len(),
+                    });
+                }
+                let x = u16::from_be_bytes(bytes[1..=2].try_into().unwrap());
+                let y = u16::from_be_bytes(bytes[3..=4].try_into().unwrap());
+                let color = Color::from_rgb(bytes[5..=7].try_into().unwrap());
+                let length = bytes[8] as usize;
+                if length > Self::MAX_TEXT_LENGTH {
+                    return Err(ParseError::TextTooLong(length));
+                }
+                let expected = Self::DRAW_TEXT_HEADER_SIZE + length;
+                if bytes.len() < expected {
+                    return Err(ParseError::TruncatedDrawText {
+                        expected,
+                        got: bytes.len(),
+                    });
+                }
+                let text = std::str::from_utf8(&bytes[Self::DRAW_TEXT_HEADER_SIZE..expected])
+                    .map_err(|_| ParseError::InvalidText)?
+                    .to_owned();
+                Ok(Self::DrawText { x, y, color, text })
+            }
+            Self::DRAW_CIRCLE_ID => {
+                if bytes.len() < Self::DRAW_CIRCLE_SIZE {
+                    return Err(ParseError::TruncatedDrawCircle {
+                        expected: Self::DRAW_CIRCLE_SIZE,
+                        got: bytes.len(),
+                    });
+                }
+                let cx = u16::from_be_bytes(bytes[1..=2].try_into().unwrap());
+                let cy = u16::from_be_bytes(bytes[3..=4].try_into().unwrap());
+                let r = u16::from_be_bytes(bytes[5..=6].try_into().unwrap());
+                if r > Self::MAX_CIRCLE_RADIUS {
+                    return Err(ParseError::CircleTooLarge(r));
+                }
+                let fill = match bytes[7] {
+                    0 => false,
+                    1 => true,
+                    style => return Err(ParseError::InvalidCircleStyle(style)),
+                };
+                let color = Color::from_rgb(bytes[8..=10].try_into().unwrap());
+                Ok(Self::DrawCircle {
+                    cx,
+                    cy,
+                    r,
+                    color,
+                    fill,
+                })
+            }
+            Self::DRAW_LINE_ID => {
+                if bytes.len() < Self::DRAW_LINE_SIZE {
+                    return Err(ParseError::TruncatedDrawLine {
+                        expected: Self::DRAW_LINE_SIZE,
+                        got: bytes.len(),
+                    });
+                }
+                let x0 = u16::from_be_bytes(bytes[1..=2].try_into().unwrap());
+                let y0 = u16::from_be_bytes(bytes[3..=4].try_into().unwrap());
+                let x1 = u16::from_be_bytes(bytes[5..=6].try_into().unwrap());
+                let y1 = u16::from_be_bytes(bytes[7..=8].try_into().unwrap());
+                let color = Color::from_rgb(bytes[9..=11].try_into().unwrap());
+                Ok(Self::DrawLine {
+                    x0,
+                    y0,
+                    x1,
+                    y1,
+                    color,
+                })
+            }
+            Self::SET_RECT_COMPRESSED_ID => {
+                if bytes.len() < Self::SET_RECT_COMPRESSED_HEADER_SIZE {
+                    return Err(ParseError::TruncatedSetRectCompressed {
+                        expected: Self::SET_RECT_COMPRESSED_HEADER_SIZE,
+                        got: bytes.len(),
+                    });
+                }
+                let (x, y, width, height) = read_rect(&bytes[1..])?;
+                let area = width as usize * height as usize;
+                if area > Self::MAX_COMPRESSED_RECT_AREA {
+                    return Err(ParseError::RectTooLarge {
+                        area,
+                        max: Self::MAX_COMPRESSED_RECT_AREA,
+                    });
+                }
+                let length = u16::from_be_bytes(bytes[9..=10].try_into().unwrap()) as usize;
+                if length > Self::MAX_COMPRESSED_DATA_SIZE {
+                    return Err(ParseError::CompressedDataTooLarge(length));
+                }
+                let expected = Self::SET_RECT_COMPRESSED_HEADER_SIZE + length;
+                if bytes.len() < expected {
+                    return Err(ParseError::TruncatedSetRectCompressed {
+                        expected,
+                        got: bytes.len(),
+                    });
+                }
+                let data = bytes[Self::SET_RECT_COMPRESSED_HEADER_SIZE..expected].to_vec();
+                Ok(Self::SetRectCompressed {
+                    x,
+                    y,
+                    width,
+                    height,
+                    data,
+                })
+            }
+            Self::SET_PIXEL_IF_EQUAL_ID => {
+                if bytes.len() < Self::SET_PIXEL_IF_EQUAL_SIZE {
+                    return Err(ParseError::TruncatedSetPixelIfEqual {
+                        expected: Self::SET_PIXEL_IF_EQUAL_SIZE,
+                        got: bytes.len(),
+                    });
+                }
+                let x = u16::from_be_bytes(bytes[1..=2].try_into().unwrap());
+                let y = u16::from_be_bytes(bytes[3..=4].try_into().unwrap());
+                let expected = Color::from_rgb(bytes[5..=7].try_into().unwrap());
+                let color = Color::from_rgb(bytes[8..=10].try_into().unwrap());
+                Ok(Self::SetPixelIfEqual {
+                    x,
+                    y,
+                    expected,
+                    color,
+                })
+            }
+            Self::GET_PIXEL_ID => {
+                if bytes.len() < Self::GET_PIXEL_SIZE {
+                    return Err(ParseError::TruncatedGetPixel);
+                }
+                let x = u16::from_be_bytes(bytes[1..=2].try_into().unwrap());
+                let y = u16::from_be_bytes(bytes[3..=4].try_into().unwrap());
+                Ok(Self::GetPixel { x, y })
+            }
+            Self::GET_RECT_ID => {
+                if bytes.len() < Self::GET_RECT_SIZE {
+                    return Err(ParseError::TruncatedGetRect {
+                        expected: Self::GET_RECT_SIZE,
+                        got: bytes.len(),
+                    });
+                }
+                let (x, y, width, height) = read_rect(&bytes[1..])?;
+                let area = width as usize * height as usize;
+                if area > Self::MAX_GET_RECT_AREA {
+                    return Err(ParseError::RectTooLarge {
+                        area,
+                        max: Self::MAX_GET_RECT_AREA,
+                    });
+                }
+                Ok(Self::GetRect {
+                    x,
+                    y,
+                    width,
+                    height,
+                })
+            }
+            Self::RECT_RESPONSE_ID => {
+                if bytes.len() < Self::RECT_RESPONSE_HEADER_SIZE {
+                    return Err(ParseError::TruncatedRectResponse {
+                        expected: Self::RECT_RESPONSE_HEADER_SIZE,
+                        got: bytes.len(),
+                    });
+                }
+                let (x, y, width, height) = read_rect(&bytes[1..])?;
+                // Check the area before allocating anything for it.
+                let area = width as usize * height as usize;
+                if area > Self::MAX_RECT_RESPONSE_PIXELS {
+                    return Err(ParseError::RectTooLarge {
+                        area,
+                        max: Self::MAX_RECT_RESPONSE_PIXELS,
+                    });
+                }
+                let expected =
+                    Self::RECT_RESPONSE_HEADER_SIZE + area * Self::RECT_RESPONSE_RECORD_SIZE;
+                if bytes.len() < expected {
+                    return Err(ParseError::TruncatedRectResponse {
+                        expected,
+                        got: bytes.len(),
+                    });
+                }
+                let pixels = bytes[Self::RECT_RESPONSE_HEADER_SIZE..expected]
+                    .chunks_exact(Self::RECT_RESPONSE_RECORD_SIZE)
+                    .map(|record| Color::from_rgb(record.try_into().unwrap()))
+                    .collect();
+                Ok(Self::RectResponse {
+                    x,
+                    y,
+                    width,
+                    height,
+                    pixels,
+                })
+            }
+            Self::GET_THUMBNAIL_ID => {
+                if bytes.len() < Self::GET_THUMBNAIL_SIZE {
+                    return Err(ParseError::TruncatedGetThumbnail {
+                        expected: Self::GET_THUMBNAIL_SIZE,
+                        got: bytes.len(),
+                    });
+                }
+                let max_dim = u16::from_be_bytes(bytes[1..=2].try_into().unwrap());
+                Ok(Self::GetThumbnail { max_dim })
+            }
+            Self::THUMBNAIL_RESPONSE_ID => {
+                if bytes.len() < Self::THUMBNAIL_RESPONSE_HEADER_SIZE {
+                    return Err(ParseError::TruncatedThumbnailResponse {
+                        expected: Self::THUMBNAIL_RESPONSE_HEADER_SIZE,
+                        got: bytes.len(),
+                    });
+                }
+                let width = u16::from_be_bytes(bytes[1..=2].try_into().unwrap());
+                let height = u16::from_be_bytes(bytes[3..=4].try_into().unwrap());
+                let y = u16::from_be_bytes(bytes[5..=6].try_into().unwrap());
+                let rows = u16::from_be_bytes(bytes[7..=8].try_into().unwrap());
+                // Check the area before allocating anything for it.
+                let area = width as usize * rows as usize;
+                if area > Self::MAX_RECT_RESPONSE_PIXELS {
+                    return Err(ParseError::RectTooLarge {
+                        area,
+                        max: Self::MAX_RECT_RESPONSE_PIXELS,
+                    });
+                }
+                let expected =
+                    Self::THUMBNAIL_RESPONSE_HEADER_SIZE + area * Self::RECT_RESPONSE_RECORD_SIZE;
+                if bytes.len() < expected {
+                    return Err(ParseError::TruncatedThumbnailResponse {
+                        expected,
+                        got: bytes.len(),
+                    });
+                }
+                let pixels = bytes[Self::THUMBNAIL_RESPONSE_HEADER_SIZE..expected]
+                    .chunks_exact(Self::RECT_RESPONSE_RECORD_SIZE)
+                    .map(|record| Color::from_rgb(record.try_into().unwrap()))
+                    .collect();
+                Ok(Self::ThumbnailResponse {
+                    width,
+                    height,
+                    y,
+                    rows,
+                    pixels,
+                })
+            }
+            Self::PIXEL_RESPONSE_ID => {
+                if bytes.len() < Self::PIXEL_RESPONSE_MIN_SIZE {
+                    return Err(ParseError::TruncatedPixelResponse {
+                        expected: Self::PIXEL_RESPONSE_MIN_SIZE,
+                        got: bytes.len(),
+                    });
+                }
+                let x = u16::from_be_bytes(bytes[1..=2].try_into().unwrap());
+                let y = u16::from_be_bytes(bytes[3..=4].try_into().unwrap());
+                let color = Color::from_bytes(&bytes[5..])
+                    .ok_or(ParseError::InvalidColorLength(bytes.len() - 5))?;
+                Ok(Self::PixelResponse { x, y, color })
+            }
+            Self::SET_PIXELS_ID => {
+                let count = *bytes.get(1).ok_or(ParseError::TruncatedSetPixels {
+                    expected: Self::SET_PIXELS_HEADER_SIZE,
+                    got: bytes.len(),
+                })? as usize;
+                // Check the count before allocating anything for it.
+                if count > Self::MAX_BATCH_SIZE {
+                    return Err(ParseError::BatchTooLarge(count));
+                }
+                let expected = Self::SET_PIXELS_HEADER_SIZE + count * Self::SET_PIXELS_RECORD_SIZE;
+                if bytes.len() < expected {
+                    return Err(ParseError::TruncatedSetPixels {
+                        expected,
+                        got: bytes.len(),
+                    });
+                }
+                let pixels = bytes[Self::SET_PIXELS_HEADER_SIZE..expected]
+                    .chunks_exact(Self::SET_PIXELS_RECORD_SIZE)
+                    .map(|record| {
+                        let x = u16::from_be_bytes(record[0..=1].try_into().unwrap());
+                        let y = u16::from_be_bytes(record[2..=3].try_into().unwrap());
+                        let color = Color::from_rgb(record[4..=6].try_into().unwrap());
+                        (x, y, color)
+                    })
+                    .collect();
+                Ok(Self::SetPixels { pixels })
+            }
+            _ => Err(ParseError::UnknownOpcode(kind)),
         }
     }
 
@@ -45,38 +837,948 @@ impl Packet {
     ///
     /// Panics if the provided buffer is not large enough.
     pub fn write_to(&self, buffer: &mut [u8]) -> usize {
+        self.write_to_with_mode(buffer, CoordinateMode::Full)
+    }
+
+    /// Write the packet data like [`Packet::write_to`], with a set pixel packet on canvas 0 in the given coordinate mode.
+    /// Returns the number of written bytes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the provided buffer is not large enough, or the coordinates do not fit the mode.
+    pub fn write_to_with_mode(&self, buffer: &mut [u8], mode: CoordinateMode) -> usize {
+        buffer[0] = PROTOCOL_VERSION;
+        match (self, mode) {
+            (
+                Packet::SetPixel {
+                    x,
+                    y,
+                    color,
+                    canvas_id: 0,
+                },
+                CoordinateMode::Compact,
+            ) => {
+                let x = u8::try_from(*x).expect("X coordinate does not fit compact coordinates");
+                let y = u8::try_from(*y).expect("Y coordinate does not fit compact coordinates");
+                buffer[1..=3].copy_from_slice(&[Self::SET_PIXEL_ID, x, y]);
+                4 + color.write_to(&mut buffer[4..])
+            }
+            _ => 1 + self.write_body_to(&mut buffer[1..]),
+        }
+    }
+
+    /// Write the packet body after the version byte, starting with the packet type.
+    fn write_body_to(&self, buffer: &mut [u8]) -> usize {
         match self {
-            Packet::SizeRequest => {
+            Packet::SizeRequest { canvas_id: 0 } => {
                 buffer[0] = Self::SIZE_REQUEST_ID;
                 1
             }
-            Packet::SizeResponse { width, height } => {
+            Packet::SizeRequest { canvas_id } => {
+                buffer[0] = Self::SIZE_REQUEST_ID;
+                buffer[1] = *canvas_id;
+                2
+            }
+            Packet::Clear => {
+                buffer[0] = Self::CLEAR_ID;
+                1
+            }
+            Packet::SizeResponse {
+                width,
+                height,
+                version,
+                capabilities,
+            } => {
                 buffer[0] = Self::SIZE_RESPONSE_ID;
                 buffer[1..=2].copy_from_slice(&width.to_be_bytes());
                 buffer[3..=4].copy_from_slice(&height.to_be_bytes());
-                5
+                buffer[5] = *version;
+                if *capabilities == 0 {
+                    return Self::SIZE_RESPONSE_SIZE;
+                }
+                buffer[6] = *capabilities;
+                Self::SIZE_RESPONSE_SIZE + 1
             }
-            Packet::SetPixel { x, y, color } => {
+            Packet::SetPixel {
+                x,
+                y,
+                color,
+                canvas_id: 0,
+            } => {
                 buffer[0] = Self::SET_PIXEL_ID;
                 buffer[1..=2].copy_from_slice(&x.to_be_bytes());
                 buffer[3..=4].copy_from_slice(&y.to_be_bytes());
                 let color_size = color.write_to(&mut buffer[5..]);
                 5 + color_size
             }
+            Packet::SetPixel {
+                x,
+                y,
+                color,
+                canvas_id,
+            } => {
+                buffer[0] = Self::SET_PIXEL_ON_CANVAS_ID;
+                buffer[1] = *canvas_id;
+                buffer[2..=3].copy_from_slice(&x.to_be_bytes());
+                buffer[4..=5].copy_from_slice(&y.to_be_bytes());
+                let color_size = color.write_to(&mut buffer[6..]);
+                6 + color_size
+            }
+            Packet::SetPixelRgba { x, y, color } => {
+                buffer[0] = Self::SET_PIXEL_RGBA_ID;
+                buffer[1..=2].copy_from_slice(&x.to_be_bytes());
+                buffer[3..=4].copy_from_slice(&y.to_be_bytes());
+                buffer[5] = color.red;
+                buffer[6] = color.green;
+                buffer[7] = color.blue;
+                buffer[8] = color.alpha();
+                Self::SET_PIXEL_RGBA_SIZE
+            }
+            Packet::SetGray { x, y, value } => {
+                buffer[0] = Self::SET_GRAY_ID;
+                buffer[1..=2].copy_from_slice(&x.to_be_bytes());
+                buffer[3..=4].copy_from_slice(&y.to_be_bytes());
+                buffer[5] = *value;
+                Self::SET_GRAY_SIZE
+            }
+            Packet::FillRect {
+                x,
+                y,
+                width,
+                height,
+                color,
+            } => {
+                buffer[0] = Self::FILL_RECT_ID;
+                write_rect(&mut buffer[1..], *x, *y, *width, *height);
+                buffer[9] = color.red;
+                buffer[10] = color.green;
+                buffer[11] = color.blue;
+                Self::FILL_RECT_SIZE
+            }
+            Packet::SetRowRle { x, y, runs } => {
+                assert!(runs.len() <= Self::MAX_RLE_RUNS, "too many runs in row");
+                buffer[0] = Self::SET_ROW_RLE_ID;
+                buffer[1..=2].copy_from_slice(&x.to_be_bytes());
+                buffer[3..=4].copy_from_slice(&y.to_be_bytes());
+                buffer[5] = runs.len() as u8;
+                let records = buffer[Self::SET_ROW_RLE_HEADER_SIZE..]
+                    .chunks_exact_mut(Self::SET_ROW_RLE_RECORD_SIZE)
+                    .zip(runs);
+                for (record, (count, color)) in records {
+                    record[0..=1].copy_from_slice(&count.to_be_bytes());
+                    record[2] = color.red;
+                    record[3] = color.green;
+                    record[4] = color.blue;
+                }
+                Self::SET_ROW_RLE_HEADER_SIZE + runs.len() * Self::SET_ROW_RLE_RECORD_SIZE
+            }
+            Packet::DrawText { x, y, color, text } => {
+                assert!(text.len() <= Self::MAX_TEXT_LENGTH, "text too long");
+                buffer[0] = Self::DRAW_TEXT_ID;
+                buffer[1..=2].copy_from_slice(&x.to_be_bytes());
+                buffer[3..=4].copy_from_slice(&y.to_be_bytes());
+                buffer[5] = color.red;
+                buffer[6] = color.green;
+                buffer[7] = color.blue;
+                buffer[8] = text.len() as u8;
+                buffer[Self::DRAW_TEXT_HEADER_SIZE..Self::DRAW_TEXT_HEADER_SIZE + text.len()]
+                    .copy_from_slice(text.as_bytes());
+                Self::DRAW_TEXT_HEADER_SIZE + text.len()
+            }
+            Packet::DrawCircle {
+                cx,
+                cy,
+                r,
+                color,
+                fill,
+            } => {
+                buffer[0] = Self::DRAW_CIRCLE_ID;
+                buffer[1..=2].copy_from_slice(&cx.to_be_bytes());
+                buffer[3..=4].copy_from_slice(&cy.to_be_bytes());
+                buffer[5..=6].copy_from_slice(&r.to_be_bytes());
+                buffer[7] = u8::from(*fill);
+                buffer[8] = color.red;
+                buffer[9] = color.green;
+                buffer[10] = color.blue;
+                Self::DRAW_CIRCLE_SIZE
+            }
+            Packet::DrawLine {
+                x0,
+                y0,
+                x1,
+                y1,
+                color,
+            } => {
+                buffer[0] = Self::DRAW_LINE_ID;
+                buffer[1..=2].copy_from_slice(&x0.to_be_bytes());
+                buffer[3..=4].copy_from_slice(&y0.to_be_bytes());
+                buffer[5..=6].copy_from_slice(&x1.to_be_bytes());
+                buffer[7..=8].copy_from_slice(&y1.to_be_bytes());
+                buffer[9] = color.red;
+                buffer[10] = color.green;
+                buffer[11] = color.blue;
+                Self::DRAW_LINE_SIZE
+            }
+            Packet::SetRectCompressed {
+                x,
+                y,
+                width,
+                height,
+                data,
+            } => {
+                assert!(
+                    data.len() <= Self::MAX_COMPRESSED_DATA_SIZE,
+                    "compressed data too large"
+                );
+                buffer[0] = Self::SET_RECT_COMPRESSED_ID;
+                write_rect(&mut buffer[1..], *x, *y, *width, *height);
+                buffer[9..=10].copy_from_slice(&(data.len() as u16).to_be_bytes());
+                buffer[Self::SET_RECT_COMPRESSED_HEADER_SIZE
+                    ..Self::SET_RECT_COMPRESSED_HEADER_SIZE + data.len()]
+                    .copy_from_slice(data);
+                Self::SET_RECT_COMPRESSED_HEADER_SIZE + data.len()
+            }
+            Packet::SetPixelIfEqual {
+                x,
+                y,
+                expected,
+                color,
+            } => {
+                buffer[0] = Self::SET_PIXEL_IF_EQUAL_ID;
+                buffer[1..=2].copy_from_slice(&x.to_be_bytes());
+                buffer[3..=4].copy_from_slice(&y.to_be_bytes());
+                buffer[5] = expected.red;
+                buffer[6] = expected.green;
+                buffer[7] = expected.blue;
+                buffer[8] = color.red;
+                buffer[9] = color.green;
+                buffer[10] = color.blue;
+                Self::SET_PIXEL_IF_EQUAL_SIZE
+            }
+            Packet::GetPixel { x, y } => {
+                buffer[0] = Self::GET_PIXEL_ID;
+                buffer[1..=2].copy_from_slice(&x.to_be_bytes());
+                buffer[3..=4].copy_from_slice(&y.to_be_bytes());
+                Self::GET_PIXEL_SIZE
+            }
+            Packet::PixelResponse { x, y, color } => {
+                buffer[0] = Self::PIXEL_RESPONSE_ID;
+                buffer[1..=2].copy_from_slice(&x.to_be_bytes());
+                buffer[3..=4].copy_from_slice(&y.to_be_bytes());
+                let color_size = color.write_to(&mut buffer[5..]);
+                5 + color_size
+            }
+            Packet::GetRect {
+                x,
+                y,
+                width,
+                height,
+            } => {
+                buffer[0] = Self::GET_RECT_ID;
+                write_rect(&mut buffer[1..], *x, *y, *width, *height);
+                Self::GET_RECT_SIZE
+            }
+            Packet::RectResponse {
+                x,
+                y,
+                width,
+                height,
+                pixels,
+            } => {
+                assert!(
+                    pixels.len() == *width as usize * *height as usize
+                        && pixels.len() <= Self::MAX_RECT_RESPONSE_PIXELS,
+                    "invalid number of pixels in rectangle response"
+                );
+                buffer[0] = Self::RECT_RESPONSE_ID;
+                write_rect(&mut buffer[1..], *x, *y, *width, *height);
+                let records = buffer[Self::RECT_RESPONSE_HEADER_SIZE..]
+                    .chunks_exact_mut(Self::RECT_RESPONSE_RECORD_SIZE)
+                    .zip(pixels);
+                for (record, color) in records {
+                    record[0] = color.red;
+                    record[1] = color.green;
+                    record[2] = color.blue;
+                }
+                Self::RECT_RESPONSE_HEADER_SIZE + pixels.len() * Self::RECT_RESPONSE_RECORD_SIZE
+            }
+            Packet::GetThumbnail { max_dim } => {
+                buffer[0] = Self::GET_THUMBNAIL_ID;
+                buffer[1..=2].copy_from_slice(&max_dim.to_be_bytes());
+                Self::GET_THUMBNAIL_SIZE
+            }
+            Packet::ThumbnailResponse {
+                width,
+                height,
+                y,
+                rows,
+                pixels,
+            } => {
+                assert!(
+                    pixels.len() == *width as usize * *rows as usize
+                        && pixels.len() <= Self::MAX_RECT_RESPONSE_PIXELS,
+                    "invalid number of pixels in thumbnail response"
+                );
+                buffer[0] = Self::THUMBNAIL_RESPONSE_ID;
+                buffer[1..=2].copy_from_slice(&width.to_be_bytes());
+                buffer[3..=4].copy_from_slice(&height.to_be_bytes());
+                buffer[5..=6].copy_from_slice(&y.to_be_bytes());
+                buffer[7..=8].copy_from_slice(&rows.to_be_bytes());
+                let records = buffer[Self::THUMBNAIL_RESPONSE_HEADER_SIZE..]
+                    .chunks_exact_mut(Self::RECT_RESPONSE_RECORD_SIZE)
+                    .zip(pixels);
+                for (record, color) in records {
+                    record[0] = color.red;
+                    record[1] = color.green;
+                    record[2] = color.blue;
+                }
+                Self::THUMBNAIL_RESPONSE_HEADER_SIZE
+                    + pixels.len() * Self::RECT_RESPONSE_RECORD_SIZE
+            }
+            Packet::SetPixels { pixels } => {
+                assert!(
+                    pixels.len() <= Self::MAX_BATCH_SIZE,
+                    "too many pixels in batch"
+                );
+                buffer[0] = Self::SET_PIXELS_ID;
+                buffer[1] = pixels.len() as u8;
+                let records = buffer[Self::SET_PIXELS_HEADER_SIZE..]
+                    .chunks_exact_mut(Self::SET_PIXELS_RECORD_SIZE)
+                    .zip(pixels);
+                for (record, (x, y, color)) in records {
+                    record[0..=1].copy_from_slice(&x.to_be_bytes());
+                    record[2..=3].copy_from_slice(&y.to_be_bytes());
+                    record[4] = color.red;
+                    record[5] = color.green;
+                    record[6] = color.blue;
+                }
+                Self::SET_PIXELS_HEADER_SIZE + pixels.len() * Self::SET_PIXELS_RECORD_SIZE
+            }
+        }
+    }
+
+    /// The type byte of the encoded packet, such as [`Packet::SET_PIXEL_ID`].
+    pub fn type_id(&self) -> u8 {
+        match self {
+            Packet::SizeRequest { .. } => Self::SIZE_REQUEST_ID,
+            Packet::SizeResponse { .. } => Self::SIZE_RESPONSE_ID,
+            Packet::SetPixel { canvas_id: 0, .. } => Self::SET_PIXEL_ID,
+            Packet::SetPixel { .. } => Self::SET_PIXEL_ON_CANVAS_ID,
+            Packet::SetPixelRgba { .. } => Self::SET_PIXEL_RGBA_ID,
+            Packet::GetPixel { .. } => Self::GET_PIXEL_ID,
+            Packet::PixelResponse { .. } => Self::PIXEL_RESPONSE_ID,
+            Packet::GetRect { .. } => Self::GET_RECT_ID,
+            Packet::RectResponse { .. } => Self::RECT_RESPONSE_ID,
+            Packet::GetThumbnail { .. } => Self::GET_THUMBNAIL_ID,
+            Packet::ThumbnailResponse { .. } => Self::THUMBNAIL_RESPONSE_ID,
+            Packet::SetPixels { .. } => Self::SET_PIXELS_ID,
+            Packet::SetGray { .. } => Self::SET_GRAY_ID,
+            Packet::FillRect { .. } => Self::FILL_RECT_ID,
+            Packet::SetRowRle { .. } => Self::SET_ROW_RLE_ID,
+            Packet::DrawText { .. } => Self::DRAW_TEXT_ID,
+            Packet::DrawCircle { .. } => Self::DRAW_CIRCLE_ID,
+            Packet::DrawLine { .. } => Self::DRAW_LINE_ID,
+            Packet::SetRectCompressed { .. } => Self::SET_RECT_COMPRESSED_ID,
+            Packet::SetPixelIfEqual { .. } => Self::SET_PIXEL_IF_EQUAL_ID,
+            Packet::Clear => Self::CLEAR_ID,
+        }
+    }
+
+    /// Size of the encoded packet, including the version byte.
+    pub fn encoded_size(&self) -> usize {
+        let body_size = match self {
+            Packet::SizeRequest { canvas_id: 0 } | Packet::Clear => 1,
+            Packet::SizeRequest { .. } => 2,
+            Packet::SizeResponse { capabilities, .. } => {
+                Self::SIZE_RESPONSE_SIZE + usize::from(*capabilities != 0)
+            }
+            Packet::SetPixel {
+                color,
+                canvas_id: 0,
+                ..
+            }
+            | Packet::PixelResponse { color, .. } => 5 + color.encoded_size(),
+            Packet::SetPixel { color, .. } => 6 + color.encoded_size(),
+            Packet::SetPixelRgba { .. } => Self::SET_PIXEL_RGBA_SIZE,
+            Packet::SetGray { .. } => Self::SET_GRAY_SIZE,
+            Packet::FillRect { .. } => Self::FILL_RECT_SIZE,
+            Packet::SetRowRle { runs, .. } => {
+                Self::SET_ROW_RLE_HEADER_SIZE + runs.len() * Self::SET_ROW_RLE_RECORD_SIZE
+            }
+            Packet::DrawText { text, .. } => Self::DRAW_TEXT_HEADER_SIZE + text.len(),
+            Packet::DrawCircle { .. } => Self::DRAW_CIRCLE_SIZE,
+            Packet::DrawLine { .. } => Self::DRAW_LINE_SIZE,
+            Packet::SetRectCompressed { data, .. } => {
+                Self::SET_RECT_COMPRESSED_HEADER_SIZE + data.len()
+            }
+            Packet::SetPixelIfEqual { .. } => Self::SET_PIXEL_IF_EQUAL_SIZE,
+            Packet::GetPixel { .. } => Self::GET_PIXEL_SIZE,
+            Packet::GetRect { .. } => Self::GET_RECT_SIZE,
+            Packet::RectResponse { pixels, .. } => {
+                Self::RECT_RESPONSE_HEADER_SIZE + pixels.len() * Self::RECT_RESPONSE_RECORD_SIZE
+            }
+            Packet::GetThumbnail { .. } => Self::GET_THUMBNAIL_SIZE,
+            Packet::ThumbnailResponse { pixels, .. } => {
+                Self::THUMBNAIL_RESPONSE_HEADER_SIZE
+                    + pixels.len() * Self::RECT_RESPONSE_RECORD_SIZE
+            }
+            Packet::SetPixels { pixels } => {
+                Self::SET_PIXELS_HEADER_SIZE + pixels.len() * Self::SET_PIXELS_RECORD_SIZE
+            }
+        };
+        1 + body_size
+    }
+
+    /// Split the colors of a rectangle into as few rectangle response packets as possible.
+    /// The pixels are given in rows from top to bottom, and each response covers a part of the rectangle.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the number of pixels does not match the rectangle size.
+    pub fn rect_responses(x: u16, y: u16, width: u16, height: u16, pixels: &[Color]) -> Vec<Self> {
+        assert_eq!(
+            pixels.len(),
+            width as usize * height as usize,
+            "number of pixels does not match rectangle size"
+        );
+        if pixels.is_empty() {
+            return Vec::new();
+        }
+        let tile_width = (width as usize).min(Self::MAX_RECT_RESPONSE_PIXELS);
+        let tile_height = (Self::MAX_RECT_RESPONSE_PIXELS / tile_width).min(height as usize);
+        let mut responses = Vec::new();
+        for tile_y in (0..height as usize).step_by(tile_height) {
+            for tile_x in (0..width as usize).step_by(tile_width) {
+                let this_width = tile_width.min(width as usize - tile_x);
+                let this_height = tile_height.min(height as usize - tile_y);
+                let tile_pixels = (tile_y..tile_y + this_height)
+                    .flat_map(|row| {
+                        let start = row * width as usize + tile_x;
+                        pixels[start..start + this_width].iter().copied()
+                    })
+                    .collect();
+                responses.push(Self::RectResponse {
+                    x: x + tile_x as u16,
+                    y: y + tile_y as u16,
+                    width: this_width as u16,
+                    height: this_height as u16,
+                    pixels: tile_pixels,
+                });
+            }
+        }
+        responses
+    }
+
+    /// Split the colors of a thumbnail into as few thumbnail response packets as possible, each with whole rows.
+    /// The pixels are given in rows from top to bottom.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the number of pixels does not match the thumbnail size,
+    /// or a row is longer than [`Packet::MAX_RECT_RESPONSE_PIXELS`].
+    pub fn thumbnail_responses(width: u16, height: u16, pixels: &[Color]) -> Vec<Self> {
+        assert_eq!(
+            pixels.len(),
+            width as usize * height as usize,
+            "number of pixels does not match thumbnail size"
+        );
+        assert!(
+            width as usize <= Self::MAX_RECT_RESPONSE_PIXELS,
+            "thumbnail rows do not fit into a response"
+        );
+        if pixels.is_empty() {
+            return Vec::new();
+        }
+        let band_height = Self::MAX_RECT_RESPONSE_PIXELS / width as usize;
+        pixels
+            .chunks(band_height * width as usize)
+            .enumerate()
+            .map(|(band, band_pixels)| Self::ThumbnailResponse {
+                width,
+                height,
+                y: (band * band_height) as u16,
+                rows: (band_pixels.len() / width as usize) as u16,
+                pixels: band_pixels.to_vec(),
+            })
+            .collect()
+    }
+
+    /// Compress the colors of a rectangle, given in rows from top to bottom, into a compressed rectangle packet.
+    /// Colors are always compressed without alpha.
+    ///
+    /// Returns [`None`] if the rectangle is larger than [`Packet::MAX_COMPRESSED_RECT_AREA`] pixels
+    /// or its colors do not compress into [`Packet::MAX_COMPRESSED_DATA_SIZE`] bytes; such rectangles have to be split up.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the number of pixels does not match the rectangle size.
+    pub fn compress_rect(
+        x: u16,
+        y: u16,
+        width: u16,
+        height: u16,
+        pixels: &[Color],
+    ) -> Option<Self> {
+        assert_eq!(
+            pixels.len(),
+            width as usize * height as usize,
+            "number of pixels does not match rectangle size"
+        );
+        if pixels.len() > Self::MAX_COMPRESSED_RECT_AREA {
+            return None;
         }
+        let raw: Vec<u8> = pixels
+            .iter()
+            .flat_map(|color| [color.red, color.green, color.blue])
+            .collect();
+        let data = miniz_oxide::deflate::compress_to_vec_zlib(&raw, COMPRESSION_LEVEL);
+        (data.len() <= Self::MAX_COMPRESSED_DATA_SIZE).then_some(Self::SetRectCompressed {
+            x,
+            y,
+            width,
+            height,
+            data,
+        })
     }
 
-    /// Convert the packet to its byte representation.
+    /// Decompress the data of a compressed rectangle packet into the colors of a rectangle of the given size, in rows from top to bottom.
+    ///
+    /// Decompression stops as soon as the output exceeds the size of the rectangle, so that small packets cannot expand into large allocations.
+    /// Data that is invalid or does not decompress to exactly the size of the rectangle is rejected.
+    pub fn decompress_rect(data: &[u8], width: u16, height: u16) -> Result<Vec<Color>, ParseError> {
+        let expected = width as usize * height as usize * Self::RECT_RESPONSE_RECORD_SIZE;
+        let raw = match miniz_oxide::inflate::decompress_to_vec_zlib_with_limit(data, expected) {
+            Ok(raw) if raw.len() == expected => raw,
+            Ok(_) => return Err(ParseError::DecompressedSizeMismatch { expected }),
+            Err(why) if why.status == TINFLStatus::HasMoreOutput => {
+                return Err(ParseError::DecompressedSizeMismatch { expected })
+            }
+            Err(_) => return Err(ParseError::InvalidCompressedData),
+        };
+        Ok(raw
+            .chunks_exact(Self::RECT_RESPONSE_RECORD_SIZE)
+            .map(|rgb| Color::from_rgb(rgb.try_into().unwrap()))
+            .collect())
+    }
+
+    /// Convert the packet to its byte representation, with multi-byte values in network order (big endian).
+    ///
+    /// For example, a [`Packet::SetPixel`] at X 300 and Y 2 with the color `ff8000` on canvas 0 is encoded as
+    /// `01 cc 01 2c 00 02 ff 80 00`.
     pub fn to_bytes(&self) -> Vec<u8> {
-        let mut buffer = vec![0; 9];
-        let length = self.write_to(&mut buffer);
+        self.to_bytes_with_mode(CoordinateMode::Full)
+    }
+
+    /// Convert the packet to its byte representation like [`Packet::to_bytes`], in the given coordinate mode.
+    /// For example, a [`Packet::SetPixel`] at X 44 and Y 2 with the color `ff8000` on canvas 0 is encoded as
+    /// `01 cc 2c 02 ff 80 00` with compact coordinates.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the coordinates do not fit the mode, see [`Packet::write_to_with_mode`].
+    pub fn to_bytes_with_mode(&self, mode: CoordinateMode) -> Vec<u8> {
+        // Compact coordinates only ever shrink the packet.
+        let mut buffer = vec![0; self.encoded_size()];
+        let length = self.write_to_with_mode(&mut buffer, mode);
         buffer.truncate(length);
         buffer
     }
 }
 
+/// Read a rectangle as position and size, which must end within the coordinate range.
+fn read_rect(bytes: &[u8]) -> Result<(u16, u16, u16, u16), ParseError> {
+    let x = u16::from_be_bytes(bytes[0..=1].try_into().unwrap());
+    let y = u16::from_be_bytes(bytes[2..=3].try_into().unwrap());
+    let width = u16::from_be_bytes(bytes[4..=5].try_into().unwrap());
+    let height = u16::from_be_bytes(bytes[6..=7].try_into().unwrap());
+    let coordinate_end = u16::MAX as u32 + 1;
+    if x as u32 + width as u32 > coordinate_end || y as u32 + height as u32 > coordinate_end {
+        return Err(ParseError::RectOutOfRange);
+    }
+    Ok((x, y, width, height))
+}
+
+/// Write a rectangle as position and size.
+fn write_rect(buffer: &mut [u8], x: u16, y: u16, width: u16, height: u16) {
+    buffer[0..=1].copy_from_slice(&x.to_be_bytes());
+    buffer[2..=3].copy_from_slice(&y.to_be_bytes());
+    buffer[4..=5].copy_from_slice(&width.to_be_bytes());
+    buffer[6..=7].copy_from_slice(&height.to_be_bytes());
+}
+
+/// Human-readable form of a packet for logging, such as `SetPixel(123,45 #ff00aa)`.
+impl Display for Packet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Packet::SizeRequest { canvas_id: 0 } => write!(f, "SizeRequest"),
+            Packet::SizeRequest { canvas_id } => write!(f, "SizeRequest(canvas {})", canvas_id),
+            Packet::SizeResponse {
+                width,
+                height,
+                version,
+                capabilities: 0,
+            } => write!(f, "SizeResponse({}x{} v{})", width, height, version),
+            Packet::SizeResponse {
+                width,
+                height,
+                version,
+                capabilities,
+            } => write!(
+                f,
+                "SizeResponse({}x{} v{} capabilities {:#04x})",
+                width, height, version, capabilities
+            ),
+            Packet::SetPixel {
+                x,
+                y,
+                color,
+                canvas_id: 0,
+            } => write!(f, "SetPixel({},{} {})", x, y, color),
+            Packet::SetPixel {
+                x,
+                y,
+                color,
+                canvas_id,
+            } => write!(f, "SetPixel({},{} {} canvas {})", x, y, color, canvas_id),
+            Packet::SetPixelRgba { x, y, color } => {
+                write!(f, "SetPixelRgba({},{} {})", x, y, color)
+            }
+            Packet::SetGray { x, y, value } => write!(f, "SetGray({},{} {})", x, y, value),
+            Packet::FillRect {
+                x,
+                y,
+                width,
+                height,
+                color,
+            } => write!(f, "FillRect({},{} {}x{} {})", x, y, width, height, color),
+            Packet::SetRowRle { x, y, runs } => {
+                write!(f, "SetRowRle({},{} {} runs)", x, y, runs.len())
+            }
+            Packet::DrawText { x, y, color, text } => {
+                write!(f, "DrawText({},{} {} {:?})", x, y, color, text)
+            }
+            Packet::DrawCircle {
+                cx,
+                cy,
+                r,
+                color,
+                fill,
+            } => write!(
+                f,
+                "DrawCircle({},{} r{} {}{})",
+                cx,
+                cy,
+                r,
+                color,
+                if *fill { " filled" } else { "" }
+            ),
+            Packet::DrawLine {
+                x0,
+                y0,
+                x1,
+                y1,
+                color,
+            } => write!(f, "DrawLine({},{} {},{} {})", x0, y0, x1, y1, color),
+            Packet::SetRectCompressed {
+                x,
+                y,
+                width,
+                height,
+                data,
+            } => write!(
+                f,
+                "SetRectCompressed({},{} {}x{} {} bytes)",
+                x,
+                y,
+                width,
+                height,
+                data.len()
+            ),
+            Packet::SetPixelIfEqual {
+                x,
+                y,
+                expected,
+                color,
+            } => write!(f, "SetPixelIfEqual({},{} {} -> {})", x, y, expected, color),
+            Packet::GetPixel { x, y } => write!(f, "GetPixel({},{})", x, y),
+            Packet::PixelResponse { x, y, color } => {
+                write!(f, "PixelResponse({},{} {})", x, y, color)
+            }
+            Packet::GetRect {
+                x,
+                y,
+                width,
+                height,
+            } => write!(f, "GetRect({},{} {}x{})", x, y, width, height),
+            Packet::RectResponse {
+                x,
+                y,
+                width,
+                height,
+                ..
+            } => write!(f, "RectResponse({},{} {}x{})", x, y, width, height),
+            Packet::GetThumbnail { max_dim } => write!(f, "GetThumbnail({})", max_dim),
+            Packet::ThumbnailResponse {
+                width,
+                height,
+                y,
+                rows,
+                ..
+            } => write!(
+                f,
+                "ThumbnailResponse({}x{} rows {}..{})",
+                width,
+                height,
+                y,
+                u32::from(*y) + u32::from(*rows)
+            ),
+            Packet::SetPixels { pixels } => write!(f, "SetPixels({} pixels)", pixels.len()),
+            Packet::Clear => write!(f, "Clear"),
+        }
+    }
+}
+
+/// Reasons why a Pingxelflut packet could not be parsed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    /// The payload contained no packet data at all.
+    Empty,
+    /// The packet uses a protocol version that is not supported.
+    UnsupportedVersion(u8),
+    /// The packet type byte is not a known packet type.
+    UnknownOpcode(u8),
+    /// A set pixel packet was shorter than its fixed-size part.
+    TruncatedSetPixel { expected: usize, got: usize },
+    /// A size response packet was shorter than its fixed size.
+    TruncatedSizeResponse,
+    /// A get pixel packet was shorter than its fixed size.
+    TruncatedGetPixel,
+    /// A pixel response packet was shorter than its fixed-size part.
+    TruncatedPixelResponse { expected: usize, got: usize },
+    /// A batched set pixel packet was shorter than its declared pixel count requires.
+    TruncatedSetPixels { expected: usize, got: usize },
+    /// A batched set pixel packet declared more than [`Packet::MAX_BATCH_SIZE`] pixels.
+    BatchTooLarge(usize),
+    /// A rectangle fill packet was shorter than its fixed size.
+    TruncatedFillRect { expected: usize, got: usize },
+    /// A rectangle extends beyond the largest possible coordinate.
+    RectOutOfRange,
+    /// A rectangle covers more pixels than allowed for its packet type.
+    RectTooLarge { area: usize, max: usize },
+    /// A run-length encoded row packet declared more than [`Packet::MAX_RLE_RUNS`] runs.
+    TooManyRuns(usize),
+    /// A run-length encoded row packet was shorter than its declared run count requires.
+    TruncatedSetRowRle { expected: usize, got: usize },
+    /// A draw text packet was shorter than its declared text length requires.
+    TruncatedDrawText { expected: usize, got: usize },
+    /// A draw text packet declared more than [`Packet::MAX_TEXT_LENGTH`] bytes of text.
+    TextTooLong(usize),
+    /// The text of a draw text packet was not valid UTF-8.
+    InvalidText,
+    /// A draw circle packet was shorter than its fixed size.
+    TruncatedDrawCircle { expected: usize, got: usize },
+    /// A draw circle packet had a radius larger than [`Packet::MAX_CIRCLE_RADIUS`].
+    CircleTooLarge(u16),
+    /// A draw circle packet had a style other than outline (0) or filled (1).
+    InvalidCircleStyle(u8),
+    /// A draw line packet was shorter than its fixed size.
+    TruncatedDrawLine { expected: usize, got: usize },
+    /// A compressed rectangle packet was shorter than its declared data length requires.
+    TruncatedSetRectCompressed { expected: usize, got: usize },
+    /// A conditional set pixel packet was shorter than its fixed size.
+    TruncatedSetPixelIfEqual { expected: usize, got: usize },
+    /// A compressed rectangle packet declared more than [`Packet::MAX_COMPRESSED_DATA_SIZE`] bytes of data.
+    CompressedDataTooLarge(usize),
+    /// The data of a compressed rectangle packet was not a valid zlib stream.
+    InvalidCompressedData,
+    /// The data of a compressed rectangle packet did not decompress to the size of its rectangle.
+    DecompressedSizeMismatch { expected: usize },
+    /// A rectangle request packet was shorter than its fixed size.
+    TruncatedGetRect { expected: usize, got: usize },
+    /// A rectangle response packet was shorter than its rectangle size requires.
+    TruncatedRectResponse { expected: usize, got: usize },
+    /// A thumbnail request packet was shorter than its fixed size.
+    TruncatedGetThumbnail { expected: usize, got: usize },
+    /// A thumbnail response packet was shorter than its rows require.
+    TruncatedThumbnailResponse { expected: usize, got: usize },
+    /// The color data was neither 3 (RGB) nor 4 (RGBA) bytes long.
+    InvalidColorLength(usize),
+    /// A pixel lies outside the canvas size given to [`Packet::from_bytes_bounded`] or [`Packet::from_bytes_bounded_with_mode`].
+    OutOfBounds { x: u16, y: u16 },
+    /// A set pixel packet has the length of the other coordinate mode, see [`Packet::from_bytes_with_mode`].
+    CoordinateModeMismatch {
+        expected: CoordinateMode,
+        found: CoordinateMode,
+    },
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Empty => write!(f, "empty packet"),
+            ParseError::UnsupportedVersion(version) => {
+                write!(f, "unsupported protocol version {}", version)
+            }
+            ParseError::UnknownOpcode(kind) => write!(f, "unknown packet type {:#04x}", kind),
+            ParseError::TruncatedSetPixel { expected, got } => write!(
+                f,
+                "truncated set pixel packet: expected at least {} bytes, got {}",
+                expected, got
+            ),
+            ParseError::TruncatedSizeResponse => write!(f, "truncated size response packet"),
+            ParseError::TruncatedGetPixel => write!(f, "truncated get pixel packet"),
+            ParseError::TruncatedPixelResponse { expected, got } => write!(
+                f,
+                "truncated pixel response packet: expected at least {} bytes, got {}",
+                expected, got
+            ),
+            ParseError::TruncatedSetPixels { expected, got } => write!(
+                f,
+                "truncated batched set pixel packet: expected at least {} bytes, got {}",
+                expected, got
+            ),
+            ParseError::BatchTooLarge(count) => write!(
+                f,
+                "batch of {} pixels exceeds maximum of {}",
+                count,
+                Packet::MAX_BATCH_SIZE
+            ),
+            ParseError::TruncatedFillRect { expected, got } => write!(
+                f,
+                "truncated rectangle fill packet: expected {} bytes, got {}",
+                expected, got
+            ),
+            ParseError::RectOutOfRange => write!(f, "rectangle exceeds the coordinate range"),
+            ParseError::RectTooLarge { area, max } => {
+                write!(f, "rectangle of {} pixels exceeds maximum of {}", area, max)
+            }
+            ParseError::TooManyRuns(count) => write!(
+                f,
+                "row of {} runs exceeds maximum of {}",
+                count,
+                Packet::MAX_RLE_RUNS
+            ),
+            ParseError::TruncatedSetRowRle { expected, got } => write!(
+                f,
+                "truncated run-length encoded row packet: expected at least {} bytes, got {}",
+                expected, got
+            ),
+            ParseError::TruncatedDrawText { expected, got } => write!(
+                f,
+                "truncated draw text packet: expected at least {} bytes, got {}",
+                expected, got
+            ),
+            ParseError::TextTooLong(length) => write!(
+                f,
+                "text of {} bytes exceeds maximum of {}",
+                length,
+                Packet::MAX_TEXT_LENGTH
+            ),
+            ParseError::InvalidText => write!(f, "text is not valid UTF-8"),
+            ParseError::TruncatedDrawCircle { expected, got } => write!(
+                f,
+                "truncated draw circle packet: expected {} bytes, got {}",
+                expected, got
+            ),
+            ParseError::CircleTooLarge(radius) => write!(
+                f,
+                "circle radius {} exceeds maximum of {}",
+                radius,
+                Packet::MAX_CIRCLE_RADIUS
+            ),
+            ParseError::TruncatedDrawLine { expected, got } => write!(
+                f,
+                "truncated draw line packet: expected {} bytes, got {}",
+                expected, got
+            ),
+            ParseError::InvalidCircleStyle(style) => {
+                write!(f, "invalid circle style {:#04x}", style)
+            }
+            ParseError::TruncatedSetRectCompressed { expected, got } => write!(
+                f,
+                "truncated compressed rectangle packet: expected at least {} bytes, got {}",
+                expected, got
+            ),
+            ParseError::TruncatedSetPixelIfEqual { expected, got } => write!(
+                f,
+                "truncated conditional set pixel packet: expected {} bytes, got {}",
+                expected, got
+            ),
+            ParseError::CompressedDataTooLarge(length) => write!(
+                f,
+                "compressed data of {} bytes exceeds maximum of {}",
+                length,
+                Packet::MAX_COMPRESSED_DATA_SIZE
+            ),
+            ParseError::InvalidCompressedData => write!(f, "invalid compressed data"),
+            ParseError::DecompressedSizeMismatch { expected } => write!(
+                f,
+                "compressed data does not decompress to the expected {} bytes",
+                expected
+            ),
+            ParseError::TruncatedGetRect { expected, got } => write!(
+                f,
+                "truncated rectangle request packet: expected {} bytes, got {}",
+                expected, got
+            ),
+            ParseError::TruncatedRectResponse { expected, got } => write!(
+                f,
+                "truncated rectangle response packet: expected at least {} bytes, got {}",
+                expected, got
+            ),
+            ParseError::TruncatedGetThumbnail { expected, got } => write!(
+                f,
+                "truncated thumbnail request packet: expected {} bytes, got {}",
+                expected, got
+            ),
+            ParseError::TruncatedThumbnailResponse { expected, got } => write!(
+                f,
+                "truncated thumbnail response packet: expected at least {} bytes, got {}",
+                expected, got
+            ),
+            ParseError::InvalidColorLength(length) => {
+                write!(f, "invalid color length {}, expected 3 or 4", length)
+            }
+            ParseError::OutOfBounds { x, y } => {
+                write!(f, "pixel {},{} is outside the canvas", x, y)
+            }
+            ParseError::CoordinateModeMismatch { expected, found } => write!(
+                f,
+                "set pixel packet has {} coordinates, but {} coordinates are expected",
+                found, expected
+            ),
+        }
+    }
+}
+
+impl Error for ParseError {}
+
+/// Reasons why a hex color could not be parsed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseColorError {
+    /// The color did not have 6 (RGB) or 8 (RGBA) hex digits.
+    InvalidLength(usize),
+    /// The color contained a character that is not a hex digit.
+    InvalidDigit,
+}
+
+impl Display for ParseColorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseColorError::InvalidLength(length) => write!(
+                f,
+                "invalid color length {}, expected 6 or 8 hex digits",
+                length
+            ),
+            ParseColorError::InvalidDigit => write!(f, "invalid hex digit in color"),
+        }
+    }
+}
+
+impl Error for ParseColorError {}
+
 /// A Pixelflut color.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Color {
     /// Red channel.
     pub red: u8,
@@ -121,6 +1823,12 @@ impl Color {
         }
     }
 
+    /// Create an opaque gray color with the given intensity in all three channels.
+    #[inline]
+    pub fn from_gray(value: u8) -> Self {
+        Self::from_rgb([value; 3])
+    }
+
     /// Create a color struct from four RGBA bytes.
     #[inline]
     pub fn from_rgba([red, green, blue, alpha]: [u8; 4]) -> Self {
@@ -132,6 +1840,46 @@ impl Color {
         }
     }
 
+    /// Create a color struct from the first three bytes of a slice, ignoring any further bytes.
+    ///
+    /// [`None`] is returned if the slice is shorter than three bytes.
+    #[inline]
+    pub fn from_rgb_bytes(bytes: &[u8]) -> Option<Self> {
+        Some(Self::from_rgb(bytes.get(..3)?.try_into().unwrap()))
+    }
+
+    /// Parse a `#rrggbb` or `#rrggbbaa` hex color; the leading `#` is optional.
+    pub fn from_hex(hex: &str) -> Result<Self, ParseColorError> {
+        let digits = hex.strip_prefix('#').unwrap_or(hex);
+        if !digits.bytes().all(|digit| digit.is_ascii_hexdigit()) {
+            return Err(ParseColorError::InvalidDigit);
+        }
+        let channel = |index: usize| u8::from_str_radix(&digits[index..index + 2], 16).unwrap();
+        match digits.len() {
+            6 => Ok(Self::from_rgb([channel(0), channel(2), channel(4)])),
+            8 => Ok(Self::from_rgba([
+                channel(0),
+                channel(2),
+                channel(4),
+                channel(6),
+            ])),
+            length => Err(ParseColorError::InvalidLength(length)),
+        }
+    }
+
+    /// Format the color as `#rrggbb`, or `#rrggbbaa` if it has an alpha value.
+    /// This is the inverse of [`Color::from_hex`].
+    pub fn to_hex(&self) -> String {
+        self.to_string()
+    }
+
+    /// The color as four RGBA bytes, fully opaque if the color doesn’t have a custom alpha value.
+    /// This is the layout of pixels in RGBA frame buffers.
+    #[inline]
+    pub fn to_rgba(&self) -> [u8; 4] {
+        [self.red, self.green, self.blue, self.alpha()]
+    }
+
     /// Returns an alpha value, possibly set to fully opaque if the color doesn’t have a custom alpha value.
     pub fn alpha(&self) -> u8 {
         self.alpha.unwrap_or(0xff)
@@ -155,6 +1903,15 @@ impl Color {
         }
     }
 
+    /// Size of the encoded color: 3 bytes for RGB, 4 bytes for RGBA.
+    pub fn encoded_size(&self) -> usize {
+        if self.alpha.is_some() {
+            4
+        } else {
+            3
+        }
+    }
+
     /// Convert the color to its byte representation.
     pub fn to_bytes(self) -> Vec<u8> {
         let mut buffer = vec![self.red, self.green, self.blue];
@@ -164,3 +1921,769 @@ impl Color {
         buffer
     }
 }
+
+/// Hexadecimal form of a color, `#rrggbb` or `#rrggbbaa` if it has an alpha value.
+impl Display for Color {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{:02x}{:02x}{:02x}", self.red, self.green, self.blue)?;
+        if let Some(alpha) = self.alpha {
+            write!(f, "{:02x}", alpha)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Color {
+    type Err = ParseColorError;
+
+    fn from_str(hex: &str) -> Result<Self, Self::Err> {
+        Self::from_hex(hex)
+    }
+}
+
+/// RGBA bytes of a color, see [`Color::to_rgba`].
+impl From<Color> for [u8; 4] {
+    fn from(color: Color) -> Self {
+        color.to_rgba()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parse a packet body, which is prefixed with the protocol version.
+    fn parse(body: &[u8]) -> Result<Packet, ParseError> {
+        let mut bytes = vec![PROTOCOL_VERSION];
+        bytes.extend_from_slice(body);
+        Packet::from_bytes(&bytes)
+    }
+
+    /// Assert that a packet is parsed back unchanged from its encoding.
+    fn assert_round_trip(packet: Packet) {
+        let bytes = packet.to_bytes();
+        assert_eq!(bytes.len(), packet.encoded_size(), "size of {}", packet);
+        assert_eq!(Packet::from_bytes(&bytes), Ok(packet));
+    }
+
+    #[test]
+    fn round_trips_basic_packets() {
+        assert_round_trip(Packet::SizeRequest { canvas_id: 0 });
+        assert_round_trip(Packet::SizeResponse {
+            width: 1920,
+            height: 1080,
+            version: PROTOCOL_VERSION,
+            capabilities: 0,
+        });
+        assert_round_trip(Packet::SetPixel {
+            x: 300,
+            y: 2,
+            color: Color::from_rgb([0xff, 0x80, 0x00]),
+            canvas_id: 0,
+        });
+        assert_round_trip(Packet::SetPixel {
+            x: 0,
+            y: u16::MAX,
+            color: Color::from_rgba([1, 2, 3, 4]),
+            canvas_id: 0,
+        });
+    }
+
+    #[test]
+    fn rejects_empty_and_unknown_packets() {
+        assert_eq!(Packet::from_bytes(&[]), Err(ParseError::Empty));
+        assert_eq!(parse(&[]), Err(ParseError::Empty));
+        assert_eq!(
+            Packet::from_bytes(&[0x02, 0xaa]),
+            Err(ParseError::UnsupportedVersion(2))
+        );
+        assert_eq!(parse(&[0x42]), Err(ParseError::UnknownOpcode(0x42)));
+    }
+
+    #[test]
+    fn rejects_truncated_packets() {
+        let cases: &[(&[u8], ParseError)] = &[
+            (
+                &[0xcc, 0, 1, 0],
+                ParseError::TruncatedSetPixel {
+                    expected: 8,
+                    got: 4,
+                },
+            ),
+            (
+                &[0xcd, 1, 0, 1, 0, 2, 0xff],
+                ParseError::TruncatedSetPixel {
+                    expected: 9,
+                    got: 7,
+                },
+            ),
+            (
+                &[0xdd, 0, 1, 0, 2, 0xff, 0, 0],
+                ParseError::TruncatedSetPixel {
+                    expected: 9,
+                    got: 8,
+                },
+            ),
+            (
+                &[0xcb, 0, 1, 0],
+                ParseError::TruncatedSetPixel {
+                    expected: 6,
+                    got: 4,
+                },
+            ),
+            (&[0xbb, 0, 16, 0], ParseError::TruncatedSizeResponse),
+            (&[0xee, 0, 1], ParseError::TruncatedGetPixel),
+            (
+                &[0xff, 0, 1, 0, 2, 0xff],
+                ParseError::TruncatedPixelResponse {
+                    expected: 8,
+                    got: 6,
+                },
+            ),
+            (
+                &[0xca],
+                ParseError::TruncatedSetPixels {
+                    expected: 2,
+                    got: 1,
+                },
+            ),
+            (
+                &[0xca, 2, 0, 1, 0, 2, 0xff, 0, 0],
+                ParseError::TruncatedSetPixels {
+                    expected: 16,
+                    got: 9,
+                },
+            ),
+            (
+                &[0xc2, 0, 0, 0, 0, 0, 1, 0, 1],
+                ParseError::TruncatedFillRect {
+                    expected: 12,
+                    got: 9,
+                },
+            ),
+            (
+                &[0xc3, 0, 0, 0, 0, 1, 0, 4],
+                ParseError::TruncatedSetRowRle {
+                    expected: 11,
+                    got: 8,
+                },
+            ),
+            (
+                &[0xc4, 0, 0, 0, 0, 0xff, 0xff, 0xff, 3, b'a'],
+                ParseError::TruncatedDrawText {
+                    expected: 12,
+                    got: 10,
+                },
+            ),
+            (
+                &[0xc5, 0, 8, 0, 8, 0, 4, 0],
+                ParseError::TruncatedDrawCircle {
+                    expected: 11,
+                    got: 8,
+                },
+            ),
+            (
+                &[0xc6, 0, 0, 0, 0, 0, 9, 0, 9],
+                ParseError::TruncatedDrawLine {
+                    expected: 12,
+                    got: 9,
+                },
+            ),
+            (
+                &[0xc7, 0, 0, 0, 0, 0, 2, 0, 2, 0, 8, 0x78],
+                ParseError::TruncatedSetRectCompressed {
+                    expected: 19,
+                    got: 12,
+                },
+            ),
+            (
+                &[0xc8, 0, 1, 0, 2, 0, 0, 0],
+                ParseError::TruncatedSetPixelIfEqual {
+                    expected: 11,
+                    got: 8,
+                },
+            ),
+            (
+                &[0xe1, 0, 0, 0, 0, 0, 4],
+                ParseError::TruncatedGetRect {
+                    expected: 9,
+                    got: 7,
+                },
+            ),
+            (
+                &[0xf1, 0, 0, 0, 0, 0, 2, 0, 1, 0xff, 0, 0],
+                ParseError::TruncatedRectResponse {
+                    expected: 15,
+                    got: 12,
+                },
+            ),
+            (
+                &[0xe2, 0],
+                ParseError::TruncatedGetThumbnail {
+                    expected: 3,
+                    got: 2,
+                },
+            ),
+            (
+                &[0xf2, 0, 2, 0, 2, 0, 0, 0],
+                ParseError::TruncatedThumbnailResponse {
+                    expected: 9,
+                    got: 8,
+                },
+            ),
+        ];
+        for (body, error) in cases {
+            assert_eq!(parse(body), Err(*error), "parsing {:02x?}", body);
+        }
+    }
+
+    #[test]
+    fn rejects_oversized_packets() {
+        assert_eq!(parse(&[0xca, 201]), Err(ParseError::BatchTooLarge(201)));
+        assert_eq!(
+            parse(&[0xc3, 0, 0, 0, 0, 201]),
+            Err(ParseError::TooManyRuns(201))
+        );
+        assert_eq!(
+            parse(&[0xc4, 0, 0, 0, 0, 0, 0, 0, 65]),
+            Err(ParseError::TextTooLong(65))
+        );
+        assert_eq!(
+            parse(&[0xc5, 0, 0, 0, 0, 0, 128, 0, 0, 0, 0]),
+            Err(ParseError::CircleTooLarge(128))
+        );
+        assert_eq!(
+            parse(&[0xc2, 0, 0, 0, 0, 1, 0, 1, 1, 0, 0, 0]),
+            Err(ParseError::RectTooLarge {
+                area: 256 * 257,
+                max: Packet::MAX_FILL_AREA
+            })
+        );
+        assert_eq!(
+            parse(&[0xe1, 0, 0, 0, 0, 0, 33, 0, 32]),
+            Err(ParseError::RectTooLarge {
+                area: 33 * 32,
+                max: Packet::MAX_GET_RECT_AREA
+            })
+        );
+        assert_eq!(
+            parse(&[0xc7, 0, 0, 0, 0, 0, 1, 0, 1, 0x05, 0xdc]),
+            Err(ParseError::CompressedDataTooLarge(1500))
+        );
+    }
+
+    #[test]
+    fn rejects_rectangles_beyond_the_coordinate_range() {
+        assert_eq!(
+            parse(&[0xc2, 0xff, 0xff, 0, 0, 0, 2, 0, 1, 0, 0, 0]),
+            Err(ParseError::RectOutOfRange)
+        );
+        // 0xfffe plus 3 pixels ends one pixel past the largest coordinate.
+        assert_eq!(
+            parse(&[0xc3, 0xff, 0xfe, 0, 0, 1, 0, 3, 0, 0, 0]),
+            Err(ParseError::RectOutOfRange)
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        assert_eq!(
+            parse(&[0xc4, 0, 0, 0, 0, 0, 0, 0, 2, 0xc3, 0x28]),
+            Err(ParseError::InvalidText)
+        );
+        assert_eq!(
+            parse(&[0xc5, 0, 0, 0, 0, 0, 4, 2, 0, 0, 0]),
+            Err(ParseError::InvalidCircleStyle(2))
+        );
+        assert_eq!(
+            parse(&[0xcc, 0, 1, 0, 2, 0xff, 0, 0, 0, 0]),
+            Err(ParseError::InvalidColorLength(5))
+        );
+        assert_eq!(
+            Packet::decompress_rect(&[1, 2, 3], 1, 1),
+            Err(ParseError::InvalidCompressedData)
+        );
+        assert_eq!(
+            Packet::from_bytes_bounded(&[1, 0xcc, 0, 16, 0, 0, 0, 0, 0], 16, 16),
+            Err(ParseError::OutOfBounds { x: 16, y: 0 })
+        );
+        assert_eq!(
+            Packet::from_bytes_with_mode(&[1, 0xcc, 3, 4, 0, 0, 0], CoordinateMode::Full),
+            Err(ParseError::CoordinateModeMismatch {
+                expected: CoordinateMode::Full,
+                found: CoordinateMode::Compact
+            })
+        );
+    }
+
+    #[test]
+    fn errors_have_messages() {
+        assert_eq!(
+            ParseError::TruncatedSetPixel {
+                expected: 8,
+                got: 4
+            }
+            .to_string(),
+            "truncated set pixel packet: expected at least 8 bytes, got 4"
+        );
+        assert_eq!(
+            ParseError::UnknownOpcode(0x42).to_string(),
+            "unknown packet type 0x42"
+        );
+    }
+
+    #[test]
+    fn round_trips_rgba_pixels() {
+        let packet = Packet::SetPixelRgba {
+            x: 7,
+            y: 9,
+            color: Color::from_rgba([0xff, 0, 0, 0x80]),
+        };
+        assert_eq!(packet.to_bytes(), [1, 0xdd, 0, 7, 0, 9, 0xff, 0, 0, 0x80]);
+        assert_round_trip(packet);
+    }
+
+    #[test]
+    fn round_trips_pixel_reads() {
+        assert_round_trip(Packet::GetPixel { x: 300, y: 2 });
+        assert_round_trip(Packet::PixelResponse {
+            x: 300,
+            y: 2,
+            color: Color::from_rgb([0x12, 0x34, 0x56]),
+        });
+        assert_round_trip(Packet::PixelResponse {
+            x: 0,
+            y: 0,
+            color: Color::from_rgba([0x12, 0x34, 0x56, 0x78]),
+        });
+    }
+
+    #[test]
+    fn accepts_only_the_current_protocol_version() {
+        let mut bytes = Packet::SetPixel {
+            x: 1,
+            y: 2,
+            color: Color::from_rgb([3, 4, 5]),
+            canvas_id: 0,
+        }
+        .to_bytes();
+        assert_eq!(bytes[0], PROTOCOL_VERSION);
+        assert!(Packet::from_bytes(&bytes).is_ok());
+        bytes[0] = 2;
+        assert_eq!(
+            Packet::from_bytes(&bytes),
+            Err(ParseError::UnsupportedVersion(2))
+        );
+    }
+
+    #[test]
+    fn round_trips_batches() {
+        for count in [0, 1, Packet::MAX_BATCH_SIZE] {
+            let pixels = (0..count)
+                .map(|i| (i as u16, u16::MAX - i as u16, Color::from_rgb([i as u8; 3])))
+                .collect();
+            let packet = Packet::SetPixels { pixels };
+            assert!(packet.encoded_size() <= Packet::MAX_SIZE);
+            assert_round_trip(packet);
+        }
+    }
+
+    #[test]
+    fn displays_every_packet_type() {
+        let color = Color::from_rgb([0xff, 0x00, 0xaa]);
+        let translucent = Color::from_rgba([0x12, 0x34, 0x56, 0x78]);
+        let cases = [
+            (Packet::SizeRequest { canvas_id: 0 }, "SizeRequest"),
+            (
+                Packet::SizeRequest { canvas_id: 2 },
+                "SizeRequest(canvas 2)",
+            ),
+            (
+                Packet::SizeResponse {
+                    width: 1920,
+                    height: 1080,
+                    version: 1,
+                    capabilities: 0,
+                },
+                "SizeResponse(1920x1080 v1)",
+            ),
+            (
+                Packet::SizeResponse {
+                    width: 1920,
+                    height: 1080,
+                    version: 1,
+                    capabilities: 0x03,
+                },
+                "SizeResponse(1920x1080 v1 capabilities 0x03)",
+            ),
+            (
+                Packet::SetPixel {
+                    x: 123,
+                    y: 45,
+                    color,
+                    canvas_id: 0,
+                },
+                "SetPixel(123,45 #ff00aa)",
+            ),
+            (
+                Packet::SetPixel {
+                    x: 123,
+                    y: 45,
+                    color,
+                    canvas_id: 1,
+                },
+                "SetPixel(123,45 #ff00aa canvas 1)",
+            ),
+            (
+                Packet::SetPixelRgba {
+                    x: 1,
+                    y: 2,
+                    color: translucent,
+                },
+                "SetPixelRgba(1,2 #12345678)",
+            ),
+            (
+                Packet::SetGray {
+                    x: 1,
+                    y: 2,
+                    value: 128,
+                },
+                "SetGray(1,2 128)",
+            ),
+            (
+                Packet::FillRect {
+                    x: 1,
+                    y: 2,
+                    width: 3,
+                    height: 4,
+                    color,
+                },
+                "FillRect(1,2 3x4 #ff00aa)",
+            ),
+            (
+                Packet::SetRowRle {
+                    x: 1,
+                    y: 2,
+                    runs: vec![(3, color), (4, translucent)],
+                },
+                "SetRowRle(1,2 2 runs)",
+            ),
+            (
+                Packet::DrawText {
+                    x: 1,
+                    y: 2,
+                    color,
+                    text: "hi".to_owned(),
+                },
+                "DrawText(1,2 #ff00aa \"hi\")",
+            ),
+            (
+                Packet::DrawCircle {
+                    cx: 10,
+                    cy: 20,
+                    r: 5,
+                    color,
+                    fill: false,
+                },
+                "DrawCircle(10,20 r5 #ff00aa)",
+            ),
+            (
+                Packet::DrawCircle {
+                    cx: 10,
+                    cy: 20,
+                    r: 5,
+                    color,
+                    fill: true,
+                },
+                "DrawCircle(10,20 r5 #ff00aa filled)",
+            ),
+            (
+                Packet::DrawLine {
+                    x0: 1,
+                    y0: 2,
+                    x1: 3,
+                    y1: 4,
+                    color,
+                },
+                "DrawLine(1,2 3,4 #ff00aa)",
+            ),
+            (
+                Packet::SetRectCompressed {
+                    x: 1,
+                    y: 2,
+                    width: 3,
+                    height: 4,
+                    data: vec![0; 5],
+                },
+                "SetRectCompressed(1,2 3x4 5 bytes)",
+            ),
+            (
+                Packet::SetPixelIfEqual {
+                    x: 1,
+                    y: 2,
+                    expected: translucent,
+                    color,
+                },
+                "SetPixelIfEqual(1,2 #12345678 -> #ff00aa)",
+            ),
+            (Packet::GetPixel { x: 1, y: 2 }, "GetPixel(1,2)"),
+            (
+                Packet::PixelResponse { x: 1, y: 2, color },
+                "PixelResponse(1,2 #ff00aa)",
+            ),
+            (
+                Packet::GetRect {
+                    x: 1,
+                    y: 2,
+                    width: 3,
+                    height: 4,
+                },
+                "GetRect(1,2 3x4)",
+            ),
+            (
+                Packet::RectResponse {
+                    x: 1,
+                    y: 2,
+                    width: 1,
+                    height: 1,
+                    pixels: vec![color],
+                },
+                "RectResponse(1,2 1x1)",
+            ),
+            (Packet::GetThumbnail { max_dim: 64 }, "GetThumbnail(64)"),
+            (
+                Packet::ThumbnailResponse {
+                    width: 64,
+                    height: 36,
+                    y: 10,
+                    rows: 2,
+                    pixels: vec![color; 128],
+                },
+                "ThumbnailResponse(64x36 rows 10..12)",
+            ),
+            (
+                Packet::SetPixels {
+                    pixels: vec![(1, 2, color), (3, 4, color)],
+                },
+                "SetPixels(2 pixels)",
+            ),
+            (Packet::Clear, "Clear"),
+        ];
+        for (packet, expected) in cases {
+            assert_eq!(packet.to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn round_trips_hex_colors() {
+        for hex in ["#000000", "#ff00aa", "#123456", "#12345678", "#ffffff00"] {
+            let color = Color::from_hex(hex).unwrap();
+            assert_eq!(color.to_hex(), hex);
+            assert_eq!(hex.parse::<Color>(), Ok(color));
+        }
+        assert_eq!(
+            Color::from_hex("ABCDEF"),
+            Ok(Color::from_rgb([0xab, 0xcd, 0xef]))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_hex_colors() {
+        for (hex, error) in [
+            ("", ParseColorError::InvalidLength(0)),
+            ("#", ParseColorError::InvalidLength(0)),
+            ("#12345", ParseColorError::InvalidLength(5)),
+            ("#1234567", ParseColorError::InvalidLength(7)),
+            ("#123456789", ParseColorError::InvalidLength(9)),
+            ("#12345g", ParseColorError::InvalidDigit),
+            ("##123456", ParseColorError::InvalidDigit),
+            ("#+12345", ParseColorError::InvalidDigit),
+            ("#ääää", ParseColorError::InvalidDigit),
+        ] {
+            assert_eq!(Color::from_hex(hex), Err(error), "{:?}", hex);
+        }
+    }
+
+    #[test]
+    fn bounded_parsing_accepts_pixels_up_to_the_canvas_edge() {
+        let (width, height) = (64, 48);
+        let color = Color::from_rgb([0xff, 0, 0]);
+        let set_pixel = |x, y| Packet::SetPixel {
+            x,
+            y,
+            color,
+            canvas_id: 0,
+        };
+        let bounded =
+            |packet: &Packet| Packet::from_bytes_bounded(&packet.to_bytes(), width, height);
+        for packet in [
+            set_pixel(width - 1, height - 1),
+            Packet::SetPixels {
+                pixels: vec![(0, 0, color), (width - 1, height - 1, color)],
+            },
+            Packet::SetRowRle {
+                x: width - 2,
+                y: height - 1,
+                runs: vec![(2, color)],
+            },
+            // Batches and rows are clipped when drawn, so their pixels on the canvas still count.
+            Packet::SetPixels {
+                pixels: vec![(0, 0, color), (0, height, color)],
+            },
+            Packet::SetRowRle {
+                x: width - 2,
+                y: 0,
+                runs: vec![(3, color)],
+            },
+            // Other canvases may be larger.
+            Packet::SetPixel {
+                x: width,
+                y: height,
+                color,
+                canvas_id: 1,
+            },
+        ] {
+            assert_eq!(bounded(&packet), Ok(packet));
+        }
+
+        assert_eq!(
+            bounded(&set_pixel(width, height - 1)),
+            Err(ParseError::OutOfBounds {
+                x: width,
+                y: height - 1
+            })
+        );
+        assert_eq!(
+            bounded(&set_pixel(width - 1, height)),
+            Err(ParseError::OutOfBounds {
+                x: width - 1,
+                y: height
+            })
+        );
+
+        let compact = set_pixel(width, 0).to_bytes_with_mode(CoordinateMode::Compact);
+        assert_eq!(
+            Packet::from_bytes_bounded_with_mode(&compact, CoordinateMode::Compact, width, height),
+            Err(ParseError::OutOfBounds { x: width, y: 0 })
+        );
+    }
+
+    #[test]
+    fn compact_set_pixel_encoding() {
+        let packet = Packet::SetPixel {
+            x: 0x2c,
+            y: 2,
+            color: Color::from_rgb([0xff, 0x80, 0x00]),
+            canvas_id: 0,
+        };
+        let bytes = packet.to_bytes_with_mode(CoordinateMode::Compact);
+        assert_eq!(bytes, [0x01, 0xcc, 0x2c, 0x02, 0xff, 0x80, 0x00]);
+        assert_eq!(
+            Packet::from_bytes_with_mode(&bytes, CoordinateMode::Compact),
+            Ok(packet)
+        );
+        // Pixels on other canvases have no compact encoding.
+        let other_canvas = Packet::SetPixel {
+            x: 0x2c,
+            y: 2,
+            color: Color::from_rgb([0xff, 0x80, 0x00]),
+            canvas_id: 1,
+        };
+        assert_eq!(
+            other_canvas.to_bytes_with_mode(CoordinateMode::Compact),
+            other_canvas.to_bytes()
+        );
+
+        assert!(CoordinateMode::fits_compact(256, 256));
+        assert!(!CoordinateMode::fits_compact(257, 1));
+        assert!(!CoordinateMode::fits_compact(1, 257));
+    }
+
+    #[test]
+    #[should_panic(expected = "X coordinate does not fit compact coordinates")]
+    fn compact_set_pixel_rejects_large_coordinates() {
+        let packet = Packet::SetPixel {
+            x: 256,
+            y: 0,
+            color: Color::from_rgb([0xff, 0x80, 0x00]),
+            canvas_id: 0,
+        };
+        packet.to_bytes_with_mode(CoordinateMode::Compact);
+    }
+
+    #[test]
+    fn compressed_rectangles_round_trip() {
+        let pixels: Vec<_> = (0..12u8)
+            .map(|i| Color::from_rgb([i, i.wrapping_mul(17), 0xff - i]))
+            .collect();
+        let Some(Packet::SetRectCompressed {
+            x,
+            y,
+            width,
+            height,
+            data,
+        }) = Packet::compress_rect(5, 6, 4, 3, &pixels)
+        else {
+            panic!("small rectangle is not compressed");
+        };
+        assert_eq!((x, y, width, height), (5, 6, 4, 3));
+        assert_eq!(Packet::decompress_rect(&data, width, height), Ok(pixels));
+
+        let too_large = vec![Color::from_rgb([0, 0, 0]); Packet::MAX_COMPRESSED_RECT_AREA + 256];
+        assert_eq!(Packet::compress_rect(0, 0, 256, 257, &too_large), None);
+    }
+
+    #[test]
+    fn decompression_stops_at_the_rectangle_size() {
+        // A megabyte of zeros compresses into about a kilobyte, but must not be inflated for a 2x2 rectangle.
+        let bomb = miniz_oxide::deflate::compress_to_vec_zlib(&vec![0; 1 << 20], COMPRESSION_LEVEL);
+        assert!(bomb.len() < Packet::MAX_COMPRESSED_DATA_SIZE);
+        assert_eq!(
+            Packet::decompress_rect(&bomb, 2, 2),
+            Err(ParseError::DecompressedSizeMismatch { expected: 12 })
+        );
+        // Too little data is rejected as well.
+        let short = miniz_oxide::deflate::compress_to_vec_zlib(&[0; 9], COMPRESSION_LEVEL);
+        assert_eq!(
+            Packet::decompress_rect(&short, 2, 2),
+            Err(ParseError::DecompressedSizeMismatch { expected: 12 })
+        );
+    }
+
+    #[test]
+    fn set_pixel_round_trips_in_both_coordinate_modes() {
+        for mode in [CoordinateMode::Full, CoordinateMode::Compact] {
+            for color in [
+                Color::from_rgb([0x12, 0x34, 0x56]),
+                Color::from_rgba([0x12, 0x34, 0x56, 0x78]),
+            ] {
+                let packet = Packet::SetPixel {
+                    x: 255,
+                    y: 7,
+                    color,
+                    canvas_id: 0,
+                };
+                let bytes = packet.to_bytes_with_mode(mode);
+                assert_eq!(
+                    Packet::from_bytes_with_mode(&bytes, mode),
+                    Ok(packet),
+                    "{} coordinates",
+                    mode
+                );
+                // The same packet in the other mode is recognized by its length.
+                assert_eq!(
+                    Packet::from_bytes_with_mode(&bytes, mode.other()),
+                    Err(ParseError::CoordinateModeMismatch {
+                        expected: mode.other(),
+                        found: mode,
+                    })
+                );
+            }
+        }
+        assert_eq!(
+            CoordinateMode::from_capabilities(CAPABILITY_COMPACT_COORDINATES),
+            CoordinateMode::Compact
+        );
+        assert_eq!(CoordinateMode::from_capabilities(0), CoordinateMode::Full);
+    }
+}
@@ -1,7 +1,9 @@
 use socket2::{Domain, Protocol, Socket, Type};
 use std::{
     io::{self, ErrorKind, Read},
-    net::SocketAddr,
+    net::{IpAddr, SocketAddr, SocketAddrV6},
+    thread,
+    time::Duration,
 };
 
 /// Includes both the real header (4 bytes) as well as the echo standard data (4 bytes).
@@ -12,6 +14,9 @@ pub const ECHO_REQUEST_V6: u8 = 128;
 pub const ECHO_REPLY_V4: u8 = 0;
 pub const ECHO_REPLY_V6: u8 = 129;
 
+/// Delay before the first retry of a failed send; it doubles with every further retry.
+const RETRY_BACKOFF: Duration = Duration::from_millis(1);
+
 /// The two kinds of echo packets, request and reply.
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum EchoDirection {
@@ -19,9 +24,24 @@ pub enum EchoDirection {
     Reply,
 }
 
+/// The kind of socket packets are sent with.
+///
+/// Raw sockets need special privileges, see the [`crate::client`] module.
+/// Unprivileged ICMP datagram sockets (`SOCK_DGRAM` with `IPPROTO_ICMP`) are supported on macOS, and on Linux if the user’s group is allowed by the `net.ipv4.ping_group_range` sysctl, which also covers ICMPv6.
+/// They can only send echo requests, and the kernel replaces the echo identifier with its own.
+/// Windows has no datagram ICMP sockets.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SendMode {
+    /// Send via a raw socket.
+    Raw,
+    /// Send via an unprivileged ICMP datagram socket.
+    Datagram,
+}
+
 /// An ICMP v4/v6 Echo Request packet.
 /// Provides functionality to send out Echo Request messages (pings) and capture their response.
-// TODO: ICMPv6 is not implemented yet.
+///
+/// The IP version is determined by the target address: IPv4 targets use ICMP, IPv6 targets use ICMPv6.
 pub struct Icmp {
     direction: EchoDirection,
     /// Ping identifier, part of the standard payload.
@@ -34,6 +54,12 @@ pub struct Icmp {
     payload: Vec<u8>,
     /// Ping sequence number, part of the standard payload.
     current_sequence_number: u16,
+    /// Kind of socket to send with; if unset, datagram sockets are used when raw sockets are not permitted.
+    send_mode: Option<SendMode>,
+    /// IPv4 time to live or IPv6 hop limit of sent packets; if unset, the operating system default is used.
+    ttl: Option<u8>,
+    /// Local address packets are sent from; if unset, the operating system picks one by routing.
+    source: Option<IpAddr>,
 }
 
 impl Icmp {
@@ -50,27 +76,92 @@ impl Icmp {
             packet: [0; ICMP_HEADER_SIZE].to_vec(),
             payload: Vec::new(),
             current_sequence_number: 0,
+            send_mode: None,
+            ttl: None,
+            source: None,
         }
     }
 
+    /// Always send with the given kind of socket, instead of choosing automatically.
+    pub fn set_send_mode(&mut self, send_mode: SendMode) {
+        self.send_mode = Some(send_mode);
+    }
+
+    /// Send with the given time to live instead of the operating system default, for example to limit how far packets reach.
+    /// For IPv6 targets, this is the hop limit. Either is set as a socket option of the matching IP version when sending.
+    pub fn set_ttl(&mut self, ttl: u8) {
+        self.ttl = Some(ttl);
+    }
+
+    /// The time to live or hop limit set with [`Icmp::set_ttl`], if any.
+    pub fn ttl(&self) -> Option<u8> {
+        self.ttl
+    }
+
+    /// Send from the given local address instead of the one the operating system picks, for example on hosts with
+    /// several addresses. The address must belong to this host and have the IP version of the target.
+    /// A link-local IPv6 address is bound in the scope of the target, so it can only be used for link-local targets.
+    pub fn set_source(&mut self, source: IpAddr) {
+        self.source = Some(source);
+    }
+
+    /// The source address set with [`Icmp::set_source`], if any.
+    pub fn source(&self) -> Option<IpAddr> {
+        self.source
+    }
+
     /// Set this ICMP packet’s custom payload.
     /// The first four bytes of the Echo Request packet are semi-standard and not affected by this payload.
     pub fn set_payload(&mut self, payload: Vec<u8>) {
         self.payload = payload;
     }
 
+    /// Set the sequence number of the next sent packet.
+    /// Subsequent sends increment the sequence number from there.
+    pub fn set_sequence_number(&mut self, sequence_number: u16) {
+        self.current_sequence_number = sequence_number;
+    }
+
+    /// Whether this packet is sent via ICMPv6, i.e. its target is an IPv6 address.
+    pub fn is_ipv6(&self) -> bool {
+        self.target.is_ipv6()
+    }
+
+    /// The ICMP message type of this packet, depending on both the echo direction and the IP version.
+    pub fn icmp_type(&self) -> u8 {
+        match (self.is_ipv6(), self.direction) {
+            (false, EchoDirection::Request) => ECHO_REQUEST_V4,
+            (false, EchoDirection::Reply) => ECHO_REPLY_V4,
+            (true, EchoDirection::Request) => ECHO_REQUEST_V6,
+            (true, EchoDirection::Reply) => ECHO_REPLY_V6,
+        }
+    }
+
     /// Send this ICMP packet.
     /// Apart from the send action this has the additional effect of incrementing the sequence number of this packet.
     ///
+    /// Without a send mode set, a raw socket is used if permitted, and an ICMP datagram socket otherwise.
+    ///
     /// Returns the socket used for sending so that responses can be received.
     pub fn send(&mut self) -> Result<Socket, io::Error> {
         self.encode();
-        let socket = if self.target.is_ipv4() {
-            Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::ICMPV4))?
-        } else {
-            Socket::new(Domain::IPV6, Type::RAW, Some(Protocol::ICMPV6))?
+        let socket = match self.send_mode {
+            Some(send_mode) => self.open_socket(send_mode)?,
+            None => match self.open_socket(SendMode::Raw) {
+                Err(why)
+                    if why.kind() == ErrorKind::PermissionDenied
+                        && self.direction == EchoDirection::Request =>
+                {
+                    self.open_socket(SendMode::Datagram)?
+                }
+                result => result?,
+            },
         };
 
+        if let Some(source) = self.source {
+            socket.bind(&self.bind_address(source)?.into())?;
+        }
+        self.apply_ttl(&socket)?;
         socket.send_to(&self.packet, &self.target.into())?;
 
         self.current_sequence_number = self.current_sequence_number.wrapping_add(1);
@@ -79,21 +170,69 @@ impl Icmp {
         Ok(socket)
     }
 
+    /// Set the time to live or hop limit on a socket of this packet’s IP version, if one was set with [`Icmp::set_ttl`].
+    fn apply_ttl(&self, socket: &Socket) -> Result<(), io::Error> {
+        match self.ttl {
+            Some(ttl) if self.is_ipv6() => socket.set_unicast_hops_v6(ttl.into()),
+            Some(ttl) => socket.set_ttl(ttl.into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Open a socket of the given kind for this packet’s IP version, without sending anything.
+    /// This is useful to check whether the process may send via this kind of socket.
+    pub fn open_socket(&self, send_mode: SendMode) -> Result<Socket, io::Error> {
+        let socket_type = match send_mode {
+            SendMode::Raw => Type::RAW,
+            SendMode::Datagram if self.direction == EchoDirection::Request => Type::DGRAM,
+            SendMode::Datagram => {
+                return Err(io::Error::new(
+                    ErrorKind::Unsupported,
+                    "echo replies can only be sent via raw sockets",
+                ))
+            }
+        };
+        if self.is_ipv6() {
+            Socket::new(Domain::IPV6, socket_type, Some(Protocol::ICMPV6))
+        } else {
+            Socket::new(Domain::IPV4, socket_type, Some(Protocol::ICMPV4))
+        }
+    }
+
+    /// The socket address to bind to for sending from a source address, in the scope of the target for IPv6.
+    fn bind_address(&self, source: IpAddr) -> Result<SocketAddr, io::Error> {
+        match (source, self.target) {
+            (IpAddr::V4(source), SocketAddr::V4(_)) => Ok(SocketAddr::new(source.into(), 0)),
+            (IpAddr::V6(source), SocketAddr::V6(target)) => Ok(SocketAddr::V6(SocketAddrV6::new(
+                source,
+                0,
+                0,
+                target.scope_id(),
+            ))),
+            _ => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "source and target address have different IP versions",
+            )),
+        }
+    }
+
+    /// Send this ICMP packet like [`Icmp::send`], but retry up to `retries` times if sending fails.
+    /// Sends can fail transiently, for example when the kernel runs out of buffer space under load.
+    /// Retries are delayed with an exponential backoff, blocking the current thread.
+    pub fn send_with_retries(&mut self, retries: u32) -> Result<Socket, io::Error> {
+        retry_with_backoff(retries, RETRY_BACKOFF, || self.send())
+    }
+
     /// Encode this packet’s data.
     fn encode(&mut self) {
         self.packet.truncate(ICMP_HEADER_SIZE);
-        self.packet[0] = match (self.target.is_ipv4(), self.direction) {
-            (true, EchoDirection::Request) => ECHO_REQUEST_V4,
-            (true, EchoDirection::Reply) => ECHO_REPLY_V4,
-            (false, EchoDirection::Request) => ECHO_REQUEST_V6,
-            (false, EchoDirection::Reply) => ECHO_REPLY_V6,
-        };
+        self.packet[0] = self.icmp_type();
 
         self.packet[1] = 0;
         self.packet[4] = (self.identifier >> 8) as u8;
         self.packet[5] = self.identifier as u8;
-        self.packet[6] = 0;
-        self.packet[7] = 0;
+        self.packet[6] = (self.current_sequence_number >> 8) as u8;
+        self.packet[7] = self.current_sequence_number as u8;
         self.packet.append(&mut self.payload.clone());
         self.checksum();
     }
@@ -108,7 +247,15 @@ impl Icmp {
     }
 
     /// Update this packet’s checksum.
+    ///
+    /// The ICMPv6 checksum covers a pseudo-header containing the source address, which is only known to the kernel.
+    /// Raw ICMPv6 sockets always have the kernel compute the checksum (RFC 3542 section 3.1), so it is left zero here.
     fn checksum(&mut self) {
+        if self.is_ipv6() {
+            self.packet[2] = 0;
+            self.packet[3] = 0;
+            return;
+        }
         let mut sum = 0u32;
         for word in self.packet.chunks(2) {
             let mut part = u16::from(word[0]) << 8;
@@ -126,11 +273,40 @@ impl Icmp {
     }
 }
 
+/// Run a fallible operation, retrying it up to `retries` times with exponential backoff starting at `backoff`.
+///
+/// Returns the last error if all attempts fail.
+pub(crate) fn retry_with_backoff<T>(
+    retries: u32,
+    mut backoff: Duration,
+    mut operation: impl FnMut() -> Result<T, io::Error>,
+) -> Result<T, io::Error> {
+    let mut attempt = 0;
+    loop {
+        match operation() {
+            Err(_) if attempt < retries => {
+                thread::sleep(backoff);
+                backoff *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
 /// Read ICMP packets from the specified socket, and return the first payload that matches a certain condition.
+///
+/// Raw IPv4 sockets receive the IP header along with the ICMP packet, while raw IPv6 sockets and datagram sockets do not.
 pub(crate) fn read_icmp_packets_until(
     socket: &mut Socket,
+    is_ipv6: bool,
     condition: impl Fn(&[u8]) -> bool,
 ) -> Result<Vec<u8>, io::Error> {
+    let header_size = if is_ipv6 || socket.r#type()? != Type::RAW {
+        0
+    } else {
+        IPV4_HEADER_SIZE
+    };
     let mut last_packet = Vec::new();
 
     loop {
@@ -142,11 +318,10 @@ pub(crate) fn read_icmp_packets_until(
                 ErrorKind::UnexpectedEof | ErrorKind::BrokenPipe => break,
                 _ => return Err(why),
             },
+            Ok(size) if size < header_size => {}
             Ok(size) => {
-                // FIXME: only works on IPv4
-                last_packet.resize(size - IPV4_HEADER_SIZE, 0);
-                last_packet.copy_from_slice(&buffer[IPV4_HEADER_SIZE..size]);
-                println!("packet {:?}", last_packet);
+                last_packet.resize(size - header_size, 0);
+                last_packet.copy_from_slice(&buffer[header_size..size]);
                 if condition(&last_packet) {
                     break;
                 }
@@ -156,13 +331,93 @@ pub(crate) fn read_icmp_packets_until(
     Ok(last_packet)
 }
 
-/// Read the first ICMP packet that has the specified type at payload index 4.
-pub(crate) fn read_first_icmp_packet_with_type(
-    socket: &mut Socket,
-    receive_type: u8,
-) -> Result<Vec<u8>, io::Error> {
-    // FIXME: use etherparse to more robustly read the packet type.
-    read_icmp_packets_until(socket, |buffer| {
-        buffer.starts_with(&[ECHO_REPLY_V4, 0]) && buffer.get(8).is_some_and(|v| *v == receive_type)
-    })
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    #[test]
+    fn ipv6_targets_use_icmpv6_types() {
+        let target = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 0);
+        let mut reply = Icmp::new(target, 0x1234, EchoDirection::Reply);
+        assert!(reply.is_ipv6());
+        assert_eq!(reply.icmp_type(), ECHO_REPLY_V6);
+        reply.set_payload(vec![1, 0xbb]);
+        reply.encode();
+        // The kernel computes the ICMPv6 checksum.
+        assert_eq!(
+            reply.packet,
+            [ECHO_REPLY_V6, 0, 0, 0, 0x12, 0x34, 0, 0, 1, 0xbb]
+        );
+
+        let request = Icmp::new(target, 0, EchoDirection::Request);
+        assert_eq!(request.icmp_type(), ECHO_REQUEST_V6);
+    }
+
+    #[test]
+    fn ipv4_targets_use_icmp_types() {
+        let target = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0);
+        let mut reply = Icmp::new(target, 0x1234, EchoDirection::Reply);
+        assert!(!reply.is_ipv6());
+        assert_eq!(reply.icmp_type(), ECHO_REPLY_V4);
+        reply.encode();
+        assert_eq!(reply.packet[0], ECHO_REPLY_V4);
+        assert_ne!(reply.packet[2..4], [0, 0]);
+        assert_eq!(
+            Icmp::new(target, 0, EchoDirection::Request).icmp_type(),
+            ECHO_REQUEST_V4
+        );
+    }
+
+    #[test]
+    fn retries_until_a_send_succeeds() {
+        let mut attempts = 0;
+        let result = retry_with_backoff(3, Duration::ZERO, || {
+            attempts += 1;
+            match attempts {
+                1 | 2 => Err(io::Error::other("no buffer space available")),
+                _ => Ok(attempts),
+            }
+        });
+        assert_eq!(result.unwrap(), 3);
+        assert_eq!(attempts, 3);
+    }
+
+    #[test]
+    fn returns_the_last_error_once_retries_are_used_up() {
+        let mut attempts = 0;
+        let result: Result<(), _> = retry_with_backoff(2, Duration::ZERO, || {
+            attempts += 1;
+            Err(io::Error::other(format!("attempt {}", attempts)))
+        });
+        assert_eq!(attempts, 3);
+        assert_eq!(result.unwrap_err().to_string(), "attempt 3");
+    }
+
+    #[test]
+    fn ttl_is_set_on_the_socket_of_the_ip_version() {
+        // Datagram sockets take the same options as ICMP sockets, without needing any privileges.
+        let v4 = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0);
+        let mut reply = Icmp::new(v4, 0, EchoDirection::Reply);
+        assert_eq!(reply.ttl(), None);
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, None).unwrap();
+        let default_ttl = socket.ttl().unwrap();
+        reply.apply_ttl(&socket).unwrap();
+        assert_eq!(socket.ttl().unwrap(), default_ttl);
+
+        reply.set_ttl(3);
+        assert_eq!(reply.ttl(), Some(3));
+        reply.apply_ttl(&socket).unwrap();
+        assert_eq!(socket.ttl().unwrap(), 3);
+
+        let v6 = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 0);
+        let mut reply = Icmp::new(v6, 0, EchoDirection::Reply);
+        reply.set_ttl(5);
+        assert_eq!(reply.ttl(), Some(5));
+        // Hosts without IPv6 cannot open the socket, but the value is still stored.
+        if let Ok(socket) = Socket::new(Domain::IPV6, Type::DGRAM, None) {
+            reply.apply_ttl(&socket).unwrap();
+            assert_eq!(socket.unicast_hops_v6().unwrap(), 5);
+        }
+    }
 }
@@ -0,0 +1,120 @@
+//! Canvas write benchmarks on an in-memory sink; run with `cargo bench -p server --bench canvas`.
+
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use rgb::{ComponentBytes, RGBA8};
+use server::canvas::Canvas;
+use server::sink::VecSink;
+
+const WIDTH: u16 = 1920;
+const HEIGHT: u16 = 1080;
+
+/// Queue `count` pixels spread over the whole canvas.
+fn queue_pixels(canvas: &mut Canvas, count: usize) {
+    let color = RGBA8::new(0x12, 0x34, 0x56, 0xff);
+    for i in 0..count {
+        let x = (i % WIDTH as usize) as u16;
+        let y = (i / WIDTH as usize % HEIGHT as usize) as u16;
+        canvas.set_pixel(x, y, color);
+    }
+}
+
+fn set_pixel(c: &mut Criterion) {
+    const BATCH: usize = 10_000;
+    let canvas = Canvas::new(Box::new(VecSink::new(WIDTH, HEIGHT)));
+    let mut group = c.benchmark_group("set_pixel");
+    group.throughput(Throughput::Elements(BATCH as u64));
+    group.bench_function(BenchmarkId::from_parameter(BATCH), |b| {
+        b.iter_batched(
+            || {
+                // Keep the queue from growing across iterations.
+                canvas.flush_queue();
+                canvas.clone()
+            },
+            |mut canvas| queue_pixels(black_box(&mut canvas), BATCH),
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+fn set_queue_pixels(c: &mut Criterion) {
+    let mut group = c.benchmark_group("set_queue_pixels");
+    // Refilling a queue of a million pixels for every sample is slow, so take fewer of them.
+    group.sample_size(10);
+    for (name, coalesce) in [("queued", false), ("coalesced", true)] {
+        let canvas = Canvas::new(Box::new(VecSink::new(WIDTH, HEIGHT))).with_coalescing(coalesce);
+        for depth in [1_000, 100_000, 1_000_000] {
+            group.throughput(Throughput::Elements(depth as u64));
+            group.bench_with_input(BenchmarkId::new(name, depth), &depth, |b, &depth| {
+                b.iter_batched(
+                    || queue_pixels(&mut canvas.clone(), depth),
+                    |()| canvas.set_queue_pixels(),
+                    BatchSize::PerIteration,
+                )
+            });
+        }
+    }
+    group.finish();
+}
+
+/// Queue `count` writes that hammer only the first `distinct` pixels of the top row.
+fn queue_hot_pixels(canvas: &mut Canvas, count: usize, distinct: usize) {
+    for i in 0..count {
+        let color = RGBA8::new(i as u8, (i >> 8) as u8, 0x56, 0xff);
+        canvas.set_pixel((i % distinct) as u16, 0, color);
+    }
+}
+
+/// Coalescing against plain queueing, for floods across distinct pixels and floods hammering a few pixels.
+fn coalescing(c: &mut Criterion) {
+    const WRITES: usize = 100_000;
+    let mut group = c.benchmark_group("coalescing");
+    group.throughput(Throughput::Elements(WRITES as u64));
+    for (name, coalesce) in [("queued", false), ("coalesced", true)] {
+        let canvas = Canvas::new(Box::new(VecSink::new(WIDTH, HEIGHT))).with_coalescing(coalesce);
+        group.bench_function(BenchmarkId::new(name, "sparse"), |b| {
+            b.iter_batched(
+                || canvas.clone(),
+                |mut canvas| {
+                    queue_pixels(&mut canvas, WRITES);
+                    canvas.set_queue_pixels();
+                },
+                BatchSize::SmallInput,
+            )
+        });
+        group.bench_function(BenchmarkId::new(name, "dense"), |b| {
+            b.iter_batched(
+                || canvas.clone(),
+                |mut canvas| {
+                    queue_hot_pixels(&mut canvas, WRITES, 100);
+                    canvas.set_queue_pixels();
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn full_frame(c: &mut Criterion) {
+    let canvas = Canvas::new(Box::new(VecSink::new(WIDTH, HEIGHT)));
+    // A full frame, as uploaded by a compressed rectangle covering the whole canvas.
+    let frame: Vec<_> = (0..WIDTH as usize * HEIGHT as usize)
+        .map(|i| RGBA8::new(i as u8, (i >> 8) as u8, (i >> 16) as u8, 0xff))
+        .collect();
+    let size = format!("{}x{}", WIDTH, HEIGHT);
+    let mut group = c.benchmark_group("full_frame");
+    group.throughput(Throughput::Elements(frame.len() as u64));
+    group.bench_function(BenchmarkId::new("set_rect", &size), |b| {
+        b.iter(|| canvas.set_rect(0, 0, WIDTH, HEIGHT, black_box(&frame)))
+    });
+    group.bench_function(BenchmarkId::new("blit_full", &size), |b| {
+        b.iter(|| assert!(canvas.blit_full(black_box(frame.as_bytes()))))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, set_pixel, set_queue_pixels, coalescing, full_frame);
+criterion_main!(benches);
@@ -0,0 +1,141 @@
+//! Packet ingestion benchmarks, comparing how received packets are handed to [`handle_packet`];
+//! run with `cargo bench -p server --bench ingestion`.
+
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use pingxelflut::format::{Color, CoordinateMode, Packet};
+use server::announce::SizeReplyCooldown;
+use server::canvas::{Canvas, ColorSpace};
+use server::handler::{handle_packet, RequestPolicy};
+use server::sink::VecSink;
+use server::source::ReceivedPacket;
+use server::stats::Stats;
+use server::thumbnail::ThumbnailJobs;
+use server::workers::PacketWorkers;
+
+/// How long each benchmark is run for.
+const MEASUREMENT_TIME: Duration = Duration::from_secs(2);
+
+const WIDTH: u16 = 1920;
+const HEIGHT: u16 = 1080;
+
+/// Number of packets handled per iteration.
+const PACKETS: usize = 100_000;
+
+/// Number of distinct client addresses the packets come from.
+const SOURCES: usize = 64;
+
+/// Handler state shared like in the server.
+#[derive(Clone)]
+struct Handler {
+    canvases: Arc<[Canvas]>,
+    stats: Arc<Stats>,
+    policy: RequestPolicy,
+}
+
+impl Handler {
+    fn handle(&self, request: ReceivedPacket) {
+        handle_packet(&self.canvases, &self.stats, &self.policy, request);
+    }
+}
+
+/// Set pixel packets spread over the canvas and the sources.
+fn packets() -> Vec<ReceivedPacket> {
+    (0..PACKETS)
+        .map(|i| ReceivedPacket {
+            packet: Packet::SetPixel {
+                x: (i % WIDTH as usize) as u16,
+                y: (i / WIDTH as usize % HEIGHT as usize) as u16,
+                color: Color::from_rgb([0x12, 0x34, 0x56]),
+                canvas_id: 0,
+            },
+            source: IpAddr::V4(Ipv4Addr::new(10, 0, 0, (i % SOURCES) as u8)),
+            destination: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            identifier: 0,
+            sequence_number: 0,
+            interface_index: None,
+            reply_channel: None,
+        })
+        .collect()
+}
+
+/// Repeatedly handle a fresh batch of packets, then print the mean throughput.
+async fn bench<F: std::future::Future<Output = ()>>(
+    name: &str,
+    handler: &Handler,
+    mut routine: impl FnMut(Handler, Vec<ReceivedPacket>) -> F,
+) {
+    let mut measured = Duration::ZERO;
+    let mut iterations = 0u32;
+    while measured < MEASUREMENT_TIME {
+        let packets = packets();
+        handler.canvases[0].flush_queue();
+        let start = Instant::now();
+        routine(handler.clone(), packets).await;
+        measured += start.elapsed();
+        iterations += 1;
+    }
+    let rate = (PACKETS as u64 * u64::from(iterations)) as f64 / measured.as_secs_f64();
+    println!("{:<40} {:>8.2}M packets/s", name, rate / 1e6);
+}
+
+#[tokio::main]
+async fn main() {
+    let handler = Handler {
+        canvases: vec![Canvas::new(Box::new(VecSink::new(WIDTH, HEIGHT)))].into(),
+        stats: Arc::default(),
+        policy: RequestPolicy {
+            rate_limiter: None,
+            admin_ips: Arc::new([]),
+            allowed_ranges: Arc::new([]),
+            client_limit: None,
+            size_announcer: None,
+            size_reply_cooldown: SizeReplyCooldown::default(),
+            color_space: ColorSpace::Srgb,
+            mirror: None,
+            recorder: None,
+            reply_ttl: None,
+            reply_sources: Arc::new([]),
+            coordinate_mode: CoordinateMode::Full,
+            thumbnail_jobs: ThumbnailJobs::default(),
+        },
+    };
+
+    bench("inline", &handler, |handler, packets| async move {
+        for request in packets {
+            handler.handle(request);
+        }
+    })
+    .await;
+
+    bench("task_per_packet", &handler, |handler, packets| async move {
+        let tasks: Vec<_> = packets
+            .into_iter()
+            .map(|request| {
+                let handler = handler.clone();
+                tokio::spawn(async move { handler.handle(request) })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+    })
+    .await;
+
+    for count in [1, 2, 4, 8] {
+        bench(
+            &format!("workers/{}", count),
+            &handler,
+            |handler, packets| async move {
+                let workers = PacketWorkers::start(count, move |request| handler.handle(request));
+                for request in packets {
+                    workers.dispatch(request).await;
+                }
+                workers.finish().await;
+            },
+        )
+        .await;
+    }
+}
@@ -0,0 +1,127 @@
+//! A line-based admin channel on a Unix domain socket, for scripts and operators on headless machines.
+//!
+//! Every line is one command, answered with one or more lines of text:
+//!
+//! | Command       | Answer                                                          |
+//! | ------------- | --------------------------------------------------------------- |
+//! | `stats`       | One `name value` line per statistic, as in the metrics endpoint |
+//! | `size`        | One `id widthxheight` line per canvas                           |
+//! | `clear`       | `ok` after clearing the main canvas like a clear packet         |
+//! | `save <path>` | `ok` after saving the main canvas to a PNG file                 |
+//! | `help`        | The list of commands                                            |
+//!
+//! Failed commands are answered with a line starting with `error:`.
+//! Anyone who can connect to the socket can clear the canvas, so access has to be restricted with the permissions of the socket's directory.
+
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tracing::{debug, info};
+
+use crate::canvas::Canvas;
+use crate::metrics::collect_metrics;
+use crate::stats::Stats;
+
+/// Maximum length of a command line in bytes; connections sending longer lines are closed.
+const MAX_LINE_LENGTH: usize = 4096;
+
+const HELP: &str = "commands: stats, size, clear, save <path>, help\n";
+
+/// Accept admin commands on a Unix domain socket at the given path until an error occurs.
+/// A socket left over at the path, for example from a crashed server, is replaced.
+pub async fn admin_socket_server(
+    path: PathBuf,
+    canvases: Arc<[Canvas]>,
+    stats: Arc<Stats>,
+) -> Result<()> {
+    if std::fs::symlink_metadata(&path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        std::fs::remove_file(&path)?;
+    }
+    let listener = UnixListener::bind(&path)?;
+    info!("accepting admin commands on {}", path.display());
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let canvases = canvases.clone();
+        let stats = stats.clone();
+        tokio::spawn(async move {
+            if let Err(why) = handle_connection(stream, &canvases, &stats).await {
+                debug!("admin connection failed: {}", why);
+            }
+        });
+    }
+}
+
+async fn handle_connection(stream: UnixStream, canvases: &[Canvas], stats: &Stats) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let size = (&mut reader)
+            .take(MAX_LINE_LENGTH as u64)
+            .read_line(&mut line)
+            .await?;
+        if size == 0 {
+            return Ok(());
+        }
+        if size == MAX_LINE_LENGTH && !line.ends_with('\n') {
+            writer.write_all(b"error: line too long\n").await?;
+            return Ok(());
+        }
+        let command = line.trim();
+        if command.is_empty() {
+            continue;
+        }
+        let answer = match run_command(command, canvases, stats).await {
+            Ok(answer) => answer,
+            Err(why) => format!("error: {}\n", why),
+        };
+        writer.write_all(answer.as_bytes()).await?;
+    }
+}
+
+/// Run a single command and return its answer, including the final line break.
+async fn run_command(command: &str, canvases: &[Canvas], stats: &Stats) -> Result<String> {
+    let (name, argument) = match command.split_once(char::is_whitespace) {
+        Some((name, argument)) => (name, Some(argument.trim())),
+        None => (command, None),
+    };
+    match (name, argument) {
+        ("stats", None) => Ok(collect_metrics(stats, canvases)
+            .into_iter()
+            .map(|(name, _, _, value)| {
+                format!("{} {}\n", name.trim_start_matches("pingxelflut_"), value)
+            })
+            .collect()),
+        ("size", None) => Ok(canvases
+            .iter()
+            .enumerate()
+            .map(|(id, canvas)| {
+                let (width, height) = canvas.logical_size();
+                format!("{} {}x{}\n", id, width, height)
+            })
+            .collect()),
+        ("clear", None) => {
+            info!("canvas cleared via the admin socket");
+            canvases[0].clear();
+            Ok("ok\n".to_owned())
+        }
+        ("save", Some(path)) => {
+            let canvas = canvases[0].clone();
+            let path = Path::new(path).to_owned();
+            // Encoding a large canvas takes a while, which would block other tasks.
+            tokio::task::spawn_blocking(move || canvas.save(&path)).await??;
+            Ok("ok\n".to_owned())
+        }
+        ("help", None) => Ok(HELP.to_owned()),
+        ("save", None) => Err(anyhow!("save needs a path")),
+        ("stats" | "size" | "clear" | "help", Some(_)) => {
+            Err(anyhow!("{} takes no arguments", name))
+        }
+        _ => Err(anyhow!("unknown command {:?}, {}", name, HELP.trim_end())),
+    }
+}
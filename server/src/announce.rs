@@ -0,0 +1,125 @@
+use dashmap::{DashMap, DashSet};
+use parking_lot::Mutex;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Maximum number of source addresses that are remembered.
+/// Once reached, new sources no longer get the size announced, which keeps memory bounded.
+const MAX_ANNOUNCED_SOURCES: usize = 1 << 20;
+
+/// Time after answering a size request during which further size requests from the same source for the same canvas are not answered.
+const SIZE_REPLY_COOLDOWN: Duration = Duration::from_millis(500);
+
+/// Number of remembered size replies above which expired ones are forgotten.
+const PRUNE_THRESHOLD: usize = 1 << 16;
+
+/// Maximum number of remembered size replies, which keeps memory bounded under a flood from many sources.
+const MAX_COOLDOWN_ENTRIES: usize = 1 << 20;
+
+/// Remembers which source addresses have been told the canvas size, so that each is told at most once.
+/// Like [`crate::canvas::Canvas`], this is cheaply clonable and all clones share the same state.
+#[derive(Debug, Clone, Default)]
+pub struct SizeAnnouncer {
+    announced: Arc<DashSet<IpAddr>>,
+}
+
+impl SizeAnnouncer {
+    /// Record a packet from the source and return whether it is the first one, in which case the size should be announced.
+    pub fn first_contact(&self, source: IpAddr) -> bool {
+        // Most packets come from known sources, which only need a shared lock on their shard.
+        if self.announced.contains(&source) {
+            return false;
+        }
+        self.announced.len() < MAX_ANNOUNCED_SOURCES && self.announced.insert(source)
+    }
+}
+
+/// Limits size responses to one per source and canvas within [`SIZE_REPLY_COOLDOWN`], so that a flood of size requests cannot turn into a flood of replies.
+/// Like [`crate::canvas::Canvas`], this is cheaply clonable and all clones share the same state.
+///
+/// At most [`MAX_COOLDOWN_ENTRIES`] replies are remembered; while that many are cooling down, requests for further sources and canvases are not answered.
+#[derive(Debug, Clone)]
+pub struct SizeReplyCooldown {
+    last_replies: Arc<DashMap<(IpAddr, u8), Instant>>,
+    /// When expired replies were last forgotten; locked only by the handler pruning them.
+    last_prune: Arc<Mutex<Instant>>,
+}
+
+impl Default for SizeReplyCooldown {
+    fn default() -> Self {
+        Self {
+            last_replies: Arc::default(),
+            last_prune: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+}
+
+impl SizeReplyCooldown {
+    /// Record a size request for a canvas and return whether it should be answered.
+    pub fn should_reply(&self, source: IpAddr, canvas_id: u8) -> bool {
+        let now = Instant::now();
+        if self.last_replies.len() > PRUNE_THRESHOLD {
+            self.prune(now);
+        }
+        if let Some(mut last_reply) = self.last_replies.get_mut(&(source, canvas_id)) {
+            if now.duration_since(*last_reply) < SIZE_REPLY_COOLDOWN {
+                return false;
+            }
+            *last_reply = now;
+            return true;
+        }
+        if self.last_replies.len() >= MAX_COOLDOWN_ENTRIES {
+            return false;
+        }
+        self.last_replies.insert((source, canvas_id), now);
+        true
+    }
+
+    /// Forget expired replies, at most once per cooldown. Handlers arriving while another one prunes skip it.
+    fn prune(&self, now: Instant) {
+        let Some(mut last_prune) = self.last_prune.try_lock() else {
+            return;
+        };
+        if now.duration_since(*last_prune) <= SIZE_REPLY_COOLDOWN {
+            return;
+        }
+        self.last_replies
+            .retain(|_, last_reply| now.duration_since(*last_reply) < SIZE_REPLY_COOLDOWN);
+        *last_prune = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::thread;
+
+    #[test]
+    fn size_replies_are_limited_per_source_and_canvas() {
+        let cooldown = SizeReplyCooldown::default();
+        let source = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let other = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        assert!(cooldown.should_reply(source, 0));
+        assert!(!cooldown.should_reply(source, 0));
+        assert!(cooldown.should_reply(source, 1));
+        assert!(cooldown.should_reply(other, 0));
+        assert!(!cooldown.should_reply(other, 0));
+
+        thread::sleep(SIZE_REPLY_COOLDOWN);
+        assert!(cooldown.should_reply(source, 0));
+        assert!(!cooldown.should_reply(source, 0));
+    }
+
+    #[test]
+    fn size_is_announced_once_per_source() {
+        let announcer = SizeAnnouncer::default();
+        let source = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let other = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        assert!(announcer.first_contact(source));
+        assert!(!announcer.first_contact(source));
+        assert!(announcer.first_contact(other));
+        assert!(!announcer.clone().first_contact(other));
+    }
+}
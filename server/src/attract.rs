@@ -0,0 +1,91 @@
+//! Built-in animation shown while no client is drawing, see [`attract_handler`].
+
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use rgb::RGBA8;
+use tracing::info;
+
+use crate::canvas::Canvas;
+use crate::stats::Stats;
+
+/// Frames per second of the attract animation.
+const ATTRACT_FPS: u64 = 20;
+
+/// How far the gradient moves per frame, in steps of its 256 colors.
+const ATTRACT_SPEED: usize = 2;
+
+/// Color of a rainbow going around the hue circle once over the 256 steps.
+fn rainbow(step: u8) -> RGBA8 {
+    let hue = u32::from(step) * 6;
+    let rising = (hue % 256) as u8;
+    let falling = 255 - rising;
+    let (r, g, b) = match hue / 256 {
+        0 => (255, rising, 0),
+        1 => (falling, 255, 0),
+        2 => (0, 255, rising),
+        3 => (0, falling, 255),
+        4 => (rising, 0, 255),
+        _ => (255, 0, falling),
+    };
+    RGBA8::new(r, g, b, 0xff)
+}
+
+/// Draw a diagonal rainbow gradient over the region, shifted by the frame number.
+fn draw_frame(colors: &mut [RGBA8], width: usize, height: usize, frame: usize) {
+    let span = width + height;
+    for (y, row) in colors.chunks_exact_mut(width).enumerate() {
+        for (x, color) in row.iter_mut().enumerate() {
+            let step = (x + y) * 256 / span + frame * ATTRACT_SPEED;
+            *color = rainbow(step as u8);
+        }
+    }
+}
+
+/// Play an animation on the part of the canvas shown by this server whenever no packet arrived for `idle`,
+/// which keeps the display lively at an otherwise empty event.
+///
+/// The animation is drawn directly onto the canvas and is not counted in the [`Stats`].
+/// It stops as soon as a client packet is handled, and the pixels it drew are then restored to what they were before,
+/// except for those a client has painted since.
+pub async fn attract_handler(canvas: Canvas, stats: Arc<Stats>, idle: Duration) {
+    let started = SystemTime::now();
+    let viewport = canvas.viewport;
+    let (x, y, width, height) = (viewport.x, viewport.y, viewport.width, viewport.height);
+    let mut demo = vec![RGBA8::default(); width as usize * height as usize];
+    loop {
+        let last_packet = stats.last_packet();
+        let idle_for = SystemTime::now()
+            .duration_since(last_packet.unwrap_or(started))
+            .unwrap_or_default();
+        if idle_for < idle {
+            tokio::time::sleep(idle - idle_for).await;
+            continue;
+        }
+        let Some(saved) = canvas.read_rect(x, y, width, height) else {
+            return;
+        };
+        info!(
+            "no packets for {} s, starting attract mode",
+            idle_for.as_secs()
+        );
+        let mut interval = tokio::time::interval(Duration::from_millis(1000 / ATTRACT_FPS));
+        let mut frame = 0;
+        while stats.last_packet() == last_packet {
+            draw_frame(&mut demo, width as usize, height as usize, frame);
+            canvas.set_rect(x, y, width, height, &demo);
+            frame = frame.wrapping_add(1);
+            interval.tick().await;
+        }
+        info!("packet received, stopping attract mode");
+        // Pixels that still show the last frame were not painted by clients in the meantime.
+        if let Some(mut current) = canvas.read_rect(x, y, width, height) {
+            for ((pixel, demo), saved) in current.iter_mut().zip(&demo).zip(saved) {
+                if pixel == demo {
+                    *pixel = saved;
+                }
+            }
+            canvas.set_rect(x, y, width, height, &current);
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use std::path::Path;
+
+use anyhow::Result;
+use clap::ValueEnum;
+use image::imageops::{self, FilterType};
+use image::RgbaImage;
+use rgb::RGBA8;
+
+/// How a background image is fit onto the canvas.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum BackgroundFit {
+    /// Scale the image to exactly the canvas size, ignoring its aspect ratio.
+    Stretch,
+    /// Center the image without scaling, cropping it if it is larger than the canvas.
+    #[default]
+    Center,
+    /// Scale the image to cover the whole canvas while keeping its aspect ratio, cropping the overflow.
+    Cover,
+}
+
+/// Load a background image and fit it to the canvas size.
+/// Pixels exactly matching the transparent color, if any, are keyed out before fitting, see [`key_out`].
+///
+/// Returns the fitted image along with the canvas position of its top left corner, which may be negative.
+pub fn load_background(
+    path: &Path,
+    fit: BackgroundFit,
+    width: u16,
+    height: u16,
+    transparent_color: Option<RGBA8>,
+) -> Result<(RgbaImage, i64, i64)> {
+    let mut image = image::open(path)?.into_rgba8();
+    if let Some(color) = transparent_color {
+        key_out(&mut image, color);
+    }
+    let (width, height) = (u32::from(width), u32::from(height));
+    let image = match fit {
+        BackgroundFit::Stretch => imageops::resize(&image, width, height, FilterType::Triangle),
+        BackgroundFit::Center => image,
+        BackgroundFit::Cover => {
+            let scale = f64::max(
+                width as f64 / image.width() as f64,
+                height as f64 / image.height() as f64,
+            );
+            let scaled_width = (image.width() as f64 * scale).ceil() as u32;
+            let scaled_height = (image.height() as f64 * scale).ceil() as u32;
+            imageops::resize(&image, scaled_width, scaled_height, FilterType::Triangle)
+        }
+    };
+    let x = (i64::from(width) - i64::from(image.width())) / 2;
+    let y = (i64::from(height) - i64::from(image.height())) / 2;
+    Ok((image, x, y))
+}
+
+/// Make all pixels that exactly match the color fully transparent, so that they are not drawn.
+/// This is the classic chroma key for images without an alpha channel; the alpha value of the color is ignored.
+pub fn key_out(image: &mut RgbaImage, color: RGBA8) {
+    for pixel in image.pixels_mut() {
+        if pixel.0[..3] == [color.r, color.g, color.b] {
+            pixel.0[3] = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canvas::Canvas;
+    use crate::sink::VecSink;
+    use image::Rgba;
+
+    #[test]
+    fn keyed_out_pixels_are_not_drawn() {
+        let mut image = RgbaImage::from_pixel(3, 1, Rgba([0xff, 0x00, 0xff, 0xff]));
+        // Close to the transparent color, but not exactly it.
+        image.put_pixel(1, 0, Rgba([0xfe, 0x00, 0xff, 0xff]));
+        key_out(&mut image, RGBA8::new(0xff, 0x00, 0xff, 0x80));
+        let alphas: Vec<_> = image.pixels().map(|pixel| pixel.0[3]).collect();
+        assert_eq!(alphas, [0, 0xff, 0]);
+
+        let canvas = Canvas::new(Box::new(VecSink::new(3, 1)));
+        canvas.draw_image(&image, 0, 0);
+        assert_eq!(canvas.get_pixel(0, 0), Some(RGBA8::new(0, 0, 0, 0xff)));
+        assert_eq!(
+            canvas.get_pixel(1, 0),
+            Some(RGBA8::new(0xfe, 0x00, 0xff, 0xff))
+        );
+        assert_eq!(canvas.get_pixel(2, 0), Some(RGBA8::new(0, 0, 0, 0xff)));
+    }
+}
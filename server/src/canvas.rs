@@ -1,45 +1,1521 @@
+use anyhow::{anyhow, Result};
 use concurrent_queue::ConcurrentQueue;
-use parking_lot::RwLock;
-use std::sync::Arc;
+use parking_lot::{Mutex, RwLock, RwLockWriteGuard};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
 
-use pixels::Pixels;
+use clap::ValueEnum;
+use image::{ImageFormat, RgbaImage};
+use pingxelflut::format::ParseColorError;
 use rgb::RGBA8;
 
+use crate::dirty::DirtyTiles;
+use crate::font;
+use crate::mask::Mask;
+use crate::sink::{PixelSink, COLOR_SIZE};
+use crate::viewport::Viewport;
+
 type Color = RGBA8;
-const COLOR_SIZE: usize = 4;
+/// The default color of an empty canvas.
+pub const DEFAULT_CLEAR_COLOR: Color = Color::new(0, 0, 0, 0xff);
+
+/// Default limit for the frame buffer size in bytes, which allows canvases of up to 16384x16384 pixels.
+pub const DEFAULT_MAX_CANVAS_BYTES: u64 = 1 << 30;
+
+/// Compute the frame buffer size of a canvas in bytes, refusing sizes above `max_bytes`.
+/// This is meant to be checked before allocating the frame buffer, so that absurd dimensions cannot exhaust memory.
+pub fn check_frame_size(width: u16, height: u16, max_bytes: u64) -> Result<u64> {
+    let bytes = u64::from(width) * u64::from(height) * COLOR_SIZE as u64;
+    if bytes > max_bytes {
+        return Err(anyhow!(
+            "a {}x{} canvas needs {} MiB of memory, which exceeds the limit of {} MiB; use smaller dimensions or raise --max-canvas-bytes",
+            width,
+            height,
+            bytes >> 20,
+            max_bytes >> 20
+        ));
+    }
+    Ok(bytes)
+}
+
+/// Parse a canvas size given as `WIDTHxHEIGHT`, such as `640x480`.
+pub fn parse_canvas_size(size: &str) -> Result<(u16, u16), String> {
+    let (width, height) = size
+        .split_once('x')
+        .ok_or_else(|| format!("expected WIDTHxHEIGHT, got {:?}", size))?;
+    let parse = |value: &str| match value.parse::<u16>() {
+        Ok(0) | Err(_) => Err(format!("invalid canvas dimension {:?}", value)),
+        Ok(value) => Ok(value),
+    };
+    Ok((parse(width)?, parse(height)?))
+}
+
+/// Parse a `#rrggbb` or `#rrggbbaa` hex color into a canvas color, see [`pingxelflut::format::Color::from_hex`].
+pub fn parse_hex_color(hex: &str) -> Result<Color, ParseColorError> {
+    pingxelflut::format::Color::from_hex(hex).map(to_internal_color)
+}
 
+/// Convert a wire color to a canvas color, which is stored in the frame buffer as R, G, B and A bytes.
+/// This is the `Rgba8UnormSrgb` layout of the window texture, so pixels are copied without swapping channels.
+///
+/// RGB colors become fully opaque, RGBA colors keep their alpha, and grayscale values are expected as
+/// [`pingxelflut::format::Color::from_gray`], so that all packet types convert alike.
+/// For example, `#123456` becomes the bytes `12 34 56 ff` and gray `80` becomes `80 80 80 ff`.
 pub fn to_internal_color(color: pingxelflut::format::Color) -> Color {
-    Color::new(color.red, color.green, color.blue, color.alpha())
+    Color::from(<[u8; 4]>::from(color))
+}
+
+/// Convert a canvas color to an RGB wire color; the canvas is opaque so alpha is dropped.
+pub fn from_internal_color(color: Color) -> pingxelflut::format::Color {
+    pingxelflut::format::Color::from_rgb([color.r, color.g, color.b])
+}
+
+/// Decode an sRGB encoded channel value to linear light intensity between 0 and 1.
+/// For example, the sRGB mid-gray 0x80 is about 0.216 in linear light, or 0x37 as a byte.
+pub fn srgb_to_linear(value: u8) -> f32 {
+    let value = value as f32 / 255.0;
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Encode a linear light intensity between 0 and 1 as an sRGB channel value; the inverse of [`srgb_to_linear`].
+pub fn linear_to_srgb(value: f32) -> u8 {
+    let value = value.clamp(0.0, 1.0);
+    let encoded = if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    };
+    (encoded * 255.0).round() as u8
+}
+
+/// Color space of the colors that clients send and receive.
+/// The frame buffer is always sRGB encoded, which is what [`pixels`] presents by default.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum ColorSpace {
+    /// sRGB encoded colors, used as they are.
+    #[default]
+    Srgb,
+    /// Linear light intensities, encoded to sRGB for the frame buffer and decoded again for pixel reads.
+    Linear,
+}
+
+impl ColorSpace {
+    /// Convert a wire color in this color space to a canvas color, see [`to_internal_color`].
+    pub fn to_internal_color(self, color: pingxelflut::format::Color) -> Color {
+        let color = to_internal_color(color);
+        match self {
+            ColorSpace::Srgb => color,
+            ColorSpace::Linear => {
+                // Byte to byte conversions are looked up, so that floods do not pay for the powers.
+                static ENCODE: OnceLock<[u8; 256]> = OnceLock::new();
+                let encode = ENCODE.get_or_init(|| {
+                    std::array::from_fn(|value| linear_to_srgb(value as f32 / 255.0))
+                });
+                Color::new(
+                    encode[color.r as usize],
+                    encode[color.g as usize],
+                    encode[color.b as usize],
+                    color.a,
+                )
+            }
+        }
+    }
+
+    /// Convert a canvas color to a wire color in this color space, see [`from_internal_color`].
+    pub fn from_internal_color(self, color: Color) -> pingxelflut::format::Color {
+        match self {
+            ColorSpace::Srgb => from_internal_color(color),
+            ColorSpace::Linear => {
+                let decode = |value| (srgb_to_linear(value) * 255.0).round() as u8;
+                pingxelflut::format::Color::from_rgb([
+                    decode(color.r),
+                    decode(color.g),
+                    decode(color.b),
+                ])
+            }
+        }
+    }
+}
+
+/// Offsets from the center of the outline pixels of a circle in its first octant, from `(r, 0)` towards the diagonal,
+/// following the midpoint circle algorithm. Each offset has a different row.
+fn circle_octant(r: u16) -> impl Iterator<Item = (i64, i64)> {
+    let r = i64::from(r);
+    let (mut dx, mut dy, mut error) = (r, 0, 1 - r);
+    std::iter::from_fn(move || {
+        if dx < dy {
+            return None;
+        }
+        let offset = (dx, dy);
+        dy += 1;
+        if error < 0 {
+            error += 2 * dy + 1;
+        } else {
+            dx -= 1;
+            error += 2 * (dy - dx) + 1;
+        }
+        Some(offset)
+    })
+}
+
+/// Number of pixels that [`Canvas::draw_circle`] sets for a circle of radius `r`, unless it is clipped.
+/// This is counted without drawing, so that the circle can be charged to a rate limit beforehand.
+pub fn circle_pixels(r: u16, fill: bool) -> u64 {
+    if !fill {
+        // Mirroring an offset into all octants gives 8 pixels, but only 4 on the axes and diagonals.
+        return circle_octant(r)
+            .map(|offset| match offset {
+                (0, 0) => 1,
+                (_, 0) => 4,
+                (x, y) if x == y => 4,
+                _ => 8,
+            })
+            .sum();
+    }
+    // Filled rows span between the outermost outline pixels, whose offsets come from both halves of the octant.
+    let mut half_widths = vec![0; usize::from(r) + 1];
+    for (x, y) in circle_octant(r) {
+        half_widths[y as usize] = half_widths[y as usize].max(x as u64);
+        half_widths[x as usize] = half_widths[x as usize].max(y as u64);
+    }
+    let row = |half_width: &u64| 2 * half_width + 1;
+    row(&half_widths[0]) + 2 * half_widths[1..].iter().map(row).sum::<u64>()
+}
+
+/// A pending write to the frame buffer.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum PixelWrite {
+    /// Replace the pixel with the color.
+    Set(Color),
+    /// Composite the color over the existing pixel.
+    Blend(Color),
+}
+
+/// Composite a color over an existing pixel (source-over), producing an opaque pixel.
+fn blend(source: Color, destination: &[u8]) -> Color {
+    let alpha = source.a as u16;
+    let mix = |source: u8, destination: u8| {
+        ((source as u16 * alpha + destination as u16 * (0xff - alpha) + 0x7f) / 0xff) as u8
+    };
+    Color::new(
+        mix(source.r, destination[0]),
+        mix(source.g, destination[1]),
+        mix(source.b, destination[2]),
+        0xff,
+    )
+}
+
+/// How frame buffer pixels are written, as passed to [`write_pixel`].
+#[derive(Debug, Clone, Copy)]
+struct WriteMode {
+    /// The clear color if fill tracking is enabled, see [`Canvas::with_fill_tracking`].
+    fill_color: Option<Color>,
+    /// The clear color if painted pixels are protected, see [`Canvas::with_write_protection`].
+    protect_color: Option<Color>,
+}
+
+/// Apply a pending write to the pixel at the given frame buffer position.
+/// Returns the change of the number of filled pixels, see [`write_pixel`].
+fn apply_write(frame: &mut [u8], pixel_pos: usize, write: PixelWrite, mode: WriteMode) -> i64 {
+    // Never write outside the frame, even if the queue somehow contains a bogus position.
+    let Some(pixel) = frame.get_mut(pixel_pos..pixel_pos + COLOR_SIZE) else {
+        return 0;
+    };
+    let color = match write {
+        PixelWrite::Set(color) => color,
+        PixelWrite::Blend(color) => blend(color, pixel),
+    };
+    write_pixel(pixel, color, mode)
+}
+
+/// Whether a frame buffer pixel counts as filled, which is when its color differs from the clear color; alpha is ignored.
+fn is_filled(pixel: &[u8], clear_color: Color) -> bool {
+    pixel[..3] != clear_color.as_ref()[..3]
+}
+
+/// Write a color to a frame buffer pixel, unless it is protected because it was painted already.
+/// With fill tracking, the change of the number of filled pixels is returned; otherwise 0.
+fn write_pixel(pixel: &mut [u8], color: Color, mode: WriteMode) -> i64 {
+    if let Some(clear_color) = mode.protect_color {
+        if is_filled(pixel, clear_color) {
+            return 0;
+        }
+    }
+    let change = mode.fill_color.map_or(0, |clear_color| {
+        i64::from(is_filled(color.as_ref(), clear_color)) - i64::from(is_filled(pixel, clear_color))
+    });
+    pixel.copy_from_slice(color.as_ref());
+    change
+}
+
+/// Composite two translucent colors into one, so that blending the result equals blending `bottom` and then `top`.
+fn compose(top: Color, bottom: Color) -> Color {
+    let (top_alpha, bottom_alpha) = (top.a as u32, bottom.a as u32);
+    // Scaled by 0xff to keep precision; non-zero because the top color is not fully transparent.
+    let alpha = top_alpha * 0xff + bottom_alpha * (0xff - top_alpha);
+    let mix = |top: u8, bottom: u8| {
+        ((top as u32 * top_alpha * 0xff
+            + bottom as u32 * bottom_alpha * (0xff - top_alpha)
+            + alpha / 2)
+            / alpha) as u8
+    };
+    Color::new(
+        mix(top.r, bottom.r),
+        mix(top.g, bottom.g),
+        mix(top.b, bottom.b),
+        ((alpha + 0x7f) / 0xff) as u8,
+    )
 }
 
 /// Canvas handling datastructures.
 /// This is a lightweight, easily clonable datastructure that contains reference-counted references to the underlying shared data, such as the frame buffer and pixel queue.
 #[derive(Debug, Clone)]
 pub struct Canvas {
-    pub(crate) pixels: Arc<RwLock<Pixels>>,
-    pub(crate) pixel_queue: Arc<ConcurrentQueue<(usize, Color)>>,
-    pub(crate) width: u16,
-    pub(crate) height: u16,
+    pub(crate) pixels: Arc<RwLock<Box<dyn PixelSink>>>,
+    pub(crate) pixel_queue: Arc<ConcurrentQueue<(usize, PixelWrite)>>,
+    /// Canvas width in pixels.
+    pub width: u16,
+    /// Canvas height in pixels.
+    pub height: u16,
+    /// Maximum number of queued pixels applied per frame.
+    pub(crate) max_pixels_per_frame: usize,
+    /// Latest pending write per pixel position, used instead of the queue when coalescing.
+    pub(crate) coalesced: Option<Arc<Mutex<HashMap<usize, PixelWrite>>>>,
+    /// Color the canvas is filled with when it is cleared.
+    pub(crate) clear_color: Color,
+    /// Region of the logical canvas that the frame buffer shows.
+    pub(crate) viewport: Viewport,
+    /// Whether the frame buffer was written to directly since the last redraw.
+    pub(crate) dirty: Arc<AtomicBool>,
+    /// Frame buffer tiles changed since the last render, if dirty tracking is enabled.
+    pub(crate) dirty_tiles: Option<Arc<Mutex<DirtyTiles>>>,
+    /// Amount by which pixels fade towards the clear color per frame, see [`Canvas::with_fade`].
+    pub(crate) fade_rate: u8,
+    /// Number of queued pixels that were dropped because the queue was full, see [`Canvas::with_queue_capacity`].
+    pub(crate) queue_full_drops: Arc<AtomicU64>,
+    /// Number of frame buffer pixels differing from the clear color, if fill tracking is enabled, see [`Canvas::with_fill_tracking`].
+    pub(crate) filled_pixels: Option<Arc<AtomicI64>>,
+    /// Logical pixels that may be drawn on, if the canvas is masked, see [`Canvas::with_mask`].
+    pub(crate) mask: Option<Arc<Mask>>,
+    /// Whether painted pixels are kept, see [`Canvas::with_write_protection`].
+    pub(crate) protect_written: bool,
 }
 
 impl Canvas {
-    pub fn set_pixel(&mut self, x: u16, y: u16, color: Color) {
-        let x = x as usize;
-        let y = y as usize;
-        if x >= self.width as usize || y >= self.height as usize {
-            return;
+    /// Create a canvas drawing into the given sink, which also determines the canvas size.
+    /// The canvas is not part of a larger logical canvas unless [`Canvas::with_viewport`] is used.
+    pub fn new(sink: Box<dyn PixelSink>) -> Self {
+        let (width, height) = sink.dimensions();
+        Self {
+            width,
+            height,
+            pixels: Arc::new(RwLock::new(sink)),
+            pixel_queue: Arc::new(ConcurrentQueue::unbounded()),
+            max_pixels_per_frame: usize::MAX,
+            coalesced: None,
+            clear_color: DEFAULT_CLEAR_COLOR,
+            viewport: Viewport::full(width, height),
+            dirty: Arc::new(AtomicBool::new(true)),
+            dirty_tiles: None,
+            fade_rate: 0,
+            queue_full_drops: Arc::default(),
+            filled_pixels: None,
+            mask: None,
+            protect_written: false,
+        }
+    }
+
+    /// Show only a region of a larger logical canvas, whose size must match the sink size.
+    /// All pixel operations then use logical coordinates.
+    pub fn with_viewport(mut self, viewport: Viewport) -> Self {
+        assert_eq!(
+            (viewport.width, viewport.height),
+            (self.width, self.height),
+            "viewport size must match the canvas size"
+        );
+        self.viewport = viewport;
+        self
+    }
+
+    /// Size of the logical canvas, which is reported to clients.
+    pub fn logical_size(&self) -> (u16, u16) {
+        (self.viewport.logical_width, self.viewport.logical_height)
+    }
+
+    /// Use a different clear color, and fill the canvas with it right away.
+    pub fn with_clear_color(mut self, clear_color: Color) -> Self {
+        self.clear_color = clear_color;
+        self.clear();
+        self
+    }
+
+    /// Coalesce pending writes, so that each pixel is written at most once per frame.
+    /// The last write to a pixel wins; blends are folded into the pending write they are drawn over.
+    ///
+    /// This saves frame buffer writes when few pixels are overwritten many times per frame, at the cost of hashing and a shared lock on every write.
+    pub fn with_coalescing(mut self, coalesce: bool) -> Self {
+        self.coalesced = coalesce.then(Default::default);
+        self
+    }
+
+    /// Track which tiles of the frame buffer changed, and skip rendering when none did.
+    pub fn with_dirty_tracking(mut self, dirty_tracking: bool) -> Self {
+        self.dirty_tiles = dirty_tracking.then(|| {
+            let mut tiles = DirtyTiles::new(self.width, self.height);
+            // Nothing has been rendered yet.
+            tiles.mark_all();
+            Arc::new(Mutex::new(tiles))
+        });
+        self
+    }
+
+    /// Whether dirty tracking is enabled, see [`Canvas::with_dirty_tracking`].
+    pub fn dirty_tracking_enabled(&self) -> bool {
+        self.dirty_tiles.is_some()
+    }
+
+    /// The region of the frame buffer changed since the last render, as `(x, y, width, height)` rounded to whole tiles.
+    /// Returns [`None`] if nothing changed or dirty tracking is disabled.
+    pub fn dirty_region(&self) -> Option<(usize, usize, usize, usize)> {
+        self.dirty_tiles.as_ref()?.lock().bounding_box()
+    }
+
+    /// Limit how many queued pixels [`Canvas::set_queue_pixels`] applies at once, so that a flood cannot stall rendering.
+    /// Without a limit, the whole queue is drained every frame.
+    pub fn with_max_pixels_per_frame(mut self, max_pixels_per_frame: Option<usize>) -> Self {
+        self.max_pixels_per_frame = max_pixels_per_frame.unwrap_or(usize::MAX);
+        self
+    }
+
+    /// Limit how many pixels can be queued, so that a flood cannot exhaust memory if drawing falls behind.
+    /// When the queue is full, the oldest queued pixel is dropped to make room, as newer pixels would overwrite it anyway in most cases.
+    /// Coalesced writes are bounded by the canvas size already and not affected. Without a capacity, the queue is unbounded.
+    pub fn with_queue_capacity(mut self, capacity: Option<usize>) -> Self {
+        self.pixel_queue = Arc::new(match capacity {
+            Some(capacity) => ConcurrentQueue::bounded(capacity),
+            None => ConcurrentQueue::unbounded(),
+        });
+        self
+    }
+
+    /// Number of queued pixels dropped so far because the queue was full.
+    pub fn queue_full_drops(&self) -> u64 {
+        self.queue_full_drops.load(Ordering::Relaxed)
+    }
+
+    /// Fade every pixel towards the clear color by this much per color channel in each [`Canvas::set_queue_pixels`] call.
+    /// Pixels that are drawn again get their full color back, so only untouched pixels disappear over time.
+    /// A rate of 0 disables fading.
+    pub fn with_fade(mut self, fade_rate: u8) -> Self {
+        self.fade_rate = fade_rate;
+        self
+    }
+
+    /// Count how many pixels of the frame buffer differ from the clear color, for example to announce how full the canvas is.
+    /// This costs reading every pixel before it is overwritten.
+    pub fn with_fill_tracking(mut self, track_fill: bool) -> Self {
+        self.filled_pixels = track_fill.then(Arc::default);
+        let pixels = self.pixels.read();
+        self.recount_filled(pixels.frame());
+        drop(pixels);
+        self
+    }
+
+    /// Drop all writes to logical pixels that the mask does not allow drawing on.
+    /// The logical canvas size stays the same, so clients still see the bounding rectangle.
+    pub fn with_mask(mut self, mask: Option<Mask>) -> Self {
+        self.mask = mask.map(Arc::new);
+        self
+    }
+
+    /// Whether a logical pixel on the canvas may be drawn on, see [`Canvas::with_mask`].
+    fn is_drawable(&self, x: usize, y: usize) -> bool {
+        self.mask
+            .as_ref()
+            .map_or(true, |mask| mask.is_drawable(x, y))
+    }
+
+    /// Number of frame buffer pixels that differ from the clear color, if fill tracking is enabled.
+    /// Pixels that are still queued are not taken into account.
+    pub fn filled_pixels(&self) -> Option<u64> {
+        let filled_pixels = self.filled_pixels.as_ref()?;
+        Some(filled_pixels.load(Ordering::Relaxed).max(0) as u64)
+    }
+
+    /// Keep pixels that were painted, that is which differ from the clear color, from being changed until the canvas is cleared.
+    /// The first client to paint a pixel claims it. This costs reading every pixel before it is overwritten, like fill tracking.
+    pub fn with_write_protection(mut self, protect_written: bool) -> Self {
+        self.protect_written = protect_written;
+        self
+    }
+
+    /// How pixels are written with the current settings, as passed to [`write_pixel`].
+    fn write_mode(&self) -> WriteMode {
+        WriteMode {
+            fill_color: self.filled_pixels.as_ref().map(|_| self.clear_color),
+            protect_color: self.protect_written.then_some(self.clear_color),
         }
-        let pixel_pos = (x + y * self.width as usize) * COLOR_SIZE;
-        let _ = self.pixel_queue.force_push((pixel_pos, color));
     }
 
-    /// Sets all the pixels from the queue.
+    /// Apply a change of the number of filled pixels, see [`Canvas::with_fill_tracking`].
+    fn add_filled(&self, change: i64) {
+        if let Some(filled_pixels) = &self.filled_pixels {
+            if change != 0 {
+                filled_pixels.fetch_add(change, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Count the filled pixels of the whole frame buffer from scratch, after it was replaced wholesale.
+    fn recount_filled(&self, frame: &[u8]) {
+        if let Some(filled_pixels) = &self.filled_pixels {
+            let count = frame
+                .chunks_exact(COLOR_SIZE)
+                .filter(|pixel| is_filled(pixel, self.clear_color))
+                .count();
+            filled_pixels.store(count as i64, Ordering::Relaxed);
+        }
+    }
+
+    /// Queue a pixel to be set on the next redraw.
+    /// Pixels outside the canvas are silently discarded.
+    /// Pixels on the logical canvas but outside the viewport are accepted, but not drawn.
+    /// Pixels masked off by [`Canvas::with_mask`] are discarded as well.
+    ///
+    /// Returns whether the pixel was accepted.
+    pub fn set_pixel(&mut self, x: u16, y: u16, color: Color) -> bool {
+        self.queue_write(x, y, PixelWrite::Set(color))
+    }
+
+    /// Queue a pixel to be alpha-blended over the existing pixel on the next redraw.
+    /// Fully opaque colors behave exactly like [`Canvas::set_pixel`], fully transparent colors are accepted but do nothing.
+    ///
+    /// Returns whether the pixel was accepted.
+    pub fn blend_pixel(&mut self, x: u16, y: u16, color: Color) -> bool {
+        match color.a {
+            0xff => self.set_pixel(x, y, color),
+            0 => self.viewport.contains_logical(x, y) && self.is_drawable(x as usize, y as usize),
+            _ => self.queue_write(x, y, PixelWrite::Blend(color)),
+        }
+    }
+
+    fn queue_write(&mut self, x: u16, y: u16, write: PixelWrite) -> bool {
+        if !self.viewport.contains_logical(x, y) || !self.is_drawable(x as usize, y as usize) {
+            return false;
+        }
+        let Some((x, y)) = self.viewport.to_local(x, y) else {
+            return true;
+        };
+        let pixel_pos = (x as usize + y as usize * self.width as usize) * COLOR_SIZE;
+        match &self.coalesced {
+            Some(coalesced) => {
+                let mut coalesced = coalesced.lock();
+                let write = match (write, coalesced.get(&pixel_pos)) {
+                    // The first pending write claims a protected pixel, like it would in the frame buffer.
+                    (_, Some(PixelWrite::Set(below)))
+                        if self.protect_written && is_filled(below.as_ref(), self.clear_color) =>
+                    {
+                        return true;
+                    }
+                    (PixelWrite::Blend(color), Some(PixelWrite::Set(below))) => {
+                        PixelWrite::Set(blend(color, below.as_ref()))
+                    }
+                    (PixelWrite::Blend(color), Some(PixelWrite::Blend(below))) => {
+                        PixelWrite::Blend(compose(color, *below))
+                    }
+                    _ => write,
+                };
+                coalesced.insert(pixel_pos, write);
+            }
+            None => {
+                // Only bounded queues replace an element, and the queue is never closed.
+                if let Ok(Some(_)) = self.pixel_queue.force_push((pixel_pos, write)) {
+                    self.queue_full_drops.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+        true
+    }
+
+    /// Read the current color of a pixel.
+    /// Pixels that are still queued are not taken into account.
+    ///
+    /// Returns [`None`] if the pixel is outside the canvas or the viewport.
+    pub fn get_pixel(&self, x: u16, y: u16) -> Option<Color> {
+        let (x, y) = self.viewport.to_local(x, y)?;
+        let pixel_pos = (x as usize + y as usize * self.width as usize) * COLOR_SIZE;
+        let pixels = self.pixels.read();
+        let pixel = pixels.frame().get(pixel_pos..pixel_pos + COLOR_SIZE)?;
+        Some(Color::new(pixel[0], pixel[1], pixel[2], pixel[3]))
+    }
+
+    /// Read the current colors of a rectangle, in rows from top to bottom.
+    /// Pixels that are still queued are not taken into account.
+    ///
+    /// Returns [`None`] unless the rectangle lies entirely within the canvas and the viewport.
+    pub fn read_rect(&self, x: u16, y: u16, width: u16, height: u16) -> Option<Vec<Color>> {
+        if width == 0 || height == 0 {
+            return Some(Vec::new());
+        }
+        let (x, y) = self.viewport.to_local(x, y)?;
+        let (x_end, y_end) = (x as usize + width as usize, y as usize + height as usize);
+        if x_end > self.width as usize || y_end > self.height as usize {
+            return None;
+        }
+        let pixels = self.pixels.read();
+        let row_size = self.width as usize * COLOR_SIZE;
+        let colors = pixels
+            .frame()
+            .chunks_exact(row_size)
+            .take(y_end)
+            .skip(y as usize)
+            .flat_map(|row| {
+                row[x as usize * COLOR_SIZE..x_end * COLOR_SIZE].chunks_exact(COLOR_SIZE)
+            })
+            .map(|pixel| Color::new(pixel[0], pixel[1], pixel[2], pixel[3]))
+            .collect();
+        Some(colors)
+    }
+
+    /// Set a pixel only if it currently has the expected color, comparing and writing under one lock.
+    /// Unlike [`Canvas::set_pixel`], this writes to the frame buffer directly instead of queueing the pixel, so that
+    /// the comparison sees the result of earlier conditional writes. Pixels that are still queued are not taken into
+    /// account, though: a queued pixel is compared as it was before, and overwrites the result once it is drawn.
+    /// Conditional writes are thus only consistent among themselves, or once the queue was drawn.
+    ///
+    /// Returns whether the pixel was written. Pixels outside the viewport are never written,
+    /// since only the server showing them knows their color.
+    pub fn set_pixel_if_equal(&self, x: u16, y: u16, expected: Color, color: Color) -> bool {
+        if !self.is_drawable(x as usize, y as usize) {
+            return false;
+        }
+        let Some((x, y)) = self.viewport.to_local(x, y) else {
+            return false;
+        };
+        let pixel_pos = (x as usize + y as usize * self.width as usize) * COLOR_SIZE;
+        let mut pixels = self.pixels.write();
+        let Some(pixel) = pixels
+            .frame_mut()
+            .get_mut(pixel_pos..pixel_pos + COLOR_SIZE)
+        else {
+            return false;
+        };
+        if pixel != expected.as_ref() {
+            return false;
+        }
+        let mode = self.write_mode();
+        if mode
+            .protect_color
+            .is_some_and(|clear_color| is_filled(pixel, clear_color))
+        {
+            return false;
+        }
+        let filled = write_pixel(pixel, color, mode);
+        self.add_filled(filled);
+        self.mark_dirty_rect(x as usize, y as usize, x as usize + 1, y as usize + 1);
+        true
+    }
+
+    /// Sets pixels from the queue, at most the per-frame limit of them.
+    /// Pixels beyond the limit stay queued for the next frame.
     pub fn set_queue_pixels(&self) {
+        self.drain_queue(self.max_pixels_per_frame);
+        if self.fade_rate > 0 {
+            self.fade();
+        }
+    }
+
+    /// Sets all the pixels from the queue, ignoring the per-frame limit.
+    pub fn flush_queue(&self) {
+        self.drain_queue(usize::MAX);
+    }
+
+    /// Whether the canvas changed since the last call to [`Canvas::set_queue_pixels`], or still has queued pixels.
+    /// Rendering can be skipped otherwise.
+    pub fn needs_redraw(&self) -> bool {
+        self.dirty.load(Ordering::Relaxed)
+            || !self.pixel_queue.is_empty()
+            || self
+                .coalesced
+                .as_ref()
+                .is_some_and(|coalesced| !coalesced.lock().is_empty())
+    }
+
+    /// Mark the whole frame buffer changed.
+    /// Must be called while holding the frame buffer write lock, so that a concurrent redraw either includes the write or is followed by another one.
+    fn mark_dirty(&self) {
+        self.dirty.store(true, Ordering::Relaxed);
+        if let Some(dirty_tiles) = &self.dirty_tiles {
+            dirty_tiles.lock().mark_all();
+        }
+    }
+
+    /// Mark the frame buffer pixels from `x..x_end` and `y..y_end` changed, see [`Canvas::mark_dirty`].
+    fn mark_dirty_rect(&self, x: usize, y: usize, x_end: usize, y_end: usize) {
+        self.dirty.store(true, Ordering::Relaxed);
+        if let Some(dirty_tiles) = &self.dirty_tiles {
+            dirty_tiles.lock().mark_rect(x, y, x_end, y_end);
+        }
+    }
+
+    /// Mark the tiles containing the given frame buffer positions changed.
+    fn mark_dirty_positions(&self, positions: impl Iterator<Item = usize>) {
+        if let Some(dirty_tiles) = &self.dirty_tiles {
+            let mut dirty_tiles = dirty_tiles.lock();
+            let width = self.width as usize;
+            for pixel_pos in positions {
+                let pixel = pixel_pos / COLOR_SIZE;
+                dirty_tiles.mark(pixel % width, pixel / width);
+            }
+        }
+    }
+
+    fn drain_queue(&self, limit: usize) {
+        // Direct writes after this point mark the canvas dirty again.
+        self.dirty.store(false, Ordering::Relaxed);
+        // Take the write guard once for the whole batch instead of once per pixel.
+        let mut pixels = self.pixels.write();
+        self.apply_queue(pixels.frame_mut(), limit);
+    }
+
+    /// Apply all queued pixels ahead of a write that bypasses the queue, so that pixels sent before it do not end up
+    /// on top of it. Must be called while holding the frame buffer write lock.
+    fn apply_queue_before_write(&self, frame: &mut [u8]) {
+        if self.apply_queue(frame, usize::MAX) > 0 {
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Apply at most `limit` queued pixels to the frame buffer, returning how many there were.
+    fn apply_queue(&self, frame: &mut [u8], limit: usize) -> usize {
+        let mode = self.write_mode();
+        let mut filled = 0;
+        if let Some(coalesced) = &self.coalesced {
+            let batch = {
+                let mut coalesced = coalesced.lock();
+                if coalesced.len() <= limit {
+                    std::mem::take(&mut *coalesced)
+                } else {
+                    let positions: Vec<_> = coalesced.keys().take(limit).copied().collect();
+                    positions
+                        .into_iter()
+                        .filter_map(|pixel_pos| coalesced.remove_entry(&pixel_pos))
+                        .collect()
+                }
+            };
+            self.mark_dirty_positions(batch.keys().copied());
+            let applied = batch.len();
+            for (pixel_pos, write) in batch {
+                filled += apply_write(frame, pixel_pos, write, mode);
+            }
+            self.add_filled(filled);
+            return applied;
+        }
+
+        let mut dirty_tiles = self.dirty_tiles.as_ref().map(|tiles| tiles.lock());
+        let width = self.width as usize;
+        let mut applied = 0;
+        while applied < limit {
+            let Ok((pixel_pos, write)) = self.pixel_queue.pop() else {
+                break;
+            };
+            filled += apply_write(frame, pixel_pos, write, mode);
+            if let Some(dirty_tiles) = &mut dirty_tiles {
+                let pixel = pixel_pos / COLOR_SIZE;
+                dirty_tiles.mark(pixel % width, pixel / width);
+            }
+            applied += 1;
+        }
+        self.add_filled(filled);
+        applied
+    }
+
+    /// Move every pixel one step towards the clear color, see [`Canvas::with_fade`].
+    /// The canvas is only marked dirty while there is something left to fade, so a fully faded canvas is not redrawn.
+    fn fade(&self) {
+        let mut pixels = self.pixels.write();
+        let rate = self.fade_rate;
+        let mut changed = false;
+        let mut filled = 0;
+        for pixel in pixels.frame_mut().chunks_exact_mut(COLOR_SIZE) {
+            let was_filled = is_filled(pixel, self.clear_color);
+            for (channel, &target) in pixel.iter_mut().zip(self.clear_color.as_ref()) {
+                let faded = if *channel > target {
+                    channel.saturating_sub(rate).max(target)
+                } else {
+                    channel.saturating_add(rate).min(target)
+                };
+                changed |= faded != *channel;
+                *channel = faded;
+            }
+            if was_filled && !is_filled(pixel, self.clear_color) {
+                filled -= 1;
+            }
+        }
+        self.add_filled(filled);
+        if changed {
+            self.mark_dirty();
+        }
+    }
+
+    /// Fill the whole canvas with the clear color.
+    /// Pixels that are still queued are discarded.
+    pub fn clear(&self) {
+        let mut pixels = self.pixels.write();
+        self.mark_dirty();
+        while self.pixel_queue.pop().is_ok() {}
+        if let Some(coalesced) = &self.coalesced {
+            coalesced.lock().clear();
+        }
+        for pixel in pixels.frame_mut().chunks_exact_mut(COLOR_SIZE) {
+            pixel.copy_from_slice(self.clear_color.as_ref());
+        }
+        if let Some(filled_pixels) = &self.filled_pixels {
+            filled_pixels.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Draw an image onto the canvas with its top left corner at the given position.
+    /// This bypasses the pixel queue; parts of the image outside the canvas and fully transparent pixels are skipped.
+    pub fn draw_image(&self, image: &RgbaImage, x: i64, y: i64) {
+        let mut pixels = self.pixels.write();
+        let clamp = |value: i64, size: u16| value.clamp(0, i64::from(size)) as usize;
+        self.mark_dirty_rect(
+            clamp(x, self.width),
+            clamp(y, self.height),
+            clamp(x + i64::from(image.width()), self.width),
+            clamp(y + i64::from(image.height()), self.height),
+        );
+        let frame = pixels.frame_mut();
+        for (image_x, image_y, pixel) in image.enumerate_pixels() {
+            let canvas_x = x + i64::from(image_x);
+            let canvas_y = y + i64::from(image_y);
+            if pixel.0[3] == 0
+                || !(0..i64::from(self.width)).contains(&canvas_x)
+                || !(0..i64::from(self.height)).contains(&canvas_y)
+            {
+                continue;
+            }
+            let color = to_internal_color(pingxelflut::format::Color::from_rgba(pixel.0));
+            let pixel_pos =
+                (canvas_x as usize + canvas_y as usize * self.width as usize) * COLOR_SIZE;
+            frame[pixel_pos..pixel_pos + COLOR_SIZE].copy_from_slice(color.as_ref());
+        }
+        self.recount_filled(frame);
+    }
+
+    /// Fill a rectangle with a color, clamped to the canvas.
+    /// Like [`Canvas::draw_image`], this bypasses the pixel queue. Pixels still queued are applied first, so that pixels
+    /// sent before the rectangle end up below it rather than on top.
+    ///
+    /// Returns the number of pixels that were filled on the logical canvas, including those outside the viewport.
+    /// Pixels masked off by [`Canvas::with_mask`] are skipped, but counted like those outside the viewport; so do the other drawing methods.
+    pub fn fill_rect(&self, x: u16, y: u16, width: u16, height: u16, color: Color) -> usize {
+        let viewport = self.viewport;
+        let x_end = (x as usize + width as usize).min(viewport.logical_width as usize);
+        let y_end = (y as usize + height as usize).min(viewport.logical_height as usize);
+        let (x, y) = (x as usize, y as usize);
+        if x >= x_end || y >= y_end {
+            return 0;
+        }
+        let area = (x_end - x) * (y_end - y);
+
+        // Clip to the viewport and translate into the frame buffer.
+        let clip = |start: usize, end: usize, offset: u16, size: u16| {
+            let start = start.max(offset as usize) - offset as usize;
+            let end = end
+                .min(offset as usize + size as usize)
+                .saturating_sub(offset as usize);
+            (start, end)
+        };
+        let (x, x_end) = clip(x, x_end, viewport.x, viewport.width);
+        let (y, y_end) = clip(y, y_end, viewport.y, viewport.height);
+        if x >= x_end || y >= y_end {
+            return area;
+        }
         let mut pixels = self.pixels.write();
         let frame = pixels.frame_mut();
-        while let Ok((pixel_pos, color)) = self.pixel_queue.pop() {
-            let pixel_end_pos = pixel_pos + COLOR_SIZE;
-            frame[pixel_pos..pixel_end_pos].copy_from_slice(color.as_ref());
+        self.apply_queue_before_write(frame);
+        self.mark_dirty_rect(x, y, x_end, y_end);
+        let row_size = self.width as usize * COLOR_SIZE;
+        let mode = self.write_mode();
+        let mut filled = 0;
+        let rows = frame.chunks_exact_mut(row_size).enumerate();
+        for (row_y, row) in rows.take(y_end).skip(y) {
+            let pixels = row[x * COLOR_SIZE..x_end * COLOR_SIZE].chunks_exact_mut(COLOR_SIZE);
+            for (pixel_x, pixel) in (x..).zip(pixels) {
+                if self.is_drawable(pixel_x + viewport.x as usize, row_y + viewport.y as usize) {
+                    filled += write_pixel(pixel, color, mode);
+                }
+            }
+        }
+        self.add_filled(filled);
+        area
+    }
+
+    /// Copy the colors of a rectangle, given in rows from top to bottom, onto the canvas, clamped to it.
+    /// Like [`Canvas::fill_rect`], this bypasses the pixel queue.
+    ///
+    /// Returns the number of pixels that were set on the logical canvas, including those outside the viewport.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the number of colors does not match the rectangle size.
+    pub fn set_rect(&self, x: u16, y: u16, width: u16, height: u16, colors: &[Color]) -> usize {
+        assert_eq!(
+            colors.len(),
+            width as usize * height as usize,
+            "number of colors does not match rectangle size"
+        );
+        let viewport = self.viewport;
+        let x_end = (x as usize + width as usize).min(viewport.logical_width as usize);
+        let y_end = (y as usize + height as usize).min(viewport.logical_height as usize);
+        let (rect_x, rect_y) = (x as usize, y as usize);
+        if rect_x >= x_end || rect_y >= y_end {
+            return 0;
+        }
+        let area = (x_end - rect_x) * (y_end - rect_y);
+
+        // Clip to the viewport and translate into the frame buffer.
+        let clip = |start: usize, end: usize, offset: u16, size: u16| {
+            let start = start.max(offset as usize) - offset as usize;
+            let end = end
+                .min(offset as usize + size as usize)
+                .saturating_sub(offset as usize);
+            (start, end)
+        };
+        let (x, x_end) = clip(rect_x, x_end, viewport.x, viewport.width);
+        let (y, y_end) = clip(rect_y, y_end, viewport.y, viewport.height);
+        if x >= x_end || y >= y_end {
+            return area;
+        }
+        // Offset of the clipped rectangle within the given colors.
+        let source_x = x + viewport.x as usize - rect_x;
+        let source_y = y + viewport.y as usize - rect_y;
+
+        let mut pixels = self.pixels.write();
+        let frame = pixels.frame_mut();
+        self.apply_queue_before_write(frame);
+        self.mark_dirty_rect(x, y, x_end, y_end);
+        let row_size = self.width as usize * COLOR_SIZE;
+        let mode = self.write_mode();
+        let mut filled = 0;
+        let rows = frame
+            .chunks_exact_mut(row_size)
+            .enumerate()
+            .take(y_end)
+            .skip(y)
+            .zip(colors.chunks_exact(width as usize).skip(source_y));
+        for ((row_y, row), source) in rows {
+            let targets = row[x * COLOR_SIZE..x_end * COLOR_SIZE].chunks_exact_mut(COLOR_SIZE);
+            for (pixel_x, (pixel, &color)) in (x..).zip(targets.zip(&source[source_x..])) {
+                if self.is_drawable(pixel_x + viewport.x as usize, row_y + viewport.y as usize) {
+                    filled += write_pixel(pixel, color, mode);
+                }
+            }
+        }
+        self.add_filled(filled);
+        area
+    }
+
+    /// Replace the whole frame buffer with RGBA colors in the internal layout, in rows from top to bottom,
+    /// with a single copy under one lock, which is several times faster than [`Canvas::set_rect`] for a full frame.
+    /// Like [`Canvas::fill_rect`], this bypasses the pixel queue.
+    ///
+    /// Returns `false` without writing anything unless the buffer covers exactly the whole logical canvas, which must
+    /// be shown entirely by the frame buffer; also if the canvas is masked or written pixels are protected, since
+    /// these need every pixel to be checked. Use [`Canvas::set_rect`] then.
+    pub fn blit_full(&self, buffer: &[u8]) -> bool {
+        if self.viewport != Viewport::full(self.width, self.height)
+            || self.mask.is_some()
+            || self.protect_written
+        {
+            return false;
+        }
+        let mut pixels = self.pixels.write();
+        let frame = pixels.frame_mut();
+        if buffer.len() != frame.len() {
+            return false;
+        }
+        self.apply_queue_before_write(frame);
+        frame.copy_from_slice(buffer);
+        self.mark_dirty();
+        self.recount_filled(frame);
+        true
+    }
+
+    /// Paint runs of colors along a row, starting at the given pixel and going right, clamped to the canvas.
+    /// Like [`Canvas::fill_rect`], this bypasses the pixel queue and writes all runs in one locked pass.
+    ///
+    /// Returns the number of pixels that were painted on the logical canvas, including those outside the viewport.
+    pub fn set_row_runs(&self, x: u16, y: u16, runs: &[(u16, Color)]) -> usize {
+        let viewport = self.viewport;
+        if y >= viewport.logical_height {
+            return 0;
+        }
+        let logical_width = viewport.logical_width as usize;
+        let painted = (x as usize + runs.iter().map(|&(count, _)| count as usize).sum::<usize>())
+            .min(logical_width)
+            .saturating_sub(x as usize);
+        let Some((_, local_y)) = viewport.to_local(viewport.x, y) else {
+            return painted;
+        };
+        // Row range of the viewport, in logical coordinates.
+        let view_start = viewport.x as usize;
+        let view_end = view_start + viewport.width as usize;
+
+        let mut pixels = self.pixels.write();
+        self.apply_queue_before_write(pixels.frame_mut());
+        let row_start = local_y as usize * self.width as usize;
+        let mut start = x as usize;
+        let (mut dirty_start, mut dirty_end) = (usize::MAX, 0);
+        let mode = self.write_mode();
+        let mut filled = 0;
+        for &(count, color) in runs {
+            let end = start + count as usize;
+            let (clip_start, clip_end) = (start.max(view_start), end.min(view_end));
+            if clip_start < clip_end {
+                let local_start = clip_start - view_start;
+                let local_end = clip_end - view_start;
+                dirty_start = dirty_start.min(local_start);
+                dirty_end = dirty_end.max(local_end);
+                let frame = pixels.frame_mut();
+                let run = &mut frame
+                    [(row_start + local_start) * COLOR_SIZE..(row_start + local_end) * COLOR_SIZE];
+                for (pixel_x, pixel) in (clip_start..).zip(run.chunks_exact_mut(COLOR_SIZE)) {
+                    if self.is_drawable(pixel_x, y as usize) {
+                        filled += write_pixel(pixel, color, mode);
+                    }
+                }
+            }
+            if end >= view_end {
+                break;
+            }
+            start = end;
+        }
+        self.add_filled(filled);
+        if dirty_start < dirty_end {
+            self.mark_dirty_rect(
+                dirty_start,
+                local_y as usize,
+                dirty_end,
+                local_y as usize + 1,
+            );
+        }
+        painted
+    }
+
+    /// Draw a line of text with its top left corner at the given pixel, using the embedded 5x7 font.
+    /// Like [`Canvas::fill_rect`], this bypasses the pixel queue; text running off the canvas is clipped.
+    ///
+    /// Returns the number of pixels that were set on the logical canvas, including those outside the viewport.
+    pub fn draw_text(&self, x: u16, y: u16, text: &str, color: Color) -> usize {
+        let viewport = self.viewport;
+        let mut pixels = self.pixels.write();
+        let frame = pixels.frame_mut();
+        self.apply_queue_before_write(frame);
+        let mut count = 0;
+        let (mut dirty_start, mut dirty_end) = ((usize::MAX, usize::MAX), (0, 0));
+        let mode = self.write_mode();
+        let mut filled = 0;
+        for (index, character) in text.chars().enumerate() {
+            let glyph = font::glyph(character);
+            let glyph_x = x as usize + index * font::GLYPH_ADVANCE;
+            if glyph_x >= viewport.logical_width as usize {
+                break;
+            }
+            for column in 0..font::GLYPH_WIDTH {
+                for row in 0..font::GLYPH_HEIGHT {
+                    let (pixel_x, pixel_y) = (glyph_x + column, y as usize + row);
+                    if !font::is_set(&glyph, column, row)
+                        || pixel_x >= viewport.logical_width as usize
+                        || pixel_y >= viewport.logical_height as usize
+                    {
+                        continue;
+                    }
+                    count += 1;
+                    if !self.is_drawable(pixel_x, pixel_y) {
+                        continue;
+                    }
+                    let Some((local_x, local_y)) =
+                        viewport.to_local(pixel_x as u16, pixel_y as u16)
+                    else {
+                        continue;
+                    };
+                    let (local_x, local_y) = (local_x as usize, local_y as usize);
+                    dirty_start = (dirty_start.0.min(local_x), dirty_start.1.min(local_y));
+                    dirty_end = (dirty_end.0.max(local_x + 1), dirty_end.1.max(local_y + 1));
+                    let pixel_pos = (local_x + local_y * self.width as usize) * COLOR_SIZE;
+                    filled +=
+                        write_pixel(&mut frame[pixel_pos..pixel_pos + COLOR_SIZE], color, mode);
+                }
+            }
+        }
+        self.add_filled(filled);
+        if dirty_start.0 < dirty_end.0 {
+            self.mark_dirty_rect(dirty_start.0, dirty_start.1, dirty_end.0, dirty_end.1);
+        }
+        count
+    }
+
+    /// Draw a circle around a center pixel with the midpoint circle algorithm, either its outline or filled.
+    /// A filled circle covers each row between the outermost outline pixels, so both look alike.
+    /// Like [`Canvas::fill_rect`], this bypasses the pixel queue; parts of the circle off the canvas are clipped.
+    ///
+    /// Returns the number of pixels that were set on the logical canvas, including those outside the viewport.
+    pub fn draw_circle(&self, cx: u16, cy: u16, r: u16, color: Color, fill: bool) -> usize {
+        let (cx, cy) = (i64::from(cx), i64::from(cy));
+        let mut outline = Vec::new();
+        for (dx, dy) in circle_octant(r) {
+            for (x, y) in [(dx, dy), (dy, dx)] {
+                outline.extend([
+                    (cx + x, cy + y),
+                    (cx - x, cy + y),
+                    (cx + x, cy - y),
+                    (cx - x, cy - y),
+                ]);
+            }
+        }
+        // The octants share their end points, which must only be counted once.
+        outline.sort_unstable_by_key(|&(x, y)| (y, x));
+        outline.dedup();
+        if !fill {
+            return self.paint_points(outline, color);
+        }
+        // Sorted by row, the first and last outline pixel of each row delimit its span.
+        let spans = outline.chunk_by(|a, b| a.1 == b.1).flat_map(|row| {
+            let (start, end) = (row[0], row[row.len() - 1]);
+            (start.0..=end.0).map(move |x| (x, start.1))
+        });
+        self.paint_points(spans, color)
+    }
+
+    /// Draw a one pixel wide line between two pixels, including both, with Bresenham's algorithm.
+    /// Like [`Canvas::fill_rect`], this bypasses the pixel queue; parts of the line off the canvas are clipped.
+    ///
+    /// Returns the number of pixels that were set on the logical canvas, including those outside the viewport.
+    pub fn draw_line(&self, x0: u16, y0: u16, x1: u16, y1: u16, color: Color) -> usize {
+        let (mut x, mut y) = (i64::from(x0), i64::from(y0));
+        let (x1, y1) = (i64::from(x1), i64::from(y1));
+        let (dx, dy) = ((x1 - x).abs(), -(y1 - y).abs());
+        let (step_x, step_y) = ((x1 - x).signum(), (y1 - y).signum());
+        // The error term handles steep and flat lines alike, without swapping the axes.
+        let mut error = dx + dy;
+        let mut done = false;
+        let points = std::iter::from_fn(move || {
+            if done {
+                return None;
+            }
+            let point = (x, y);
+            if point == (x1, y1) {
+                done = true;
+                return Some(point);
+            }
+            let doubled = 2 * error;
+            if doubled >= dy {
+                error += dy;
+                x += step_x;
+            }
+            if doubled <= dx {
+                error += dx;
+                y += step_y;
+            }
+            Some(point)
+        });
+        self.paint_points(points, color)
+    }
+
+    /// Set each of the given logical pixels in one locked pass, skipping those off the canvas.
+    ///
+    /// Returns the number of pixels that were on the logical canvas, including those outside the viewport.
+    fn paint_points(&self, points: impl IntoIterator<Item = (i64, i64)>, color: Color) -> usize {
+        let viewport = self.viewport;
+        let mut pixels = self.pixels.write();
+        let frame = pixels.frame_mut();
+        self.apply_queue_before_write(frame);
+        let mut count = 0;
+        let (mut dirty_start, mut dirty_end) = ((usize::MAX, usize::MAX), (0, 0));
+        let mode = self.write_mode();
+        let mut filled = 0;
+        for (x, y) in points {
+            if !(0..i64::from(viewport.logical_width)).contains(&x)
+                || !(0..i64::from(viewport.logical_height)).contains(&y)
+            {
+                continue;
+            }
+            count += 1;
+            if !self.is_drawable(x as usize, y as usize) {
+                continue;
+            }
+            let Some((local_x, local_y)) = viewport.to_local(x as u16, y as u16) else {
+                continue;
+            };
+            let (local_x, local_y) = (local_x as usize, local_y as usize);
+            dirty_start = (dirty_start.0.min(local_x), dirty_start.1.min(local_y));
+            dirty_end = (dirty_end.0.max(local_x + 1), dirty_end.1.max(local_y + 1));
+            let pixel_pos = (local_x + local_y * self.width as usize) * COLOR_SIZE;
+            filled += write_pixel(&mut frame[pixel_pos..pixel_pos + COLOR_SIZE], color, mode);
+        }
+        self.add_filled(filled);
+        if dirty_start.0 < dirty_end.0 {
+            self.mark_dirty_rect(dirty_start.0, dirty_start.1, dirty_end.0, dirty_end.1);
+        }
+        count
+    }
+
+    /// Present the canvas on its sink.
+    /// With dirty tracking, this does nothing if the frame buffer did not change since the last render.
+    /// The whole frame is uploaded otherwise, as [`pixels`] offers no way to update only part of its texture.
+    pub fn render(&self) -> Result<()> {
+        let mut pixels = self.pixels.write();
+        let mut dirty_tiles = self.dirty_tiles.as_ref().map(|tiles| tiles.lock());
+        if dirty_tiles.as_ref().is_some_and(|tiles| tiles.is_clean()) {
+            return Ok(());
         }
+        pixels.prepare();
+        // Presenting may wait for the display, which should not block writers for longer than necessary.
+        let pixels = RwLockWriteGuard::downgrade(pixels);
+        pixels.render()?;
+        if let Some(dirty_tiles) = &mut dirty_tiles {
+            dirty_tiles.clear();
+        }
+        Ok(())
+    }
+
+    /// Present another canvas on this canvas's sink, for example to show one of several canvases in the same window.
+    /// This canvas's own frame buffer is left as is; use [`Canvas::invalidate`] before rendering it again.
+    pub fn present(&self, other: &Canvas) -> Result<()> {
+        let frame = other.frame_copy();
+        self.pixels
+            .write()
+            .present(&frame, other.width, other.height)
+    }
+
+    /// Mark the whole canvas changed, so that the next render presents it even with dirty tracking.
+    pub fn invalidate(&self) {
+        let _pixels = self.pixels.write();
+        self.mark_dirty();
+    }
+
+    /// Adapt to a new size of the surface the canvas is presented on.
+    pub fn resize_surface(&self, width: u32, height: u32) -> Result<()> {
+        let mut pixels = self.pixels.write();
+        self.mark_dirty();
+        pixels.resize_surface(width, height)
+    }
+
+    /// Copy the current frame buffer contents.
+    pub fn frame_copy(&self) -> Vec<u8> {
+        self.pixels.read().frame().to_vec()
+    }
+
+    /// Save the current frame buffer to a PNG file.
+    pub fn save(&self, path: &Path) -> image::ImageResult<()> {
+        write_png(path, self.width, self.height, self.frame_copy())
+    }
+
+    /// Replace the frame buffer with the contents of an image file.
+    /// The image must have exactly the canvas size.
+    pub fn load(&self, path: &Path) -> Result<()> {
+        let image = image::open(path)?.into_rgba8();
+        if image.dimensions() != (self.width.into(), self.height.into()) {
+            return Err(anyhow!(
+                "{} is {}x{}, but the canvas is {}x{}",
+                path.display(),
+                image.width(),
+                image.height(),
+                self.width,
+                self.height
+            ));
+        }
+        let mut pixels = self.pixels.write();
+        self.mark_dirty();
+        pixels.frame_mut().copy_from_slice(&image);
+        self.recount_filled(pixels.frame());
+        Ok(())
+    }
+}
+
+/// Write an RGBA frame buffer to a PNG file.
+/// The file is replaced atomically, so that readers never observe a partially written image.
+pub fn write_png(path: &Path, width: u16, height: u16, frame: Vec<u8>) -> image::ImageResult<()> {
+    // The frame buffer size always matches the canvas size.
+    let image = RgbaImage::from_raw(width.into(), height.into(), frame).unwrap();
+    let temporary_path = path.with_extension("png.tmp");
+    image.save_with_format(&temporary_path, ImageFormat::Png)?;
+    std::fs::rename(temporary_path, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dirty::TILE_SIZE;
+    use crate::sink::VecSink;
+
+    const RED: Color = Color::new(0xff, 0, 0, 0xff);
+
+    /// A canvas on an in-memory frame buffer of the given size.
+    fn canvas(width: u16, height: u16) -> Canvas {
+        Canvas::new(Box::new(VecSink::new(width, height)))
+    }
+
+    #[test]
+    fn set_pixel_rejects_pixels_outside_the_canvas() {
+        let mut canvas = canvas(4, 3);
+        let before = canvas.frame_copy();
+        for (x, y) in [(4, 0), (0, 3), (u16::MAX, u16::MAX)] {
+            assert!(!canvas.set_pixel(x, y, RED), "accepted {},{}", x, y);
+        }
+        canvas.flush_queue();
+        assert_eq!(canvas.frame_copy(), before);
+
+        assert!(canvas.set_pixel(3, 2, RED));
+        canvas.flush_queue();
+        assert_eq!(canvas.get_pixel(3, 2), Some(RED));
+    }
+
+    #[test]
+    fn blend_pixel_mixes_half_transparent_red_over_blue() {
+        let mut canvas = canvas(2, 1);
+        assert!(canvas.set_pixel(0, 0, Color::new(0, 0, 0xff, 0xff)));
+        canvas.flush_queue();
+        assert!(canvas.blend_pixel(0, 0, Color::new(0xff, 0, 0, 0x80)));
+        canvas.flush_queue();
+        assert_eq!(
+            canvas.get_pixel(0, 0),
+            Some(Color::new(0x80, 0, 0x7f, 0xff))
+        );
+    }
+
+    #[test]
+    fn set_queue_pixels_draws_at_most_the_per_frame_limit() {
+        let mut canvas = canvas(4, 1).with_max_pixels_per_frame(Some(3));
+        for x in 0..4 {
+            assert!(canvas.set_pixel(x, 0, RED));
+        }
+        canvas.set_queue_pixels();
+        let drawn = (0..4)
+            .filter(|&x| canvas.get_pixel(x, 0) == Some(RED))
+            .count();
+        assert_eq!(drawn, 3);
+        assert!(canvas.needs_redraw());
+
+        canvas.set_queue_pixels();
+        assert!((0..4).all(|x| canvas.get_pixel(x, 0) == Some(RED)));
+    }
+
+    #[test]
+    fn shapes_are_drawn_over_pixels_queued_before_them() {
+        let blue = Color::new(0, 0, 0xff, 0xff);
+        for coalesce in [false, true] {
+            let mut canvas = canvas(4, 4).with_coalescing(coalesce);
+            assert!(canvas.set_pixel(1, 1, RED));
+            canvas.fill_rect(0, 0, 4, 4, blue);
+            canvas.flush_queue();
+            assert_eq!(
+                canvas.get_pixel(1, 1),
+                Some(blue),
+                "coalescing {}",
+                coalesce
+            );
+        }
+    }
+
+    #[test]
+    fn one_changed_pixel_marks_exactly_one_tile_dirty() {
+        let mut canvas = canvas(100, 100).with_dirty_tracking(true);
+        canvas.render().unwrap();
+        assert_eq!(canvas.dirty_region(), None);
+
+        assert!(canvas.set_pixel(40, 70, RED));
+        canvas.set_queue_pixels();
+        let tiles = canvas.dirty_tiles.as_ref().unwrap().lock().clone();
+        assert_eq!(tiles.dirty_count(), 1);
+        assert!(tiles.is_dirty(40 / TILE_SIZE, 70 / TILE_SIZE));
+        assert_eq!(
+            canvas.dirty_region(),
+            Some((TILE_SIZE, 2 * TILE_SIZE, TILE_SIZE, TILE_SIZE))
+        );
+
+        canvas.render().unwrap();
+        assert_eq!(canvas.dirty_region(), None);
+    }
+
+    #[test]
+    fn check_frame_size_refuses_frame_buffers_above_the_limit() {
+        assert_eq!(
+            check_frame_size(1920, 1080, DEFAULT_MAX_CANVAS_BYTES).unwrap(),
+            8_294_400
+        );
+        assert_eq!(
+            check_frame_size(16384, 16384, DEFAULT_MAX_CANVAS_BYTES).unwrap(),
+            1 << 30
+        );
+        assert!(check_frame_size(16384, 16385, DEFAULT_MAX_CANVAS_BYTES).is_err());
+        // The largest possible canvas needs almost 16 GiB, without overflowing.
+        let error = check_frame_size(u16::MAX, u16::MAX, DEFAULT_MAX_CANVAS_BYTES).unwrap_err();
+        assert!(error.to_string().contains("16383 MiB"), "{}", error);
+        assert_eq!(check_frame_size(10, 10, 400).unwrap(), 400);
+        assert!(check_frame_size(10, 10, 399).is_err());
+    }
+
+    #[test]
+    fn mid_gray_converts_between_srgb_and_linear() {
+        assert!((srgb_to_linear(0x80) - 0.2158).abs() < 0.0005);
+        assert_eq!(linear_to_srgb(srgb_to_linear(0x80)), 0x80);
+
+        let gray = pingxelflut::format::Color::from_rgb([0x80, 0x80, 0x80]);
+        assert_eq!(
+            ColorSpace::Linear.from_internal_color(to_internal_color(gray)),
+            pingxelflut::format::Color::from_rgb([0x37, 0x37, 0x37])
+        );
+        let linear_gray = pingxelflut::format::Color::from_rgb([0x37, 0x37, 0x37]);
+        assert_eq!(
+            ColorSpace::Linear.to_internal_color(linear_gray),
+            Color::new(0x80, 0x80, 0x80, 0xff)
+        );
+        assert_eq!(
+            ColorSpace::Srgb.to_internal_color(gray),
+            Color::new(0x80, 0x80, 0x80, 0xff)
+        );
+    }
+
+    #[test]
+    fn full_queue_drops_the_oldest_pixels() {
+        const CAPACITY: usize = 4;
+        const EXTRA: u16 = 3;
+        let mut canvas = canvas(16, 1).with_queue_capacity(Some(CAPACITY));
+        for x in 0..CAPACITY as u16 + EXTRA {
+            assert!(canvas.set_pixel(x, 0, RED));
+        }
+        assert_eq!(canvas.queue_full_drops(), u64::from(EXTRA));
+        canvas.flush_queue();
+        for x in 0..EXTRA {
+            assert_eq!(canvas.get_pixel(x, 0), Some(Color::new(0, 0, 0, 0xff)));
+        }
+        for x in EXTRA..CAPACITY as u16 + EXTRA {
+            assert_eq!(canvas.get_pixel(x, 0), Some(RED));
+        }
+    }
+
+    #[test]
+    fn circle_pixels_match_the_drawn_circles() {
+        for r in [0, 1, 2, 3, 7, 10, 31] {
+            for fill in [false, true] {
+                let canvas = canvas(64, 64);
+                let drawn = canvas.draw_circle(32, 32, r, RED, fill);
+                assert_eq!(
+                    circle_pixels(r, fill),
+                    drawn as u64,
+                    "radius {}, fill {}",
+                    r,
+                    fill
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn fill_tracking_counts_pixels_differing_from_the_clear_color() {
+        let black = Color::new(0, 0, 0, 0xff);
+        let blue = Color::new(0, 0, 0xff, 0xff);
+        assert_eq!(canvas(4, 4).filled_pixels(), None);
+        let mut canvas = canvas(4, 4).with_fill_tracking(true);
+        assert_eq!(canvas.filled_pixels(), Some(0));
+
+        canvas.set_pixel(0, 0, RED);
+        canvas.flush_queue();
+        assert_eq!(canvas.filled_pixels(), Some(1));
+        // Overwriting a filled pixel keeps it filled.
+        canvas.set_pixel(0, 0, blue);
+        canvas.flush_queue();
+        assert_eq!(canvas.filled_pixels(), Some(1));
+
+        canvas.fill_rect(0, 0, 2, 2, RED);
+        assert_eq!(canvas.filled_pixels(), Some(4));
+        canvas.fill_rect(0, 0, 2, 2, blue);
+        assert_eq!(canvas.filled_pixels(), Some(4));
+        canvas.set_pixel(1, 1, black);
+        canvas.flush_queue();
+        assert_eq!(canvas.filled_pixels(), Some(3));
+
+        canvas.clear();
+        assert_eq!(canvas.filled_pixels(), Some(0));
+    }
+
+    #[test]
+    fn wire_colors_convert_to_frame_buffer_bytes() {
+        use pingxelflut::format::Color as WireColor;
+        let bytes = |color| <[u8; 4]>::from(to_internal_color(color));
+        assert_eq!(
+            bytes(WireColor::from_rgb([0x12, 0x34, 0x56])),
+            [0x12, 0x34, 0x56, 0xff]
+        );
+        assert_eq!(
+            bytes(WireColor::from_rgba([0x12, 0x34, 0x56, 0x78])),
+            [0x12, 0x34, 0x56, 0x78]
+        );
+        assert_eq!(bytes(WireColor::from_gray(0x80)), [0x80, 0x80, 0x80, 0xff]);
+        assert_eq!(
+            parse_hex_color("#123456").map(<[u8; 4]>::from),
+            Ok([0x12, 0x34, 0x56, 0xff])
+        );
+        // Alpha is dropped on the way back.
+        assert_eq!(
+            from_internal_color(Color::new(0x12, 0x34, 0x56, 0x78)),
+            WireColor::from_rgb([0x12, 0x34, 0x56])
+        );
+    }
+
+    #[test]
+    fn written_pixels_are_protected_until_cleared() {
+        let black = Color::new(0, 0, 0, 0xff);
+        let blue = Color::new(0, 0, 0xff, 0xff);
+        let mut canvas = canvas(4, 1).with_write_protection(true);
+        // The first of several queued writes claims the pixel.
+        canvas.set_pixel(0, 0, RED);
+        canvas.set_pixel(0, 0, blue);
+        canvas.flush_queue();
+        assert_eq!(canvas.get_pixel(0, 0), Some(RED));
+
+        // Direct writes only fill the pixels that are still unpainted.
+        canvas.fill_rect(0, 0, 2, 1, blue);
+        assert_eq!(canvas.get_pixel(0, 0), Some(RED));
+        assert_eq!(canvas.get_pixel(1, 0), Some(blue));
+        // Painting the clear color does not claim a pixel.
+        canvas.set_pixel(2, 0, black);
+        canvas.set_pixel(2, 0, RED);
+        canvas.flush_queue();
+        assert_eq!(canvas.get_pixel(2, 0), Some(RED));
+        assert!(!canvas.blit_full(&[0; 4 * COLOR_SIZE]));
+
+        canvas.clear();
+        canvas.set_pixel(0, 0, blue);
+        canvas.flush_queue();
+        assert_eq!(canvas.get_pixel(0, 0), Some(blue));
+    }
+
+    #[test]
+    fn conditional_writes_compare_the_current_color() {
+        let black = Color::new(0, 0, 0, 0xff);
+        let blue = Color::new(0, 0, 0xff, 0xff);
+        let canvas = canvas(2, 2);
+        assert!(canvas.set_pixel_if_equal(1, 1, black, RED));
+        assert_eq!(canvas.get_pixel(1, 1), Some(RED));
+        // Of two writes expecting the same color, only the first one succeeds.
+        assert!(!canvas.set_pixel_if_equal(1, 1, black, blue));
+        assert_eq!(canvas.get_pixel(1, 1), Some(RED));
+        assert!(canvas.set_pixel_if_equal(1, 1, RED, blue));
+        assert_eq!(canvas.get_pixel(1, 1), Some(blue));
+        // The alpha value is part of the comparison.
+        assert!(!canvas.set_pixel_if_equal(1, 1, Color::new(0, 0, 0xff, 0x80), RED));
+        assert!(!canvas.set_pixel_if_equal(2, 0, black, RED));
     }
 }
@@ -0,0 +1,102 @@
+use dashmap::DashMap;
+use parking_lot::Mutex;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Minimum time between removing inactive sources while the limit is reached, which keeps floods from new sources cheap.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Limit on the number of distinct source addresses that are active at the same time.
+/// A source stays active until it has not sent anything for the window; while the limit is reached, packets from other sources are rejected.
+/// Unlike [`crate::rate_limit::RateLimiter`], this caps how many sources draw, not how fast each of them draws.
+/// Like [`crate::canvas::Canvas`], this is cheaply clonable and all clones share the same state.
+///
+/// Like the rate limiter, the sources are kept in a sharded map, so that handlers checking different sources rarely contend for a lock.
+#[derive(Debug, Clone)]
+pub struct ClientLimit {
+    max_clients: usize,
+    window: Duration,
+    /// Time of the last accepted packet of each source.
+    last_seen: Arc<DashMap<IpAddr, Instant>>,
+    /// When inactive sources were last removed; locked only by the handler pruning them.
+    last_prune: Arc<Mutex<Instant>>,
+}
+
+impl ClientLimit {
+    /// Create a limit of `max_clients` sources that were active within the last `window`.
+    pub fn new(max_clients: usize, window: Duration) -> Self {
+        Self {
+            max_clients,
+            window,
+            last_seen: Arc::default(),
+            last_prune: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// Record a packet from a source.
+    ///
+    /// Returns whether the source is active or there is room for another active source.
+    pub fn check(&self, source: IpAddr) -> bool {
+        let now = Instant::now();
+        // The entry guard locks its shard until the end of this block, before pruning.
+        if let Some(mut last_seen) = self.last_seen.get_mut(&source) {
+            if now.duration_since(*last_seen) < self.window {
+                *last_seen = now;
+                return true;
+            }
+        }
+        if self.last_seen.len() >= self.max_clients {
+            self.prune(now);
+        }
+        // A returning source whose entry has not been pruned yet takes its old place.
+        if self.last_seen.len() >= self.max_clients && !self.last_seen.contains_key(&source) {
+            return false;
+        }
+        self.last_seen.insert(source, now);
+        true
+    }
+
+    /// Remove inactive sources, at most once per [`PRUNE_INTERVAL`]. Handlers arriving while another one prunes skip it.
+    fn prune(&self, now: Instant) {
+        let Some(mut last_prune) = self.last_prune.try_lock() else {
+            return;
+        };
+        if now.duration_since(*last_prune) < PRUNE_INTERVAL {
+            return;
+        }
+        self.last_seen
+            .retain(|_, last_seen| now.duration_since(*last_seen) < self.window);
+        *last_prune = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn sources_beyond_the_limit_are_rejected() {
+        let limit = ClientLimit::new(2, Duration::from_secs(60));
+        let source = |last| IpAddr::V4(Ipv4Addr::new(10, 0, 0, last));
+        assert!(limit.check(source(1)));
+        assert!(limit.check(source(2)));
+        assert!(!limit.check(source(3)));
+        // Active sources keep drawing while others are rejected.
+        assert!(limit.check(source(1)));
+        assert!(limit.check(source(2)));
+        assert!(!limit.check(source(3)));
+    }
+
+    #[test]
+    fn inactive_sources_make_room() {
+        let limit = ClientLimit::new(1, Duration::from_millis(10));
+        let source = |last| IpAddr::V4(Ipv4Addr::new(10, 0, 0, last));
+        assert!(limit.check(source(1)));
+        assert!(!limit.check(source(2)));
+        std::thread::sleep(PRUNE_INTERVAL);
+        assert!(limit.check(source(2)));
+        assert!(!limit.check(source(1)));
+    }
+}
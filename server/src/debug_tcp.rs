@@ -0,0 +1,238 @@
+//! A text protocol over TCP like classic Pixelflut, for testing clients without crafting ICMP packets.
+//!
+//! Every line is one command, which is turned into the equivalent Pingxelflut packet and handled exactly like a captured one,
+//! including rate limits, allowed sources and size announcements. Replies are sent back as lines:
+//!
+//! | Command            | Packet       | Reply                   |
+//! | ------------------ | ------------ | ----------------------- |
+//! | `PX <x> <y> <rgb>` | Set pixel    |                         |
+//! | `PX <x> <y>`       | Get pixel    | `PX <x> <y> <rrggbb>`   |
+//! | `SIZE [canvas]`    | Size request | `SIZE <width> <height>` |
+//!
+//! Colors are hexadecimal, `rrggbb` or `rrggbbaa`. Invalid commands are answered with a line starting with `ERROR`.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use anyhow::Result;
+use pingxelflut::format::{Color, Packet};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tracing::{debug, info};
+
+use crate::canvas::Canvas;
+use crate::handler::{handle_packet, RequestPolicy};
+use crate::source::ReceivedPacket;
+use crate::stats::Stats;
+
+/// Maximum length of a command line in bytes; connections sending longer lines are closed.
+const MAX_LINE_LENGTH: usize = 256;
+
+/// Number of replies buffered per connection.
+const REPLY_CAPACITY: usize = 16;
+
+/// Accept text commands on the given address until an error occurs.
+pub async fn debug_tcp_server(
+    address: SocketAddr,
+    canvases: Arc<[Canvas]>,
+    stats: Arc<Stats>,
+    policy: RequestPolicy,
+) -> Result<()> {
+    let listener = TcpListener::bind(address).await?;
+    info!("accepting text commands on {}", address);
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let canvases = canvases.clone();
+        let stats = stats.clone();
+        let policy = policy.clone();
+        tokio::spawn(async move {
+            if let Err(why) = handle_connection(stream, &canvases, &stats, &policy).await {
+                debug!("text command connection from {} failed: {}", peer, why);
+            }
+        });
+    }
+}
+
+async fn handle_connection(
+    stream: TcpStream,
+    canvases: &[Canvas],
+    stats: &Stats,
+    policy: &RequestPolicy,
+) -> Result<()> {
+    let peer = stream.peer_addr()?;
+    let local = stream.local_addr()?;
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let (reply_channel, mut replies) = mpsc::channel(REPLY_CAPACITY);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let size = (&mut reader)
+            .take(MAX_LINE_LENGTH as u64)
+            .read_line(&mut line)
+            .await?;
+        if size == 0 {
+            return Ok(());
+        }
+        if size == MAX_LINE_LENGTH && !line.ends_with('\n') {
+            writer.write_all(b"ERROR line too long\n").await?;
+            return Ok(());
+        }
+        let command = line.trim();
+        if command.is_empty() {
+            continue;
+        }
+        let packet = match parse_command(command) {
+            Ok(packet) => packet,
+            Err(why) => {
+                writer
+                    .write_all(format!("ERROR {}\n", why).as_bytes())
+                    .await?;
+                continue;
+            }
+        };
+        let request = ReceivedPacket {
+            packet,
+            source: peer.ip(),
+            destination: local.ip(),
+            identifier: 0,
+            sequence_number: 0,
+            interface_index: None,
+            reply_channel: Some(reply_channel.clone()),
+        };
+        handle_packet(canvases, stats, policy, request);
+        // The handler sends its replies before returning, so they are all queued by now.
+        while let Ok(reply) = replies.try_recv() {
+            if let Some(reply) = format_reply(&reply) {
+                writer.write_all(reply.as_bytes()).await?;
+            }
+        }
+    }
+}
+
+/// Turn a text command into the equivalent packet, or describe why it is invalid.
+fn parse_command(command: &str) -> Result<Packet, String> {
+    let coordinate = |word: &str| {
+        word.parse::<u16>()
+            .map_err(|_| format!("invalid coordinate {:?}", word))
+    };
+    let words: Vec<_> = command.split_whitespace().collect();
+    match words.as_slice() {
+        ["PX", x, y] => Ok(Packet::GetPixel {
+            x: coordinate(x)?,
+            y: coordinate(y)?,
+        }),
+        ["PX", x, y, color] => Ok(Packet::SetPixel {
+            x: coordinate(x)?,
+            y: coordinate(y)?,
+            color: Color::from_hex(color)
+                .map_err(|why| format!("invalid color {:?}: {}", color, why))?,
+            canvas_id: 0,
+        }),
+        ["SIZE"] => Ok(Packet::SizeRequest { canvas_id: 0 }),
+        ["SIZE", canvas_id] => Ok(Packet::SizeRequest {
+            canvas_id: canvas_id
+                .parse()
+                .map_err(|_| format!("invalid canvas ID {:?}", canvas_id))?,
+        }),
+        [command, ..] => Err(format!("unknown command {:?}", command)),
+        [] => Err("empty command".to_owned()),
+    }
+}
+
+/// Text form of a reply packet, including the line break; other packets are not sent as text.
+fn format_reply(packet: &Packet) -> Option<String> {
+    match packet {
+        Packet::SizeResponse { width, height, .. } => Some(format!("SIZE {} {}\n", width, height)),
+        Packet::PixelResponse { x, y, color } => Some(format!(
+            "PX {} {} {:02x}{:02x}{:02x}\n",
+            x, y, color.red, color.green, color.blue
+        )),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_become_packets() {
+        assert_eq!(
+            parse_command("PX 12 34 ff8000"),
+            Ok(Packet::SetPixel {
+                x: 12,
+                y: 34,
+                color: Color::from_rgb([0xff, 0x80, 0x00]),
+                canvas_id: 0,
+            })
+        );
+        assert_eq!(
+            parse_command("PX  1\t2 ff800080"),
+            Ok(Packet::SetPixel {
+                x: 1,
+                y: 2,
+                color: Color::from_rgba([0xff, 0x80, 0x00, 0x80]),
+                canvas_id: 0,
+            })
+        );
+        assert_eq!(
+            parse_command("PX 12 34"),
+            Ok(Packet::GetPixel { x: 12, y: 34 })
+        );
+        assert_eq!(
+            parse_command("SIZE"),
+            Ok(Packet::SizeRequest { canvas_id: 0 })
+        );
+        assert_eq!(
+            parse_command("SIZE 2"),
+            Ok(Packet::SizeRequest { canvas_id: 2 })
+        );
+    }
+
+    #[test]
+    fn invalid_commands_are_described() {
+        assert_eq!(
+            parse_command("PX -1 0 ff0000"),
+            Err("invalid coordinate \"-1\"".to_owned())
+        );
+        assert_eq!(
+            parse_command("PX 0 65536"),
+            Err("invalid coordinate \"65536\"".to_owned())
+        );
+        assert!(parse_command("PX 0 0 red")
+            .unwrap_err()
+            .starts_with("invalid color \"red\""));
+        assert_eq!(
+            parse_command("SIZE 256"),
+            Err("invalid canvas ID \"256\"".to_owned())
+        );
+        assert_eq!(
+            parse_command("px 0 0"),
+            Err("unknown command \"px\"".to_owned())
+        );
+        assert_eq!(
+            parse_command("PX 0 0 ff0000 extra"),
+            Err("unknown command \"PX\"".to_owned())
+        );
+    }
+
+    #[test]
+    fn replies_are_formatted_as_lines() {
+        let size = Packet::SizeResponse {
+            width: 1920,
+            height: 1080,
+            version: 1,
+            capabilities: 0,
+        };
+        assert_eq!(format_reply(&size).as_deref(), Some("SIZE 1920 1080\n"));
+        let pixel = Packet::PixelResponse {
+            x: 3,
+            y: 4,
+            color: Color::from_rgb([0x0a, 0xbc, 0x00]),
+        };
+        assert_eq!(format_reply(&pixel).as_deref(), Some("PX 3 4 0abc00\n"));
+        assert_eq!(format_reply(&Packet::Clear), None);
+    }
+}
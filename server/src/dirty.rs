@@ -0,0 +1,131 @@
+//! Tracking of the frame buffer regions that changed since the last render.
+
+/// Width and height of a dirty tile in pixels.
+pub const TILE_SIZE: usize = 32;
+
+/// A grid of square tiles covering the frame buffer, each marked dirty when a pixel inside it changes.
+#[derive(Debug, Clone)]
+pub struct DirtyTiles {
+    tiles: Vec<bool>,
+    columns: usize,
+    rows: usize,
+    dirty_count: usize,
+}
+
+impl DirtyTiles {
+    /// Create a grid for a frame buffer of the given size, with all tiles clean.
+    pub fn new(width: u16, height: u16) -> Self {
+        let columns = (width as usize).div_ceil(TILE_SIZE);
+        let rows = (height as usize).div_ceil(TILE_SIZE);
+        Self {
+            tiles: vec![false; columns * rows],
+            columns,
+            rows,
+            dirty_count: 0,
+        }
+    }
+
+    /// Mark the tile containing the given frame buffer pixel dirty.
+    pub fn mark(&mut self, x: usize, y: usize) {
+        let (column, row) = (x / TILE_SIZE, y / TILE_SIZE);
+        if column < self.columns && row < self.rows {
+            self.mark_tile(column, row);
+        }
+    }
+
+    /// Mark all tiles overlapping the pixels from `x..x_end` and `y..y_end` dirty.
+    pub fn mark_rect(&mut self, x: usize, y: usize, x_end: usize, y_end: usize) {
+        if x >= x_end || y >= y_end {
+            return;
+        }
+        let columns = x / TILE_SIZE..((x_end - 1) / TILE_SIZE + 1).min(self.columns);
+        let rows = y / TILE_SIZE..((y_end - 1) / TILE_SIZE + 1).min(self.rows);
+        for row in rows {
+            for column in columns.clone() {
+                self.mark_tile(column, row);
+            }
+        }
+    }
+
+    /// Mark the whole frame buffer dirty.
+    pub fn mark_all(&mut self) {
+        self.tiles.fill(true);
+        self.dirty_count = self.tiles.len();
+    }
+
+    fn mark_tile(&mut self, column: usize, row: usize) {
+        let tile = &mut self.tiles[column + row * self.columns];
+        if !*tile {
+            *tile = true;
+            self.dirty_count += 1;
+        }
+    }
+
+    /// Whether the tile at the given column and row is dirty.
+    pub fn is_dirty(&self, column: usize, row: usize) -> bool {
+        column < self.columns && row < self.rows && self.tiles[column + row * self.columns]
+    }
+
+    /// Number of dirty tiles.
+    pub fn dirty_count(&self) -> usize {
+        self.dirty_count
+    }
+
+    /// Whether no tile is dirty.
+    pub fn is_clean(&self) -> bool {
+        self.dirty_count == 0
+    }
+
+    /// The smallest rectangle of pixels covering all dirty tiles, as `(x, y, width, height)`.
+    /// The rectangle may extend past the frame buffer at the right and bottom edges.
+    pub fn bounding_box(&self) -> Option<(usize, usize, usize, usize)> {
+        if self.is_clean() {
+            return None;
+        }
+        let (mut min_column, mut min_row) = (usize::MAX, usize::MAX);
+        let (mut max_column, mut max_row) = (0, 0);
+        for (index, _) in self.tiles.iter().enumerate().filter(|(_, dirty)| **dirty) {
+            let (column, row) = (index % self.columns, index / self.columns);
+            min_column = min_column.min(column);
+            min_row = min_row.min(row);
+            max_column = max_column.max(column);
+            max_row = max_row.max(row);
+        }
+        Some((
+            min_column * TILE_SIZE,
+            min_row * TILE_SIZE,
+            (max_column - min_column + 1) * TILE_SIZE,
+            (max_row - min_row + 1) * TILE_SIZE,
+        ))
+    }
+
+    /// Mark all tiles clean, typically after rendering.
+    pub fn clear(&mut self) {
+        self.tiles.fill(false);
+        self.dirty_count = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rectangles_mark_the_tiles_they_overlap() {
+        let mut tiles = DirtyTiles::new(100, 70);
+        // Spans the boundary between the first and second column, within the first row.
+        tiles.mark_rect(TILE_SIZE - 1, 0, TILE_SIZE + 1, 1);
+        assert_eq!(tiles.dirty_count(), 2);
+        assert!(tiles.is_dirty(0, 0) && tiles.is_dirty(1, 0));
+        assert_eq!(tiles.bounding_box(), Some((0, 0, 2 * TILE_SIZE, TILE_SIZE)));
+
+        // Partial tiles at the right and bottom edges, and rectangles past them, are clamped.
+        tiles.mark_rect(99, 69, 200, 200);
+        assert!(tiles.is_dirty(3, 2));
+        assert_eq!(tiles.dirty_count(), 3);
+
+        tiles.clear();
+        assert!(tiles.is_clean());
+        assert_eq!(tiles.bounding_box(), None);
+    }
+}
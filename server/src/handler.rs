@@ -0,0 +1,1030 @@
+//! Handling of received packets, independent of where they come from.
+
+use std::io;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::sync::Arc;
+
+use ipnet::IpNet;
+use pingxelflut::format::{
+    CoordinateMode, Packet, CAPABILITY_COMPACT_COORDINATES, PROTOCOL_VERSION,
+};
+use pingxelflut::icmp::{EchoDirection, Icmp};
+use rgb::ComponentBytes;
+use tracing::{debug, debug_span, info, trace, warn};
+
+use crate::announce::{SizeAnnouncer, SizeReplyCooldown};
+use crate::canvas::{circle_pixels, Canvas, ColorSpace};
+use crate::client_limit::ClientLimit;
+use crate::font;
+use crate::mirror::Mirror;
+use crate::rate_limit::RateLimiter;
+use crate::recording::Recorder;
+use crate::source::ReceivedPacket;
+use crate::stats::Stats;
+use crate::thumbnail::{downscale, thumbnail_size, ThumbnailJobs};
+use crate::viewport::Viewport;
+
+/// How often sending a size response is retried if it fails.
+const SIZE_RESPONSE_RETRIES: u32 = 3;
+
+/// Settings deciding which requests are accepted and how they are answered, shared by all packet handlers.
+#[derive(Debug, Clone)]
+pub struct RequestPolicy {
+    /// Present if pixels are rate-limited per source.
+    pub rate_limiter: Option<RateLimiter>,
+    /// Sources allowed to clear the canvas.
+    pub admin_ips: Arc<[IpAddr]>,
+    /// Source ranges that packets are accepted from; all sources are accepted if empty.
+    pub allowed_ranges: Arc<[IpNet]>,
+    /// Present if the number of active sources is limited.
+    pub client_limit: Option<ClientLimit>,
+    /// Present if the canvas size is announced to new sources.
+    pub size_announcer: Option<SizeAnnouncer>,
+    /// Suppresses repeated size responses to the same source.
+    pub size_reply_cooldown: SizeReplyCooldown,
+    /// Color space of the colors in client packets.
+    pub color_space: ColorSpace,
+    /// Server that drawn pixels are forwarded to, if any.
+    pub mirror: Option<Mirror>,
+    /// Recording that all received packets are appended to, if any.
+    pub recorder: Option<Recorder>,
+    /// Time to live or hop limit of ICMP replies; the operating system default if unset.
+    pub reply_ttl: Option<u8>,
+    /// Local addresses ICMP replies are sent from, see [`RequestPolicy::reply_source`]; picked by routing if empty.
+    pub reply_sources: Arc<[IpAddr]>,
+    /// Coordinate mode of set pixel packets, advertised in size responses.
+    pub coordinate_mode: CoordinateMode,
+    /// Limits the thumbnails that are computed at the same time.
+    pub thumbnail_jobs: ThumbnailJobs,
+}
+
+impl RequestPolicy {
+    /// Forward a packet that was applied to the canvas to the mirror server, unless it came from there.
+    fn mirror(&self, stats: &Stats, source: IpAddr, packet: Packet) {
+        if let Some(mirror) = &self.mirror {
+            if !mirror.is_mirror(source) && !mirror.forward(packet) {
+                Stats::count(&stats.mirror_dropped);
+            }
+        }
+    }
+
+    /// The reply source address for a client: the first one of the same IP version, and for IPv6,
+    /// link-local if and only if the client's address is. Other clients are replied to from the address picked by routing.
+    pub fn reply_source(&self, client: IpAddr) -> Option<IpAddr> {
+        let is_same_kind = |source: &IpAddr| match (source, client) {
+            (IpAddr::V4(_), IpAddr::V4(_)) => true,
+            (&IpAddr::V6(source), IpAddr::V6(client)) => {
+                is_unicast_link_local(source) == is_unicast_link_local(client)
+            }
+            _ => false,
+        };
+        self.reply_sources.iter().copied().find(is_same_kind)
+    }
+}
+
+/// Handle a single received packet: check it against the policy, apply it to the canvases and reply if needed.
+/// `canvases` holds the canvas with each ID, starting with the main canvas; only single pixels and size requests address other canvases.
+pub fn handle_packet(
+    canvases: &[Canvas],
+    stats: &Stats,
+    policy: &RequestPolicy,
+    request: ReceivedPacket,
+) {
+    let mut canvas = canvases[0].clone();
+    let target_addr = request.source;
+    trace!("{} from {}", request.packet, target_addr);
+    if !is_allowed_source(&policy.allowed_ranges, target_addr) {
+        Stats::count(&stats.disallowed_packets);
+        return;
+    }
+    if policy
+        .client_limit
+        .as_ref()
+        .is_some_and(|client_limit| !client_limit.check(target_addr))
+    {
+        Stats::count(&stats.clients_rejected);
+        return;
+    }
+    // Packets this server sends to its mirror are captured as well.
+    if policy
+        .mirror
+        .as_ref()
+        .is_some_and(|mirror| mirror.is_mirror(request.destination))
+    {
+        return;
+    }
+    if let Some(recorder) = &policy.recorder {
+        if !recorder.record(target_addr, request.packet.clone()) {
+            Stats::count(&stats.recording_dropped);
+        }
+    }
+    stats.record_packet(request.packet.type_id());
+    stats.record_source(target_addr);
+    // Sources starting with a size request are remembered, but get the size only once.
+    if policy
+        .size_announcer
+        .as_ref()
+        .is_some_and(|announcer| announcer.first_contact(target_addr))
+        && !matches!(request.packet, Packet::SizeRequest { .. })
+    {
+        debug!("announcing canvas size to {}", target_addr);
+        if let Err(why) = send_size_response(&canvas, &request, policy) {
+            warn!("size announcement error: {}", why);
+        }
+    }
+    match &request.packet {
+        &Packet::SizeRequest { canvas_id } => {
+            let Some(requested) = canvases.get(canvas_id as usize) else {
+                debug!("{} requested nonexistent canvas {}", target_addr, canvas_id);
+                return;
+            };
+            if !policy
+                .size_reply_cooldown
+                .should_reply(target_addr, canvas_id)
+            {
+                Stats::count(&stats.size_replies_suppressed);
+                return;
+            }
+            match send_size_response(requested, &request, policy) {
+                Ok(()) => Stats::count(&stats.size_requests),
+                Err(why) => warn!("size response error: {}", why),
+            }
+        }
+        &Packet::GetPixel { x, y } => {
+            if let Some(color) = canvas.get_pixel(x, y) {
+                let response = Packet::PixelResponse {
+                    x,
+                    y,
+                    color: policy.color_space.from_internal_color(color),
+                };
+                if let Err(why) = send_reply(&request, response, 0, policy) {
+                    warn!("pixel response error: {}", why)
+                }
+            }
+        }
+        &Packet::GetRect {
+            x,
+            y,
+            width,
+            height,
+        } => {
+            // Every pixel is answered, so every pixel is charged like a get pixel request.
+            let pixels = u64::from(width) * u64::from(height);
+            if !is_within_pixel_budget(&policy.rate_limiter, target_addr, pixels) {
+                return;
+            }
+            if let Some(pixels) = canvas.read_rect(x, y, width, height) {
+                let pixels: Vec<_> = pixels
+                    .into_iter()
+                    .map(|color| policy.color_space.from_internal_color(color))
+                    .collect();
+                for response in Packet::rect_responses(x, y, width, height, &pixels) {
+                    if let Err(why) = send_reply(&request, response, 0, policy) {
+                        warn!("rectangle response error: {}", why);
+                        break;
+                    }
+                }
+            }
+        }
+        &Packet::GetThumbnail { max_dim } => {
+            // Thumbnails show the whole canvas, which only servers showing all of it have.
+            if max_dim == 0 || canvas.viewport != Viewport::full(canvas.width, canvas.height) {
+                return;
+            }
+            let Some(permit) = policy.thumbnail_jobs.try_start() else {
+                debug!(
+                    "too many thumbnails in progress, dropping request from {}",
+                    target_addr
+                );
+                return;
+            };
+            let max_dim = max_dim.min(Packet::MAX_THUMBNAIL_SIZE);
+            let (width, height) = thumbnail_size(canvas.width, canvas.height, max_dim);
+            let pixels = u64::from(width) * u64::from(height);
+            if !is_within_pixel_budget(&policy.rate_limiter, target_addr, pixels) {
+                return;
+            }
+            let (request, policy) = (request.clone(), policy.clone());
+            // Copying and averaging the frame takes far longer than applying a pixel, so keep it off the packet handlers.
+            tokio::task::spawn_blocking(move || {
+                let _permit = permit;
+                let frame = canvas.frame_copy();
+                let (width, height, pixels) =
+                    downscale(&frame, canvas.width, canvas.height, max_dim);
+                let pixels: Vec<_> = pixels
+                    .into_iter()
+                    .map(|color| policy.color_space.from_internal_color(color))
+                    .collect();
+                for response in Packet::thumbnail_responses(width, height, &pixels) {
+                    if let Err(why) = send_reply(&request, response, 0, &policy) {
+                        warn!("thumbnail response error: {}", why);
+                        break;
+                    }
+                }
+            });
+        }
+        // ignore
+        Packet::SizeResponse { .. }
+        | Packet::PixelResponse { .. }
+        | Packet::RectResponse { .. }
+        | Packet::ThumbnailResponse { .. } => {}
+        &Packet::SetPixel {
+            x,
+            y,
+            color,
+            canvas_id,
+        } => {
+            let Some(mut target) = canvases.get(canvas_id as usize).cloned() else {
+                debug!("{} drew on nonexistent canvas {}", target_addr, canvas_id);
+                stats.count_pixel(target_addr, false);
+                return;
+            };
+            let accepted = is_within_rate_limit(&policy.rate_limiter, target_addr)
+                && target.set_pixel(x, y, policy.color_space.to_internal_color(color));
+            stats.count_pixel(target_addr, accepted);
+            if accepted {
+                policy.mirror(stats, target_addr, request.packet.clone());
+            }
+        }
+        &Packet::SetGray { x, y, value } => {
+            let accepted = is_within_rate_limit(&policy.rate_limiter, target_addr)
+                && canvas.set_pixel(
+                    x,
+                    y,
+                    policy
+                        .color_space
+                        .to_internal_color(pingxelflut::format::Color::from_gray(value)),
+                );
+            stats.count_pixel(target_addr, accepted);
+            if accepted {
+                policy.mirror(stats, target_addr, request.packet.clone());
+            }
+        }
+        &Packet::FillRect {
+            x,
+            y,
+            width,
+            height,
+            color,
+        } => {
+            let area = u64::from(width) * u64::from(height);
+            let filled = if is_within_pixel_budget(&policy.rate_limiter, target_addr, area) {
+                canvas.fill_rect(
+                    x,
+                    y,
+                    width,
+                    height,
+                    policy.color_space.to_internal_color(color),
+                )
+            } else {
+                0
+            };
+            if filled > 0 {
+                stats.count_pixels_set(target_addr, filled as u64);
+                policy.mirror(stats, target_addr, request.packet.clone());
+            } else {
+                Stats::count(&stats.pixels_dropped);
+            }
+        }
+        Packet::SetRowRle { x, y, runs } => {
+            let length = runs.iter().map(|&(count, _)| u64::from(count)).sum();
+            let painted = if is_within_pixel_budget(&policy.rate_limiter, target_addr, length) {
+                let runs: Vec<_> = runs
+                    .iter()
+                    .map(|&(count, color)| (count, policy.color_space.to_internal_color(color)))
+                    .collect();
+                canvas.set_row_runs(*x, *y, &runs)
+            } else {
+                0
+            };
+            if painted > 0 {
+                stats.count_pixels_set(target_addr, painted as u64);
+                policy.mirror(stats, target_addr, request.packet.clone());
+            } else {
+                Stats::count(&stats.pixels_dropped);
+            }
+        }
+        Packet::DrawText { x, y, color, text } => {
+            let pixels = font::text_pixels(text) as u64;
+            let drawn = if is_within_pixel_budget(&policy.rate_limiter, target_addr, pixels) {
+                canvas.draw_text(*x, *y, text, policy.color_space.to_internal_color(*color))
+            } else {
+                0
+            };
+            if drawn > 0 {
+                stats.count_pixels_set(target_addr, drawn as u64);
+                policy.mirror(stats, target_addr, request.packet.clone());
+            } else {
+                Stats::count(&stats.pixels_dropped);
+            }
+        }
+        &Packet::DrawCircle {
+            cx,
+            cy,
+            r,
+            color,
+            fill,
+        } => {
+            let pixels = circle_pixels(r, fill);
+            let drawn = if is_within_pixel_budget(&policy.rate_limiter, target_addr, pixels) {
+                canvas.draw_circle(cx, cy, r, policy.color_space.to_internal_color(color), fill)
+            } else {
+                0
+            };
+            if drawn > 0 {
+                stats.count_pixels_set(target_addr, drawn as u64);
+                policy.mirror(stats, target_addr, request.packet.clone());
+            } else {
+                Stats::count(&stats.pixels_dropped);
+            }
+        }
+        &Packet::DrawLine {
+            x0,
+            y0,
+            x1,
+            y1,
+            color,
+        } => {
+            // Bresenham's algorithm sets one pixel per step along the longer axis.
+            let pixels = u64::from(x0.abs_diff(x1).max(y0.abs_diff(y1))) + 1;
+            let drawn = if is_within_pixel_budget(&policy.rate_limiter, target_addr, pixels) {
+                canvas.draw_line(x0, y0, x1, y1, policy.color_space.to_internal_color(color))
+            } else {
+                0
+            };
+            if drawn > 0 {
+                stats.count_pixels_set(target_addr, drawn as u64);
+                policy.mirror(stats, target_addr, request.packet.clone());
+            } else {
+                Stats::count(&stats.pixels_dropped);
+            }
+        }
+        Packet::SetRectCompressed {
+            x,
+            y,
+            width,
+            height,
+            data,
+        } => {
+            // Charged before decompressing, so that clients beyond their budget cannot make the server inflate data.
+            let area = u64::from(*width) * u64::from(*height);
+            if !is_within_pixel_budget(&policy.rate_limiter, target_addr, area) {
+                Stats::count(&stats.pixels_dropped);
+                return;
+            }
+            let colors = match Packet::decompress_rect(data, *width, *height) {
+                Ok(colors) => colors,
+                Err(why) => {
+                    debug!("invalid compressed rectangle from {}: {}", target_addr, why);
+                    Stats::count(&stats.pixels_dropped);
+                    return;
+                }
+            };
+            let colors: Vec<_> = colors
+                .into_iter()
+                .map(|color| policy.color_space.to_internal_color(color))
+                .collect();
+            // Small canvases can be uploaded in a single packet, which is then copied at once.
+            let is_full_frame = (*x, *y) == (0, 0) && (*width, *height) == canvas.logical_size();
+            let painted = if is_full_frame && canvas.blit_full(colors.as_bytes()) {
+                colors.len()
+            } else {
+                canvas.set_rect(*x, *y, *width, *height, &colors)
+            };
+            if painted > 0 {
+                stats.count_pixels_set(target_addr, painted as u64);
+                policy.mirror(stats, target_addr, request.packet.clone());
+            } else {
+                Stats::count(&stats.pixels_dropped);
+            }
+        }
+        &Packet::SetPixelIfEqual {
+            x,
+            y,
+            expected,
+            color,
+        } => {
+            let accepted = is_within_rate_limit(&policy.rate_limiter, target_addr)
+                && canvas.set_pixel_if_equal(
+                    x,
+                    y,
+                    policy.color_space.to_internal_color(expected),
+                    policy.color_space.to_internal_color(color),
+                );
+            stats.count_pixel(target_addr, accepted);
+            if accepted {
+                policy.mirror(stats, target_addr, request.packet.clone());
+            }
+        }
+        &Packet::SetPixelRgba { x, y, color } => {
+            let accepted = is_within_rate_limit(&policy.rate_limiter, target_addr)
+                && canvas.blend_pixel(x, y, policy.color_space.to_internal_color(color));
+            stats.count_pixel(target_addr, accepted);
+            if accepted {
+                policy.mirror(stats, target_addr, request.packet.clone());
+            }
+        }
+        Packet::Clear => {
+            if policy.admin_ips.contains(&target_addr) {
+                info!("canvas cleared by {}", target_addr);
+                canvas.clear();
+            } else {
+                warn!("rejected clear request from non-admin {}", target_addr);
+            }
+        }
+        Packet::SetPixels { pixels } => {
+            let mut mirrored = Vec::new();
+            let mut accepted_count = 0;
+            for &(x, y, color) in pixels {
+                let accepted = is_within_rate_limit(&policy.rate_limiter, target_addr)
+                    && canvas.set_pixel(x, y, policy.color_space.to_internal_color(color));
+                accepted_count += u64::from(accepted);
+                if accepted && policy.mirror.is_some() {
+                    mirrored.push((x, y, color));
+                }
+            }
+            // Counted once per batch, which keeps the contribution lock off the per-pixel path.
+            stats.count_pixels_set(target_addr, accepted_count);
+            stats.count_pixels_dropped(pixels.len() as u64 - accepted_count);
+            if !mirrored.is_empty() {
+                let packet = Packet::SetPixels { pixels: mirrored };
+                policy.mirror(stats, target_addr, packet);
+            }
+        }
+    }
+}
+
+/// Check whether packets from a source are accepted; always true without any allowed ranges.
+/// IPv4-mapped IPv6 addresses are treated as the IPv4 addresses they represent.
+fn is_allowed_source(allowed_ranges: &[IpNet], source: IpAddr) -> bool {
+    let source = source.to_canonical();
+    allowed_ranges.is_empty() || allowed_ranges.iter().any(|range| range.contains(&source))
+}
+
+/// Check whether a source may set another pixel; always true without a rate limit.
+fn is_within_rate_limit(rate_limiter: &Option<RateLimiter>, source: IpAddr) -> bool {
+    is_within_pixel_budget(rate_limiter, source, 1)
+}
+
+/// Whether a packet drawing up to `pixels` pixels at once is within the source’s rate limit, charging all of them.
+fn is_within_pixel_budget(rate_limiter: &Option<RateLimiter>, source: IpAddr, pixels: u64) -> bool {
+    rate_limiter
+        .as_ref()
+        .map_or(true, |rate_limiter| rate_limiter.check_n(source, pixels))
+}
+
+/// Tell a client the canvas size in reply to its packet.
+fn send_size_response(
+    canvas: &Canvas,
+    request: &ReceivedPacket,
+    policy: &RequestPolicy,
+) -> io::Result<()> {
+    let _span = debug_span!("size_response", client = %request.source).entered();
+    let (width, height) = canvas.logical_size();
+    let response = Packet::SizeResponse {
+        width,
+        height,
+        version: PROTOCOL_VERSION,
+        capabilities: match policy.coordinate_mode {
+            CoordinateMode::Full => 0,
+            CoordinateMode::Compact => CAPABILITY_COMPACT_COORDINATES,
+        },
+    };
+    // A lost size response leaves the client stuck, so it is worth retrying.
+    send_reply(request, response, SIZE_RESPONSE_RETRIES, policy)
+}
+
+/// The address to reply to a request at.
+/// Link-local IPv6 addresses are only unique per link, so they are scoped to the interface the request arrived on.
+fn reply_address(request: &ReceivedPacket) -> SocketAddr {
+    match request.source {
+        IpAddr::V6(source) if is_unicast_link_local(source) => {
+            let scope_id = request.interface_index.unwrap_or_else(|| {
+                warn!(
+                    "replying to link-local {} without knowing its interface, the reply may not arrive",
+                    source
+                );
+                0
+            });
+            SocketAddr::V6(SocketAddrV6::new(source, 0, 0, scope_id))
+        }
+        source => SocketAddr::new(source, 0),
+    }
+}
+
+/// Whether an address is an IPv6 link-local unicast address, in `fe80::/10`.
+pub fn is_unicast_link_local(address: Ipv6Addr) -> bool {
+    address.segments()[0] & 0xffc0 == 0xfe80
+}
+
+/// Send a packet back to a client as an ICMP echo reply, or on the request's reply channel if it has one.
+/// The reply mirrors the request’s echo identifier and sequence number, which many clients use to match replies.
+/// Failed sends are retried up to `retries` times. ICMP replies are sent with the time to live and from the source
+/// address of the policy, if any.
+fn send_reply(
+    request: &ReceivedPacket,
+    packet: Packet,
+    retries: u32,
+    policy: &RequestPolicy,
+) -> io::Result<()> {
+    if let Some(reply_channel) = &request.reply_channel {
+        // Like a lost ICMP reply, the reply is dropped if the client does not keep up.
+        let _ = reply_channel.try_send(packet);
+        return Ok(());
+    }
+    let mut response = Icmp::new(
+        reply_address(request),
+        request.identifier,
+        EchoDirection::Reply,
+    );
+    response.set_sequence_number(request.sequence_number);
+    response.set_payload(packet.to_bytes());
+    if let Some(ttl) = policy.reply_ttl {
+        response.set_ttl(ttl);
+    }
+    if let Some(source) = policy.reply_source(request.source) {
+        response.set_source(source);
+    }
+    response.send_with_retries(retries)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::VecSink;
+    use pingxelflut::format::Color;
+    use rgb::RGBA8;
+    use std::net::Ipv4Addr;
+    use std::sync::atomic::Ordering;
+    use tokio::sync::mpsc::{self, Receiver};
+
+    const ADMIN: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+    const CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+    const RED: RGBA8 = RGBA8::new(0xff, 0, 0, 0xff);
+
+    /// A policy accepting everything, with the given administrators.
+    fn policy(admin_ips: &[IpAddr]) -> RequestPolicy {
+        RequestPolicy {
+            rate_limiter: None,
+            admin_ips: admin_ips.into(),
+            allowed_ranges: Arc::new([]),
+            client_limit: None,
+            size_announcer: None,
+            size_reply_cooldown: SizeReplyCooldown::default(),
+            color_space: ColorSpace::Srgb,
+            mirror: None,
+            recorder: None,
+            reply_ttl: None,
+            reply_sources: Arc::new([]),
+            coordinate_mode: CoordinateMode::Full,
+            thumbnail_jobs: ThumbnailJobs::default(),
+        }
+    }
+
+    /// A packet from a source, whose replies are sent to the returned channel instead of via ICMP.
+    fn request(source: IpAddr, packet: Packet) -> (ReceivedPacket, Receiver<Packet>) {
+        let (sender, receiver) = mpsc::channel(16);
+        let request = ReceivedPacket {
+            packet,
+            source,
+            destination: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            identifier: 0,
+            sequence_number: 0,
+            interface_index: None,
+            reply_channel: Some(sender),
+        };
+        (request, receiver)
+    }
+
+    /// A policy accepting everything, but charging each source a budget of `pixels` pixels per second.
+    fn limited_policy(pixels: u64) -> RequestPolicy {
+        RequestPolicy {
+            rate_limiter: Some(RateLimiter::new(pixels as u32)),
+            ..policy(&[])
+        }
+    }
+
+    /// A single canvas on an in-memory frame buffer.
+    fn canvases(width: u16, height: u16) -> [Canvas; 1] {
+        [Canvas::new(Box::new(VecSink::new(width, height)))]
+    }
+
+    /// Handle a packet from a source, returning the replies it got.
+    fn handle(
+        canvases: &[Canvas],
+        stats: &Stats,
+        policy: &RequestPolicy,
+        source: IpAddr,
+        packet: Packet,
+    ) -> Vec<Packet> {
+        let (request, mut replies) = request(source, packet);
+        handle_packet(canvases, stats, policy, request);
+        canvases[0].flush_queue();
+        std::iter::from_fn(|| replies.try_recv().ok()).collect()
+    }
+
+    #[test]
+    fn only_admins_clear_the_canvas() {
+        let canvases = canvases(4, 4);
+        let stats = Stats::default();
+        let policy = policy(&[ADMIN]);
+        let red = Color::from_rgb([0xff, 0, 0]);
+        let fill = Packet::FillRect {
+            x: 0,
+            y: 0,
+            width: 4,
+            height: 4,
+            color: red,
+        };
+        handle(&canvases, &stats, &policy, CLIENT, fill);
+
+        handle(&canvases, &stats, &policy, CLIENT, Packet::Clear);
+        assert_eq!(canvases[0].get_pixel(2, 2), Some(RED));
+
+        handle(&canvases, &stats, &policy, ADMIN, Packet::Clear);
+        let frame = canvases[0].frame_copy();
+        assert!(frame.chunks_exact(4).all(|pixel| pixel == [0, 0, 0, 0xff]));
+    }
+
+    #[test]
+    fn gray_pixels_round_trip_to_equal_rgb() {
+        let canvases = canvases(3, 1);
+        let stats = Stats::default();
+        let policy = policy(&[]);
+        // Start from a color that none of the grays matches, so that black is really drawn.
+        let fill = Packet::FillRect {
+            x: 0,
+            y: 0,
+            width: 3,
+            height: 1,
+            color: Color::from_rgb([0x12, 0x34, 0x56]),
+        };
+        handle(&canvases, &stats, &policy, CLIENT, fill);
+        for (x, value) in [(0, 0), (1, 128), (2, 255)] {
+            let bytes = Packet::SetGray { x, y: 0, value }.to_bytes();
+            let packet = Packet::from_bytes(&bytes).unwrap();
+            handle(&canvases, &stats, &policy, CLIENT, packet);
+        }
+        assert_eq!(canvases[0].get_pixel(0, 0), Some(RGBA8::new(0, 0, 0, 0xff)));
+        assert_eq!(
+            canvases[0].get_pixel(1, 0),
+            Some(RGBA8::new(128, 128, 128, 0xff))
+        );
+        assert_eq!(
+            canvases[0].get_pixel(2, 0),
+            Some(RGBA8::new(0xff, 0xff, 0xff, 0xff))
+        );
+    }
+
+    #[test]
+    fn drawing_packets_are_charged_for_their_pixels() {
+        let red = Color::from_rgb([0xff, 0, 0]);
+        let fill = |width, height| Packet::FillRect {
+            x: 0,
+            y: 0,
+            width,
+            height,
+            color: red,
+        };
+        let row = |counts: &[u16]| Packet::SetRowRle {
+            x: 0,
+            y: 0,
+            runs: counts.iter().map(|&count| (count, red)).collect(),
+        };
+        let text = |x| Packet::DrawText {
+            x,
+            y: 0,
+            color: red,
+            text: "Hi".to_owned(),
+        };
+        let circle = |fill| Packet::DrawCircle {
+            cx: 8,
+            cy: 8,
+            r: 4,
+            color: red,
+            fill,
+        };
+        let line = |x1, y1| Packet::DrawLine {
+            x0: 0,
+            y0: 0,
+            x1,
+            y1,
+            color: red,
+        };
+        let compressed = |width, height| {
+            let pixels = vec![red; width as usize * height as usize];
+            Packet::compress_rect(0, 0, width, height, &pixels).unwrap()
+        };
+        let invalid = Packet::SetRectCompressed {
+            x: 0,
+            y: 0,
+            width: 1,
+            height: 4,
+            data: vec![1, 2, 3],
+        };
+        let text_pixels = font::text_pixels("Hi") as u64;
+        let circle_pixels = circle_pixels(4, true);
+        // The budget per source, and packets along with the number of pixels set once each of them is handled.
+        let cases: [(u64, Vec<(Packet, u64)>); 6] = [
+            (
+                20,
+                vec![
+                    // Larger than the whole budget, so it is dropped without taking any of it.
+                    (fill(5, 5), 0),
+                    (fill(4, 4), 16),
+                    // 4 tokens are left, so a single row of 5 is too much.
+                    (fill(5, 1), 16),
+                ],
+            ),
+            (10, vec![(row(&[3, 4]), 7), (row(&[2, 2]), 7)]),
+            (
+                text_pixels,
+                vec![(text(0), text_pixels), (text(16), text_pixels)],
+            ),
+            (
+                circle_pixels,
+                vec![
+                    (circle(true), circle_pixels),
+                    (circle(false), circle_pixels),
+                ],
+            ),
+            // A steep line of 8 pixels, then lines of 5 and 4 pixels.
+            (12, vec![(line(3, 7), 8), (line(4, 0), 8), (line(3, 0), 12)]),
+            (
+                20,
+                vec![
+                    (compressed(4, 4), 16),
+                    (compressed(1, 5), 16),
+                    // Invalid data uses up the budget anyway.
+                    (invalid, 16),
+                    (compressed(1, 1), 16),
+                ],
+            ),
+        ];
+        for (budget, packets) in cases {
+            let canvases = canvases(16, 16);
+            let stats = Stats::default();
+            let policy = limited_policy(budget);
+            for (packet, pixels_set) in packets {
+                let description = format!("{:?} with a budget of {}", packet, budget);
+                handle(&canvases, &stats, &policy, CLIENT, packet);
+                assert_eq!(
+                    stats.pixels_set.load(Ordering::Relaxed),
+                    pixels_set,
+                    "{}",
+                    description
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn only_sources_in_allowed_ranges_draw() {
+        let canvases = canvases(6, 1);
+        let stats = Stats::default();
+        let policy = RequestPolicy {
+            allowed_ranges: ["10.0.0.0/8".parse().unwrap(), "fd00::/64".parse().unwrap()].into(),
+            ..policy(&[])
+        };
+        let sources = [
+            ("10.1.2.3", true),
+            ("192.168.0.1", false),
+            ("fd00::1", true),
+            ("fe80::1", false),
+            ("::ffff:10.1.2.3", true),
+            ("::a01:203", false),
+        ];
+        for (x, (source, _)) in sources.iter().enumerate() {
+            let set_pixel = Packet::SetPixel {
+                x: x as u16,
+                y: 0,
+                color: Color::from_rgb([0xff, 0, 0]),
+                canvas_id: 0,
+            };
+            handle(
+                &canvases,
+                &stats,
+                &policy,
+                source.parse().unwrap(),
+                set_pixel,
+            );
+        }
+        for (x, (source, allowed)) in sources.iter().enumerate() {
+            let drawn = canvases[0].get_pixel(x as u16, 0) == Some(RED);
+            assert_eq!(drawn, *allowed, "pixel from {}", source);
+        }
+        assert_eq!(stats.disallowed_packets.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn handled_packets_draw_and_are_counted() {
+        let canvases = canvases(8, 4);
+        let stats = Stats::default();
+        let policy = policy(&[]);
+        let red = Color::from_rgb([0xff, 0, 0]);
+        let blue = Color::from_rgb([0, 0, 0xff]);
+        let set_pixel = |x, y, color| Packet::SetPixel {
+            x,
+            y,
+            color,
+            canvas_id: 0,
+        };
+
+        let replies = handle(
+            &canvases,
+            &stats,
+            &policy,
+            CLIENT,
+            Packet::SizeRequest { canvas_id: 0 },
+        );
+        assert_eq!(
+            replies,
+            [Packet::SizeResponse {
+                width: 8,
+                height: 4,
+                version: PROTOCOL_VERSION,
+                capabilities: 0,
+            }]
+        );
+        for packet in [
+            set_pixel(0, 0, red),
+            set_pixel(7, 3, red),
+            Packet::SetPixels {
+                pixels: vec![(1, 1, blue), (2, 1, blue), (8, 1, blue)],
+            },
+            set_pixel(0, 4, red),
+        ] {
+            assert!(handle(&canvases, &stats, &policy, CLIENT, packet).is_empty());
+        }
+
+        let frame = canvases[0].frame_copy();
+        let colored: Vec<_> = frame
+            .chunks_exact(4)
+            .enumerate()
+            .filter(|(_, pixel)| *pixel != [0, 0, 0, 0xff])
+            .map(|(index, pixel)| (index % 8, index / 8, pixel.to_vec()))
+            .collect();
+        assert_eq!(
+            colored,
+            [
+                (0, 0, vec![0xff, 0, 0, 0xff]),
+                (1, 1, vec![0, 0, 0xff, 0xff]),
+                (2, 1, vec![0, 0, 0xff, 0xff]),
+                (7, 3, vec![0xff, 0, 0, 0xff]),
+            ]
+        );
+        assert_eq!(stats.size_requests.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.pixels_set.load(Ordering::Relaxed), 4);
+        assert_eq!(stats.pixels_dropped.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn packets_address_canvases_by_id() {
+        let canvases = [
+            Canvas::new(Box::new(VecSink::new(4, 4))),
+            Canvas::new(Box::new(VecSink::new(2, 3))),
+        ];
+        let stats = Stats::default();
+        let policy = policy(&[]);
+        let red = Color::from_rgb([0xff, 0, 0]);
+        let set_pixel = |canvas_id| Packet::SetPixel {
+            x: 1,
+            y: 1,
+            color: red,
+            canvas_id,
+        };
+
+        let replies = handle(
+            &canvases,
+            &stats,
+            &policy,
+            CLIENT,
+            Packet::SizeRequest { canvas_id: 1 },
+        );
+        assert!(matches!(
+            replies[..],
+            [Packet::SizeResponse {
+                width: 2,
+                height: 3,
+                ..
+            }]
+        ));
+        assert!(handle(
+            &canvases,
+            &stats,
+            &policy,
+            CLIENT,
+            Packet::SizeRequest { canvas_id: 2 }
+        )
+        .is_empty());
+
+        handle(&canvases, &stats, &policy, CLIENT, set_pixel(1));
+        handle(&canvases, &stats, &policy, CLIENT, set_pixel(2));
+        canvases[1].flush_queue();
+        assert_eq!(canvases[1].get_pixel(1, 1), Some(RED));
+        assert_eq!(canvases[0].get_pixel(1, 1), Some(RGBA8::new(0, 0, 0, 0xff)));
+        assert_eq!(stats.pixels_set.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.pixels_dropped.load(Ordering::Relaxed), 1);
+
+        // Reads always go to the main canvas.
+        handle(&canvases, &stats, &policy, CLIENT, set_pixel(0));
+        let replies = handle(
+            &canvases,
+            &stats,
+            &policy,
+            CLIENT,
+            Packet::GetPixel { x: 1, y: 1 },
+        );
+        assert_eq!(
+            replies,
+            [Packet::PixelResponse {
+                x: 1,
+                y: 1,
+                color: red
+            }]
+        );
+    }
+
+    #[test]
+    fn sources_beyond_the_client_limit_are_rejected() {
+        let canvases = canvases(4, 1);
+        let stats = Stats::default();
+        let policy = RequestPolicy {
+            client_limit: Some(ClientLimit::new(2, std::time::Duration::from_secs(60))),
+            ..policy(&[])
+        };
+        for x in 0..4 {
+            let source = IpAddr::V4(Ipv4Addr::new(10, 0, 1, x as u8));
+            let set_pixel = Packet::SetPixel {
+                x,
+                y: 0,
+                color: Color::from_rgb([0xff, 0, 0]),
+                canvas_id: 0,
+            };
+            handle(&canvases, &stats, &policy, source, set_pixel);
+        }
+        let drawn: Vec<_> = (0..4)
+            .map(|x| canvases[0].get_pixel(x, 0) == Some(RED))
+            .collect();
+        assert_eq!(drawn, [true, true, false, false]);
+        assert_eq!(stats.clients_rejected.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn thumbnails_are_dropped_while_too_many_are_in_progress() {
+        let canvases = canvases(64, 32);
+        let stats = Stats::default();
+        let policy = policy(&[]);
+        let thumbnail = Packet::GetThumbnail { max_dim: 8 };
+        let busy: Vec<_> = std::iter::from_fn(|| policy.thumbnail_jobs.try_start()).collect();
+        let (dropped, mut replies) = request(CLIENT, thumbnail.clone());
+        handle_packet(&canvases, &stats, &policy, dropped);
+        drop(busy);
+
+        let (answered, mut answers) = request(CLIENT, thumbnail);
+        handle_packet(&canvases, &stats, &policy, answered);
+        let answer = answers.recv().await.expect("thumbnail is answered");
+        assert!(matches!(
+            answer,
+            Packet::ThumbnailResponse {
+                width: 8,
+                height: 4,
+                ..
+            }
+        ));
+        // The dropped request's reply channel is closed without any reply.
+        assert!(replies.recv().await.is_none());
+    }
+
+    #[test]
+    fn rectangle_reads_are_charged_for_each_pixel() {
+        let canvases = canvases(8, 8);
+        let stats = Stats::default();
+        let policy = limited_policy(20);
+        let get_rect = |width, height| Packet::GetRect {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        };
+        assert!(!handle(&canvases, &stats, &policy, CLIENT, get_rect(4, 4)).is_empty());
+        // 4 tokens are left, so a single row of 5 is too much.
+        assert!(handle(&canvases, &stats, &policy, CLIENT, get_rect(5, 1)).is_empty());
+    }
+
+    #[tokio::test]
+    async fn thumbnails_are_charged_for_each_pixel() {
+        let canvases = canvases(64, 32);
+        let stats = Stats::default();
+        let policy = limited_policy(40);
+        // An 8 by 4 thumbnail costs 32 of the 40 tokens.
+        let (answered, mut answers) = request(CLIENT, Packet::GetThumbnail { max_dim: 8 });
+        handle_packet(&canvases, &stats, &policy, answered);
+        assert!(answers.recv().await.is_some());
+
+        let (dropped, mut replies) = request(CLIENT, Packet::GetThumbnail { max_dim: 8 });
+        handle_packet(&canvases, &stats, &policy, dropped);
+        assert!(replies.recv().await.is_none());
+    }
+}
@@ -0,0 +1,44 @@
+//! Serving HTTP on a TCP listener, shared by the metrics and WebSocket servers.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use axum::extract::ConnectInfo;
+use axum::{Extension, Router};
+use hyper::server::conn::http1;
+use hyper_util::rt::{TokioIo, TokioTimer};
+use hyper_util::service::TowerToHyperService;
+use tokio::net::TcpListener;
+use tokio::sync::Semaphore;
+use tracing::debug;
+
+/// Time a client has to send the head of a request, so that idle connections do not pile up.
+const HEADER_READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Maximum number of connections served at the same time; further connections wait to be accepted.
+const MAX_CONNECTIONS: usize = 256;
+
+/// Serve the routes on connections from the listener until accepting fails.
+/// Handlers can extract the client address as a [`ConnectInfo`] of a [`std::net::SocketAddr`].
+///
+/// Connections upgraded to another protocol, such as WebSocket, no longer count towards [`MAX_CONNECTIONS`].
+pub(crate) async fn serve(listener: TcpListener, router: Router) -> Result<()> {
+    let connections = Arc::new(Semaphore::new(MAX_CONNECTIONS));
+    loop {
+        let permit = connections.clone().acquire_owned().await?;
+        let (stream, peer) = listener.accept().await?;
+        let service = TowerToHyperService::new(router.clone().layer(Extension(ConnectInfo(peer))));
+        tokio::spawn(async move {
+            let connection = http1::Builder::new()
+                .timer(TokioTimer::new())
+                .header_read_timeout(HEADER_READ_TIMEOUT)
+                .serve_connection(TokioIo::new(stream), service)
+                .with_upgrades();
+            if let Err(why) = connection.await {
+                debug!("HTTP connection from {} failed: {}", peer, why);
+            }
+            drop(permit);
+        });
+    }
+}
@@ -0,0 +1,32 @@
+//! Canvas and server infrastructure of the Pingxelflut server, shared by the server binary and the benchmarks.
+
+#![deny(clippy::all)]
+#![forbid(unsafe_code)]
+
+#[cfg(unix)]
+pub mod admin;
+pub mod announce;
+pub mod attract;
+pub mod background;
+pub mod canvas;
+pub mod client_limit;
+pub mod debug_tcp;
+pub mod dirty;
+pub mod font;
+pub mod handler;
+pub mod http;
+pub mod logging;
+pub mod mask;
+pub mod metrics;
+pub mod mirror;
+pub mod rate_limit;
+pub mod recording;
+pub mod shutdown;
+pub mod sink;
+pub mod source;
+pub mod stats;
+pub mod thumbnail;
+pub mod transform;
+pub mod viewport;
+pub mod websocket;
+pub mod workers;
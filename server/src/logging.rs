@@ -0,0 +1,46 @@
+//! Log output setup. Log levels are configured with the `RUST_LOG` environment variable in the syntax of
+//! [`EnvFilter`], or for casual use, with a default level given on the command line.
+//!
+//! Records of the `log` crate, which dependencies such as `wgpu` use, are logged alike.
+
+use clap::ValueEnum;
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::EnvFilter;
+
+/// How log lines are formatted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// Human-readable lines.
+    #[default]
+    Text,
+    /// One JSON object per line, with `timestamp`, `level`, `target` and `message` fields,
+    /// as well as the fields of the current span in `span` and of all entered spans in `spans`.
+    Json,
+}
+
+/// The default log level for a number of `--verbose` flags, or with `--quiet`, only errors.
+/// Warnings are logged without either flag, and three `--verbose` flags enable the per-packet trace logs.
+pub fn log_level(quiet: bool, verbose: u8) -> LevelFilter {
+    if quiet {
+        return LevelFilter::ERROR;
+    }
+    match verbose {
+        0 => LevelFilter::WARN,
+        1 => LevelFilter::INFO,
+        2 => LevelFilter::DEBUG,
+        _ => LevelFilter::TRACE,
+    }
+}
+
+/// Install the global subscriber with the given format.
+/// The `RUST_LOG` environment variable takes precedence over the default level, if it is set.
+pub fn init_logging(format: LogFormat, default_level: LevelFilter) {
+    let filter = EnvFilter::builder()
+        .with_default_directive(default_level.into())
+        .from_env_lossy();
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+    match format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.json().flatten_event(true).init(),
+    }
+}
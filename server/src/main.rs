@@ -2,50 +2,509 @@
 #![forbid(unsafe_code)]
 #![allow(clippy::single_match)]
 
-mod canvas;
-
-use std::net::{IpAddr, SocketAddr};
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::pin::pin;
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use anyhow::Result;
-use canvas::{to_internal_color, Canvas};
-use concurrent_queue::ConcurrentQueue;
-use etherparse::{Icmpv4Type, Icmpv6Type, NetSlice, SlicedPacket, TransportSlice};
+use clap::{Parser, Subcommand};
+use etherparse::{IcmpEchoHeader, Icmpv4Type, Icmpv6Type, NetSlice, SlicedPacket, TransportSlice};
+use futures::stream::BoxStream;
 use futures::{Future, StreamExt};
-use log::{error, warn};
-use parking_lot::RwLock;
-use pcap::{Capture, Device, PacketCodec};
-use pingxelflut::format::Packet;
-use pingxelflut::icmp::{EchoDirection, Icmp};
+use ipnet::IpNet;
+use pcap::{Active, Capture, Device, Linktype, PacketCodec, PacketStream};
+use pingxelflut::client::Client;
+use pingxelflut::format::{CoordinateMode, Packet, ParseError, PROTOCOL_VERSION};
+use pingxelflut::icmp::{EchoDirection, Icmp, SendMode, ICMP_HEADER_SIZE};
 use pixels::wgpu::Color;
 use pixels::{Pixels, SurfaceTexture};
+use rgb::RGBA8;
+#[cfg(unix)]
+use server::admin::admin_socket_server;
+use server::announce::{SizeAnnouncer, SizeReplyCooldown};
+use server::attract::attract_handler;
+use server::background::{load_background, BackgroundFit};
+use server::canvas::{
+    check_frame_size, parse_canvas_size, parse_hex_color, write_png, Canvas, ColorSpace,
+    DEFAULT_MAX_CANVAS_BYTES,
+};
+use server::client_limit::ClientLimit;
+use server::debug_tcp::debug_tcp_server;
+use server::handler::{handle_packet, is_unicast_link_local, RequestPolicy};
+use server::logging::{init_logging, log_level, LogFormat};
+use server::mask::Mask;
+use server::metrics::metrics_server;
+use server::mirror::Mirror;
+use server::rate_limit::RateLimiter;
+use server::recording::{read_record, Recorder};
+use server::shutdown::Shutdown;
+use server::sink::{FramebufferSink, PixelSink, VecSink, WindowSink};
+use server::source::{FilePacketSource, PacketSource, ReceivedPacket};
+use server::stats::{stats_file_handler, stats_handler, Stats};
+use server::thumbnail::ThumbnailJobs;
+use server::transform::{parse_window_scale, Flip, Rotation, Transform, WindowScale};
+use server::viewport::Viewport;
+use server::websocket::websocket_server;
+use server::workers::{PacketWorkers, DEFAULT_WORKERS};
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, info_span, trace, warn, Instrument};
 use winit::application::ApplicationHandler;
-use winit::event::WindowEvent;
-use winit::event_loop::{ActiveEventLoop, EventLoop};
-use winit::window::{Window, WindowId};
+use winit::event::{ElementState, KeyEvent, WindowEvent};
+use winit::event_loop::{ActiveEventLoop, ControlFlow, EventLoop};
+use winit::keyboard::{KeyCode, PhysicalKey};
+use winit::window::{Fullscreen, Window, WindowId};
+
+/// Capture length large enough for the largest Pingxelflut packet: Ethernet, IPv6 and ICMP headers plus payload.
+const MIN_SNAPLEN: usize = 14 + 40 + ICMP_HEADER_SIZE + Packet::MAX_SIZE;
+
+/// Capture filter used without `--bpf-filter`.
+const DEFAULT_CAPTURE_FILTER: &str = "icmp or icmp6";
+
+/// Default size of the kernel capture buffer, 64 MiB.
+const DEFAULT_CAPTURE_BUFFER_SIZE: i32 = 64 << 20;
+
+/// How often the canvas is updated in headless mode.
+const HEADLESS_TICK: Duration = Duration::from_millis(16);
+
+/// How long the packet handlers may take to stop after a shutdown was requested, before they are abandoned.
+const SHUTDOWN_DRAIN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How often the window checks for canvas changes while nothing is being drawn.
+const IDLE_POLL_INTERVAL: Duration = Duration::from_millis(16);
+
+/// How long the `doctor` command waits for its test packet to be captured.
+const DOCTOR_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Window title, which live statistics are appended to.
+const WINDOW_TITLE: &str = "Pingxelflut";
 
-const WIDTH: u32 = 1920;
-const HEIGHT: u32 = 1080;
+/// How often live statistics in the window title are updated.
+const TITLE_UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A simple Pingxelflut server.
+#[derive(Clone, Parser, Debug)]
+struct Arguments {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Canvas width in pixels.
+    #[arg(long, value_name = "WIDTH", default_value = "1920", value_parser = clap::value_parser!(u16).range(1..))]
+    width: u16,
+    /// Canvas height in pixels.
+    #[arg(long, value_name = "HEIGHT", default_value = "1080", value_parser = clap::value_parser!(u16).range(1..))]
+    height: u16,
+    /// Add another canvas of this size, as `WIDTHxHEIGHT`; can be given multiple times.
+    /// Additional canvases get IDs from 1 on and only live in memory, but the number keys show them in the window.
+    #[arg(long = "canvas", value_name = "SIZE", value_parser = parse_canvas_size)]
+    extra_canvas: Vec<(u16, u16)>,
+    /// Run without a window, only keeping the canvas in memory.
+    /// Use `--snapshot` to get at the canvas contents.
+    #[arg(long)]
+    headless: bool,
+    /// Draw to a Linux framebuffer device such as /dev/fb0 instead of opening a window.
+    /// The canvas is shown at the top left of the screen; RGB565 and XRGB8888 devices are supported.
+    #[arg(long, value_name = "PATH")]
+    framebuffer: Option<PathBuf>,
+    /// Periodically write the canvas to this PNG file.
+    #[arg(long, value_name = "PATH")]
+    snapshot: Option<PathBuf>,
+    /// Interval between canvas snapshots in seconds.
+    #[arg(long, value_name = "SECONDS", default_value = "10", value_parser = clap::value_parser!(u64).range(1..))]
+    snapshot_interval: u64,
+    /// Periodically write the statistics, with packet counts per type and the top contributors, to this JSON file.
+    #[arg(long, value_name = "PATH")]
+    stats_file: Option<PathBuf>,
+    /// Interval between writes of the statistics file in seconds.
+    #[arg(long, value_name = "SECONDS", default_value = "10", requires = "stats_file", value_parser = clap::value_parser!(u64).range(1..))]
+    stats_interval: u64,
+    /// Append every received packet with its source address to this recording, for replaying it with `--replay`.
+    #[arg(long, value_name = "PATH")]
+    record: Option<PathBuf>,
+    /// Replay the packets in this recording instead of capturing network traffic.
+    /// This needs neither libpcap nor capture privileges, for example for tests and demos.
+    #[arg(long, value_name = "PATH", conflicts_with = "interface")]
+    replay: Option<PathBuf>,
+    /// Check the recording at this path instead of starting the server, and exit.
+    /// Every packet must decode and set pixels on a canvas of `--width` by `--height`; the exit status is nonzero otherwise.
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["replay", "record"])]
+    validate: Option<PathBuf>,
+    /// Replay the whole recording given with `--replay` without a window, write the resulting canvas to the `--snapshot` file and exit.
+    /// This is meant for generating reference images.
+    #[arg(long, requires_all = ["replay", "snapshot"])]
+    once: bool,
+    /// Only capture packets on the network interface with this name.
+    /// By default, all interfaces are captured.
+    #[arg(long, value_name = "NAME")]
+    interface: Option<String>,
+    /// Directory to write screenshots to, taken with the S key.
+    #[arg(long, value_name = "DIRECTORY", default_value = ".")]
+    screenshot_dir: PathBuf,
+    /// Maximum number of pixels per second accepted from a single source address.
+    /// Pixels beyond this budget are dropped. Rectangles, compressed rectangles, row runs, text, circles and lines are
+    /// charged for each of their pixels, and dropped as a whole if the budget does not cover them. By default, there is no limit.
+    #[arg(long, value_name = "PIXELS_PER_SEC", value_parser = clap::value_parser!(u32).range(1..))]
+    rate_limit: Option<u32>,
+    /// Maximum number of queued pixels drawn per frame; the rest are drawn in later frames.
+    /// This keeps the window responsive under heavy load. By default, all queued pixels are drawn every frame.
+    #[arg(long, value_name = "PIXELS", value_parser = clap::value_parser!(u32).range(1..))]
+    max_pixels_per_frame: Option<u32>,
+    /// Maximum number of pixels waiting to be drawn; when full, the oldest waiting pixel is dropped for each new one.
+    /// This bounds memory under floods. By default, the queue is unbounded.
+    #[arg(long, value_name = "PIXELS", value_parser = clap::value_parser!(u32).range(1..))]
+    queue_capacity: Option<u32>,
+    /// Number of tasks applying the packets of each capture device.
+    /// The packets of each client are always applied by the same task, in the order they arrived.
+    #[arg(long, value_name = "COUNT", default_value_t = DEFAULT_WORKERS as u16, value_parser = clap::value_parser!(u16).range(1..))]
+    device_workers: u16,
+    /// Refuse to start if the frame buffer would need more than this many bytes, 4 per pixel.
+    /// For video walls, only the rendered region counts.
+    #[arg(long, value_name = "BYTES", default_value_t = DEFAULT_MAX_CANVAS_BYTES)]
+    max_canvas_bytes: u64,
+    /// Maximum number of frames rendered per second.
+    /// Frames are only rendered when the canvas changed, so an idle canvas uses little CPU regardless.
+    /// Packet handling, including size requests, is not affected by this limit.
+    #[arg(long, value_name = "FPS", value_parser = clap::value_parser!(u32).range(1..))]
+    max_fps: Option<u32>,
+    /// Drop packets with a protocol version below this one, for example to phase out old clients during an event.
+    /// Size requests of outdated clients are still answered, so that they learn the server's version.
+    #[arg(long, value_name = "VERSION", default_value_t = 1, value_parser = clap::value_parser!(u8).range(1..=PROTOCOL_VERSION as i64))]
+    min_protocol_version: u8,
+    /// Also decode Pingxelflut packets carried in captured ICMP echo replies, not just in echo requests.
+    /// Hosts answer pings with the request payload, so packets may be applied twice, unless the capture filter or
+    /// the network ensures that only one of both is seen.
+    #[arg(long)]
+    accept_replies: bool,
+    /// Number of bytes captured per packet.
+    /// Values below the default truncate large Pingxelflut packets, which are then dropped as malformed.
+    #[arg(long, value_name = "BYTES", default_value_t = MIN_SNAPLEN as i32, value_parser = clap::value_parser!(i32).range(1..))]
+    snaplen: i32,
+    /// Size of the kernel buffer for captured packets.
+    /// Larger buffers lose fewer packets under bursts of traffic.
+    #[arg(long, value_name = "BYTES", default_value_t = DEFAULT_CAPTURE_BUFFER_SIZE, value_parser = clap::value_parser!(i32).range(1..))]
+    capture_buffer_size: i32,
+    /// Capture in promiscuous mode, which also sees packets addressed to other hosts.
+    /// This is needed when the server only receives a copy of the traffic, for example behind a mirror port.
+    /// Otherwise, leave it off, as it captures more unrelated traffic and may be unwanted on shared networks.
+    #[arg(long)]
+    promiscuous: bool,
+    /// Capture filter in pcap syntax, replacing the default of "icmp or icmp6".
+    /// This helps when Pingxelflut traffic is tunneled or tagged; the filter must still match the ICMP packets to be handled.
+    #[arg(long, value_name = "EXPR")]
+    bpf_filter: Option<String>,
+    /// Only capture packets addressed to this IP address, by adding a "dst host" clause to the capture filter.
+    #[arg(long, value_name = "IP")]
+    dest_ip: Option<IpAddr>,
+    /// Write each pixel at most once per frame, keeping only its latest color.
+    /// This helps when few pixels are overwritten very often, but slows down floods that touch many distinct pixels.
+    #[arg(long)]
+    coalesce: bool,
+    /// Fade untouched pixels towards the clear color by this much per color channel and frame, up to 255.
+    /// Drawing a pixel again restores its full color. By default, pixels never fade.
+    #[arg(long, value_name = "RATE", default_value_t = 0)]
+    fade: u8,
+    /// Count the pixels differing from the clear color, shown in the window title and exported as a metric.
+    /// This reads every pixel before it is overwritten, which slows down drawing a little.
+    #[arg(long)]
+    track_fill: bool,
+    /// Let the first client to paint a pixel claim it: pixels differing from the clear color cannot be changed until the canvas is cleared.
+    /// Like `--track-fill`, this reads every pixel before it is overwritten.
+    #[arg(long)]
+    protect_written: bool,
+    /// Play a built-in animation on the canvas while no packets arrive, restoring the canvas once one does.
+    /// The animated pixels are not counted in the statistics.
+    #[arg(long, conflicts_with_all = ["protect_written", "once"])]
+    attract: bool,
+    /// Seconds without packets after which `--attract` starts the animation.
+    #[arg(long, value_name = "SECONDS", default_value_t = 60, requires = "attract", value_parser = clap::value_parser!(u64).range(1..))]
+    attract_idle: u64,
+    /// Track which parts of the canvas changed and skip rendering frames in which nothing did.
+    /// This costs a little extra work per pixel.
+    #[arg(long)]
+    dirty_tracking: bool,
+    /// Format of the log output; log levels are set with `--quiet` and `--verbose`, or the `RUST_LOG` environment variable.
+    #[arg(long, value_name = "FORMAT", default_value = "text")]
+    log_format: LogFormat,
+    /// Only log errors. Ignored if `RUST_LOG` is set.
+    #[arg(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Log more: once for informational messages, twice for debugging and three times for every packet.
+    /// By default, errors and warnings are logged. Ignored if `RUST_LOG` is set.
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+    /// Serve Prometheus metrics at `/metrics` on this address, for example `127.0.0.1:9100`.
+    /// The canvas is also served at `/snapshot.png` and as a moving picture at `/stream.mjpeg`.
+    /// By default, no HTTP server is started.
+    #[arg(long, value_name = "ADDRESS")]
+    metrics_addr: Option<SocketAddr>,
+    /// Broadcast the main canvas to WebSocket spectators on this address, for example `0.0.0.0:9101`.
+    /// Spectators get the whole canvas first and then the changed pixels ten times per second.
+    #[arg(long, value_name = "ADDRESS")]
+    ws_addr: Option<SocketAddr>,
+    /// Accept admin commands such as `stats`, `clear` and `save <path>` on a Unix domain socket at this path.
+    /// Anyone who can connect can clear the canvas, so put it in a directory with restricted permissions.
+    #[cfg(unix)]
+    #[arg(long, value_name = "PATH")]
+    admin_socket: Option<PathBuf>,
+    /// Accept text commands like classic Pixelflut on this TCP address, for example `127.0.0.1:1337`, for testing clients without ICMP.
+    /// Commands are handled exactly like the equivalent packets; see the `debug_tcp` module for the commands.
+    #[arg(long, value_name = "ADDRESS")]
+    debug_tcp: Option<SocketAddr>,
+    /// Maximum number of frames per second sent to each viewer of `/stream.mjpeg` on the metrics address.
+    #[arg(long, value_name = "FPS", default_value_t = 5, value_parser = clap::value_parser!(u32).range(1..=60))]
+    stream_fps: u32,
+    /// Persist the canvas in this PNG file.
+    /// The canvas is loaded from the file at startup if it exists, and saved to it on exit.
+    #[arg(long, value_name = "PATH")]
+    state_file: Option<PathBuf>,
+    /// Additionally save the canvas to the state file periodically, with this interval in seconds.
+    #[arg(long, value_name = "SECONDS", requires = "state_file", value_parser = clap::value_parser!(u64).range(1..))]
+    state_save_interval: Option<u64>,
+    /// Source address allowed to clear the canvas; can be given multiple times.
+    /// Without any admin addresses, clear requests are always rejected.
+    #[arg(long, value_name = "ADDRESS")]
+    admin_ip: Vec<IpAddr>,
+    /// Only accept packets from this address range, for example `10.0.0.0/8` or `fd00::/64`; can be given multiple times.
+    /// By default, packets from all addresses are accepted.
+    #[arg(long, value_name = "CIDR")]
+    allow_cidr: Vec<IpNet>,
+    /// Only accept packets from this many distinct source addresses at a time.
+    /// Packets from further sources are dropped until an active source has been silent for `--client-window` seconds.
+    #[arg(long, value_name = "COUNT", value_parser = clap::value_parser!(u32).range(1..))]
+    max_clients: Option<u32>,
+    /// Seconds after its last packet that a source stops counting towards `--max-clients`.
+    #[arg(long, value_name = "SECONDS", default_value_t = 60, requires = "max_clients", value_parser = clap::value_parser!(u64).range(1..))]
+    client_window: u64,
+    /// Send the canvas size to every source address the first time it sends any packet.
+    /// This lets clients configure themselves without a size request.
+    #[arg(long)]
+    announce_size: bool,
+    /// Time to live of ICMP replies, or hop limit for IPv6, for example to keep replies from leaving the local network.
+    /// By default, the operating system default is used.
+    #[arg(long, value_name = "HOPS", value_parser = clap::value_parser!(u8).range(1..))]
+    reply_ttl: Option<u8>,
+    /// Send ICMP replies from this local address instead of the one picked by routing, for example on hosts with
+    /// several addresses. Can be given once each for IPv4, IPv6 and link-local IPv6 clients; other clients are
+    /// replied to as usual. A link-local address is used on the interface the request arrived on.
+    #[arg(long, value_name = "ADDRESS")]
+    reply_source: Vec<IpAddr>,
+    /// Accept set pixel packets with single byte coordinates, and advertise this in size responses.
+    /// Only possible for canvases of up to 256x256 pixels; clients sending full coordinates keep working.
+    #[arg(long)]
+    compact_coordinates: bool,
+    /// Forward all pixels drawn on this canvas to another server at this address.
+    /// Pixels received from that server are not sent back, so two servers can mirror each other.
+    #[arg(long, value_name = "ADDRESS")]
+    mirror: Option<IpAddr>,
+    /// Keep the window title static instead of showing the pixel rate and number of clients.
+    #[arg(long)]
+    plain_title: bool,
+    /// Rotate the window contents clockwise by this many degrees, for example for projectors mounted sideways.
+    /// Clients still see the unrotated canvas size.
+    #[arg(long, value_name = "DEGREES", default_value = "0")]
+    rotate: Rotation,
+    /// Mirror the window contents after rotating them, for example for rear projection.
+    #[arg(long, value_name = "AXIS", default_value = "none")]
+    flip: Flip,
+    /// Open the window this many times as large as the canvas, or with `auto`, as large as fits the screen.
+    /// The canvas is scaled up by whole pixels without blurring, also when the window is resized.
+    /// Clients still see the canvas size.
+    #[arg(long, value_name = "FACTOR", default_value = "1", value_parser = parse_window_scale)]
+    window_scale: WindowScale,
+    /// Start in borderless fullscreen mode. Fullscreen can also be toggled with F11.
+    #[arg(long)]
+    fullscreen: bool,
+    /// Image to draw onto the canvas at startup.
+    #[arg(long, value_name = "IMAGE")]
+    background: Option<PathBuf>,
+    /// How to fit the background image onto the canvas.
+    #[arg(long, value_name = "FIT", default_value = "center")]
+    background_fit: BackgroundFit,
+    /// Color of the background image that is treated as transparent and not drawn, as `#rrggbb`.
+    #[arg(long, value_name = "COLOR", value_parser = parse_hex_color)]
+    transparent_color: Option<RGBA8>,
+    /// Image of the canvas size whose opaque or bright pixels are the only ones clients can draw on, for canvases that are not rectangular.
+    /// The whole rectangle is still reported to clients as the canvas size.
+    #[arg(long, value_name = "IMAGE")]
+    mask: Option<PathBuf>,
+    /// Color space of the colors that clients send and read back.
+    /// With `linear`, colors are treated as linear light intensities and converted to sRGB for display.
+    #[arg(long, value_name = "SPACE", default_value = "srgb")]
+    color_space: ColorSpace,
+    /// Color of the empty canvas and of the window area around it, as `#rrggbb` or `#rrggbbaa`.
+    /// The canvas is reset to this color when it is cleared.
+    #[arg(long, value_name = "COLOR", default_value = "#000000", value_parser = parse_hex_color)]
+    clear_color: RGBA8,
+    /// Only render the region of the canvas starting at this X position, for splitting one canvas across several servers.
+    /// The canvas size given by `--width` and `--height` is still reported to clients.
+    #[arg(long, value_name = "X", default_value_t = 0)]
+    view_x: u16,
+    /// Only render the region of the canvas starting at this Y position.
+    #[arg(long, value_name = "Y", default_value_t = 0)]
+    view_y: u16,
+    /// Width of the rendered region. By default, it extends to the right edge of the canvas.
+    #[arg(long, value_name = "PIXELS", value_parser = clap::value_parser!(u16).range(1..))]
+    view_width: Option<u16>,
+    /// Height of the rendered region. By default, it extends to the bottom edge of the canvas.
+    #[arg(long, value_name = "PIXELS", value_parser = clap::value_parser!(u16).range(1..))]
+    view_height: Option<u16>,
+}
+
+impl Arguments {
+    /// The region of the canvas rendered by this server.
+    fn viewport(&self) -> Result<Viewport> {
+        Viewport::new(
+            self.view_x,
+            self.view_y,
+            self.view_width
+                .unwrap_or(self.width.saturating_sub(self.view_x)),
+            self.view_height
+                .unwrap_or(self.height.saturating_sub(self.view_y)),
+            self.width,
+            self.height,
+        )
+    }
+}
 
-#[derive(Default)]
 struct App {
+    arguments: Arguments,
+    devices: Vec<Device>,
+    viewport: Viewport,
+    /// Mask of the main canvas, if any.
+    mask: Option<Mask>,
     window_id: Option<WindowId>,
     window: Option<Arc<Window>>,
-    pixels: Option<Arc<RwLock<Pixels>>>,
     canvas: Option<Canvas>,
+    /// Canvases with IDs from 1 on, which are shown in place of the main canvas on request.
+    extra_canvases: Vec<Canvas>,
+    /// ID of the canvas shown in the window.
+    shown_canvas_id: usize,
+    /// Error that prevented the canvas tasks from starting, reported once the event loop exits.
+    startup_error: Option<anyhow::Error>,
+    stats: Arc<Stats>,
+    shutdown: Shutdown,
+    /// Task running the packet handlers, which is waited for after a shutdown.
+    packet_handler: Option<JoinHandle<()>>,
+    /// Earliest time the next frame may be rendered.
+    next_frame: Instant,
+    /// Next time the statistics in the window title are updated.
+    next_title_update: Instant,
+}
+
+impl App {
+    fn new(
+        arguments: Arguments,
+        devices: Vec<Device>,
+        viewport: Viewport,
+        mask: Option<Mask>,
+        extra_canvases: Vec<Canvas>,
+    ) -> Self {
+        Self {
+            arguments,
+            devices,
+            viewport,
+            mask,
+            window_id: None,
+            window: None,
+            canvas: None,
+            extra_canvases,
+            shown_canvas_id: 0,
+            startup_error: None,
+            stats: Arc::default(),
+            shutdown: Shutdown::default(),
+            packet_handler: None,
+            next_frame: Instant::now(),
+            next_title_update: Instant::now(),
+        }
+    }
+
+    /// Render the shown canvas into the window.
+    fn render(&self) -> Result<()> {
+        let canvas = self.canvas.as_ref().unwrap();
+        match self.shown_canvas_id {
+            0 => canvas.render(),
+            _ => canvas.present(self.shown_canvas().unwrap()),
+        }
+    }
+
+    /// The canvas shown in the window, once the window exists.
+    fn shown_canvas(&self) -> Option<&Canvas> {
+        match self.shown_canvas_id.checked_sub(1) {
+            Some(index) => self.extra_canvases.get(index),
+            None => self.canvas.as_ref(),
+        }
+    }
 }
 
 impl ApplicationHandler for App {
-    fn about_to_wait(&mut self, _event_loop: &ActiveEventLoop) {
-        if let Some(window) = self.window.as_ref() {
-            window.request_redraw();
+    fn about_to_wait(&mut self, event_loop: &ActiveEventLoop) {
+        if self.shutdown.is_triggered() {
+            event_loop.exit();
+            return;
+        }
+        let now = Instant::now();
+        if now >= self.next_title_update && !self.arguments.plain_title {
+            self.next_title_update = now + TITLE_UPDATE_INTERVAL;
+            if let Some(window) = &self.window {
+                let mut title = format!(
+                    "{} — {} px/s — {} clients",
+                    WINDOW_TITLE,
+                    format_count(self.stats.pixel_rate()),
+                    self.stats.unique_sources()
+                );
+                if let Some(canvas) = &self.canvas {
+                    if let Some(filled_pixels) = canvas.filled_pixels() {
+                        let total = u64::from(canvas.width) * u64::from(canvas.height);
+                        title.push_str(&format!(" — {}% full", filled_pixels * 100 / total));
+                    }
+                }
+                window.set_title(&title);
+            }
+        }
+        if now < self.next_frame {
+            // Throttled by the frame rate limit.
+            event_loop.set_control_flow(ControlFlow::WaitUntil(self.next_frame));
+            return;
+        }
+        if let (Some(window), Some(canvas)) = (&self.window, &self.canvas) {
+            if canvas.needs_redraw() || self.extra_canvases.iter().any(Canvas::needs_redraw) {
+                window.request_redraw();
+                if let Some(max_fps) = self.arguments.max_fps {
+                    self.next_frame = now + Duration::from_secs(1) / max_fps;
+                }
+            }
         }
+        // Pixels arrive from other threads without waking up the event loop, so check again soon.
+        event_loop.set_control_flow(ControlFlow::WaitUntil(
+            self.next_frame.max(now + IDLE_POLL_INTERVAL),
+        ));
     }
 
     fn resumed(&mut self, event_loop: &ActiveEventLoop) {
+        let width = self.viewport.width;
+        let height = self.viewport.height;
+        let transform = Transform {
+            rotation: self.arguments.rotate,
+            flip: self.arguments.flip,
+        };
+        let (output_width, output_height) = transform.output_size(width, height);
+        let monitor = event_loop
+            .primary_monitor()
+            .or_else(|| event_loop.available_monitors().next())
+            .map(|monitor| (monitor.size().width, monitor.size().height));
+        let (window_width, window_height) =
+            self.arguments
+                .window_scale
+                .window_size(output_width, output_height, monitor);
         let window_attributes = Window::default_attributes()
-            .with_title("Pingxelflut")
-            .with_inner_size(winit::dpi::PhysicalSize::new(WIDTH, HEIGHT));
+            .with_title(WINDOW_TITLE)
+            .with_inner_size(winit::dpi::PhysicalSize::new(window_width, window_height))
+            .with_fullscreen(
+                self.arguments
+                    .fullscreen
+                    .then_some(Fullscreen::Borderless(None)),
+            );
 
         let window = Arc::new(event_loop.create_window(window_attributes).unwrap());
         self.window_id = Some(window.id());
@@ -53,22 +512,47 @@ impl ApplicationHandler for App {
 
         let window = self.window.as_ref().unwrap().clone();
         let mut pixels = {
-            let surface_texture = SurfaceTexture::new(WIDTH, HEIGHT, &window);
-            Pixels::new(WIDTH, HEIGHT, surface_texture).unwrap()
+            let surface_texture = SurfaceTexture::new(window_width, window_height, &window);
+            Pixels::new(output_width.into(), output_height.into(), surface_texture).unwrap()
         };
-        pixels.clear_color(Color::BLACK);
-        self.pixels = Some(Arc::new(RwLock::new(pixels)));
-
-        let canvas = Canvas {
-            width: WIDTH as u16,
-            height: HEIGHT as u16,
-            pixels: self.pixels.as_ref().unwrap().clone(),
-            pixel_queue: Arc::new(ConcurrentQueue::unbounded()),
-        };
-        self.canvas = Some(canvas.clone());
-        tokio::spawn(async move {
-            ping_handler(canvas).await;
+        let clear_color = self.arguments.clear_color;
+        pixels.clear_color(Color {
+            r: clear_color.r as f64 / 255.0,
+            g: clear_color.g as f64 / 255.0,
+            b: clear_color.b as f64 / 255.0,
+            a: clear_color.a as f64 / 255.0,
         });
+
+        let canvas = Canvas::new(Box::new(WindowSink::new(pixels, width, height, transform)))
+            .with_max_pixels_per_frame(max_pixels_per_frame(&self.arguments))
+            .with_queue_capacity(
+                self.arguments
+                    .queue_capacity
+                    .map(|capacity| capacity as usize),
+            )
+            .with_coalescing(self.arguments.coalesce)
+            .with_fade(self.arguments.fade)
+            .with_fill_tracking(self.arguments.track_fill)
+            .with_write_protection(self.arguments.protect_written)
+            .with_dirty_tracking(self.arguments.dirty_tracking)
+            .with_clear_color(self.arguments.clear_color)
+            .with_viewport(self.viewport)
+            .with_mask(self.mask.clone());
+        self.canvas = Some(canvas.clone());
+        match start_canvas_tasks(
+            canvas,
+            &self.extra_canvases,
+            &self.arguments,
+            self.devices.clone(),
+            self.stats.clone(),
+            self.shutdown.clone(),
+        ) {
+            Ok(packet_handler) => self.packet_handler = Some(packet_handler),
+            Err(why) => {
+                self.startup_error = Some(why);
+                event_loop.exit();
+            }
+        }
     }
 
     fn window_event(
@@ -78,27 +562,114 @@ impl ApplicationHandler for App {
         event: WindowEvent,
     ) {
         if event == WindowEvent::Destroyed && self.window_id == Some(window_id) {
-            log::info!("window {:?} destroyed", window_id);
+            info!("window {:?} destroyed", window_id);
             self.window_id = None;
             event_loop.exit();
             return;
         }
 
-        let window = match self.window.as_mut() {
-            Some(window) => window,
+        let window = match &self.window {
+            Some(window) => window.clone(),
             None => return,
         };
 
         match event {
             WindowEvent::CloseRequested => {
-                log::debug!("window {:?} closed", window.id());
+                debug!("window {:?} closed", window.id());
                 self.window = None;
             }
+            WindowEvent::KeyboardInput {
+                event:
+                    KeyEvent {
+                        physical_key: PhysicalKey::Code(KeyCode::KeyS),
+                        state: ElementState::Pressed,
+                        repeat: false,
+                        ..
+                    },
+                ..
+            } => {
+                take_screenshot(self.shown_canvas().unwrap(), &self.arguments.screenshot_dir);
+            }
+            WindowEvent::KeyboardInput {
+                event:
+                    KeyEvent {
+                        physical_key: PhysicalKey::Code(KeyCode::KeyL),
+                        state: ElementState::Pressed,
+                        repeat: false,
+                        ..
+                    },
+                ..
+            } => self.stats.print_leaderboard(),
+            WindowEvent::KeyboardInput {
+                event:
+                    KeyEvent {
+                        physical_key: PhysicalKey::Code(KeyCode::F11),
+                        state: ElementState::Pressed,
+                        repeat: false,
+                        ..
+                    },
+                ..
+            } => {
+                // The canvas size stays the same, only the surface is resized once the window reports its new size.
+                if window.fullscreen().is_some() {
+                    window.set_fullscreen(None);
+                } else {
+                    window.set_fullscreen(Some(Fullscreen::Borderless(None)));
+                }
+            }
+            WindowEvent::KeyboardInput {
+                event:
+                    KeyEvent {
+                        physical_key: PhysicalKey::Code(code),
+                        state: ElementState::Pressed,
+                        repeat: false,
+                        ..
+                    },
+                ..
+            } => {
+                if let Some(canvas_id) =
+                    canvas_id_for_key(code).filter(|&id| id <= self.extra_canvases.len())
+                {
+                    info!("showing canvas {}", canvas_id);
+                    self.shown_canvas_id = canvas_id;
+                    // The main canvas may not have changed since it was last rendered, but was covered since.
+                    self.canvas.as_ref().unwrap().invalidate();
+                    window.request_redraw();
+                }
+            }
+            WindowEvent::Resized(size) => {
+                // Minimized windows report a size of zero, which is not a valid surface size.
+                if size.width == 0 || size.height == 0 {
+                    return;
+                }
+                // Only the surface follows the window size; the frame buffer keeps the canvas size and is scaled to fit.
+                let canvas = self.canvas.as_ref().unwrap();
+                if let Err(err) = canvas.resize_surface(size.width, size.height) {
+                    error!("pixels.resize_surface: {}", err);
+                }
+                // Draw the new surface right away instead of waiting for the canvas to change.
+                window.request_redraw();
+            }
             WindowEvent::RedrawRequested => {
-                self.canvas.as_mut().unwrap().set_queue_pixels();
-                if let Err(err) = self.pixels.as_ref().unwrap().read().render() {
-                    error!("pixels.render: {}", err);
-                    event_loop.exit();
+                let canvas = self.canvas.as_ref().unwrap();
+                canvas.set_queue_pixels();
+                for extra_canvas in &self.extra_canvases {
+                    extra_canvas.set_queue_pixels();
+                }
+                if let Err(err) = self.render() {
+                    // Surfaces can get lost after the display sleeps or changes resolution, so reconfigure it and retry once.
+                    warn!("pixels.render: {}, recreating the surface", err);
+                    let size = window.inner_size();
+                    let result = canvas
+                        .resize_surface(size.width, size.height)
+                        .and_then(|()| self.render());
+                    match result {
+                        Ok(()) => info!("recovered from render error"),
+                        Err(err) => {
+                            error!("pixels.render after recreating the surface: {}", err);
+                            event_loop.exit();
+                        }
+                    }
                 }
             }
             _ => (),
@@ -106,42 +677,529 @@ impl ApplicationHandler for App {
     }
 }
 
+/// Commands run instead of the server.
+#[derive(Clone, Subcommand, Debug)]
+enum Command {
+    /// Check capture devices, privileges and packet decoding, the usual reasons why nothing is drawn, and exit.
+    ///
+    /// The capture options, such as `--interface` and `--bpf-filter`, are checked as given.
+    Doctor,
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    env_logger::init();
+    let arguments: Arguments = Parser::parse();
+    init_logging(
+        arguments.log_format,
+        log_level(arguments.quiet, arguments.verbose),
+    );
+    if let Some(Command::Doctor) = arguments.command {
+        return doctor(&arguments).await;
+    }
+    if let Some(path) = &arguments.validate {
+        return validate_recording(path, arguments.width, arguments.height).await;
+    }
+    if arguments.compact_coordinates
+        && !CoordinateMode::fits_compact(arguments.width, arguments.height)
+    {
+        return Err(anyhow::anyhow!(
+            "compact coordinates need a canvas of at most 256x256 pixels"
+        ));
+    }
+    check_reply_sources(&arguments.reply_source)?;
+    let viewport = arguments.viewport()?;
+    check_frame_size(viewport.width, viewport.height, arguments.max_canvas_bytes)?;
+    if arguments.extra_canvas.len() > u8::MAX.into() {
+        return Err(anyhow::anyhow!(
+            "at most {} additional canvases are supported",
+            u8::MAX
+        ));
+    }
+    for &(width, height) in &arguments.extra_canvas {
+        check_frame_size(width, height, arguments.max_canvas_bytes)?;
+    }
+    let mask = match &arguments.mask {
+        Some(path) => Some(Mask::load(path, arguments.width, arguments.height)?),
+        None => None,
+    };
+    let extra_canvases = extra_canvases(&arguments);
+
+    let devices = if let Some(path) = &arguments.replay {
+        info!("replaying {}", path.display());
+        Vec::new()
+    } else {
+        check_capture_filter(&capture_filter(&arguments))?;
+        let devices = capture_devices(arguments.interface.as_deref())?;
+        let device_names: Vec<_> = devices.iter().map(|device| device.name.as_str()).collect();
+        info!("capturing on {}", device_names.join(", "));
+        devices
+    };
+
+    if arguments.headless || arguments.once || arguments.framebuffer.is_some() {
+        return run_headless(arguments, devices, viewport, mask, extra_canvases).await;
+    }
 
     let event_loop = EventLoop::new().unwrap();
-    let mut app = App::default();
+    let mut app = App::new(arguments, devices, viewport, mask, extra_canvases);
+    // The event loop checks for a shutdown whenever it wakes up, so an interrupt closes the window like the user would.
+    app.shutdown.trigger_on_ctrl_c();
     event_loop.run_app(&mut app)?;
+    if let Some(why) = app.startup_error {
+        return Err(why);
+    }
+
+    app.shutdown.trigger();
+    if let Some(packet_handler) = app.packet_handler.take() {
+        let _ = packet_handler.await;
+    }
+    match &app.canvas {
+        Some(canvas) => finish(canvas, &app.extra_canvases, &app.arguments, &app.stats),
+        None => Ok(()),
+    }
+}
+
+/// Apply the remaining queued pixels, save the canvas state if configured, and log the final statistics.
+/// Only the main canvas is saved.
+fn finish(
+    canvas: &Canvas,
+    extra_canvases: &[Canvas],
+    arguments: &Arguments,
+    stats: &Stats,
+) -> Result<()> {
+    canvas.flush_queue();
+    for extra_canvas in extra_canvases {
+        extra_canvas.flush_queue();
+    }
+    if let Some(path) = &arguments.state_file {
+        canvas.save(path)?;
+        info!("saved canvas to {}", path.display());
+    }
+    stats.log_summary();
+    Ok(())
+}
+
+/// Start the background tasks operating on the canvas, independent of how it is presented.
+/// The background and saved canvas state are drawn first, if there are any.
+/// The packet handlers stop once a shutdown is requested; their task is returned so that it can be waited for.
+fn start_canvas_tasks(
+    canvas: Canvas,
+    extra_canvases: &[Canvas],
+    arguments: &Arguments,
+    devices: Vec<Device>,
+    stats: Arc<Stats>,
+    shutdown: Shutdown,
+) -> Result<JoinHandle<()>> {
+    if let Some(path) = &arguments.background {
+        let (image, x, y) = load_background(
+            path,
+            arguments.background_fit,
+            canvas.width,
+            canvas.height,
+            arguments.transparent_color,
+        )?;
+        canvas.draw_image(&image, x, y);
+    }
+    if let Some(path) = &arguments.state_file {
+        if path.exists() {
+            canvas.load(path)?;
+            info!("loaded canvas from {}", path.display());
+        }
+        if let Some(interval) = arguments.state_save_interval {
+            let interval = Duration::from_secs(interval);
+            tokio::spawn(snapshot_handler(canvas.clone(), path.clone(), interval));
+        }
+    }
+    // In single frame mode, the snapshot is only written once everything was replayed.
+    if let Some(path) = arguments.snapshot.clone().filter(|_| !arguments.once) {
+        let interval = Duration::from_secs(arguments.snapshot_interval);
+        tokio::spawn(snapshot_handler(canvas.clone(), path, interval));
+    }
+    let canvases: Arc<[Canvas]> = std::iter::once(canvas)
+        .chain(extra_canvases.iter().cloned())
+        .collect();
+    tokio::spawn(stats_handler(stats.clone()));
+    if let Some(path) = arguments.stats_file.clone() {
+        let interval = Duration::from_secs(arguments.stats_interval);
+        tokio::spawn(stats_file_handler(stats.clone(), path, interval));
+    }
+    if arguments.attract {
+        let idle = Duration::from_secs(arguments.attract_idle);
+        tokio::spawn(attract_handler(canvases[0].clone(), stats.clone(), idle));
+    }
+    if let Some(address) = arguments.metrics_addr {
+        tokio::spawn(handle_error(metrics_server(
+            address,
+            stats.clone(),
+            canvases.clone(),
+            arguments.stream_fps,
+        )));
+    }
+    if let Some(address) = arguments.ws_addr {
+        tokio::spawn(handle_error(websocket_server(address, canvases[0].clone())));
+    }
+    #[cfg(unix)]
+    if let Some(path) = arguments.admin_socket.clone() {
+        tokio::spawn(handle_error(admin_socket_server(
+            path,
+            canvases.clone(),
+            stats.clone(),
+        )));
+    }
+    let sources = match &arguments.replay {
+        Some(path) => vec![Box::new(FilePacketSource::open(path)?) as Box<dyn PacketSource>],
+        None => open_captures(devices, arguments, &stats)?,
+    };
+    let policy = RequestPolicy {
+        rate_limiter: arguments.rate_limit.map(RateLimiter::new),
+        admin_ips: arguments.admin_ip.clone().into(),
+        allowed_ranges: arguments.allow_cidr.clone().into(),
+        client_limit: arguments.max_clients.map(|max_clients| {
+            ClientLimit::new(
+                max_clients as usize,
+                Duration::from_secs(arguments.client_window),
+            )
+        }),
+        size_announcer: arguments.announce_size.then(SizeAnnouncer::default),
+        size_reply_cooldown: SizeReplyCooldown::default(),
+        color_space: arguments.color_space,
+        mirror: arguments.mirror.map(Mirror::start),
+        recorder: arguments
+            .record
+            .as_deref()
+            .map(Recorder::start)
+            .transpose()?,
+        reply_ttl: arguments.reply_ttl,
+        reply_sources: arguments.reply_source.clone().into(),
+        coordinate_mode: coordinate_mode(arguments),
+        thumbnail_jobs: ThumbnailJobs::default(),
+    };
+    if let Some(address) = arguments.debug_tcp {
+        tokio::spawn(handle_error(debug_tcp_server(
+            address,
+            canvases.clone(),
+            stats.clone(),
+            policy.clone(),
+        )));
+    }
+    let once = arguments.once;
+    let workers = usize::from(arguments.device_workers);
+    Ok(tokio::spawn(async move {
+        ping_handler(canvases, stats, policy, shutdown.clone(), sources, workers).await;
+        if once {
+            shutdown.trigger();
+        }
+    }))
+}
+
+/// Format a count compactly with a metric suffix, such as `1.2M`.
+fn format_count(count: u64) -> String {
+    match count {
+        0..=999 => count.to_string(),
+        // Upper bounds leave room for rounding up, which would otherwise show 1000.0k.
+        1_000..=999_949 => format!("{:.1}k", count as f64 / 1e3),
+        999_950..=999_949_999 => format!("{:.1}M", count as f64 / 1e6),
+        _ => format!("{:.1}G", count as f64 / 1e9),
+    }
+}
+
+fn max_pixels_per_frame(arguments: &Arguments) -> Option<usize> {
+    arguments
+        .max_pixels_per_frame
+        .map(|max_pixels_per_frame| max_pixels_per_frame as usize)
+}
+
+fn queue_capacity(arguments: &Arguments) -> Option<usize> {
+    arguments
+        .queue_capacity
+        .map(|queue_capacity| queue_capacity as usize)
+}
+
+/// Create the additional canvases, which only live in memory regardless of how the main canvas is presented.
+fn extra_canvases(arguments: &Arguments) -> Vec<Canvas> {
+    arguments
+        .extra_canvas
+        .iter()
+        .map(|&(width, height)| {
+            Canvas::new(Box::new(VecSink::new(width, height)))
+                .with_max_pixels_per_frame(max_pixels_per_frame(arguments))
+                .with_queue_capacity(queue_capacity(arguments))
+                .with_coalescing(arguments.coalesce)
+                .with_fade(arguments.fade)
+                .with_fill_tracking(arguments.track_fill)
+                .with_write_protection(arguments.protect_written)
+                .with_clear_color(arguments.clear_color)
+        })
+        .collect()
+}
+
+/// The canvas ID shown with a number key.
+fn canvas_id_for_key(code: KeyCode) -> Option<usize> {
+    let digits = [
+        KeyCode::Digit0,
+        KeyCode::Digit1,
+        KeyCode::Digit2,
+        KeyCode::Digit3,
+        KeyCode::Digit4,
+        KeyCode::Digit5,
+        KeyCode::Digit6,
+        KeyCode::Digit7,
+        KeyCode::Digit8,
+        KeyCode::Digit9,
+    ];
+    digits.iter().position(|&digit| digit == code)
+}
+
+/// Run the server without a window; the canvas only lives in memory.
+async fn run_headless(
+    arguments: Arguments,
+    devices: Vec<Device>,
+    viewport: Viewport,
+    mask: Option<Mask>,
+    extra_canvases: Vec<Canvas>,
+) -> Result<()> {
+    let sink: Box<dyn PixelSink> = match &arguments.framebuffer {
+        Some(path) => Box::new(FramebufferSink::open(
+            path,
+            viewport.width,
+            viewport.height,
+        )?),
+        None => Box::new(VecSink::new(viewport.width, viewport.height)),
+    };
+    let canvas = Canvas::new(sink)
+        .with_max_pixels_per_frame(max_pixels_per_frame(&arguments))
+        .with_queue_capacity(queue_capacity(&arguments))
+        .with_coalescing(arguments.coalesce)
+        .with_fade(arguments.fade)
+        .with_fill_tracking(arguments.track_fill)
+        .with_write_protection(arguments.protect_written)
+        .with_dirty_tracking(arguments.dirty_tracking)
+        .with_clear_color(arguments.clear_color)
+        .with_viewport(viewport)
+        .with_mask(mask);
+    let stats = Arc::new(Stats::default());
+    let shutdown = Shutdown::default();
+    shutdown.trigger_on_ctrl_c();
+    let packet_handler = start_canvas_tasks(
+        canvas.clone(),
+        &extra_canvases,
+        &arguments,
+        devices,
+        stats.clone(),
+        shutdown.clone(),
+    )?;
+    match &arguments.framebuffer {
+        Some(path) => info!("drawing to framebuffer {}", path.display()),
+        None => info!("running headless"),
+    }
+
+    let mut interval = tokio::time::interval(HEADLESS_TICK);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                let redraw = canvas.needs_redraw();
+                canvas.set_queue_pixels();
+                // Only the framebuffer sink presents anything.
+                if redraw {
+                    if let Err(why) = canvas.render() {
+                        error!("cannot render: {}", why);
+                    }
+                }
+                for extra_canvas in &extra_canvases {
+                    extra_canvas.set_queue_pixels();
+                }
+            }
+            _ = shutdown.wait() => break,
+        }
+    }
+    let _ = packet_handler.await;
+    finish(&canvas, &extra_canvases, &arguments, &stats)?;
+    if let Some(path) = arguments.snapshot.as_ref().filter(|_| arguments.once) {
+        canvas.save(path)?;
+        info!("wrote canvas to {}", path.display());
+    }
     Ok(())
 }
 
-struct PingxelflutPacketStream;
+/// Write the canvas to a timestamped PNG file in the given directory.
+/// The image is encoded on a blocking task, so that rendering is not stalled.
+fn take_screenshot(canvas: &Canvas, directory: &Path) {
+    let frame = canvas.frame_copy();
+    let (width, height) = (canvas.width, canvas.height);
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let path = directory.join(format!("pingxelflut-{}.png", timestamp));
+    tokio::task::spawn_blocking(move || match write_png(&path, width, height, frame) {
+        Ok(()) => info!("wrote screenshot to {}", path.display()),
+        Err(why) => error!("error while writing screenshot: {}", why),
+    });
+}
+
+/// Periodically write the canvas to a PNG file.
+async fn snapshot_handler(canvas: Canvas, path: PathBuf, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        let frame = canvas.frame_copy();
+        let (width, height) = (canvas.width, canvas.height);
+        let path = path.clone();
+        let result =
+            tokio::task::spawn_blocking(move || write_png(&path, width, height, frame)).await;
+        match result {
+            Ok(Err(why)) => warn!("error while writing snapshot: {}", why),
+            Err(why) => error!("snapshot task failed: {}", why),
+            Ok(Ok(())) => {}
+        }
+    }
+}
+
+struct PingxelflutPacketStream {
+    stats: Arc<Stats>,
+    /// Oldest protocol version that is accepted.
+    min_version: u8,
+    /// Index of the captured interface, see [`ReceivedPacket::interface_index`].
+    interface_index: Option<u32>,
+    /// Coordinate mode of set pixel packets; with compact coordinates, full ones are still accepted.
+    coordinate_mode: CoordinateMode,
+    /// Logical size of the main canvas; packets setting pixels outside of it are dropped while decoding.
+    canvas_size: (u16, u16),
+    /// Whether echo replies are decoded like echo requests, rather than only counted.
+    accept_replies: bool,
+}
 
-/// Extract the IP source address from a parsed network layer packet.
+type CaptureStream = PacketStream<Active, PingxelflutPacketStream>;
+
+/// Live capture on a network device.
+struct CaptureSource {
+    name: String,
+    stream: CaptureStream,
+}
+
+impl PacketSource for CaptureSource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn into_stream(self: Box<Self>) -> BoxStream<'static, ReceivedPacket> {
+        // Capture errors and frames that are not Pingxelflut packets are skipped alike.
+        self.stream
+            .filter_map(|maybe_packet| futures::future::ready(maybe_packet.ok().flatten()))
+            .boxed()
+    }
+}
+
+/// Extract the IP source and destination addresses from a parsed network layer packet.
 /// Works for both IP versions.
-fn ip_addr_from_net_packet(packet: &NetSlice) -> IpAddr {
+fn ip_addrs_from_net_packet(packet: &NetSlice) -> (IpAddr, IpAddr) {
     match packet {
-        NetSlice::Ipv4(ip_packet) => ip_packet.header().source_addr().into(),
-        NetSlice::Ipv6(ip_packet) => ip_packet.header().source_addr().into(),
+        NetSlice::Ipv4(ip_packet) => (
+            ip_packet.header().source_addr().into(),
+            ip_packet.header().destination_addr().into(),
+        ),
+        NetSlice::Ipv6(ip_packet) => (
+            ip_packet.header().source_addr().into(),
+            ip_packet.header().destination_addr().into(),
+        ),
     }
 }
 
-impl PacketCodec for PingxelflutPacketStream {
-    type Item = Option<(Packet, IpAddr)>;
+impl PingxelflutPacketStream {
+    /// Parse an ICMP echo payload into a Pingxelflut packet.
+    /// Malformed payloads are logged, counted and discarded, as required by the protocol.
+    fn parse_payload(
+        &self,
+        payload: &[u8],
+        source: IpAddr,
+        destination: IpAddr,
+        echo: IcmpEchoHeader,
+    ) -> Option<ReceivedPacket> {
+        // Reject oversized payloads before parsing, so that their size cannot drive any allocation.
+        if payload.len() > Packet::MAX_SIZE {
+            debug!(
+                "discarding oversized packet of {} bytes from {}",
+                payload.len(),
+                source
+            );
+            Stats::count(&self.stats.oversized_packets);
+            return None;
+        }
+        let received = |packet| ReceivedPacket {
+            packet,
+            source,
+            destination,
+            identifier: echo.id,
+            sequence_number: echo.seq,
+            interface_index: self.interface_index,
+            reply_channel: None,
+        };
+        if payload
+            .first()
+            .is_some_and(|&version| version < self.min_version)
+        {
+            // Outdated clients still learn the server's version from the size response, so they know to upgrade.
+            if payload.get(1) == Some(&Packet::SIZE_REQUEST_ID) {
+                return Some(received(Packet::SizeRequest { canvas_id: 0 }));
+            }
+            debug!(
+                "discarding outdated packet of version {} from {}",
+                payload[0], source
+            );
+            Stats::count(&self.stats.outdated_packets);
+            return None;
+        }
+        let (width, height) = self.canvas_size;
+        let parse = |mode| Packet::from_bytes_bounded_with_mode(payload, mode, width, height);
+        let packet = match parse(self.coordinate_mode) {
+            // Clients unaware of compact coordinates keep working.
+            Err(ParseError::CoordinateModeMismatch { found, .. }) => parse(found),
+            result => result,
+        };
+        match packet {
+            Ok(packet) => Some(received(packet)),
+            Err(ParseError::OutOfBounds { x, y }) => {
+                trace!(
+                    "discarding pixel outside the canvas at {},{} from {}",
+                    x,
+                    y,
+                    source
+                );
+                Stats::count(&self.stats.pixels_dropped);
+                None
+            }
+            Err(why) => {
+                debug!("discarding malformed packet from {}: {}", source, why);
+                Stats::count(&self.stats.malformed_packets);
+                None
+            }
+        }
+    }
 
-    fn decode(&mut self, packet: pcap::Packet<'_>) -> Self::Item {
-        let parsed_packet = SlicedPacket::from_ethernet(&packet).ok()?;
+    /// Extract a Pingxelflut packet from a raw ethernet frame.
+    /// This is independent of pcap, so that it also works on frames that were not captured live.
+    ///
+    /// Returns [`None`] for anything but valid Pingxelflut packets in ICMP or ICMPv6 echo requests,
+    /// or echo replies if they are accepted.
+    /// Echo requests and replies are counted either way.
+    fn decode_frame(&self, frame: &[u8]) -> Option<ReceivedPacket> {
+        let parsed_packet = SlicedPacket::from_ethernet(frame).ok()?;
         let transport_packet = parsed_packet.transport?;
-        let destination_address = ip_addr_from_net_packet(&parsed_packet.net?);
+        let (source, destination) = ip_addrs_from_net_packet(&parsed_packet.net?);
 
         match transport_packet {
             TransportSlice::Icmpv4(data) => {
                 let payload = data.payload();
                 let packet_type = data.icmp_type();
                 match packet_type {
-                    Icmpv4Type::EchoRequest(_) => {
-                        Packet::from_bytes(payload).map(|p| (p, destination_address))
+                    Icmpv4Type::EchoRequest(echo) => {
+                        Stats::count(&self.stats.echo_requests);
+                        self.parse_payload(payload, source, destination, echo)
+                    }
+                    Icmpv4Type::EchoReply(echo) => {
+                        Stats::count(&self.stats.echo_replies);
+                        self.accept_replies
+                            .then(|| self.parse_payload(payload, source, destination, echo))
+                            .flatten()
                     }
                     _ => None,
                 }
@@ -150,8 +1208,15 @@ impl PacketCodec for PingxelflutPacketStream {
                 let payload = data.payload();
                 let packet_type = data.icmp_type();
                 match packet_type {
-                    Icmpv6Type::EchoRequest(_) => {
-                        Packet::from_bytes(payload).map(|p| (p, destination_address))
+                    Icmpv6Type::EchoRequest(echo) => {
+                        Stats::count(&self.stats.echo_requests);
+                        self.parse_payload(payload, source, destination, echo)
+                    }
+                    Icmpv6Type::EchoReply(echo) => {
+                        Stats::count(&self.stats.echo_replies);
+                        self.accept_replies
+                            .then(|| self.parse_payload(payload, source, destination, echo))
+                            .flatten()
                     }
                     _ => None,
                 }
@@ -161,55 +1226,189 @@ impl PacketCodec for PingxelflutPacketStream {
     }
 }
 
-async fn device_ping_handler(canvas: Canvas, device: Device) -> Result<()> {
+impl PacketCodec for PingxelflutPacketStream {
+    type Item = Option<ReceivedPacket>;
+
+    fn decode(&mut self, packet: pcap::Packet<'_>) -> Self::Item {
+        self.decode_frame(packet.data)
+    }
+}
+
+/// Open a non-blocking ICMP capture on a device.
+fn open_capture(device: Device, arguments: &Arguments, stats: Arc<Stats>) -> Result<CaptureStream> {
+    let interface_index = interface_index(&device.name);
+    if interface_index.is_none() {
+        debug!(
+            "index of {} is unknown, replies to link-local addresses may fail",
+            device.name
+        );
+    }
     let mut capture = Capture::from_device(device)?
-        .snaplen(128)
-        .buffer_size(1 << 31)
+        .snaplen(arguments.snaplen)
+        .buffer_size(arguments.capture_buffer_size)
+        .promisc(arguments.promiscuous)
         .open()?
         .setnonblock()?;
 
-    capture.filter("icmp or icmp6", true)?;
-    let stream = capture.stream(PingxelflutPacketStream)?;
-
-    stream
-        .for_each(move |maybe_packet| {
-            let mut canvas = canvas.clone();
-            tokio::spawn(async move {
-                if let Ok(Some((packet, target_addr))) = maybe_packet {
-                    match packet {
-                        Packet::SizeRequest => {
-                            // TODO: Figure out if the identifier is important for getting the packet delivered.
-                            let mut response =
-                                Icmp::new(SocketAddr::new(target_addr, 0), 0, EchoDirection::Reply);
-                            response.set_payload(
-                                Packet::SizeResponse {
-                                    width: WIDTH as u16,
-                                    height: HEIGHT as u16,
-                                }
-                                .to_bytes(),
-                            );
-                            let result = response.send();
-                            match result {
-                                Ok(_) => {}
-                                Err(why) => {
-                                    warn!("size response error: {}", why)
-                                }
-                            }
-                        }
-                        // ignore
-                        Packet::SizeResponse { .. } => {}
-                        Packet::SetPixel { x, y, color } => {
-                            canvas.set_pixel(x, y, to_internal_color(color));
-                        }
-                    }
-                }
-            });
-            futures::future::ready(())
-        })
-        .await;
+    capture.filter(&capture_filter(arguments), true)?;
+    Ok(capture.stream(PingxelflutPacketStream {
+        stats,
+        min_version: arguments.min_protocol_version,
+        interface_index,
+        coordinate_mode: coordinate_mode(arguments),
+        canvas_size: (arguments.width, arguments.height),
+        accept_replies: arguments.accept_replies,
+    })?)
+}
+
+/// The coordinate mode of set pixel packets given by the arguments.
+fn coordinate_mode(arguments: &Arguments) -> CoordinateMode {
+    if arguments.compact_coordinates {
+        CoordinateMode::Compact
+    } else {
+        CoordinateMode::Full
+    }
+}
+
+/// The capture filter given by the arguments, restricted to the destination IP address if one is given.
+fn capture_filter(arguments: &Arguments) -> String {
+    let filter = arguments
+        .bpf_filter
+        .as_deref()
+        .unwrap_or(DEFAULT_CAPTURE_FILTER);
+    match arguments.dest_ip {
+        Some(address) => format!("({}) and dst host {}", filter, address),
+        None => filter.to_owned(),
+    }
+}
+
+/// Compile a capture filter without a device, so that invalid filters are reported before any capture is started.
+fn check_capture_filter(filter: &str) -> Result<()> {
+    Capture::dead(Linktype::ETHERNET)?
+        .compile(filter, true)
+        .map_err(|why| anyhow::anyhow!("invalid capture filter {:?}: {}", filter, why))?;
     Ok(())
 }
 
+/// Check that the reply source addresses belong to this host, and that each kind of client has at most one.
+fn check_reply_sources(sources: &[IpAddr]) -> Result<()> {
+    let kind = |source: &IpAddr| match source {
+        IpAddr::V4(_) => "IPv4",
+        &IpAddr::V6(source) if is_unicast_link_local(source) => "link-local IPv6",
+        IpAddr::V6(_) => "IPv6",
+    };
+    for (index, source) in sources.iter().enumerate() {
+        if let Some(other) = sources[..index]
+            .iter()
+            .find(|other| kind(other) == kind(source))
+        {
+            return Err(anyhow::anyhow!(
+                "reply sources {} and {} are both {} addresses",
+                other,
+                source,
+                kind(source)
+            ));
+        }
+        // Link-local addresses can only be bound on an interface, which is only known per request.
+        if !matches!(source, &IpAddr::V6(source) if is_unicast_link_local(source)) {
+            std::net::UdpSocket::bind((*source, 0))
+                .map_err(|why| anyhow::anyhow!("reply source {} is not usable: {}", source, why))?;
+        }
+    }
+    Ok(())
+}
+
+/// Index of a network interface, which is the scope of link-local IPv6 addresses on it.
+/// Only known on Linux, where it is read from sysfs.
+fn interface_index(name: &str) -> Option<u32> {
+    let path = Path::new("/sys/class/net").join(name).join("ifindex");
+    std::fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// Open captures on all devices, skipping the devices that fail with a warning.
+///
+/// Fails if no capture could be opened at all.
+fn open_captures(
+    devices: Vec<Device>,
+    arguments: &Arguments,
+    stats: &Arc<Stats>,
+) -> Result<Vec<Box<dyn PacketSource>>> {
+    if (arguments.snaplen as usize) < MIN_SNAPLEN {
+        warn!(
+            "snaplen {} is below {} bytes, large packets will be truncated and dropped",
+            arguments.snaplen, MIN_SNAPLEN
+        );
+    }
+    let mut sources = Vec::new();
+    let mut failures = Vec::new();
+    for device in devices {
+        let name = device.name.clone();
+        match open_capture(device, arguments, stats.clone()) {
+            Ok(stream) => sources.push(Box::new(CaptureSource { name, stream }) as Box<_>),
+            Err(why) => {
+                warn!("cannot capture on {}: {}", name, why);
+                failures.push(name);
+            }
+        }
+    }
+    if sources.is_empty() {
+        return Err(if failures.is_empty() {
+            anyhow::anyhow!("no capturable devices found")
+        } else {
+            anyhow::anyhow!("capturing failed on all devices: {}", failures.join(", "))
+        });
+    }
+    Ok(sources)
+}
+
+/// Handle the packets from one source, such as a captured device, see [`handle_packet`].
+/// Sequential sources are handled inline, all others by a pool of `workers` tasks, see [`PacketWorkers`].
+#[allow(clippy::too_many_arguments)]
+async fn device_ping_handler(
+    canvases: Arc<[Canvas]>,
+    stats: Arc<Stats>,
+    policy: RequestPolicy,
+    shutdown: Shutdown,
+    name: String,
+    stream: BoxStream<'static, ReceivedPacket>,
+    sequential: bool,
+    workers: usize,
+) {
+    let stopped = shutdown.clone();
+    let drained = shutdown.clone();
+    let handler = move |request| {
+        // Packets that were already captured when a shutdown was requested are dropped.
+        if !shutdown.is_triggered() {
+            handle_packet(&canvases, &stats, &policy, request);
+        }
+    };
+    let handled = async move {
+        let workers = (!sequential).then(|| PacketWorkers::start(workers, handler.clone()));
+        let mut stream = pin!(stream.take_until(stopped.wait()));
+        while let Some(request) = stream.next().await {
+            match &workers {
+                Some(workers) => workers.dispatch(request).await,
+                None => handler(request),
+            }
+        }
+        if let Some(workers) = workers {
+            workers.finish().await;
+        }
+    };
+    // A busy capture may not notice the shutdown for a while, so it is abandoned after a grace period.
+    let timeout = async move {
+        drained.wait().await;
+        tokio::time::sleep(SHUTDOWN_DRAIN_TIMEOUT).await;
+    };
+    tokio::select! {
+        _ = handled => {}
+        _ = timeout => warn!(
+            "{} did not stop within {:?} after the shutdown request",
+            name, SHUTDOWN_DRAIN_TIMEOUT
+        ),
+    }
+}
+
 /// Handle an error, but ignore it.
 async fn handle_error(future: impl Future<Output = Result<()>>) {
     let result = future.await;
@@ -221,12 +1420,511 @@ async fn handle_error(future: impl Future<Output = Result<()>>) {
     }
 }
 
-async fn ping_handler(canvas: Canvas) {
-    let devices = Device::list().unwrap();
-    let device_iter = futures::stream::iter(devices.into_iter());
-    device_iter
-        .for_each_concurrent(None, |device| {
-            handle_error(device_ping_handler(canvas.clone(), device))
+/// Determine the devices to capture on: either the one device with the given name, or all devices.
+fn capture_devices(interface: Option<&str>) -> Result<Vec<Device>> {
+    let devices = Device::list()?;
+    match interface {
+        None => Ok(devices),
+        Some(name) => {
+            let names: Vec<_> = devices.iter().map(|device| device.name.clone()).collect();
+            let device = devices.into_iter().find(|device| device.name == name);
+            match device {
+                Some(device) => Ok(vec![device]),
+                None => Err(anyhow::anyhow!(
+                    "no interface named {}, available interfaces: {}",
+                    name,
+                    names.join(", ")
+                )),
+            }
+        }
+    }
+}
+
+/// Check the setup for the usual reasons why nothing is drawn, printing the result of each check.
+///
+/// Fails if any check fails.
+async fn doctor(arguments: &Arguments) -> Result<()> {
+    let mut checks = 0;
+    let mut failures = 0;
+    let mut report = |name: &str, result: Result<String>| {
+        checks += 1;
+        match result {
+            Ok(detail) => println!("ok    {}: {}", name, detail),
+            Err(why) => {
+                failures += 1;
+                println!("FAIL  {}: {:#}", name, why);
+            }
+        }
+    };
+
+    let filter = capture_filter(arguments);
+    report(
+        "capture filter",
+        check_capture_filter(&filter).map(|()| format!("{:?} compiles", filter)),
+    );
+    report(
+        "capture devices",
+        capture_devices(arguments.interface.as_deref()).and_then(|devices| {
+            if devices.is_empty() {
+                return Err(anyhow::anyhow!(
+                    "no capturable devices found, capturing needs cap_net_raw and cap_net_admin"
+                ));
+            }
+            let names: Vec<_> = devices.iter().map(|device| device.name.as_str()).collect();
+            Ok(names.join(", "))
+        }),
+    );
+    report("capabilities", check_capabilities());
+
+    // Replies are always sent via raw sockets, while requests fall back to datagram sockets.
+    let loopback = IpAddr::V4(Ipv4Addr::LOCALHOST);
+    let raw_socket =
+        Icmp::new(SocketAddr::new(loopback, 0), 0, EchoDirection::Reply).open_socket(SendMode::Raw);
+    report(
+        "raw sockets",
+        match raw_socket {
+            Ok(_) => Ok("replies can be sent".to_owned()),
+            Err(why) => Err(anyhow::anyhow!(
+                "cannot open a raw socket for replies: {}",
+                why
+            )),
+        },
+    );
+    // A size request doesn't change the canvas of a server that happens to listen on the loopback device.
+    let test_packet = Packet::SizeRequest { canvas_id: 0 };
+    report(
+        "loopback decoding",
+        check_loopback(arguments, &test_packet).await,
+    );
+
+    if failures > 0 {
+        return Err(anyhow::anyhow!("{} of {} checks failed", failures, checks));
+    }
+    println!("all {} checks passed", checks);
+    Ok(())
+}
+
+/// Check that the process has the Linux capabilities for capturing and replying; only supported on Linux.
+fn check_capabilities() -> Result<String> {
+    if !cfg!(target_os = "linux") {
+        return Ok("not checked on this system".to_owned());
+    }
+    let missing = missing_capabilities(&std::fs::read_to_string("/proc/self/status")?)?;
+    if !missing.is_empty() {
+        let executable = std::env::current_exe()?;
+        return Err(anyhow::anyhow!(
+            "missing {}, grant them with `sudo setcap cap_net_raw,cap_net_admin=eip {}` or run as root",
+            missing.join(" and "),
+            executable.display()
+        ));
+    }
+    Ok("cap_net_raw and cap_net_admin are effective".to_owned())
+}
+
+/// Names of the capabilities for capturing and replying that are not effective, given the contents of `/proc/<pid>/status`.
+fn missing_capabilities(status: &str) -> Result<Vec<&'static str>> {
+    const CAP_NET_ADMIN: u32 = 12;
+    const CAP_NET_RAW: u32 = 13;
+    let effective = status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))
+        .ok_or_else(|| anyhow::anyhow!("effective capabilities are unknown"))?;
+    let effective = u64::from_str_radix(effective.trim(), 16)?;
+    Ok([
+        (CAP_NET_RAW, "cap_net_raw"),
+        (CAP_NET_ADMIN, "cap_net_admin"),
+    ]
+    .into_iter()
+    .filter(|&(capability, _)| effective & (1 << capability) == 0)
+    .map(|(_, name)| name)
+    .collect())
+}
+
+/// Send a packet to this host and check that it is captured and decoded on the loopback device.
+async fn check_loopback(arguments: &Arguments, test_packet: &Packet) -> Result<String> {
+    let device = Device::list()?
+        .into_iter()
+        .find(|device| device.flags.is_loopback())
+        .ok_or_else(|| anyhow::anyhow!("no loopback device found"))?;
+    let name = device.name.clone();
+    let mut stream = open_capture(device, arguments, Arc::new(Stats::default()))?;
+    Client::new().send(IpAddr::V4(Ipv4Addr::LOCALHOST), test_packet)?;
+
+    let expected = test_packet.to_bytes();
+    let captured = tokio::time::timeout(DOCTOR_TIMEOUT, async {
+        while let Some(packet) = stream.next().await {
+            if let Ok(Some(request)) = packet {
+                if request.packet.to_bytes() == expected {
+                    return true;
+                }
+            }
+        }
+        false
+    })
+    .await;
+    match captured {
+        Ok(true) => Ok(format!("test packet captured and decoded on {}", name)),
+        _ => Err(anyhow::anyhow!(
+            "test packet to {} was not captured on {} within {:?}",
+            Ipv4Addr::LOCALHOST,
+            name,
+            DOCTOR_TIMEOUT
+        )),
+    }
+}
+
+/// Parse every packet of a recording without drawing anything, and print how many packets of each type it contains.
+/// Packets that fail to decode or set pixels outside the canvas are listed, see [`Packet::from_bytes_bounded`].
+///
+/// Fails if any packet is invalid or the recording is truncated.
+async fn validate_recording(path: &Path, width: u16, height: u16) -> Result<()> {
+    let mut reader = tokio::io::BufReader::new(tokio::fs::File::open(path).await?);
+    let mut types = BTreeMap::new();
+    let (mut total, mut invalid) = (0u64, 0u64);
+    let truncated = loop {
+        let (source, payload) = match read_record(&mut reader).await {
+            Ok(Some(record)) => record,
+            Ok(None) => break None,
+            Err(why) => break Some(why),
+        };
+        total += 1;
+        // The first byte is the protocol version, followed by the packet type.
+        *types.entry(payload.get(1).copied()).or_insert(0u64) += 1;
+        if let Err(why) = Packet::from_bytes_bounded(&payload, width, height) {
+            invalid += 1;
+            println!("invalid packet {} from {}: {}", total, source, why);
+        }
+    };
+
+    println!("{} packets in {}", total, path.display());
+    for (packet_type, count) in types {
+        match packet_type {
+            Some(packet_type) => println!("  {:02x}  {}", packet_type, count),
+            None => println!("  --  {} without a type", count),
+        }
+    }
+    if let Some(why) = truncated {
+        return Err(anyhow::anyhow!(
+            "cannot read the recording after {} packets: {}",
+            total,
+            why
+        ));
+    }
+    if invalid > 0 {
+        return Err(anyhow::anyhow!(
+            "{} of {} packets are invalid",
+            invalid,
+            total
+        ));
+    }
+    println!("all packets are valid for a {}x{} canvas", width, height);
+    Ok(())
+}
+
+async fn ping_handler(
+    canvases: Arc<[Canvas]>,
+    stats: Arc<Stats>,
+    policy: RequestPolicy,
+    shutdown: Shutdown,
+    sources: Vec<Box<dyn PacketSource>>,
+    workers: usize,
+) {
+    // Each source runs on its own task, so that a busy one cannot keep the others from being polled.
+    let handlers: Vec<_> = sources
+        .into_iter()
+        .map(|source| {
+            let name = source.name().to_owned();
+            let sequential = source.is_sequential();
+            let handler = device_ping_handler(
+                canvases.clone(),
+                stats.clone(),
+                policy.clone(),
+                shutdown.clone(),
+                name.clone(),
+                source.into_stream(),
+                sequential,
+                workers,
+            );
+            tokio::spawn(handler.instrument(info_span!("source", name = %name)))
         })
-        .await;
+        .collect();
+    for handler in handlers {
+        let _ = handler.await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use etherparse::PacketBuilder;
+    use std::sync::atomic::Ordering;
+
+    const CLIENT_V4: [u8; 4] = [10, 0, 0, 1];
+    const SERVER_V4: [u8; 4] = [10, 0, 0, 2];
+    const WIDTH: u16 = 64;
+    const HEIGHT: u16 = 48;
+
+    /// A decoder as used for live captures on a `WIDTH` by `HEIGHT` canvas, accepting all protocol versions.
+    fn stream() -> PingxelflutPacketStream {
+        PingxelflutPacketStream {
+            stats: Arc::default(),
+            min_version: 0,
+            interface_index: None,
+            coordinate_mode: CoordinateMode::Full,
+            canvas_size: (WIDTH, HEIGHT),
+            accept_replies: false,
+        }
+    }
+
+    /// An ethernet frame with an IPv4 ICMP echo request from the client to the server carrying a payload.
+    fn echo_request_v4(identifier: u16, sequence_number: u16, payload: &[u8]) -> Vec<u8> {
+        let builder = PacketBuilder::ethernet2([1; 6], [2; 6])
+            .ipv4(CLIENT_V4, SERVER_V4, 64)
+            .icmpv4_echo_request(identifier, sequence_number);
+        let mut frame = Vec::with_capacity(builder.size(payload.len()));
+        builder.write(&mut frame, payload).unwrap();
+        frame
+    }
+
+    #[test]
+    fn decode_keeps_echo_identifier_and_sequence_number() {
+        let frame = echo_request_v4(
+            0x1234,
+            0xbeef,
+            &Packet::SizeRequest { canvas_id: 0 }.to_bytes(),
+        );
+        let received = stream()
+            .decode_frame(&frame)
+            .expect("size request is decoded");
+        assert_eq!(received.identifier, 0x1234);
+        assert_eq!(received.sequence_number, 0xbeef);
+    }
+
+    #[test]
+    fn payloads_beyond_the_largest_packet_are_dropped_unparsed() {
+        // A batched set pixel packet of a million pixels, far beyond what a valid batch can declare.
+        const PIXELS: usize = 1_000_000;
+        let mut payload = vec![PROTOCOL_VERSION, Packet::SET_PIXELS_ID, u8::MAX];
+        payload.resize(
+            payload.len() + PIXELS * Packet::SET_PIXELS_RECORD_SIZE,
+            0xff,
+        );
+        let stream = stream();
+        let echo = IcmpEchoHeader { id: 1, seq: 1 };
+        let client = IpAddr::from(CLIENT_V4);
+        let server = IpAddr::from(SERVER_V4);
+        assert!(stream
+            .parse_payload(&payload, client, server, echo)
+            .is_none());
+        // Counted as oversized rather than malformed, so the parser never saw it.
+        assert_eq!(stream.stats.oversized_packets.load(Ordering::Relaxed), 1);
+        assert_eq!(stream.stats.malformed_packets.load(Ordering::Relaxed), 0);
+
+        // The largest valid packet still gets through.
+        let batch = Packet::SetPixels {
+            pixels: vec![
+                (0, 0, pingxelflut::format::Color::from_rgb([0xff, 0, 0]));
+                Packet::MAX_BATCH_SIZE
+            ],
+        };
+        let largest = batch.to_bytes();
+        assert_eq!(largest.len(), Packet::MAX_SIZE);
+        let received = stream.parse_payload(&largest, client, server, echo);
+        assert_eq!(received.map(|received| received.packet), Some(batch));
+        assert_eq!(stream.stats.oversized_packets.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn pixels_outside_the_canvas_are_dropped_while_decoding() {
+        let stream = stream();
+        let set_pixel = |x, y| {
+            let packet = Packet::SetPixel {
+                x,
+                y,
+                color: pingxelflut::format::Color::from_rgb([0xff, 0, 0]),
+                canvas_id: 0,
+            };
+            echo_request_v4(0, 0, &packet.to_bytes())
+        };
+        assert!(stream
+            .decode_frame(&set_pixel(WIDTH - 1, HEIGHT - 1))
+            .is_some());
+        assert!(stream.decode_frame(&set_pixel(WIDTH, 0)).is_none());
+        assert!(stream.decode_frame(&set_pixel(0, HEIGHT)).is_none());
+        // Batches are clipped when drawn instead.
+        let batch = Packet::SetPixels {
+            pixels: vec![
+                (0, 0, pingxelflut::format::Color::from_rgb([0xff, 0, 0])),
+                (WIDTH, 0, pingxelflut::format::Color::from_rgb([0xff, 0, 0])),
+            ],
+        };
+        assert!(stream
+            .decode_frame(&echo_request_v4(0, 0, &batch.to_bytes()))
+            .is_some());
+        assert_eq!(stream.stats.pixels_dropped.load(Ordering::Relaxed), 2);
+        assert_eq!(stream.stats.malformed_packets.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn decodes_echo_requests_over_both_ip_versions() {
+        let packet = Packet::SetPixel {
+            x: 12,
+            y: 34,
+            color: pingxelflut::format::Color::from_rgb([0x12, 0x34, 0x56]),
+            canvas_id: 0,
+        };
+        let stream = stream();
+
+        let received = stream
+            .decode_frame(&echo_request_v4(1, 2, &packet.to_bytes()))
+            .expect("IPv4 echo request is decoded");
+        assert_eq!(received.packet, packet);
+        assert_eq!(received.source, IpAddr::from(CLIENT_V4));
+        assert_eq!(received.destination, IpAddr::from(SERVER_V4));
+
+        let client_v6 = [0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+        let server_v6 = [0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2];
+        let builder = PacketBuilder::ethernet2([1; 6], [2; 6])
+            .ipv6(client_v6, server_v6, 64)
+            .icmpv6_echo_request(1, 2);
+        let payload = packet.to_bytes();
+        let mut frame = Vec::with_capacity(builder.size(payload.len()));
+        builder.write(&mut frame, &payload).unwrap();
+        let received = stream
+            .decode_frame(&frame)
+            .expect("IPv6 echo request is decoded");
+        assert_eq!(received.packet, packet);
+        assert_eq!(received.source, IpAddr::from(client_v6));
+        assert_eq!(received.destination, IpAddr::from(server_v6));
+        assert_eq!(stream.stats.echo_requests.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn ignores_replies_other_protocols_and_truncated_frames() {
+        let payload = Packet::SizeRequest { canvas_id: 0 }.to_bytes();
+        let stream = stream();
+
+        let builder = PacketBuilder::ethernet2([1; 6], [2; 6])
+            .ipv4(CLIENT_V4, SERVER_V4, 64)
+            .icmpv4_echo_reply(1, 2);
+        let mut reply = Vec::with_capacity(builder.size(payload.len()));
+        builder.write(&mut reply, &payload).unwrap();
+        assert!(stream.decode_frame(&reply).is_none());
+        // Replies are still counted.
+        assert_eq!(stream.stats.echo_replies.load(Ordering::Relaxed), 1);
+
+        let builder = PacketBuilder::ethernet2([1; 6], [2; 6])
+            .ipv4(CLIENT_V4, SERVER_V4, 64)
+            .udp(1234, 1234);
+        let mut udp = Vec::with_capacity(builder.size(payload.len()));
+        builder.write(&mut udp, &payload).unwrap();
+        assert!(stream.decode_frame(&udp).is_none());
+
+        let request = echo_request_v4(1, 2, &payload);
+        // Cut off within the IP header, and within the ICMP header.
+        for length in [20, request.len() - payload.len() - 4] {
+            assert!(stream.decode_frame(&request[..length]).is_none());
+        }
+        assert!(stream.decode_frame(&[]).is_none());
+        assert_eq!(stream.stats.echo_requests.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn packets_below_the_minimum_version_are_dropped() {
+        let stream = PingxelflutPacketStream {
+            min_version: 1,
+            ..stream()
+        };
+        let echo = IcmpEchoHeader { id: 1, seq: 1 };
+        let client = IpAddr::from(CLIENT_V4);
+        let server = IpAddr::from(SERVER_V4);
+        let set_pixel = Packet::SetPixel {
+            x: 1,
+            y: 2,
+            color: pingxelflut::format::Color::from_rgb([0xff, 0, 0]),
+            canvas_id: 0,
+        }
+        .to_bytes();
+        let with_version = |version| [&[version][..], &set_pixel[1..]].concat();
+
+        let received = stream.parse_payload(&set_pixel, client, server, echo);
+        assert!(received.is_some());
+        assert!(stream
+            .parse_payload(&with_version(0), client, server, echo)
+            .is_none());
+        assert_eq!(stream.stats.outdated_packets.load(Ordering::Relaxed), 1);
+        assert_eq!(stream.stats.malformed_packets.load(Ordering::Relaxed), 0);
+
+        // Outdated clients still get a size response telling them the server's version.
+        let received = stream.parse_payload(&[0, Packet::SIZE_REQUEST_ID], client, server, echo);
+        assert_eq!(
+            received.map(|received| received.packet),
+            Some(Packet::SizeRequest { canvas_id: 0 })
+        );
+        assert_eq!(stream.stats.outdated_packets.load(Ordering::Relaxed), 1);
+
+        // Versions newer than the server's are not understood at all.
+        assert!(stream
+            .parse_payload(&with_version(PROTOCOL_VERSION + 1), client, server, echo)
+            .is_none());
+        assert_eq!(stream.stats.outdated_packets.load(Ordering::Relaxed), 1);
+        assert_eq!(stream.stats.malformed_packets.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn doctor_reads_effective_capabilities() {
+        let status = |effective| {
+            format!(
+                "Name:\tserver\nCapPrm:\t0000003fffffffff\nCapEff:\t{}\n",
+                effective
+            )
+        };
+        assert!(missing_capabilities(&status("000001ffffffffff"))
+            .unwrap()
+            .is_empty());
+        // Only cap_net_admin (bit 12) and cap_net_raw (bit 13).
+        assert!(missing_capabilities(&status("0000000000003000"))
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            missing_capabilities(&status("0000000000001000")).unwrap(),
+            ["cap_net_raw"]
+        );
+        assert_eq!(
+            missing_capabilities(&status("0000000000000000")).unwrap(),
+            ["cap_net_raw", "cap_net_admin"]
+        );
+        assert!(missing_capabilities("Name:\tserver\n").is_err());
+        assert!(missing_capabilities(&status("not hex")).is_err());
+    }
+
+    #[test]
+    fn size_responses_in_replies_are_decoded_only_if_accepted() {
+        let response = Packet::SizeResponse {
+            width: 320,
+            height: 240,
+            version: PROTOCOL_VERSION,
+            capabilities: 0,
+        };
+        let builder = PacketBuilder::ethernet2([1; 6], [2; 6])
+            .ipv4(CLIENT_V4, SERVER_V4, 64)
+            .icmpv4_echo_reply(7, 8);
+        let payload = response.to_bytes();
+        let mut frame = Vec::with_capacity(builder.size(payload.len()));
+        builder.write(&mut frame, &payload).unwrap();
+
+        let ignoring = stream();
+        assert!(ignoring.decode_frame(&frame).is_none());
+        assert_eq!(ignoring.stats.echo_replies.load(Ordering::Relaxed), 1);
+
+        let accepting = PingxelflutPacketStream {
+            accept_replies: true,
+            ..stream()
+        };
+        let received = accepting.decode_frame(&frame).expect("reply is decoded");
+        assert_eq!(received.packet, response);
+        assert_eq!((received.identifier, received.sequence_number), (7, 8));
+        assert_eq!(accepting.stats.echo_replies.load(Ordering::Relaxed), 1);
+        assert_eq!(accepting.stats.echo_requests.load(Ordering::Relaxed), 0);
+    }
 }
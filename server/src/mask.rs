@@ -0,0 +1,108 @@
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use image::DynamicImage;
+
+/// Pixels of the logical canvas that clients may draw on, for canvases that are not rectangular, such as oddly shaped LED panels.
+/// Writes to masked-off pixels are dropped, while the canvas size reported to clients stays the bounding rectangle.
+#[derive(Debug, Clone)]
+pub struct Mask {
+    width: usize,
+    drawable: Vec<bool>,
+}
+
+impl Mask {
+    /// Load a mask from an image of exactly the logical canvas size.
+    /// Pixels are drawable if they are mostly opaque, or for images without an alpha channel, mostly bright;
+    /// so both black and white images and transparent cutouts work.
+    pub fn load(path: &Path, width: u16, height: u16) -> Result<Self> {
+        let image = image::open(path)?;
+        if (image.width(), image.height()) != (u32::from(width), u32::from(height)) {
+            bail!(
+                "mask is {}x{} pixels, but the canvas is {}x{}",
+                image.width(),
+                image.height(),
+                width,
+                height
+            );
+        }
+        Ok(Self::from_image(&image))
+    }
+
+    fn from_image(image: &DynamicImage) -> Self {
+        let drawable = if image.color().has_alpha() {
+            image
+                .to_rgba8()
+                .pixels()
+                .map(|pixel| pixel.0[3] >= 0x80)
+                .collect()
+        } else {
+            image
+                .to_luma8()
+                .pixels()
+                .map(|pixel| pixel.0[0] >= 0x80)
+                .collect()
+        };
+        Self {
+            width: image.width() as usize,
+            drawable,
+        }
+    }
+
+    /// Whether the logical pixel may be drawn on. Pixels outside the mask are not.
+    pub fn is_drawable(&self, x: usize, y: usize) -> bool {
+        x < self.width
+            && self
+                .drawable
+                .get(x + y * self.width)
+                .copied()
+                .unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canvas::Canvas;
+    use crate::sink::VecSink;
+    use image::{GrayImage, Luma, Rgba, RgbaImage};
+    use rgb::RGBA8;
+
+    #[test]
+    fn bright_or_opaque_pixels_are_drawable() {
+        let mut gray = GrayImage::new(2, 1);
+        gray.put_pixel(1, 0, Luma([0x80]));
+        let mask = Mask::from_image(&DynamicImage::ImageLuma8(gray));
+        assert!(!mask.is_drawable(0, 0));
+        assert!(mask.is_drawable(1, 0));
+        assert!(!mask.is_drawable(2, 0));
+        assert!(!mask.is_drawable(0, 1));
+
+        // With an alpha channel, only the alpha value counts, not the color.
+        let mut cutout = RgbaImage::from_pixel(2, 1, Rgba([0xff, 0xff, 0xff, 0x7f]));
+        cutout.put_pixel(1, 0, Rgba([0, 0, 0, 0xff]));
+        let mask = Mask::from_image(&DynamicImage::ImageRgba8(cutout));
+        assert!(!mask.is_drawable(0, 0));
+        assert!(mask.is_drawable(1, 0));
+    }
+
+    #[test]
+    fn masked_off_pixels_are_not_drawn() {
+        let black = RGBA8::new(0, 0, 0, 0xff);
+        let red = RGBA8::new(0xff, 0, 0, 0xff);
+        // Only the left half of the canvas is drawable.
+        let image = GrayImage::from_fn(4, 2, |x, _| Luma([if x < 2 { 0xff } else { 0 }]));
+        let mask = Mask::from_image(&DynamicImage::ImageLuma8(image));
+        let mut canvas = Canvas::new(Box::new(VecSink::new(4, 2))).with_mask(Some(mask));
+
+        assert!(canvas.set_pixel(1, 0, red));
+        assert!(!canvas.set_pixel(2, 0, red));
+        canvas.flush_queue();
+        assert_eq!(canvas.get_pixel(1, 0), Some(red));
+        assert_eq!(canvas.get_pixel(2, 0), Some(black));
+
+        canvas.fill_rect(0, 1, 4, 1, red);
+        let row: Vec<_> = (0..4).map(|x| canvas.get_pixel(x, 1)).collect();
+        assert_eq!(row, [Some(red), Some(red), Some(black), Some(black)]);
+    }
+}
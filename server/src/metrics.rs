@@ -0,0 +1,256 @@
+//! An HTTP server exposing [`Stats`] in the Prometheus text format, along with images of the main canvas.
+
+use std::io::Cursor;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use axum::body::Body;
+use axum::extract::State;
+use axum::http::header::{CACHE_CONTROL, CONTENT_TYPE};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::Router;
+use futures::stream;
+use image::{DynamicImage, ImageFormat, RgbaImage};
+use tokio::net::TcpListener;
+use tracing::{debug, info};
+
+use crate::canvas::Canvas;
+use crate::http::serve;
+use crate::stats::Stats;
+
+/// State shared by the request handlers.
+#[derive(Clone)]
+struct Metrics {
+    stats: Arc<Stats>,
+    canvases: Arc<[Canvas]>,
+    stream_fps: u32,
+}
+
+/// Serve `/metrics` on the given address until an error occurs.
+/// Metrics of all canvases are added up.
+///
+/// The main canvas is also served as a PNG image at `/snapshot.png`, and as an MJPEG stream with at most `stream_fps` frames per second at `/stream.mjpeg`.
+pub async fn metrics_server(
+    address: SocketAddr,
+    stats: Arc<Stats>,
+    canvases: Arc<[Canvas]>,
+    stream_fps: u32,
+) -> Result<()> {
+    let listener = TcpListener::bind(address).await?;
+    info!("serving metrics on http://{}/metrics", address);
+    let router = Router::new()
+        .route("/metrics", get(metrics))
+        .route("/snapshot.png", get(snapshot))
+        .route("/stream.mjpeg", get(stream_frames))
+        .with_state(Metrics {
+            stats,
+            canvases,
+            stream_fps,
+        });
+    serve(listener, router).await
+}
+
+async fn metrics(State(metrics): State<Metrics>) -> impl IntoResponse {
+    (
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        render_metrics(&metrics.stats, &metrics.canvases),
+    )
+}
+
+async fn snapshot(State(metrics): State<Metrics>) -> Response {
+    match encode_frame(&metrics.canvases[0], ImageFormat::Png).await {
+        Ok(image) => (
+            [(CONTENT_TYPE, "image/png"), (CACHE_CONTROL, "no-cache")],
+            image,
+        )
+            .into_response(),
+        Err(why) => {
+            debug!("encoding a snapshot failed: {}", why);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Send JPEG images of the canvas as a multipart stream, which browsers show as a moving picture, until the client disconnects.
+async fn stream_frames(State(metrics): State<Metrics>) -> impl IntoResponse {
+    let canvas = metrics.canvases[0].clone();
+    let interval = tokio::time::interval(Duration::from_secs(1) / metrics.stream_fps);
+    let frames = stream::unfold((canvas, interval), |(canvas, mut interval)| async move {
+        interval.tick().await;
+        let part = encode_frame(&canvas, ImageFormat::Jpeg).await.map(|image| {
+            let mut part = format!(
+                "--frame\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+                image.len()
+            )
+            .into_bytes();
+            part.extend(image);
+            part.extend_from_slice(b"\r\n");
+            part
+        });
+        Some((part, (canvas, interval)))
+    });
+    (
+        [
+            (CONTENT_TYPE, "multipart/x-mixed-replace; boundary=frame"),
+            (CACHE_CONTROL, "no-cache"),
+        ],
+        Body::from_stream(frames),
+    )
+}
+
+/// Encode a copy of the canvas frame buffer without alpha.
+/// Encoding happens on a blocking task, so that rendering and packet handling are not stalled.
+async fn encode_frame(canvas: &Canvas, format: ImageFormat) -> Result<Vec<u8>> {
+    let (width, height, frame) = (canvas.width, canvas.height, canvas.frame_copy());
+    let encoded = tokio::task::spawn_blocking(move || {
+        // The frame buffer size always matches the canvas size.
+        let image = RgbaImage::from_raw(width.into(), height.into(), frame).unwrap();
+        let mut encoded = Cursor::new(Vec::new());
+        DynamicImage::ImageRgba8(image)
+            .into_rgb8()
+            .write_to(&mut encoded, format)
+            .map(|()| encoded.into_inner())
+    })
+    .await??;
+    Ok(encoded)
+}
+
+/// A single metric: name, Prometheus type, description and value.
+pub(crate) type Metric = (&'static str, &'static str, &'static str, u64);
+
+/// Collect the current statistics as metrics; metrics of all canvases are added up.
+pub(crate) fn collect_metrics(stats: &Stats, canvases: &[Canvas]) -> Vec<Metric> {
+    let mut metrics = vec![
+        (
+            "pingxelflut_pixels_set_total",
+            "counter",
+            "Pixels accepted onto the canvas.",
+            stats.pixels_set.load(Ordering::Relaxed),
+        ),
+        (
+            "pingxelflut_pixels_dropped_total",
+            "counter",
+            "Pixels dropped because they were outside the canvas or rate-limited.",
+            stats.pixels_dropped.load(Ordering::Relaxed),
+        ),
+        (
+            "pingxelflut_echo_requests_total",
+            "counter",
+            "Captured ICMP echo requests.",
+            stats.echo_requests.load(Ordering::Relaxed),
+        ),
+        (
+            "pingxelflut_echo_replies_total",
+            "counter",
+            "Captured ICMP echo replies, which are only decoded with --accept-replies.",
+            stats.echo_replies.load(Ordering::Relaxed),
+        ),
+        (
+            "pingxelflut_malformed_packets_total",
+            "counter",
+            "ICMP echo packets without a valid Pingxelflut payload.",
+            stats.malformed_packets.load(Ordering::Relaxed),
+        ),
+        (
+            "pingxelflut_oversized_packets_total",
+            "counter",
+            "ICMP echo packets with a payload larger than any valid Pingxelflut packet.",
+            stats.oversized_packets.load(Ordering::Relaxed),
+        ),
+        (
+            "pingxelflut_disallowed_packets_total",
+            "counter",
+            "Packets dropped because their source address was not in an allowed range.",
+            stats.disallowed_packets.load(Ordering::Relaxed),
+        ),
+        (
+            "pingxelflut_clients_rejected_total",
+            "counter",
+            "Packets dropped because the maximum number of active sources was reached.",
+            stats.clients_rejected.load(Ordering::Relaxed),
+        ),
+        (
+            "pingxelflut_outdated_packets_total",
+            "counter",
+            "Packets dropped because their protocol version is below the minimum version.",
+            stats.outdated_packets.load(Ordering::Relaxed),
+        ),
+        (
+            "pingxelflut_queue_full_drops_total",
+            "counter",
+            "Queued pixels dropped because the pixel queue was full.",
+            canvases.iter().map(Canvas::queue_full_drops).sum(),
+        ),
+        (
+            "pingxelflut_mirror_dropped_total",
+            "counter",
+            "Packets that could not be mirrored because the mirror queue was full.",
+            stats.mirror_dropped.load(Ordering::Relaxed),
+        ),
+        (
+            "pingxelflut_recording_dropped_total",
+            "counter",
+            "Packets that could not be recorded because the recording queue was full.",
+            stats.recording_dropped.load(Ordering::Relaxed),
+        ),
+        (
+            "pingxelflut_size_requests_total",
+            "counter",
+            "Size requests that were answered.",
+            stats.size_requests.load(Ordering::Relaxed),
+        ),
+        (
+            "pingxelflut_size_replies_suppressed_total",
+            "counter",
+            "Size requests not answered because the source was answered shortly before.",
+            stats.size_replies_suppressed.load(Ordering::Relaxed),
+        ),
+        (
+            "pingxelflut_pixel_rate",
+            "gauge",
+            "Pixels accepted during the last full second.",
+            stats.pixel_rate(),
+        ),
+        (
+            "pingxelflut_unique_sources",
+            "gauge",
+            "Distinct source addresses seen.",
+            stats.unique_sources() as u64,
+        ),
+    ];
+    let canvas = &canvases[0];
+    if let Some(filled_pixels) = canvas.filled_pixels() {
+        metrics.extend([
+            (
+                "pingxelflut_filled_pixels",
+                "gauge",
+                "Pixels of the main canvas differing from the clear color.",
+                filled_pixels,
+            ),
+            (
+                "pingxelflut_canvas_pixels",
+                "gauge",
+                "Pixels of the main canvas in total.",
+                u64::from(canvas.width) * u64::from(canvas.height),
+            ),
+        ]);
+    }
+    metrics
+}
+
+/// Render the statistics in the Prometheus text exposition format.
+fn render_metrics(stats: &Stats, canvases: &[Canvas]) -> String {
+    let mut output = String::new();
+    for (name, kind, help, value) in collect_metrics(stats, canvases) {
+        output.push_str(&format!(
+            "# HELP {0} {1}\n# TYPE {0} {2}\n{0} {3}\n",
+            name, help, kind, value
+        ));
+    }
+    output
+}
@@ -0,0 +1,85 @@
+//! Forwarding of applied pixels to a second server, for redundancy.
+
+use std::net::IpAddr;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread;
+
+use pingxelflut::client::Client;
+use pingxelflut::format::Packet;
+use tracing::{info, warn};
+
+/// Number of packets waiting to be mirrored, beyond which further packets are dropped.
+const MIRROR_QUEUE_SIZE: usize = 1 << 16;
+
+/// Sends copies of pixel packets to another server.
+///
+/// Packets are sent from a dedicated thread, so that a slow or unreachable mirror never stalls packet handling.
+/// Like [`crate::canvas::Canvas`], this is cheaply clonable and all clones share the same sending thread.
+#[derive(Debug, Clone)]
+pub struct Mirror {
+    target: IpAddr,
+    sender: SyncSender<Packet>,
+}
+
+impl Mirror {
+    /// Start mirroring to the server at the given address.
+    pub fn start(target: IpAddr) -> Self {
+        let (sender, receiver) = mpsc::sync_channel(MIRROR_QUEUE_SIZE);
+        thread::Builder::new()
+            .name("mirror".to_owned())
+            .spawn(move || send_loop(target, receiver))
+            .expect("failed to spawn mirror thread");
+        info!("mirroring pixels to {}", target);
+        Self { target, sender }
+    }
+
+    /// Whether packets from this source must not be mirrored, because they come from the mirror itself.
+    /// This keeps two servers mirroring to each other from bouncing pixels back and forth forever.
+    pub fn is_mirror(&self, source: IpAddr) -> bool {
+        source.to_canonical() == self.target.to_canonical()
+    }
+
+    /// Queue a packet for mirroring without waiting.
+    ///
+    /// Returns false if the packet was dropped because too many packets are already waiting.
+    pub fn forward(&self, packet: Packet) -> bool {
+        self.sender.try_send(packet).is_ok()
+    }
+}
+
+/// Send queued packets until all [`Mirror`] clones are gone.
+/// Single pixels that are queued at the same time are combined into batches.
+fn send_loop(target: IpAddr, receiver: Receiver<Packet>) {
+    let mut client = Client::new();
+    let mut send = |packet: &Packet| {
+        if let Err(why) = client.send(target, packet) {
+            warn!("cannot mirror to {}: {}", target, why);
+        }
+    };
+    while let Ok(first) = receiver.recv() {
+        let mut next = Some(first);
+        let mut batch = Vec::new();
+        while let Some(packet) = next.take() {
+            match packet {
+                Packet::SetPixel {
+                    x,
+                    y,
+                    color,
+                    canvas_id: 0,
+                } if color.alpha.is_none() => {
+                    batch.push((x, y, color));
+                    if batch.len() == Packet::MAX_BATCH_SIZE {
+                        send(&Packet::SetPixels {
+                            pixels: std::mem::take(&mut batch),
+                        });
+                    }
+                }
+                packet => send(&packet),
+            }
+            next = receiver.try_recv().ok();
+        }
+        if !batch.is_empty() {
+            send(&Packet::SetPixels { pixels: batch });
+        }
+    }
+}
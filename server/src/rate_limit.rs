@@ -0,0 +1,118 @@
+use dashmap::DashMap;
+use parking_lot::Mutex;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Number of tracked sources above which idle sources are forgotten.
+const PRUNE_THRESHOLD: usize = 1 << 16;
+
+/// Token bucket of a single source.
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Per-source-address token bucket rate limiter.
+/// Like [`crate::canvas::Canvas`], this is cheaply clonable and all clones share the same state.
+///
+/// The buckets are kept in a sharded map, so that handlers checking different sources rarely contend for a lock.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    /// Tokens regained per second, which is also the bucket capacity.
+    rate: f64,
+    buckets: Arc<DashMap<IpAddr, Bucket>>,
+    /// When idle buckets were last forgotten; locked only by the handler pruning them.
+    last_prune: Arc<Mutex<Instant>>,
+}
+
+impl RateLimiter {
+    /// Create a rate limiter allowing `rate` operations per second and source.
+    pub fn new(rate: u32) -> Self {
+        Self {
+            rate: rate.into(),
+            buckets: Arc::default(),
+            last_prune: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// Take one token from the source’s bucket.
+    ///
+    /// Returns whether the operation is within the source’s budget.
+    pub fn check(&self, source: IpAddr) -> bool {
+        self.check_n(source, 1)
+    }
+
+    /// Take `n` tokens from the source’s bucket at once, for an operation that costs as much as `n` single ones.
+    /// If the bucket holds fewer tokens, none are taken; so operations costing more than the rate never pass.
+    ///
+    /// Returns whether the operation is within the source’s budget.
+    pub fn check_n(&self, source: IpAddr, n: u64) -> bool {
+        let now = Instant::now();
+        if self.buckets.len() > PRUNE_THRESHOLD {
+            self.prune(now);
+        }
+
+        let mut bucket = self.buckets.entry(source).or_insert(Bucket {
+            tokens: self.rate,
+            last_refill: now,
+        });
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.rate);
+        bucket.last_refill = now;
+        let cost = n as f64;
+        if bucket.tokens >= cost {
+            bucket.tokens -= cost;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Forget idle sources, at most once a second. Handlers arriving while another one prunes skip it.
+    fn prune(&self, now: Instant) {
+        let Some(mut last_prune) = self.last_prune.try_lock() else {
+            return;
+        };
+        if now.duration_since(*last_prune) <= Duration::from_secs(1) {
+            return;
+        }
+        // Buckets idle for a second are full again, so forgetting them changes nothing.
+        self.buckets
+            .retain(|_, bucket| now.duration_since(bucket.last_refill) < Duration::from_secs(1));
+        *last_prune = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn sources_are_limited_independently() {
+        let limiter = RateLimiter::new(3);
+        let throttled = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let other = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        for _ in 0..3 {
+            assert!(limiter.check(throttled));
+        }
+        assert!(!limiter.check(throttled));
+        for _ in 0..3 {
+            assert!(limiter.check(other));
+        }
+        assert!(!limiter.check(throttled));
+    }
+
+    #[test]
+    fn check_n_takes_all_tokens_or_none() {
+        let limiter = RateLimiter::new(10);
+        let source = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        assert!(!limiter.check_n(source, 11));
+        assert!(limiter.check_n(source, 8));
+        assert!(!limiter.check_n(source, 3));
+        assert!(limiter.check_n(source, 2));
+        assert!(!limiter.check(source));
+    }
+}
@@ -0,0 +1,191 @@
+//! Recording received Pingxelflut packets to a file, and the file format used for it.
+//!
+//! A recording is a sequence of records, each holding one packet and the address it came from:
+//!
+//! | Bytes    | Value                                              |
+//! | -------- | -------------------------------------------------- |
+//! | 1        | IP version of the source address, `4` or `6`      |
+//! | 4 or 16  | Source address                                     |
+//! | 2        | Payload length, big endian                         |
+//! | Variable | Payload as produced by [`Packet::to_bytes`]        |
+//!
+//! [`Packet::to_bytes`]: pingxelflut::format::Packet::to_bytes
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread;
+
+use pingxelflut::format::Packet;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tracing::{error, info};
+
+/// Number of packets waiting to be written, beyond which further packets are dropped.
+const RECORDING_QUEUE_SIZE: usize = 1 << 16;
+
+/// Appends received packets to a recording.
+///
+/// Packets are written from a dedicated thread, so that a slow disk never stalls packet handling.
+/// Like [`crate::canvas::Canvas`], this is cheaply clonable and all clones share the same writing thread.
+#[derive(Debug, Clone)]
+pub struct Recorder {
+    sender: SyncSender<(IpAddr, Packet)>,
+}
+
+impl Recorder {
+    /// Start appending to the recording at the given path, creating it if it does not exist.
+    pub fn start(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let (sender, receiver) = mpsc::sync_channel(RECORDING_QUEUE_SIZE);
+        thread::Builder::new()
+            .name("recorder".to_owned())
+            .spawn(move || write_loop(file, receiver))?;
+        info!("recording packets to {}", path.display());
+        Ok(Self { sender })
+    }
+
+    /// Queue a packet for recording without waiting.
+    ///
+    /// Returns false if the packet was dropped because too many packets are already waiting.
+    pub fn record(&self, source: IpAddr, packet: Packet) -> bool {
+        self.sender.try_send((source, packet)).is_ok()
+    }
+}
+
+/// Write queued packets until all [`Recorder`] clones are gone or writing fails.
+/// The file is flushed whenever the queue runs empty, so that little is lost if the server is killed.
+fn write_loop(file: File, receiver: Receiver<(IpAddr, Packet)>) {
+    let mut writer = BufWriter::new(file);
+    while let Ok(first) = receiver.recv() {
+        let mut next = Some(first);
+        let mut result = Ok(());
+        while let Some((source, packet)) = next.take() {
+            result = result.and_then(|()| write_record(&mut writer, source, &packet.to_bytes()));
+            next = receiver.try_recv().ok();
+        }
+        if let Err(why) = result.and_then(|()| writer.flush()) {
+            error!("stopped recording: {}", why);
+            return;
+        }
+    }
+}
+
+/// Append a record with the source address and raw packet payload.
+pub fn write_record(writer: &mut impl Write, source: IpAddr, payload: &[u8]) -> io::Result<()> {
+    match source {
+        IpAddr::V4(address) => {
+            writer.write_all(&[4])?;
+            writer.write_all(&address.octets())?;
+        }
+        IpAddr::V6(address) => {
+            writer.write_all(&[6])?;
+            writer.write_all(&address.octets())?;
+        }
+    }
+    // Payloads are bounded by the packet size limit, far below the length field's range.
+    let length = u16::try_from(payload.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "payload too long"))?;
+    writer.write_all(&length.to_be_bytes())?;
+    writer.write_all(payload)
+}
+
+/// Read the next record, returning the source address and the raw packet payload.
+///
+/// Returns [`None`] at the end of the recording. A recording that ends in the middle of a record is an error.
+pub async fn read_record(
+    reader: &mut (impl AsyncRead + Unpin),
+) -> io::Result<Option<(IpAddr, Vec<u8>)>> {
+    let version = match reader.read_u8().await {
+        Ok(version) => version,
+        Err(why) if why.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(why) => return Err(why),
+    };
+    let source = match version {
+        4 => {
+            let mut octets = [0; 4];
+            reader.read_exact(&mut octets).await?;
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        6 => {
+            let mut octets = [0; 16];
+            reader.read_exact(&mut octets).await?;
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid IP version {} in recording", version),
+            ))
+        }
+    };
+    let length = reader.read_u16().await?;
+    let mut payload = vec![0; length.into()];
+    reader.read_exact(&mut payload).await?;
+    Ok(Some((source, payload)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pingxelflut::format::Color;
+    use std::time::{Duration, Instant};
+
+    #[tokio::test]
+    async fn recorded_packets_are_read_back() {
+        let path =
+            std::env::temp_dir().join(format!("pingxelflut-recording-{}.rec", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let packets = [
+            (
+                IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+                Packet::SizeRequest { canvas_id: 0 },
+            ),
+            (
+                IpAddr::V6(Ipv6Addr::LOCALHOST),
+                Packet::SetPixel {
+                    x: 7,
+                    y: 9,
+                    color: Color::from_rgb([0xab, 0xcd, 0xef]),
+                    canvas_id: 0,
+                },
+            ),
+        ];
+        let recorder = Recorder::start(&path).unwrap();
+        for (source, packet) in packets.clone() {
+            assert!(recorder.record(source, packet));
+        }
+        drop(recorder);
+
+        // The writing thread finishes in the background, so wait until the whole recording is there.
+        let expected_len: usize = packets
+            .iter()
+            .map(|(source, packet)| if source.is_ipv4() { 5 } else { 17 } + 2 + packet.to_bytes().len())
+            .sum();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let bytes = loop {
+            let bytes = std::fs::read(&path).unwrap();
+            if bytes.len() >= expected_len || Instant::now() > deadline {
+                break bytes;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        std::fs::remove_file(&path).unwrap();
+
+        let mut reader = &bytes[..];
+        let mut replayed = Vec::new();
+        while let Some((source, payload)) = read_record(&mut reader).await.unwrap() {
+            replayed.push((source, Packet::from_bytes(&payload).unwrap()));
+        }
+        assert_eq!(replayed, packets);
+    }
+
+    #[tokio::test]
+    async fn truncated_record_is_an_error() {
+        let mut bytes = Vec::new();
+        write_record(&mut bytes, IpAddr::V4(Ipv4Addr::LOCALHOST), &[1, 2, 3]).unwrap();
+        bytes.pop();
+        assert!(read_record(&mut &bytes[..]).await.is_err());
+    }
+}
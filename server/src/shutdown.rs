@@ -0,0 +1,52 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use tokio::sync::Notify;
+use tracing::{error, info};
+
+/// Shutdown signal shared between the window, the capture handlers and the background tasks.
+/// Like [`crate::canvas::Canvas`], this is cheaply clonable and all clones share the same state.
+#[derive(Debug, Clone, Default)]
+pub struct Shutdown {
+    triggered: Arc<AtomicBool>,
+    notify: Arc<Notify>,
+}
+
+impl Shutdown {
+    /// Request a shutdown, waking up everything waiting for it.
+    pub fn trigger(&self) {
+        self.triggered.store(true, Ordering::SeqCst);
+        self.notify.notify_waiters();
+    }
+
+    /// Whether a shutdown was requested.
+    pub fn is_triggered(&self) -> bool {
+        self.triggered.load(Ordering::SeqCst)
+    }
+
+    /// Wait until a shutdown is requested; returns immediately if it already was.
+    pub async fn wait(&self) {
+        let notified = self.notify.notified();
+        tokio::pin!(notified);
+        // Register for the notification before checking the flag, so that a trigger in between is not missed.
+        notified.as_mut().enable();
+        if !self.is_triggered() {
+            notified.await;
+        }
+    }
+
+    /// Request a shutdown once the process is interrupted, for example with Ctrl+C.
+    /// Spawns a task on the current Tokio runtime.
+    pub fn trigger_on_ctrl_c(&self) {
+        let shutdown = self.clone();
+        tokio::spawn(async move {
+            match tokio::signal::ctrl_c().await {
+                Ok(()) => {
+                    info!("interrupted, shutting down");
+                    shutdown.trigger();
+                }
+                Err(why) => error!("cannot listen for interrupts: {}", why),
+            }
+        });
+    }
+}
@@ -0,0 +1,311 @@
+//! Frame buffers that the canvas draws into, decoupling the canvas from how it is presented.
+
+use std::fmt::Debug;
+use std::fs::{File, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use pixels::Pixels;
+use tracing::error;
+
+use crate::transform::Transform;
+
+/// Bytes per pixel in a frame buffer.
+pub const COLOR_SIZE: usize = 4;
+
+/// A frame buffer that the canvas draws into, along with the means of presenting it.
+pub trait PixelSink: Debug + Send + Sync {
+    /// The frame buffer, as RGBA pixels row by row.
+    fn frame(&self) -> &[u8];
+
+    /// The mutable frame buffer, as RGBA pixels row by row.
+    fn frame_mut(&mut self) -> &mut [u8];
+
+    /// Width and height of the frame buffer in pixels.
+    fn dimensions(&self) -> (u16, u16);
+
+    /// Prepare presenting the current frame buffer contents, right before [`PixelSink::render`].
+    fn prepare(&mut self) {}
+
+    /// Present the current frame buffer contents.
+    /// Sinks that are not displayed anywhere do nothing.
+    fn render(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Present a frame of another size instead of the sink's own, for example from another canvas.
+    /// The sink's own frame buffer is left as is and presented again by the next [`PixelSink::render`].
+    fn present(&mut self, _frame: &[u8], _width: u16, _height: u16) -> Result<()> {
+        Ok(())
+    }
+
+    /// Adapt to a new size of the surface the frame buffer is presented on.
+    /// The frame buffer size itself never changes.
+    fn resize_surface(&mut self, _width: u32, _height: u32) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// A frame buffer rendered into a window with [`Pixels`].
+#[derive(Debug)]
+pub struct WindowSink {
+    pixels: Pixels,
+    width: u16,
+    height: u16,
+    transform: Transform,
+    /// Untransformed frame buffer, if the presented image is transformed or another frame was presented.
+    /// Otherwise, the canvas draws directly into the [`Pixels`] frame buffer.
+    buffer: Option<Vec<u8>>,
+    /// Current size of the [`Pixels`] frame buffer, which differs from the output size while another frame is presented.
+    buffer_size: (u16, u16),
+}
+
+impl WindowSink {
+    /// Create a sink for a canvas of the given size.
+    /// The [`Pixels`] frame buffer must have the canvas size after applying the transform, see [`Transform::output_size`].
+    pub fn new(pixels: Pixels, width: u16, height: u16, transform: Transform) -> Self {
+        let buffer = (!transform.is_identity()).then(|| pixels.frame().to_vec());
+        Self {
+            pixels,
+            width,
+            height,
+            transform,
+            buffer,
+            buffer_size: transform.output_size(width, height),
+        }
+    }
+
+    /// Write a frame into the [`Pixels`] frame buffer, transformed and resizing the buffer if needed.
+    fn blit(&mut self, frame: &[u8], width: u16, height: u16) -> Result<()> {
+        let output_size = self.transform.output_size(width, height);
+        if self.buffer_size != output_size {
+            self.pixels
+                .resize_buffer(output_size.0.into(), output_size.1.into())?;
+            self.buffer_size = output_size;
+        }
+        let (width, height) = (width as usize, height as usize);
+        let output_width = output_size.0 as usize;
+        let output = self.pixels.frame_mut();
+        for (index, pixel) in frame.chunks_exact(COLOR_SIZE).enumerate() {
+            let (x, y) = self
+                .transform
+                .apply(index % width, index / width, width, height);
+            let output_pos = (x + y * output_width) * COLOR_SIZE;
+            output[output_pos..output_pos + COLOR_SIZE].copy_from_slice(pixel);
+        }
+        Ok(())
+    }
+}
+
+impl PixelSink for WindowSink {
+    fn frame(&self) -> &[u8] {
+        match &self.buffer {
+            Some(buffer) => buffer,
+            None => self.pixels.frame(),
+        }
+    }
+
+    fn frame_mut(&mut self) -> &mut [u8] {
+        match &mut self.buffer {
+            Some(buffer) => buffer,
+            None => self.pixels.frame_mut(),
+        }
+    }
+
+    fn prepare(&mut self) {
+        let Some(buffer) = self.buffer.take() else {
+            return;
+        };
+        // Only fails if the buffer cannot be restored to the size it was created with.
+        if let Err(why) = self.blit(&buffer, self.width, self.height) {
+            error!("cannot restore frame buffer: {}", why);
+        }
+        self.buffer = Some(buffer);
+    }
+
+    fn dimensions(&self) -> (u16, u16) {
+        (self.width, self.height)
+    }
+
+    fn render(&self) -> Result<()> {
+        Ok(self.pixels.render()?)
+    }
+
+    fn present(&mut self, frame: &[u8], width: u16, height: u16) -> Result<()> {
+        // From now on, the canvas keeps its own buffer so that it survives presenting other frames.
+        if self.buffer.is_none() {
+            self.buffer = Some(self.pixels.frame().to_vec());
+        }
+        self.blit(frame, width, height)?;
+        Ok(self.pixels.render()?)
+    }
+
+    fn resize_surface(&mut self, width: u32, height: u32) -> Result<()> {
+        Ok(self.pixels.resize_surface(width, height)?)
+    }
+}
+
+/// A plain in-memory frame buffer, used in headless mode.
+#[derive(Debug, Clone)]
+pub struct VecSink {
+    buffer: Vec<u8>,
+    width: u16,
+    height: u16,
+}
+
+impl VecSink {
+    /// Create an opaque black frame buffer of the given size.
+    pub fn new(width: u16, height: u16) -> Self {
+        Self {
+            buffer: [0, 0, 0, 0xff].repeat(width as usize * height as usize),
+            width,
+            height,
+        }
+    }
+}
+
+impl PixelSink for VecSink {
+    fn frame(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    fn frame_mut(&mut self) -> &mut [u8] {
+        &mut self.buffer
+    }
+
+    fn dimensions(&self) -> (u16, u16) {
+        (self.width, self.height)
+    }
+}
+
+/// Pixel formats of Linux framebuffer devices that [`FramebufferSink`] can write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FramebufferFormat {
+    /// 16 bits per pixel, with 5 bits of red, 6 of green and 5 of blue, little endian.
+    Rgb565,
+    /// 32 bits per pixel, stored as blue, green, red and an unused byte.
+    Xrgb8888,
+}
+
+impl FramebufferFormat {
+    /// Bytes per pixel in the framebuffer device.
+    fn pixel_size(self) -> usize {
+        match self {
+            Self::Rgb565 => 2,
+            Self::Xrgb8888 => 4,
+        }
+    }
+
+    /// Convert an RGBA pixel into the device format.
+    fn write(self, rgba: &[u8], output: &mut [u8]) {
+        let (red, green, blue) = (rgba[0], rgba[1], rgba[2]);
+        match self {
+            Self::Rgb565 => {
+                let value = (u16::from(red) >> 3) << 11
+                    | (u16::from(green) >> 2) << 5
+                    | u16::from(blue) >> 3;
+                output.copy_from_slice(&value.to_le_bytes());
+            }
+            Self::Xrgb8888 => output.copy_from_slice(&[blue, green, red, 0xff]),
+        }
+    }
+}
+
+/// A frame buffer written to a Linux framebuffer device such as `/dev/fb0`, for displays without a GPU stack.
+///
+/// The canvas is shown at the top left corner of the screen and cropped to it. The device geometry and pixel format are read from sysfs,
+/// and every render converts and writes the whole frame, which is simple but fast enough for the small screens this is meant for.
+#[derive(Debug)]
+pub struct FramebufferSink {
+    buffer: Vec<u8>,
+    width: u16,
+    height: u16,
+    device: File,
+    format: FramebufferFormat,
+    /// Bytes per row of the device, which may include padding.
+    stride: usize,
+    /// Visible width and height of the device in pixels.
+    screen_size: (usize, usize),
+    /// The converted frame, as written to the device.
+    output: Vec<u8>,
+}
+
+impl FramebufferSink {
+    /// Open the framebuffer device at the given path for a canvas of the given size.
+    pub fn open(path: &Path, width: u16, height: u16) -> Result<Self> {
+        let name = path
+            .file_name()
+            .ok_or_else(|| anyhow!("{} is not a framebuffer device", path.display()))?;
+        let sysfs = Path::new("/sys/class/graphics").join(name);
+        let read = |attribute: &str| {
+            let path = sysfs.join(attribute);
+            std::fs::read_to_string(&path)
+                .with_context(|| format!("cannot read {}", path.display()))
+        };
+        let format = match read("bits_per_pixel")?.trim() {
+            "16" => FramebufferFormat::Rgb565,
+            "32" => FramebufferFormat::Xrgb8888,
+            bits => return Err(anyhow!("unsupported framebuffer depth of {} bits", bits)),
+        };
+        let stride = read("stride")?.trim().parse()?;
+        let virtual_size = read("virtual_size")?;
+        let (screen_width, screen_height) = virtual_size
+            .trim()
+            .split_once(',')
+            .ok_or_else(|| anyhow!("invalid framebuffer size {:?}", virtual_size))?;
+        let screen_size = (screen_width.parse()?, screen_height.parse()?);
+        let device = OpenOptions::new()
+            .write(true)
+            .open(path)
+            .with_context(|| format!("cannot open {}", path.display()))?;
+        Ok(Self {
+            buffer: [0, 0, 0, 0xff].repeat(width as usize * height as usize),
+            width,
+            height,
+            device,
+            format,
+            stride,
+            screen_size,
+            output: vec![0; stride * screen_size.1],
+        })
+    }
+}
+
+impl PixelSink for FramebufferSink {
+    fn frame(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    fn frame_mut(&mut self) -> &mut [u8] {
+        &mut self.buffer
+    }
+
+    fn dimensions(&self) -> (u16, u16) {
+        (self.width, self.height)
+    }
+
+    fn prepare(&mut self) {
+        let pixel_size = self.format.pixel_size();
+        let width = (self.width as usize).min(self.screen_size.0);
+        let rows = self
+            .buffer
+            .chunks_exact(self.width as usize * COLOR_SIZE)
+            .zip(self.output.chunks_exact_mut(self.stride));
+        for (row, output_row) in rows {
+            let pixels = row
+                .chunks_exact(COLOR_SIZE)
+                .zip(output_row[..width * pixel_size].chunks_exact_mut(pixel_size));
+            for (pixel, output) in pixels {
+                self.format.write(pixel, output);
+            }
+        }
+    }
+
+    fn render(&self) -> Result<()> {
+        let mut device = &self.device;
+        device.seek(SeekFrom::Start(0))?;
+        device.write_all(&self.output)?;
+        Ok(())
+    }
+}
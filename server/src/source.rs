@@ -0,0 +1,151 @@
+//! Sources of received Pingxelflut packets, decoupling packet handling from how packets arrive.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::Path;
+
+use futures::stream::{self, BoxStream, StreamExt};
+use pingxelflut::format::Packet;
+use tokio::fs::File;
+use tokio::io::BufReader;
+use tokio::sync::mpsc::Sender;
+use tracing::{debug, warn};
+
+use crate::recording::read_record;
+
+/// A Pingxelflut packet received via an ICMP echo request.
+#[derive(Debug, Clone)]
+pub struct ReceivedPacket {
+    pub packet: Packet,
+    /// Address of the sender, which replies are sent to.
+    pub source: IpAddr,
+    /// Address the packet was sent to; captures also contain packets sent by this host.
+    pub destination: IpAddr,
+    /// Echo identifier of the request, mirrored in replies.
+    pub identifier: u16,
+    /// Echo sequence number of the request, mirrored in replies.
+    pub sequence_number: u16,
+    /// Index of the network interface the packet arrived on, if known.
+    /// Replies to link-local IPv6 addresses need it to pick the right link.
+    pub interface_index: Option<u32>,
+    /// Channel that replies are sent on instead of ICMP, for packets that did not arrive via ICMP.
+    pub reply_channel: Option<Sender<Packet>>,
+}
+
+/// Something that packets are received from, such as a network capture or a recording.
+pub trait PacketSource: Send {
+    /// Name of the source for log messages, such as the captured device.
+    fn name(&self) -> &str;
+
+    /// Whether packets must be handled one after another in their original order, instead of concurrently.
+    /// This makes replaying a recording deterministic.
+    fn is_sequential(&self) -> bool {
+        false
+    }
+
+    /// Turn the source into a stream of received packets, which ends once the source is exhausted.
+    /// Anything that is not a valid packet is skipped.
+    fn into_stream(self: Box<Self>) -> BoxStream<'static, ReceivedPacket>;
+}
+
+/// Replays a recording, see [`crate::recording`], as fast as the packets are handled.
+///
+/// Replies, such as size responses, are still sent to the recorded source addresses.
+#[derive(Debug)]
+pub struct FilePacketSource {
+    name: String,
+    reader: BufReader<File>,
+}
+
+impl FilePacketSource {
+    /// Open a recording for replay.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            name: path.display().to_string(),
+            reader: BufReader::new(File::from_std(std::fs::File::open(path)?)),
+        })
+    }
+}
+
+impl PacketSource for FilePacketSource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn is_sequential(&self) -> bool {
+        true
+    }
+
+    fn into_stream(self: Box<Self>) -> BoxStream<'static, ReceivedPacket> {
+        let Self { name, reader } = *self;
+        stream::unfold((name, reader), |(name, mut reader)| async move {
+            loop {
+                let (source, payload) = match read_record(&mut reader).await {
+                    Ok(Some(record)) => record,
+                    Ok(None) => return None,
+                    Err(why) => {
+                        warn!("cannot read {}: {}", name, why);
+                        return None;
+                    }
+                };
+                match Packet::from_bytes(&payload) {
+                    Ok(packet) => {
+                        let received = ReceivedPacket {
+                            packet,
+                            source,
+                            destination: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                            identifier: 0,
+                            sequence_number: 0,
+                            interface_index: None,
+                            reply_channel: None,
+                        };
+                        return Some((received, (name, reader)));
+                    }
+                    Err(why) => debug!("skipping malformed packet in {}: {}", name, why),
+                }
+            }
+        })
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recording::write_record;
+    use pingxelflut::format::Color;
+    use std::net::Ipv6Addr;
+
+    #[tokio::test]
+    async fn file_source_replays_valid_packets_in_order() {
+        let path =
+            std::env::temp_dir().join(format!("pingxelflut-source-{}.rec", std::process::id()));
+        let first = Packet::SizeRequest { canvas_id: 0 };
+        let second = Packet::SetPixel {
+            x: 3,
+            y: 4,
+            color: Color::from_rgb([0x12, 0x34, 0x56]),
+            canvas_id: 0,
+        };
+        let v4 = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let v6 = IpAddr::V6(Ipv6Addr::LOCALHOST);
+        let mut file = std::fs::File::create(&path).unwrap();
+        write_record(&mut file, v4, &first.to_bytes()).unwrap();
+        write_record(&mut file, v4, &[0xde, 0xad]).unwrap();
+        write_record(&mut file, v6, &second.to_bytes()).unwrap();
+        drop(file);
+
+        let source = FilePacketSource::open(&path).unwrap();
+        assert!(source.is_sequential());
+        let received: Vec<_> = (Box::new(source) as Box<dyn PacketSource>)
+            .into_stream()
+            .collect()
+            .await;
+        std::fs::remove_file(&path).unwrap();
+        let received: Vec<_> = received
+            .into_iter()
+            .map(|received| (received.source, received.packet))
+            .collect();
+        assert_eq!(received, [(v4, first), (v6, second)]);
+    }
+}
@@ -0,0 +1,334 @@
+use std::fmt::Write as _;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use dashmap::DashMap;
+use tracing::{debug, info, warn};
+
+/// Maximum number of distinct source addresses that are remembered.
+/// This keeps memory bounded even if a client cycles through an IPv6 prefix.
+const MAX_TRACKED_SOURCES: usize = 1 << 20;
+
+/// Number of sources shown on the leaderboard.
+const LEADERBOARD_SIZE: usize = 10;
+
+/// Number of handled packets per type byte.
+#[derive(Debug)]
+struct PacketTypeCounts([AtomicU64; 256]);
+
+impl Default for PacketTypeCounts {
+    fn default() -> Self {
+        Self(std::array::from_fn(|_| AtomicU64::new(0)))
+    }
+}
+
+/// Server statistics counters, shared between all packet handlers via an [`Arc`].
+#[derive(Debug, Default)]
+pub struct Stats {
+    /// Pixels that were accepted onto the canvas.
+    pub pixels_set: AtomicU64,
+    /// Pixels that were dropped, for example because they were outside the canvas or rate-limited.
+    pub pixels_dropped: AtomicU64,
+    /// Captured ICMP echo requests, whatever their payload.
+    pub echo_requests: AtomicU64,
+    /// Captured ICMP echo replies, whatever their payload; they are only decoded if replies are accepted.
+    pub echo_replies: AtomicU64,
+    /// ICMP echo packets whose payload was not a valid Pingxelflut packet.
+    pub malformed_packets: AtomicU64,
+    /// ICMP echo packets whose payload was larger than any valid Pingxelflut packet.
+    pub oversized_packets: AtomicU64,
+    /// Packets dropped because their protocol version is below the minimum version.
+    pub outdated_packets: AtomicU64,
+    /// Packets dropped because their source address was not in an allowed range.
+    pub disallowed_packets: AtomicU64,
+    /// Packets dropped because too many other sources were active, see [`crate::client_limit::ClientLimit`].
+    pub clients_rejected: AtomicU64,
+    /// Packets that could not be mirrored because the mirror queue was full.
+    pub mirror_dropped: AtomicU64,
+    /// Packets that could not be recorded because the recording queue was full.
+    pub recording_dropped: AtomicU64,
+    /// Size requests that were answered.
+    pub size_requests: AtomicU64,
+    /// Size requests that were not answered because the source was answered shortly before.
+    pub size_replies_suppressed: AtomicU64,
+    /// Distinct source addresses that sent valid packets, with the number of pixels each of them set.
+    /// The map is sharded, so that handlers crediting different sources rarely contend for a lock.
+    sources: DashMap<IpAddr, AtomicU64>,
+    /// Accepted pixels during the last full second.
+    pixel_rate: AtomicU64,
+    /// Time the last packet from a client was handled, in milliseconds since the Unix epoch, or 0 if none was.
+    last_packet: AtomicU64,
+    /// Packets from clients that were handled, by type.
+    packet_types: PacketTypeCounts,
+}
+
+impl Stats {
+    /// Count an increment of one of the counters.
+    #[inline]
+    pub fn count(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a pixel write from a source, depending on whether it was accepted.
+    #[inline]
+    pub fn count_pixel(&self, source: IpAddr, accepted: bool) {
+        if accepted {
+            self.count_pixels_set(source, 1);
+        } else {
+            Self::count(&self.pixels_dropped);
+        }
+    }
+
+    /// Count pixels from a source that were accepted in bulk, for example by a rectangle fill.
+    #[inline]
+    pub fn count_pixels_set(&self, source: IpAddr, count: u64) {
+        self.pixels_set.fetch_add(count, Ordering::Relaxed);
+        if count > 0 {
+            if let Some(contribution) = self.sources.get(&source) {
+                contribution.fetch_add(count, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Count pixels that were dropped in bulk.
+    #[inline]
+    pub fn count_pixels_dropped(&self, count: u64) {
+        self.pixels_dropped.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Note that a packet of the given type, see [`Packet::type_id`](pingxelflut::format::Packet::type_id),
+    /// from a client is being handled; see [`Stats::last_packet`].
+    #[inline]
+    pub fn record_packet(&self, type_id: u8) {
+        Self::count(&self.packet_types.0[type_id as usize]);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        self.last_packet
+            .store((now.as_millis() as u64).max(1), Ordering::Relaxed);
+    }
+
+    /// Time the last packet from a client was handled, or [`None`] if none was yet.
+    /// Pixels the server draws itself, such as the attract mode, do not count.
+    pub fn last_packet(&self) -> Option<SystemTime> {
+        match self.last_packet.load(Ordering::Relaxed) {
+            0 => None,
+            millis => Some(UNIX_EPOCH + Duration::from_millis(millis)),
+        }
+    }
+
+    /// Remember a source address that sent a valid packet.
+    /// Only remembered sources are credited with their pixels.
+    pub fn record_source(&self, source: IpAddr) {
+        // Most packets come from known sources, which only need a shared lock on their shard.
+        if self.sources.contains_key(&source) {
+            return;
+        }
+        if self.sources.len() < MAX_TRACKED_SOURCES {
+            self.sources.entry(source).or_default();
+        }
+    }
+
+    /// The sources that set the most pixels, with their pixel counts, at most `count` of them.
+    pub fn top_contributors(&self, count: usize) -> Vec<(IpAddr, u64)> {
+        let mut contributors: Vec<_> = self
+            .sources
+            .iter()
+            .map(|entry| (*entry.key(), entry.value().load(Ordering::Relaxed)))
+            .filter(|&(_, pixels)| pixels > 0)
+            .collect();
+        contributors.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        contributors.truncate(count);
+        contributors
+    }
+
+    /// Number of distinct source addresses seen, saturating at [`MAX_TRACKED_SOURCES`].
+    pub fn unique_sources(&self) -> usize {
+        self.sources.len()
+    }
+
+    /// Log the totals, for example when the server shuts down.
+    pub fn log_summary(&self) {
+        info!(
+            "{} pixels set, {} pixels dropped, {} malformed packets, {} size requests from {} sources",
+            self.pixels_set.load(Ordering::Relaxed),
+            self.pixels_dropped.load(Ordering::Relaxed),
+            self.malformed_packets.load(Ordering::Relaxed),
+            self.size_requests.load(Ordering::Relaxed),
+            self.unique_sources()
+        );
+        let top: Vec<_> = self
+            .top_contributors(LEADERBOARD_SIZE)
+            .into_iter()
+            .map(|(source, pixels)| format!("{} ({})", source, pixels))
+            .collect();
+        if !top.is_empty() {
+            info!("top contributors: {}", top.join(", "));
+        }
+    }
+
+    /// Print the sources that set the most pixels as a table.
+    pub fn print_leaderboard(&self) {
+        println!("rank  pixels        source");
+        for (rank, (source, pixels)) in self
+            .top_contributors(LEADERBOARD_SIZE)
+            .into_iter()
+            .enumerate()
+        {
+            println!("{:>4}  {:>12}  {}", rank + 1, pixels, source);
+        }
+    }
+
+    /// Accepted pixels per second, measured over the last full second.
+    pub fn pixel_rate(&self) -> u64 {
+        self.pixel_rate.load(Ordering::Relaxed)
+    }
+
+    /// Copy the current totals, for example to export them.
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            pixels_set: self.pixels_set.load(Ordering::Relaxed),
+            pixels_dropped: self.pixels_dropped.load(Ordering::Relaxed),
+            malformed_packets: self.malformed_packets.load(Ordering::Relaxed),
+            packet_types: (0..=u8::MAX)
+                .zip(&self.packet_types.0)
+                .map(|(type_id, count)| (type_id, count.load(Ordering::Relaxed)))
+                .filter(|&(_, count)| count > 0)
+                .collect(),
+            unique_sources: self.unique_sources(),
+            top_contributors: self.top_contributors(LEADERBOARD_SIZE),
+        }
+    }
+}
+
+/// The totals of [`Stats`] at one point in time, see [`Stats::snapshot`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatsSnapshot {
+    /// Time the snapshot was taken, in seconds since the Unix epoch.
+    pub timestamp: u64,
+    pub pixels_set: u64,
+    pub pixels_dropped: u64,
+    pub malformed_packets: u64,
+    /// Number of handled packets per type byte, for the types that occurred, in ascending order.
+    pub packet_types: Vec<(u8, u64)>,
+    pub unique_sources: usize,
+    /// The sources that set the most pixels, see [`Stats::top_contributors`].
+    pub top_contributors: Vec<(IpAddr, u64)>,
+}
+
+impl StatsSnapshot {
+    /// Serialize the snapshot as a single JSON object.
+    /// Packet types are keyed by their type byte in hex, such as `"cc"` for set pixel packets.
+    pub fn to_json(&self) -> String {
+        let packet_types: Vec<_> = self
+            .packet_types
+            .iter()
+            .map(|(type_id, count)| format!(r#""{:02x}":{}"#, type_id, count))
+            .collect();
+        let top_contributors: Vec<_> = self
+            .top_contributors
+            .iter()
+            .map(|(source, pixels)| format!(r#"{{"source":"{}","pixels":{}}}"#, source, pixels))
+            .collect();
+        let mut json = String::new();
+        let _ = write!(
+            json,
+            r#"{{"timestamp":{},"pixels_set":{},"pixels_dropped":{},"malformed_packets":{},"packet_types":{{{}}},"unique_sources":{},"top_contributors":[{}]}}"#,
+            self.timestamp,
+            self.pixels_set,
+            self.pixels_dropped,
+            self.malformed_packets,
+            packet_types.join(","),
+            self.unique_sources,
+            top_contributors.join(",")
+        );
+        json
+    }
+}
+
+/// Write the statistics to a JSON file at every interval, see [`StatsSnapshot::to_json`].
+/// The file is replaced atomically, so that readers never observe a partially written file.
+pub async fn stats_file_handler(stats: Arc<Stats>, path: PathBuf, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    let temporary_path = path.with_extension("json.tmp");
+    loop {
+        interval.tick().await;
+        let json = stats.snapshot().to_json();
+        let result = async {
+            tokio::fs::write(&temporary_path, json).await?;
+            tokio::fs::rename(&temporary_path, &path).await
+        };
+        if let Err(why) = result.await {
+            warn!(
+                "error while writing statistics to {}: {}",
+                path.display(),
+                why
+            );
+        }
+    }
+}
+
+/// Update the pixel rate once per second.
+pub async fn stats_handler(stats: Arc<Stats>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    let mut last_pixels_set = stats.pixels_set.load(Ordering::Relaxed);
+    loop {
+        interval.tick().await;
+        let pixels_set = stats.pixels_set.load(Ordering::Relaxed);
+        let rate = pixels_set - last_pixels_set;
+        last_pixels_set = pixels_set;
+        stats.pixel_rate.store(rate, Ordering::Relaxed);
+        debug!(
+            "{} px/s, {} pixels dropped, {} malformed packets",
+            stats.pixel_rate(),
+            stats.pixels_dropped.load(Ordering::Relaxed),
+            stats.malformed_packets.load(Ordering::Relaxed)
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    #[test]
+    fn snapshot_serializes_to_json() {
+        let snapshot = StatsSnapshot {
+            timestamp: 1_700_000_000,
+            pixels_set: 1234,
+            pixels_dropped: 5,
+            malformed_packets: 2,
+            packet_types: vec![(0xaa, 1), (0xcc, 1200)],
+            unique_sources: 2,
+            top_contributors: vec![
+                (IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 1000),
+                (IpAddr::V6(Ipv6Addr::LOCALHOST), 234),
+            ],
+        };
+        assert_eq!(
+            snapshot.to_json(),
+            concat!(
+                r#"{"timestamp":1700000000,"pixels_set":1234,"pixels_dropped":5,"malformed_packets":2,"#,
+                r#""packet_types":{"aa":1,"cc":1200},"unique_sources":2,"#,
+                r#""top_contributors":[{"source":"10.0.0.1","pixels":1000},{"source":"::1","pixels":234}]}"#
+            )
+        );
+
+        let empty = StatsSnapshot {
+            packet_types: Vec::new(),
+            top_contributors: Vec::new(),
+            ..snapshot
+        };
+        assert!(empty
+            .to_json()
+            .ends_with(r#""packet_types":{},"unique_sources":2,"top_contributors":[]}"#));
+    }
+}
@@ -0,0 +1,133 @@
+//! Downscaled images of the canvas, answering [`Packet::GetThumbnail`](pingxelflut::format::Packet::GetThumbnail).
+
+use std::sync::Arc;
+
+use rgb::RGBA8;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Number of thumbnails that are computed at the same time; further requests are dropped meanwhile.
+const MAX_THUMBNAIL_JOBS: usize = 2;
+
+/// Limits how many thumbnails are computed at the same time, so that a flood of requests cannot occupy all blocking threads.
+/// Like [`crate::canvas::Canvas`], this is cheaply clonable and all clones share the same limit.
+#[derive(Debug, Clone)]
+pub struct ThumbnailJobs {
+    permits: Arc<Semaphore>,
+}
+
+impl Default for ThumbnailJobs {
+    fn default() -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(MAX_THUMBNAIL_JOBS)),
+        }
+    }
+}
+
+impl ThumbnailJobs {
+    /// Start a thumbnail job, which lasts until the returned permit is dropped.
+    ///
+    /// Returns [`None`] if the maximum number of jobs is already running.
+    pub fn try_start(&self) -> Option<OwnedSemaphorePermit> {
+        self.permits.clone().try_acquire_owned().ok()
+    }
+}
+
+/// Size of a thumbnail of a `width` by `height` image that fits within `max_size` pixels in both dimensions.
+/// The aspect ratio is kept as well as possible, images are never scaled up, and neither dimension is less than 1.
+pub fn thumbnail_size(width: u16, height: u16, max_size: u16) -> (u16, u16) {
+    let (width, height, max_size) = (u32::from(width), u32::from(height), u32::from(max_size));
+    let longest = width.max(height);
+    if longest <= max_size {
+        return (width as u16, height as u16);
+    }
+    let scale = |size: u32| ((size * max_size + longest / 2) / longest).max(1) as u16;
+    (scale(width), scale(height))
+}
+
+/// Downscale an RGBA frame of `width` by `height` pixels to fit within `max_size` pixels in both dimensions,
+/// returning the thumbnail size and its colors in rows from top to bottom.
+///
+/// Each thumbnail pixel is the average of the block of frame pixels it covers (a box filter), which keeps fine
+/// details such as text visible as a blend rather than dropping them like sampling single pixels would.
+///
+/// # Panics
+///
+/// Panics if the frame is smaller than its size.
+pub fn downscale(frame: &[u8], width: u16, height: u16, max_size: u16) -> (u16, u16, Vec<RGBA8>) {
+    let (thumb_width, thumb_height) = thumbnail_size(width, height, max_size);
+    let (width, height) = (width as usize, height as usize);
+    assert!(frame.len() >= width * height * 4, "frame is too small");
+    let mut pixels = Vec::with_capacity(thumb_width as usize * thumb_height as usize);
+    for thumb_y in 0..thumb_height as usize {
+        let y_range = thumb_y * height / thumb_height as usize
+            ..(thumb_y + 1) * height / thumb_height as usize;
+        for thumb_x in 0..thumb_width as usize {
+            let x_range = thumb_x * width / thumb_width as usize
+                ..(thumb_x + 1) * width / thumb_width as usize;
+            let mut sums = [0u64; 3];
+            for y in y_range.clone() {
+                let row = &frame[(y * width + x_range.start) * 4..(y * width + x_range.end) * 4];
+                for pixel in row.chunks_exact(4) {
+                    for (sum, &component) in sums.iter_mut().zip(pixel) {
+                        *sum += u64::from(component);
+                    }
+                }
+            }
+            let count = (y_range.len() * x_range.len()) as u64;
+            let [r, g, b] = sums.map(|sum| ((sum + count / 2) / count) as u8);
+            pixels.push(RGBA8::new(r, g, b, 0xff));
+        }
+    }
+    (thumb_width, thumb_height, pixels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn thumbnails_fit_and_keep_the_aspect_ratio() {
+        assert_eq!(thumbnail_size(1920, 1080, 32), (32, 18));
+        assert_eq!(thumbnail_size(1080, 1920, 32), (18, 32));
+        assert_eq!(thumbnail_size(10, 8, 32), (10, 8));
+        assert_eq!(thumbnail_size(1000, 1, 32), (32, 1));
+    }
+
+    #[test]
+    fn downscale_averages_blocks() {
+        let (red, blue, white) = ([0xff, 0, 0, 0xff], [0, 0, 0xff, 0xff], [0xff; 4]);
+        #[rustfmt::skip]
+        let frame = [
+            red, blue, white, white,
+            blue, red, white, white,
+        ]
+        .concat();
+        let (width, height, pixels) = downscale(&frame, 4, 2, 2);
+        assert_eq!((width, height), (2, 1));
+        assert_eq!(
+            pixels,
+            [
+                RGBA8::new(0x80, 0, 0x80, 0xff),
+                RGBA8::new(0xff, 0xff, 0xff, 0xff)
+            ]
+        );
+
+        // Small frames are copied unscaled, and always made opaque.
+        let (width, height, pixels) = downscale(&[1, 2, 3, 4], 1, 1, 32);
+        assert_eq!(
+            (width, height, pixels),
+            (1, 1, vec![RGBA8::new(1, 2, 3, 0xff)])
+        );
+    }
+
+    #[test]
+    fn thumbnail_jobs_are_limited() {
+        let jobs = ThumbnailJobs::default();
+        let permits: Vec<_> = (0..MAX_THUMBNAIL_JOBS)
+            .map(|_| jobs.try_start().expect("job is started"))
+            .collect();
+        assert!(jobs.try_start().is_none());
+        drop(permits);
+        assert!(jobs.try_start().is_some());
+    }
+}
@@ -0,0 +1,206 @@
+//! Rotating and mirroring the presented canvas, for example for projectors mounted upside down.
+//! Only the presentation is transformed; clients and the frame buffer always use canvas coordinates.
+
+use clap::ValueEnum;
+
+/// Clockwise rotation of the presented canvas.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Rotation {
+    #[default]
+    #[value(name = "0")]
+    None,
+    #[value(name = "90")]
+    Quarter,
+    #[value(name = "180")]
+    Half,
+    #[value(name = "270")]
+    ThreeQuarters,
+}
+
+/// Mirroring of the presented canvas, applied after rotating it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Flip {
+    #[default]
+    None,
+    /// Mirror left and right.
+    Horizontal,
+    /// Mirror top and bottom.
+    Vertical,
+}
+
+/// How the canvas is transformed when it is presented.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Transform {
+    pub rotation: Rotation,
+    pub flip: Flip,
+}
+
+impl Transform {
+    /// Whether the canvas is presented as is.
+    pub fn is_identity(&self) -> bool {
+        self.rotation == Rotation::None && self.flip == Flip::None
+    }
+
+    /// Size of the presented image for a canvas of the given size; rotating by 90 or 270 degrees swaps width and height.
+    pub fn output_size(&self, width: u16, height: u16) -> (u16, u16) {
+        match self.rotation {
+            Rotation::None | Rotation::Half => (width, height),
+            Rotation::Quarter | Rotation::ThreeQuarters => (height, width),
+        }
+    }
+
+    /// Position in the presented image of a canvas pixel, for a canvas of the given size.
+    pub fn apply(&self, x: usize, y: usize, width: usize, height: usize) -> (usize, usize) {
+        let (x, y, output_width, output_height) = match self.rotation {
+            Rotation::None => (x, y, width, height),
+            Rotation::Quarter => (height - 1 - y, x, height, width),
+            Rotation::Half => (width - 1 - x, height - 1 - y, width, height),
+            Rotation::ThreeQuarters => (y, width - 1 - x, height, width),
+        };
+        match self.flip {
+            Flip::None => (x, y),
+            Flip::Horizontal => (output_width - 1 - x, y),
+            Flip::Vertical => (x, output_height - 1 - y),
+        }
+    }
+}
+
+/// Largest factor the window can be scaled up by.
+pub const MAX_WINDOW_SCALE: u16 = 64;
+
+/// Initial size of the window as an integer multiple of the presented canvas size.
+/// The canvas is always scaled up without blurring, so small canvases stay crisp in larger windows.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WindowScale {
+    /// Scale by a fixed factor.
+    Factor(u16),
+    /// Scale by the largest factor that still fits the monitor.
+    Auto,
+}
+
+impl WindowScale {
+    /// Size of the window for a presented image of the given size, on a monitor of the given size if it is known.
+    /// Automatic scaling falls back to the image size if the monitor is unknown or too small.
+    pub fn window_size(self, width: u16, height: u16, monitor: Option<(u32, u32)>) -> (u32, u32) {
+        let (width, height) = (u32::from(width), u32::from(height));
+        let factor = match self {
+            WindowScale::Factor(factor) => u32::from(factor),
+            WindowScale::Auto => monitor.map_or(1, |(monitor_width, monitor_height)| {
+                (monitor_width / width.max(1))
+                    .min(monitor_height / height.max(1))
+                    .clamp(1, MAX_WINDOW_SCALE.into())
+            }),
+        };
+        (width * factor, height * factor)
+    }
+}
+
+/// Parse a window scale given as a factor from 1 to [`MAX_WINDOW_SCALE`], or `auto`.
+pub fn parse_window_scale(scale: &str) -> Result<WindowScale, String> {
+    if scale == "auto" {
+        return Ok(WindowScale::Auto);
+    }
+    match scale.parse::<u16>() {
+        Ok(factor @ 1..=MAX_WINDOW_SCALE) => Ok(WindowScale::Factor(factor)),
+        _ => Err(format!(
+            "expected a factor from 1 to {} or \"auto\", got {:?}",
+            MAX_WINDOW_SCALE, scale
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Where the top left, top right, bottom left and bottom right corners of a 4x3 canvas are presented.
+    fn corners(rotation: Rotation, flip: Flip) -> [(usize, usize); 4] {
+        let transform = Transform { rotation, flip };
+        [(0, 0), (3, 0), (0, 2), (3, 2)].map(|(x, y)| transform.apply(x, y, 4, 3))
+    }
+
+    #[test]
+    fn rotates_corners_clockwise() {
+        assert_eq!(
+            corners(Rotation::None, Flip::None),
+            [(0, 0), (3, 0), (0, 2), (3, 2)]
+        );
+        // The presented image is 3x4 here: the top left corner ends up at the top right.
+        assert_eq!(
+            corners(Rotation::Quarter, Flip::None),
+            [(2, 0), (2, 3), (0, 0), (0, 3)]
+        );
+        assert_eq!(
+            corners(Rotation::Half, Flip::None),
+            [(3, 2), (0, 2), (3, 0), (0, 0)]
+        );
+        assert_eq!(
+            corners(Rotation::ThreeQuarters, Flip::None),
+            [(0, 3), (0, 0), (2, 3), (2, 0)]
+        );
+    }
+
+    #[test]
+    fn flips_corners_after_rotating() {
+        assert_eq!(
+            corners(Rotation::None, Flip::Horizontal),
+            [(3, 0), (0, 0), (3, 2), (0, 2)]
+        );
+        assert_eq!(
+            corners(Rotation::None, Flip::Vertical),
+            [(0, 2), (3, 2), (0, 0), (3, 0)]
+        );
+        assert_eq!(
+            corners(Rotation::Quarter, Flip::Horizontal),
+            [(0, 0), (0, 3), (2, 0), (2, 3)]
+        );
+        // Rotating by 180 degrees and then mirroring top and bottom only mirrors left and right.
+        assert_eq!(
+            corners(Rotation::Half, Flip::Vertical),
+            corners(Rotation::None, Flip::Horizontal)
+        );
+    }
+
+    #[test]
+    fn quarter_rotations_swap_the_output_size() {
+        let transform = |rotation| Transform {
+            rotation,
+            flip: Flip::Vertical,
+        };
+        assert_eq!(transform(Rotation::None).output_size(4, 3), (4, 3));
+        assert_eq!(transform(Rotation::Quarter).output_size(4, 3), (3, 4));
+        assert_eq!(transform(Rotation::Half).output_size(4, 3), (4, 3));
+        assert_eq!(transform(Rotation::ThreeQuarters).output_size(4, 3), (3, 4));
+    }
+
+    #[test]
+    fn window_size_scales_the_presented_image() {
+        assert_eq!(
+            WindowScale::Factor(1).window_size(320, 240, None),
+            (320, 240)
+        );
+        assert_eq!(
+            WindowScale::Factor(3).window_size(320, 240, Some((100, 100))),
+            (960, 720)
+        );
+        // The largest whole factor that fits both monitor dimensions.
+        assert_eq!(
+            WindowScale::Auto.window_size(320, 240, Some((1920, 1080))),
+            (1280, 960)
+        );
+        // Tiny canvases are not scaled beyond the largest factor.
+        assert_eq!(
+            WindowScale::Auto.window_size(8, 8, Some((3840, 2160))),
+            (512, 512)
+        );
+        // Unknown or small monitors get the image size.
+        assert_eq!(WindowScale::Auto.window_size(320, 240, None), (320, 240));
+        assert_eq!(
+            WindowScale::Auto.window_size(320, 240, Some((200, 100))),
+            (320, 240)
+        );
+        assert_eq!(parse_window_scale("auto"), Ok(WindowScale::Auto));
+        assert_eq!(parse_window_scale("2"), Ok(WindowScale::Factor(2)));
+        assert!(parse_window_scale("0").is_err());
+    }
+}
@@ -0,0 +1,121 @@
+use anyhow::{anyhow, Result};
+
+/// The region of the logical canvas that is rendered locally.
+///
+/// Several servers can each render one region of a large logical canvas, for example on a video wall.
+/// Clients always use logical coordinates, which are translated into the local frame buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Viewport {
+    /// Logical position of the viewport’s left edge.
+    pub x: u16,
+    /// Logical position of the viewport’s top edge.
+    pub y: u16,
+    /// Viewport width, which is also the local frame buffer width.
+    pub width: u16,
+    /// Viewport height, which is also the local frame buffer height.
+    pub height: u16,
+    /// Width of the whole logical canvas.
+    pub logical_width: u16,
+    /// Height of the whole logical canvas.
+    pub logical_height: u16,
+}
+
+impl Viewport {
+    /// A viewport showing the whole logical canvas.
+    pub fn full(width: u16, height: u16) -> Self {
+        Self {
+            x: 0,
+            y: 0,
+            width,
+            height,
+            logical_width: width,
+            logical_height: height,
+        }
+    }
+
+    /// A viewport showing a region of the logical canvas, which must lie entirely within it.
+    pub fn new(
+        x: u16,
+        y: u16,
+        width: u16,
+        height: u16,
+        logical_width: u16,
+        logical_height: u16,
+    ) -> Result<Self> {
+        if width == 0
+            || height == 0
+            || x as u32 + width as u32 > logical_width as u32
+            || y as u32 + height as u32 > logical_height as u32
+        {
+            return Err(anyhow!(
+                "viewport {}x{} at {},{} does not fit into the {}x{} canvas",
+                width,
+                height,
+                x,
+                y,
+                logical_width,
+                logical_height
+            ));
+        }
+        Ok(Self {
+            x,
+            y,
+            width,
+            height,
+            logical_width,
+            logical_height,
+        })
+    }
+
+    /// Whether a logical position is on the logical canvas.
+    pub fn contains_logical(&self, x: u16, y: u16) -> bool {
+        x < self.logical_width && y < self.logical_height
+    }
+
+    /// Translate a logical position into the local frame buffer.
+    ///
+    /// Returns [`None`] if the position is outside the viewport.
+    pub fn to_local(&self, x: u16, y: u16) -> Option<(u16, u16)> {
+        let local_x = x.checked_sub(self.x)?;
+        let local_y = y.checked_sub(self.y)?;
+        (local_x < self.width && local_y < self.height).then_some((local_x, local_y))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn translates_positions_at_the_viewport_edges() {
+        // The right half of a 200x100 canvas, minus a 10 pixel border at the top and bottom.
+        let viewport = Viewport::new(100, 10, 100, 80, 200, 100).unwrap();
+        assert_eq!(viewport.to_local(100, 10), Some((0, 0)));
+        assert_eq!(viewport.to_local(199, 10), Some((99, 0)));
+        assert_eq!(viewport.to_local(100, 89), Some((0, 79)));
+        assert_eq!(viewport.to_local(199, 89), Some((99, 79)));
+
+        // Just outside each edge.
+        assert_eq!(viewport.to_local(99, 10), None);
+        assert_eq!(viewport.to_local(200, 10), None);
+        assert_eq!(viewport.to_local(100, 9), None);
+        assert_eq!(viewport.to_local(100, 90), None);
+        assert_eq!(viewport.to_local(0, 0), None);
+        assert_eq!(viewport.to_local(u16::MAX, u16::MAX), None);
+
+        // Positions outside the viewport are still on the logical canvas.
+        assert!(viewport.contains_logical(0, 0));
+        assert!(viewport.contains_logical(199, 99));
+        assert!(!viewport.contains_logical(200, 0));
+        assert!(!viewport.contains_logical(0, 100));
+    }
+
+    #[test]
+    fn viewports_must_fit_into_the_canvas() {
+        assert!(Viewport::new(0, 0, 200, 100, 200, 100).is_ok());
+        assert!(Viewport::new(1, 0, 200, 100, 200, 100).is_err());
+        assert!(Viewport::new(0, 1, 200, 100, 200, 100).is_err());
+        assert!(Viewport::new(0, 0, 0, 100, 200, 100).is_err());
+        assert!(Viewport::new(u16::MAX, 0, 1, 1, u16::MAX, 1).is_err());
+    }
+}
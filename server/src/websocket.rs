@@ -0,0 +1,223 @@
+//! A WebSocket server broadcasting the canvas to spectators, for example a web page showing it live.
+//!
+//! Every spectator first receives the whole frame buffer, then the pixels that changed in each tick.
+//! All messages are binary, with big endian numbers:
+//!
+//! | Message | Layout                                                                |
+//! | ------- | --------------------------------------------------------------------- |
+//! | Frame   | `00`, width and height (2 bytes each), RGB of all pixels in rows      |
+//! | Delta   | `01`, pixel count (4 bytes), X and Y (2 bytes each) and RGB per pixel |
+//!
+//! A frame is also sent instead of a delta that would be larger, and to spectators that fell behind.
+//! Messages from spectators are ignored, apart from close requests.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{ConnectInfo, State};
+use axum::response::Response;
+use axum::Router;
+use parking_lot::Mutex;
+use tokio::net::TcpListener;
+use tokio::sync::broadcast::{self, error::RecvError, Receiver, Sender};
+use tracing::{debug, info};
+
+use crate::canvas::Canvas;
+use crate::http::serve;
+use crate::sink::COLOR_SIZE;
+
+/// How often changed pixels are broadcast.
+const BROADCAST_TICK: Duration = Duration::from_millis(100);
+
+/// Number of messages buffered per spectator; spectators falling further behind are sent a whole frame instead.
+const BROADCAST_CAPACITY: usize = 16;
+
+/// Number of pixels that are compared as a whole before looking for the changed pixels among them.
+const DIFF_CHUNK_PIXELS: usize = 64;
+
+const FRAME_MESSAGE: u8 = 0x00;
+const DELTA_MESSAGE: u8 = 0x01;
+
+/// The frame buffer last broadcast, along with the channel the following deltas are sent on.
+struct Broadcast {
+    latest: Mutex<Arc<Vec<u8>>>,
+    sender: Sender<Arc<Vec<u8>>>,
+    width: u16,
+    height: u16,
+}
+
+impl Broadcast {
+    /// Start receiving deltas, along with the frame buffer they apply to.
+    fn subscribe(&self) -> (Receiver<Arc<Vec<u8>>>, Arc<Vec<u8>>) {
+        let latest = self.latest.lock();
+        (self.sender.subscribe(), latest.clone())
+    }
+}
+
+/// Broadcast the canvas to WebSocket spectators on the given address until an error occurs.
+pub async fn websocket_server(address: SocketAddr, canvas: Canvas) -> Result<()> {
+    let listener = TcpListener::bind(address).await?;
+    info!("broadcasting the canvas on ws://{}", address);
+    let (sender, _) = broadcast::channel(BROADCAST_CAPACITY);
+    let broadcast = Arc::new(Broadcast {
+        latest: Mutex::new(Arc::new(canvas.frame_copy())),
+        sender,
+        width: canvas.width,
+        height: canvas.height,
+    });
+    tokio::spawn(broadcast_changes(canvas, broadcast.clone()));
+    // Spectators may connect on any path.
+    let router = Router::new().fallback(spectate).with_state(broadcast);
+    serve(listener, router).await
+}
+
+/// Send the changed pixels to all spectators once per tick.
+/// Without spectators, the canvas is not compared at all; the next delta then covers everything since the last broadcast.
+async fn broadcast_changes(canvas: Canvas, broadcast: Arc<Broadcast>) {
+    let mut interval = tokio::time::interval(BROADCAST_TICK);
+    loop {
+        interval.tick().await;
+        if broadcast.sender.receiver_count() == 0 {
+            continue;
+        }
+        let frame = canvas.frame_copy();
+        let mut latest = broadcast.latest.lock();
+        let Some(delta) = delta_message(&latest, &frame, canvas.width) else {
+            continue;
+        };
+        let message = if delta.len() > frame.len() / COLOR_SIZE * 3 {
+            frame_message(&frame, canvas.width, canvas.height)
+        } else {
+            delta
+        };
+        // Sending only fails without spectators, who get the latest frame when they connect.
+        let _ = broadcast.sender.send(Arc::new(message));
+        *latest = Arc::new(frame);
+    }
+}
+
+/// Encode a whole frame buffer.
+fn frame_message(frame: &[u8], width: u16, height: u16) -> Vec<u8> {
+    let mut message = Vec::with_capacity(5 + frame.len() / COLOR_SIZE * 3);
+    message.push(FRAME_MESSAGE);
+    message.extend_from_slice(&width.to_be_bytes());
+    message.extend_from_slice(&height.to_be_bytes());
+    for pixel in frame.chunks_exact(COLOR_SIZE) {
+        message.extend_from_slice(&pixel[..3]);
+    }
+    message
+}
+
+/// Encode the pixels whose color differs between two frame buffers, or [`None`] if none does.
+fn delta_message(previous: &[u8], frame: &[u8], width: u16) -> Option<Vec<u8>> {
+    let mut message = vec![DELTA_MESSAGE, 0, 0, 0, 0];
+    let mut count: u32 = 0;
+    let chunk_size = DIFF_CHUNK_PIXELS * COLOR_SIZE;
+    let chunks = previous.chunks(chunk_size).zip(frame.chunks(chunk_size));
+    for (chunk_index, (previous, current)) in chunks.enumerate() {
+        if previous == current {
+            continue;
+        }
+        let pixels = previous
+            .chunks_exact(COLOR_SIZE)
+            .zip(current.chunks_exact(COLOR_SIZE));
+        for (pixel_index, (previous, current)) in pixels.enumerate() {
+            if previous[..3] == current[..3] {
+                continue;
+            }
+            let pixel = chunk_index * DIFF_CHUNK_PIXELS + pixel_index;
+            let (x, y) = (pixel % width as usize, pixel / width as usize);
+            message.extend_from_slice(&(x as u16).to_be_bytes());
+            message.extend_from_slice(&(y as u16).to_be_bytes());
+            message.extend_from_slice(&current[..3]);
+            count += 1;
+        }
+    }
+    message[1..5].copy_from_slice(&count.to_be_bytes());
+    (count > 0).then_some(message)
+}
+
+async fn spectate(
+    State(broadcast): State<Arc<Broadcast>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    upgrade.on_upgrade(move |socket| async move {
+        if let Err(why) = watch(socket, &broadcast).await {
+            debug!("spectator connection from {} failed: {}", peer, why);
+        }
+    })
+}
+
+/// Send the frame buffer and then each delta to a spectator until either side closes the connection.
+async fn watch(mut socket: WebSocket, broadcast: &Broadcast) -> Result<()> {
+    let (mut receiver, frame) = broadcast.subscribe();
+    let message = frame_message(&frame, broadcast.width, broadcast.height);
+    socket.send(Message::Binary(message)).await?;
+    loop {
+        tokio::select! {
+            message = receiver.recv() => match message {
+                Ok(message) => socket.send(Message::Binary(message.to_vec())).await?,
+                Err(RecvError::Lagged(_)) => {
+                    // Missed deltas cannot be recovered, so start over from the latest frame.
+                    let frame;
+                    (receiver, frame) = broadcast.subscribe();
+                    let message = frame_message(&frame, broadcast.width, broadcast.height);
+                    socket.send(Message::Binary(message)).await?;
+                }
+                Err(RecvError::Closed) => return Ok(()),
+            },
+            // Close requests and pings are answered by the WebSocket implementation.
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | None => return Ok(()),
+                Some(Ok(_)) => {}
+                Some(Err(why)) => return Err(why.into()),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_are_encoded_without_alpha() {
+        let frame = [1, 2, 3, 0xff, 4, 5, 6, 0xff];
+        assert_eq!(
+            frame_message(&frame, 2, 1),
+            [FRAME_MESSAGE, 0, 2, 0, 1, 1, 2, 3, 4, 5, 6]
+        );
+    }
+
+    #[test]
+    fn deltas_contain_only_changed_pixels() {
+        let (width, height) = (10u16, 10u16);
+        let previous = vec![0; usize::from(width) * usize::from(height) * COLOR_SIZE];
+        assert_eq!(delta_message(&previous, &previous, width), None);
+
+        let mut frame = previous.clone();
+        // Alpha changes are not visible to spectators.
+        frame[3] = 0xff;
+        assert_eq!(delta_message(&previous, &frame, width), None);
+
+        // The second pixel is in the first compared chunk, the last one in the second.
+        frame[COLOR_SIZE..COLOR_SIZE + 3].copy_from_slice(&[0xff, 0, 0]);
+        let last = frame.len() - COLOR_SIZE;
+        frame[last..last + 3].copy_from_slice(&[0, 0x80, 0xff]);
+        assert_eq!(
+            delta_message(&previous, &frame, width),
+            Some(
+                [
+                    &[DELTA_MESSAGE, 0, 0, 0, 2][..],
+                    &[0, 1, 0, 0, 0xff, 0, 0],
+                    &[0, 9, 0, 9, 0, 0x80, 0xff],
+                ]
+                .concat()
+            )
+        );
+    }
+}
@@ -0,0 +1,75 @@
+//! Bounded pools of tasks applying the packets of one source, see [`PacketWorkers`].
+//!
+//! Spawning a task per packet lets a single busy capture device create tasks faster than they finish, and those tasks
+//! compete with the other devices for the runtime. A fixed pool per device with bounded queues keeps memory and
+//! scheduling predictable instead: a device that is flooded only slows down its own capture, and the kernel drops what
+//! it cannot keep up with.
+//!
+//! `cargo bench -p server --bench ingestion` compares both on an in-memory canvas. On a single core, 4 workers applied
+//! about 1.5 million set pixel packets per second from 64 sources, against 0.65 million with a task per packet, while
+//! applying all packets inline on one task managed 4.3 million. Handing a packet to another task costs more than
+//! applying it, so more workers only pay off with several cores, and sequential sources are still handled inline.
+
+use std::hash::{BuildHasher, BuildHasherDefault, DefaultHasher};
+
+use tokio::sync::mpsc::{self, Sender};
+use tokio::task::JoinHandle;
+use tracing::Instrument;
+
+use crate::source::ReceivedPacket;
+
+/// Default number of workers per source.
+pub const DEFAULT_WORKERS: usize = 4;
+
+/// Number of packets each worker queues before dispatching waits for it.
+pub const WORKER_QUEUE_CAPACITY: usize = 1024;
+
+/// A fixed number of tasks applying packets from one source, each fed through a bounded queue.
+///
+/// Packets are assigned to workers by their sender address, so the packets of each client are applied in the order
+/// they were received, while different clients are handled in parallel.
+#[derive(Debug)]
+pub struct PacketWorkers {
+    senders: Vec<Sender<ReceivedPacket>>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl PacketWorkers {
+    /// Start `count` workers calling the handler with each packet, in the current tracing span.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `count` is zero.
+    pub fn start(count: usize, handler: impl Fn(ReceivedPacket) + Clone + Send + 'static) -> Self {
+        assert!(count > 0, "at least one worker is required");
+        let (senders, tasks) = (0..count)
+            .map(|_| {
+                let (sender, mut receiver) = mpsc::channel(WORKER_QUEUE_CAPACITY);
+                let handler = handler.clone();
+                let task = async move {
+                    while let Some(request) = receiver.recv().await {
+                        handler(request);
+                    }
+                };
+                (sender, tokio::spawn(task.in_current_span()))
+            })
+            .unzip();
+        Self { senders, tasks }
+    }
+
+    /// Queue a packet on the worker responsible for its sender, waiting while that worker's queue is full.
+    pub async fn dispatch(&self, request: ReceivedPacket) {
+        let hash = BuildHasherDefault::<DefaultHasher>::default().hash_one(request.source);
+        let sender = &self.senders[hash as usize % self.senders.len()];
+        // Workers only stop once all senders are gone, so sending cannot fail.
+        let _ = sender.send(request).await;
+    }
+
+    /// Wait until all queued packets have been applied and the workers stopped.
+    pub async fn finish(self) {
+        drop(self.senders);
+        for task in self.tasks {
+            let _ = task.await;
+        }
+    }
+}